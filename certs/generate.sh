@@ -0,0 +1,31 @@
+#!/usr/bin/env sh
+# Generates the self-signed certificates used by the tests and for local development.
+# A CA signs a server certificate for localhost/127.0.0.1 and a client certificate
+# for mutual TLS. A second, unrelated CA is used to test certificate pinning.
+set -e
+cd "$(dirname "$0")"
+
+DAYS=36500
+
+ca() {
+    openssl req -x509 -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+        -keyout "$1.key" -out "$1.pem" -days $DAYS -subj "/CN=$2" \
+        -addext "basicConstraints=critical,CA:TRUE" \
+        -addext "keyUsage=critical,keyCertSign,cRLSign"
+}
+
+leaf() {
+    openssl req -newkey ec -pkeyopt ec_paramgen_curve:prime256v1 -nodes \
+        -keyout "$1.key" -out "$1.csr" -subj "/CN=$2"
+    printf "basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=%s\n%s" \
+        "$3" "$4" > "$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial \
+        -out "$1.pem" -days $DAYS -extfile "$1.ext"
+    rm "$1.csr" "$1.ext"
+}
+
+ca ca "verifile test ca"
+ca other-ca "verifile other ca"
+leaf server localhost serverAuth "subjectAltName=DNS:localhost,IP:127.0.0.1"
+leaf client alice clientAuth ""
+rm -f ca.srl other-ca.key
@@ -1,17 +1,39 @@
 use clap::Parser;
+use common::compression::Compression;
+use common::protocol::Role;
+use common::tls;
+use common::transport::ClientTls;
+use std::fmt;
 use std::fmt::Debug;
 use std::str::FromStr;
+use verifile_client::crypto::KeyMaterial;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub enum Action {
+    #[default]
     Send,
     Download(usize),
+    DownloadAll,
+    List,
+    Grant,
+    Receipt,
+    Export(usize),
+    VerifyProof,
+    Delete,
+    Sessions,
+    Sync,
+    Diff,
 }
 
-impl Default for Action {
-    fn default() -> Self {
-        Self::Send
-    }
+/// DiffTarget is what the last upload is compared to with the 'diff' action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffTarget {
+    /// a local directory, to tell what changed in it since the last upload
+    Dir(String),
+    /// another saved batch
+    Batch(String),
+    /// another session on the server
+    Session(String),
 }
 
 impl FromStr for Action {
@@ -20,30 +42,58 @@ impl FromStr for Action {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "send" => Ok(Action::Send),
+            "list" => Ok(Action::List),
+            "grant" => Ok(Action::Grant),
+            "receipt" => Ok(Action::Receipt),
+            "verify-proof" => Ok(Action::VerifyProof),
+            "delete" => Ok(Action::Delete),
+            "sessions" => Ok(Action::Sessions),
+            "sync" => Ok(Action::Sync),
+            "diff" => Ok(Action::Diff),
+            "download-all" => Ok(Action::DownloadAll),
             _ if s.starts_with("download-") => {
                 let number = s
                     .split('-')
-                    .last()
+                    .next_back()
                     .unwrap()
                     .parse::<usize>()
                     .map_err(|_| "Invalid number")?;
                 Ok(Action::Download(number))
             }
+            _ if s.starts_with("export-") => {
+                let number = s
+                    .split('-')
+                    .next_back()
+                    .unwrap()
+                    .parse::<usize>()
+                    .map_err(|_| "Invalid number")?;
+                Ok(Action::Export(number))
+            }
             _ => Err(format!("{} is not a valid Action", s)),
         }
     }
 }
 
-impl ToString for Action {
-    fn to_string(&self) -> String {
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Action::Send => "send".to_string(),
-            Action::Download(n) => n.to_string(),
+            Action::Send => write!(f, "send"),
+            Action::Download(n) => write!(f, "{}", n),
+            Action::DownloadAll => write!(f, "download-all"),
+            Action::List => write!(f, "list"),
+            Action::Grant => write!(f, "grant"),
+            Action::Receipt => write!(f, "receipt"),
+            Action::Export(n) => write!(f, "export-{}", n),
+            Action::VerifyProof => write!(f, "verify-proof"),
+            Action::Delete => write!(f, "delete"),
+            Action::Sessions => write!(f, "sessions"),
+            Action::Sync => write!(f, "sync"),
+            Action::Diff => write!(f, "diff"),
         }
     }
 }
 
-#[derive(Parser, Debug, Default)]
+#[derive(Parser, Default)]
 #[clap(author = "Author Name", version, about)]
 pub struct Argument {
     #[clap(short, long)]
@@ -51,6 +101,123 @@ pub struct Argument {
 
     #[clap(short, long, value_delimiter = ',')]
     file_names: Option<Vec<String>>,
+
+    /// address of the server
+    #[clap(long, env = "VERIFILE_ADDRESS", default_value_t = String::from(common::SERVER_ADDRESS))]
+    address: String,
+
+    /// addresses of replicas of the server that reads fail over to, separated by commas
+    #[clap(long, value_delimiter = ',')]
+    replicas: Vec<String>,
+
+    /// addresses of the servers each file is spread across with erasure coding, separated by
+    /// commas, instead of the server at --address. Only for the 'send' and 'download' actions
+    #[clap(long, value_delimiter = ',', requires = "data_shards")]
+    servers: Vec<String>,
+
+    /// number of the servers given with --servers whose shards are enough to rebuild a file
+    #[clap(long, requires = "servers")]
+    data_shards: Option<usize>,
+
+    /// passphrase used to encrypt files before upload and decrypt them after download
+    #[clap(long, env = "VERIFILE_PASSPHRASE", hide_env_values = true)]
+    passphrase: Option<String>,
+
+    /// path to a file holding a raw 32 byte key, used instead of a passphrase
+    #[clap(long)]
+    key_file: Option<String>,
+
+    /// PEM file with the CA the server certificate must be signed by, enables TLS
+    #[clap(long)]
+    tls_ca: Option<String>,
+
+    /// PEM file with the client certificate presented to the server for mutual TLS
+    #[clap(long, requires = "tls_key", requires = "tls_ca")]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the client certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// name the server certificate must be valid for
+    #[clap(long, default_value = "localhost")]
+    tls_server_name: String,
+
+    /// API token sent to the server to authenticate the user
+    #[clap(long, env = "VERIFILE_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// user to give access to the session of the last upload, with the 'grant' action
+    #[clap(long)]
+    user: Option<String>,
+
+    /// access to give to the user, either 'owner' or 'read-only'
+    #[clap(long, default_value = "read-only")]
+    role: Option<Role>,
+
+    /// hex encoded ed25519 key the server must sign upload receipts with
+    #[clap(long)]
+    server_public_key: Option<String>,
+
+    /// file the proof is written to with the 'export' action. A '.cbor' file gets
+    /// the binary encoding, anything else gets JSON. With the 'download-all' action,
+    /// the directory the files are written to
+    #[clap(long)]
+    output: Option<String>,
+
+    /// proof file checked with the 'verify-proof' action, in either encoding
+    #[clap(long)]
+    proof: Option<String>,
+
+    /// codec large transfers are compressed with if the server supports it,
+    /// either 'zstd', 'gzip' or 'none'
+    #[clap(long, default_value = "zstd")]
+    compression: String,
+
+    /// directory mirrored to the session of the last upload with the 'sync' action,
+    /// or compared to the last upload with the 'diff' action
+    #[clap(long)]
+    dir: Option<String>,
+
+    /// saved batch compared to the last upload with the 'diff' action
+    #[clap(long)]
+    batch: Option<String>,
+
+    /// session on the server compared to the one of the last upload with the 'diff' action
+    #[clap(long)]
+    session: Option<String>,
+}
+
+impl Debug for Argument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Argument")
+            .field("action", &self.action)
+            .field("file_names", &self.file_names)
+            .field("address", &self.address)
+            .field("replicas", &self.replicas)
+            .field("servers", &self.servers)
+            .field("data_shards", &self.data_shards)
+            .field(
+                "passphrase",
+                &self.passphrase.as_ref().map(|_| "<redacted>"),
+            )
+            .field("key_file", &self.key_file)
+            .field("tls_ca", &self.tls_ca)
+            .field("tls_cert", &self.tls_cert)
+            .field("tls_key", &self.tls_key)
+            .field("tls_server_name", &self.tls_server_name)
+            .field("token", &self.token.as_ref().map(|_| "<redacted>"))
+            .field("user", &self.user)
+            .field("role", &self.role)
+            .field("server_public_key", &self.server_public_key)
+            .field("output", &self.output)
+            .field("proof", &self.proof)
+            .field("compression", &self.compression)
+            .field("dir", &self.dir)
+            .field("batch", &self.batch)
+            .field("session", &self.session)
+            .finish()
+    }
 }
 
 impl Argument {
@@ -64,6 +231,93 @@ impl Argument {
             .expect("file names should not be absent")
     }
 
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    pub fn replicas(&self) -> Vec<String> {
+        self.replicas.clone()
+    }
+
+    /// sharding returns the servers files are spread across and the number of them
+    /// enough to rebuild a file, if files are spread
+    pub fn sharding(&self) -> Option<(Vec<String>, usize)> {
+        self.data_shards
+            .map(|data_shards| (self.servers.clone(), data_shards))
+    }
+
+    /// key_material returns the key used for end-to-end encryption, if any was given
+    pub fn key_material(&self) -> Option<KeyMaterial> {
+        match (&self.passphrase, &self.key_file) {
+            (Some(passphrase), _) => Some(KeyMaterial::Passphrase(passphrase.clone())),
+            (None, Some(path)) => Some(KeyMaterial::KeyFile(path.clone())),
+            (None, None) => None,
+        }
+    }
+
+    pub fn token(&self) -> Option<String> {
+        self.token.clone()
+    }
+
+    pub fn server_public_key(&self) -> Option<String> {
+        self.server_public_key.clone()
+    }
+
+    /// grant returns the user and role to give access to with the 'grant' action
+    pub fn grant(&self) -> (String, Role) {
+        (
+            self.user.clone().expect("user should not be absent"),
+            self.role.unwrap_or(Role::ReadOnly),
+        )
+    }
+
+    /// output returns the file to export a proof to, or the directory to download all the files to
+    pub fn output(&self) -> String {
+        self.output.clone().expect("output should not be absent")
+    }
+
+    /// proof returns the proof file to check with the 'verify-proof' action
+    pub fn proof(&self) -> String {
+        self.proof.clone().expect("proof should not be absent")
+    }
+
+    /// dir returns the directory to mirror with the 'sync' action
+    pub fn dir(&self) -> String {
+        self.dir.clone().expect("dir should not be absent")
+    }
+
+    /// diff_target returns what to compare the last upload to with the 'diff' action
+    pub fn diff_target(&self) -> DiffTarget {
+        match (&self.dir, &self.batch, &self.session) {
+            (Some(dir), _, _) => DiffTarget::Dir(dir.clone()),
+            (_, Some(batch), _) => DiffTarget::Batch(batch.clone()),
+            (_, _, Some(session)) => DiffTarget::Session(session.clone()),
+            _ => panic!("diff target should not be absent"),
+        }
+    }
+
+    /// compression returns the codec to ask the server for, None to turn compression off
+    pub fn compression(&self) -> Result<Option<Compression>, String> {
+        match self.compression.as_str() {
+            "none" => Ok(None),
+            name => Compression::from_str(name).map(Some),
+        }
+    }
+
+    /// tls builds the TLS settings of the client if a CA was given
+    pub fn tls(&self) -> Result<Option<ClientTls>, String> {
+        let Some(ca) = &self.tls_ca else {
+            return Ok(None);
+        };
+
+        let client_auth = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some((cert.as_str(), key.as_str())),
+            _ => None,
+        };
+        let config = tls::client_config(ca, client_auth)?;
+        ClientTls::new(config, &self.tls_server_name).map(Some)
+    }
+
     // TODO(production): should add more validations and file sanitization
     fn validate_file_names(&self) -> Result<(), String> {
         for name in self
@@ -84,6 +338,11 @@ impl Argument {
 
     /// validate validates the Argument instance
     pub fn validate(&self) -> Result<(), String> {
+        if self.passphrase.is_some() && self.key_file.is_some() {
+            return Err(String::from(
+                "only one of a passphrase or a key file should be given",
+            ));
+        }
         if let Action::Send = self.action {
             if self.file_names.is_none() {
                 return Err(String::from(
@@ -92,6 +351,62 @@ impl Argument {
             }
             self.validate_file_names()?;
         }
+        if let Action::Export(_) = self.action {
+            if self.output.is_none() {
+                return Err(String::from(
+                    "an output file should be given with the 'export' action",
+                ));
+            }
+        }
+        if let Action::DownloadAll = self.action {
+            if self.output.is_none() {
+                return Err(String::from(
+                    "an output directory should be given with the 'download-all' action",
+                ));
+            }
+        }
+        if let Action::Sync = self.action {
+            if self.dir.is_none() {
+                return Err(String::from(
+                    "a directory should be given with the 'sync' action",
+                ));
+            }
+        }
+        if let Action::Diff = self.action {
+            let targets = [&self.dir, &self.batch, &self.session]
+                .iter()
+                .filter(|target| target.is_some())
+                .count();
+            if targets != 1 {
+                return Err(String::from(
+                    "one of a directory, a batch or a session should be given with the 'diff' action",
+                ));
+            }
+        }
+        if let Action::VerifyProof = self.action {
+            if self.proof.is_none() || self.file_names.as_ref().is_none_or(|f| f.len() != 1) {
+                return Err(String::from(
+                    "a proof and a single file should be given with the 'verify-proof' action",
+                ));
+            }
+        }
+        if self.data_shards.is_some()
+            && !matches!(
+                self.action,
+                Action::Send | Action::Download(_) | Action::DownloadAll
+            )
+        {
+            return Err(String::from(
+                "files spread across servers can only be sent and downloaded",
+            ));
+        }
+        if let Action::Grant = self.action {
+            if self.user.is_none() {
+                return Err(String::from(
+                    "a user should be given with the 'grant' action",
+                ));
+            }
+        }
         Ok(())
     }
 }
@@ -106,9 +421,151 @@ mod test {
         let args = Argument {
             action: Default::default(),
             file_names: Some(file_names.clone()),
+            ..Default::default()
         };
 
         assert_eq!(args.file_names.unwrap(), file_names);
         assert_eq!(args.action.to_string(), Action::Send.to_string());
     }
+
+    #[test]
+    fn parsing_grant_works() {
+        let args =
+            Argument::parse_from(["client", "-a", "grant", "--user", "bob", "--role", "owner"]);
+        args.validate().unwrap();
+        assert_eq!(args.grant(), (String::from("bob"), Role::Owner));
+
+        let args = Argument::parse_from(["client", "-a", "grant"]);
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_export_and_verify_proof_works() {
+        let args = Argument::parse_from(["client", "-a", "export-2", "--output", "p.cbor"]);
+        args.validate().unwrap();
+        assert!(matches!(args.action(), Action::Export(2)));
+
+        let args = Argument::parse_from(["client", "-a", "export-2"]);
+        assert!(args.validate().is_err());
+
+        let args = Argument::parse_from(["client", "-a", "verify-proof", "--proof", "p.json"]);
+        assert!(args.validate().is_err());
+        let args = Argument::parse_from([
+            "client",
+            "-a",
+            "verify-proof",
+            "--proof",
+            "p.json",
+            "-f",
+            "cv.txt",
+        ]);
+        args.validate().unwrap();
+    }
+
+    #[test]
+    fn parsing_download_all_works() {
+        let args = Argument::parse_from(["client", "-a", "download-all", "--output", "restored"]);
+        args.validate().unwrap();
+        assert!(matches!(args.action(), Action::DownloadAll));
+
+        let args = Argument::parse_from(["client", "-a", "download-all"]);
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_sync_works() {
+        let args = Argument::parse_from(["client", "-a", "sync", "--dir", "project"]);
+        args.validate().unwrap();
+        assert!(matches!(args.action(), Action::Sync));
+        assert_eq!(args.dir(), "project");
+
+        let args = Argument::parse_from(["client", "-a", "sync"]);
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_diff_works() {
+        let args = Argument::parse_from(["client", "-a", "diff", "--dir", "project"]);
+        args.validate().unwrap();
+        assert_eq!(args.diff_target(), DiffTarget::Dir(String::from("project")));
+
+        let args = Argument::parse_from(["client", "-a", "diff", "--session", "abc"]);
+        args.validate().unwrap();
+        assert_eq!(args.diff_target(), DiffTarget::Session(String::from("abc")));
+
+        let args = Argument::parse_from(["client", "-a", "diff"]);
+        assert!(args.validate().is_err());
+        let args = Argument::parse_from(["client", "-a", "diff", "--dir", "a", "--batch", "b"]);
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_replicas_works() {
+        let args = Argument::parse_from([
+            "client",
+            "-a",
+            "list",
+            "--address",
+            "127.0.0.1:8000",
+            "--replicas",
+            "127.0.0.1:8001,127.0.0.1:8002",
+        ]);
+        assert_eq!(args.address(), "127.0.0.1:8000");
+        assert_eq!(args.replicas(), vec!["127.0.0.1:8001", "127.0.0.1:8002"]);
+    }
+
+    #[test]
+    fn parsing_sharding_works() {
+        let args = Argument::parse_from([
+            "client",
+            "-a",
+            "download-all",
+            "--output",
+            "restored",
+            "--servers",
+            "127.0.0.1:8000,127.0.0.1:8001,127.0.0.1:8002",
+            "--data-shards",
+            "2",
+        ]);
+        args.validate().unwrap();
+        let (servers, data_shards) = args.sharding().unwrap();
+        assert_eq!(servers.len(), 3);
+        assert_eq!(data_shards, 2);
+
+        assert!(Argument::try_parse_from(["client", "-a", "send", "--data-shards", "2"]).is_err());
+        let args = Argument::parse_from([
+            "client",
+            "-a",
+            "sessions",
+            "--servers",
+            "127.0.0.1:8000",
+            "--data-shards",
+            "1",
+        ]);
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn parsing_compression_works() {
+        let args = Argument::parse_from(["client", "-a", "list"]);
+        assert_eq!(args.compression().unwrap(), Some(Compression::Zstd));
+
+        let args = Argument::parse_from(["client", "-a", "list", "--compression", "none"]);
+        assert_eq!(args.compression().unwrap(), None);
+
+        let args = Argument::parse_from(["client", "-a", "list", "--compression", "lz4"]);
+        assert!(args.compression().is_err());
+    }
+
+    #[test]
+    fn passphrase_and_key_file_are_exclusive() {
+        let args = Argument {
+            file_names: Some(vec![String::from("dummy1.txt")]),
+            passphrase: Some(String::from("correct horse")),
+            key_file: Some(String::from("key.bin")),
+            ..Default::default()
+        };
+
+        assert!(args.validate().is_err());
+    }
 }
@@ -1,303 +1,487 @@
-use common::model::file_info::FileInfo;
-use common::model::merkle::{MerkleProof, MerkleTree};
-use common::SERVER_ADDRESS;
+use crate::args::DiffTarget;
+use common::diff::FileDiff;
+use common::model::merkle::hash_leaf_reader;
+use common::model::proof_file::ProofFile;
+use common::model::receipt::SignedReceipt;
+use common::protocol::{FileEntry, Role, SessionInfo};
 use log::{error, info};
-use serde::{Deserialize, Serialize};
-use sha256::digest;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::str::FromStr;
+use std::path::{Component, Path, PathBuf};
+use verifile_client::{Batch, DownloadedFile, ReceiptCheck, ShardedBatch, ShardedClient};
 
 const FILES_DATA_NAME: &str = "merkle.json";
 
-#[derive(Serialize, Deserialize)]
-struct DiskData {
-    merkle_root: String,
-    files_count: usize,
+/// SHARDS_DATA_NAME is where the batch of the last upload spread across servers is saved
+const SHARDS_DATA_NAME: &str = "shards.json";
+
+/// SERVER_KEYS_NAME is where the receipt keys of the servers are pinned by address, the first
+/// time a receipt of theirs is seen. Later receipts must be signed with the pinned key
+const SERVER_KEYS_NAME: &str = "server_keys.json";
+
+/// load_server_keys loads the pinned receipt keys of the servers by address
+pub fn load_server_keys() -> Result<HashMap<String, String>, String> {
+    let path = Path::new(SERVER_KEYS_NAME);
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&json).map_err(|e| format!("{} is malformed: {}", path.display(), e))
 }
 
-impl DiskData {
-    fn build(merkle_root: String, files_count: usize) -> Self {
-        Self {
-            merkle_root,
-            files_count,
+/// pin_server_keys pins the keys the servers at the addresses signed receipts with,
+/// for the servers that have no pinned key yet
+fn pin_server_keys(signers: Vec<(&str, String)>) -> Result<(), String> {
+    let mut keys = load_server_keys()?;
+    let mut pinned = false;
+    for (address, public_key) in signers {
+        if keys.contains_key(address) {
+            continue;
         }
+        info!(
+            "Pinned key {} of server {}, its receipts must be signed with it from now on",
+            public_key, address
+        );
+        keys.insert(address.to_string(), public_key);
+        pinned = true;
+    }
+    if !pinned {
+        return Ok(());
     }
+    let json = serde_json::to_string(&keys).expect("keys serialization should not fail");
+    std::fs::write(SERVER_KEYS_NAME, json)
+        .map_err(|e| format!("failed to save {}: {}", SERVER_KEYS_NAME, e))
 }
 
-impl FromStr for DiskData {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        serde_json::from_str(s)
-            .map_err(|_| String::from("disk data deserialization should not fail"))
-    }
+/// save_batch saves the batch of the last upload to disk
+fn save_batch<B: Serialize>(path: &Path, batch: &B) -> Result<(), String> {
+    let json = serde_json::to_string(batch).expect("batch serialization should not fail");
+    std::fs::write(path, json).map_err(|e| format!("failed to save {}: {}", path.display(), e))
+}
+
+/// load_batch loads the batch saved by the last upload from disk
+fn load_batch<B: DeserializeOwned>(path: &Path) -> Result<B, String> {
+    let json = std::fs::read_to_string(path).map_err(|_| {
+        format!(
+            "{} not found, the files should be sent first",
+            path.display()
+        )
+    })?;
+    serde_json::from_str(&json).map_err(|e| format!("{} is malformed: {}", path.display(), e))
 }
 
-impl ToString for DiskData {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).expect("disk data deserialization should not fail")
+/// restore_path is where a file uploaded under the name is restored in the directory.
+/// Only the normal components of the name are kept, so that a file cannot be written
+/// outside of the directory
+fn restore_path(dir: &Path, name: &str) -> Option<PathBuf> {
+    let relative = Path::new(name)
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(part) => Some(part),
+            _ => None,
+        })
+        .collect::<PathBuf>();
+    (!relative.as_os_str().is_empty()).then(|| dir.join(relative))
+}
+
+/// local_files lists the files under the directory with their path relative to it, sorted by
+/// path so that a directory is always uploaded in the same order. The skipped file is left out
+fn local_files(dir: &Path, skipped: Option<&Path>) -> std::io::Result<Vec<(String, PathBuf)>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in std::fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.is_file() && path.canonicalize().ok().as_deref() != skipped {
+                let name = path
+                    .strip_prefix(dir)
+                    .expect("listed paths should be under the directory")
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                files.push((name, path));
+            }
+        }
     }
+    files.sort();
+    Ok(files)
 }
 
-pub struct Client {
-    files: Vec<FileInfo>,
-    file_names: Vec<String>,
-    files_data: Vec<Vec<u8>>,
+/// Restored counts the downloaded files restored under the output directory and reports
+/// the ones that could not be
+struct Restored<'a> {
+    output: &'a str,
     files_count: usize,
-    merkle_root: String,
-    downloaded_file: Vec<u8>,
+    failures: Vec<String>,
 }
 
-impl Client {
-    pub fn new() -> Self {
+impl<'a> Restored<'a> {
+    fn new(output: &'a str) -> Self {
         Self {
-            files: Vec::new(),
-            file_names: Vec::new(),
-            files_data: Vec::new(),
+            output,
             files_count: 0,
-            merkle_root: String::new(),
-            downloaded_file: Vec::new(),
+            failures: Vec::new(),
         }
     }
+
+    /// restore writes a downloaded file under the output directory with the layout it was
+    /// uploaded with, or records why it could not be
+    fn restore(&mut self, file: DownloadedFile) {
+        self.files_count += 1;
+        let restored = file.content.map_err(|e| e.to_string()).and_then(|content| {
+            let path = restore_path(Path::new(self.output), &file.name)
+                .ok_or_else(|| String::from("the name is not a valid path"))?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            std::fs::write(&path, content).map_err(|e| e.to_string())?;
+            Ok(path)
+        });
+        match restored {
+            Ok(path) => info!("Downloaded and verified {}", path.display()),
+            Err(e) => self
+                .failures
+                .push(format!("{} {}: {}", file.index, file.name, e)),
+        }
+    }
+
+    /// finish reports the files that could not be restored by index and name
+    fn finish(self) -> Result<(), Box<dyn Error>> {
+        if self.failures.is_empty() {
+            info!("Restored all {} files to {}", self.files_count, self.output);
+            return Ok(());
+        }
+        for failure in &self.failures {
+            error!("Failed to restore file {}", failure);
+        }
+        Err(format!(
+            "{} of {} files failed",
+            self.failures.len(),
+            self.files_count
+        )
+        .into())
+    }
+}
+
+/// Client is the command line front of the verifile client library: it keeps the batch
+/// of the last upload on disk and works with the files in the current directory
+pub struct Client {
+    inner: verifile_client::Client,
+    /// spreads files across servers instead of sending them to the inner client, if set
+    sharded: Option<ShardedClient>,
 }
 
-/// this implementation has methods concerned with sending files to the server
 impl Client {
-    /// load_files_into_memory loads the contents of the specified files into memory
-    pub fn load_files_into_memory(&mut self, file_names: Vec<String>) {
-        file_names
-            .into_iter()
-            .enumerate()
-            .for_each(|(index, file_name)| {
-                let mut file = File::open(&file_name).expect("file should be present");
-                let mut file_buf = Vec::new();
-                file.read_to_end(&mut file_buf).unwrap();
-                self.files.push(FileInfo::new(index, file_name, file_buf));
-            });
-
-        self.files_count = self.files.len();
+    pub fn new(inner: verifile_client::Client) -> Self {
+        Self {
+            inner,
+            sharded: None,
+        }
     }
 
-    /// build_merkle_tree_and_save_to_disk builds a merkle tree from the files
-    /// and saves the merkle root and the number of files to disk
-    pub fn build_merkle_tree_and_save_to_disk(&mut self) {
-        let bufs = self
-            .files
-            .iter()
-            .map(|file| file.content())
-            .collect::<Vec<Vec<u8>>>();
-        let merkle_tree = MerkleTree::from(bufs);
-        self.merkle_root = merkle_tree.root_hash();
-        let disk_json = DiskData::build(merkle_tree.root_hash(), self.files.len()).to_string();
-        let mut file = File::create(FILES_DATA_NAME).expect("json file creation should not fail");
-        file.write_all(disk_json.as_bytes())
-            .expect("writing data to the stream should not fail");
+    /// set_sharded makes the files be sent to and downloaded from several servers
+    pub fn set_sharded(&mut self, sharded: ShardedClient) {
+        self.sharded = Some(sharded);
     }
 
-    /// send_files_and_clear_file_data sends the files stored in memory to the server
-    /// over a TCP connection and consumes up the files in memory
-    pub fn send_files_and_clear_file_data(&mut self) {
-        let mut stream =
-            TcpStream::connect(SERVER_ADDRESS).expect("should connect to tcp server stream");
+    fn batch(&self) -> Result<Batch, String> {
+        load_batch(Path::new(FILES_DATA_NAME))
+    }
 
-        let files_json = serde_json::to_string(&self.files)
-            .expect("serializing the list of files should not fail");
-        stream
-            .write_all(files_json.as_bytes())
-            .expect("sending files over tcp stream should not fail");
+    /// pin_server_key pins the key the server signed the receipt of the batch with,
+    /// if it has no pinned key yet
+    fn pin_server_key(&self, batch: &Batch) -> Result<(), String> {
+        pin_server_keys(vec![(self.inner.address(), batch.receipt().public_key())])
+    }
+
+    /// prepare_and_send_files sends the files to the server, saves the batch
+    /// they were stored in and deletes the files from the client
+    pub async fn prepare_and_send_files(
+        &self,
+        file_names: Vec<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        match &self.sharded {
+            Some(sharded) => {
+                let batch = sharded.upload_paths(&file_names).await?;
+                save_batch(Path::new(SHARDS_DATA_NAME), &batch)?;
+                pin_server_keys(
+                    batch
+                        .shares()
+                        .iter()
+                        .map(|share| (share.address.as_str(), share.batch.receipt().public_key()))
+                        .collect(),
+                )?;
+            }
+            None => {
+                let batch = self.inner.upload_paths(&file_names).await?;
+                save_batch(Path::new(FILES_DATA_NAME), &batch)?;
+                self.pin_server_key(&batch)?;
+            }
+        }
 
-        self.files.iter().for_each(|file| {
-            std::fs::remove_file(file.name())
+        file_names.iter().for_each(|file_name| {
+            std::fs::remove_file(file_name)
                 .expect("removing file from the directory should not fail")
         });
-
-        info!("Files sent successfully");
+        Ok(())
     }
 
-    /// prepare_and_send_files validates the files, computes the merkle root,
-    /// sends the files to the server and deletes the files from the client
-    pub fn prepare_and_send_files(&mut self, file_names: Vec<String>) {
-        self.load_files_into_memory(file_names);
-        self.build_merkle_tree_and_save_to_disk();
-        self.send_files_and_clear_file_data();
+    /// download_verify_and_write_file downloads the file at the index, verifies it
+    /// against the saved merkle root and writes it under the name it was uploaded with,
+    /// kept inside the current directory
+    pub async fn download_verify_and_write_file(&self, index: usize) -> Result<(), Box<dyn Error>> {
+        let mut content = Vec::new();
+        let file_name = match &self.sharded {
+            Some(sharded) => {
+                let batch: ShardedBatch = load_batch(Path::new(SHARDS_DATA_NAME))?;
+                sharded.download(&batch, index, &mut content).await?
+            }
+            None => {
+                self.inner
+                    .download(&self.batch()?, index, &mut content)
+                    .await?
+            }
+        };
+
+        let path = restore_path(Path::new("."), &file_name)
+            .ok_or_else(|| format!("{} is not a valid file name", file_name))?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, content)?;
+        info!("Downloaded and verified {}", path.display());
+        Ok(())
     }
-}
 
-/// this implementation has methods concerned with receiving and verifying files from the server
-impl Client {
-    /// compute_merkle_root_from_proof computes the root of the merkle tree given the siblings
-    /// by walking up the merkle tree until the root.
-    /// Each node can either be a left or right node, compute the node hash with that information
-    fn compute_merkle_root_from_proof(&self, proof: &MerkleProof, index: usize) -> String {
-        let mut curr_hash = digest(proof.file_content());
-        let mut siblings = proof.siblings();
-        let mut curr_index = index;
-
-        siblings.sort_by(|(lvl1, _, _), (lvl2, _, _)| lvl2.cmp(&lvl1));
-
-        for (_, _, sibling_hash) in siblings {
-            curr_hash = if curr_index % 2 == 0 {
-                digest(format!("{}{}", curr_hash, sibling_hash))
-            } else {
-                digest(format!("{}{}", sibling_hash, curr_hash))
-            };
-            curr_index /= 2;
+    /// download_all downloads every file of the last upload, verifies them against the saved
+    /// merkle root, or rebuilds them from verified shards if they were spread across servers,
+    /// and restores them under the output directory. Files that are not spread are written
+    /// as they arrive
+    pub async fn download_all(&self, output: &str) -> Result<(), Box<dyn Error>> {
+        let mut restored = Restored::new(output);
+        match &self.sharded {
+            Some(sharded) => {
+                let batch: ShardedBatch = load_batch(Path::new(SHARDS_DATA_NAME))?;
+                for file in sharded.download_all(&batch).await? {
+                    restored.restore(file);
+                }
+            }
+            None => {
+                self.inner
+                    .download_all_with(&self.batch()?, |file| {
+                        restored.restore(file);
+                        Ok(())
+                    })
+                    .await?
+            }
         }
-
-        curr_hash
+        restored.finish()
     }
 
-    /// fetch_merkle_proof fetches the Merkle proof for a given file index from the server,
-    fn fetch_merkle_proof(&mut self, index: usize) -> Result<MerkleProof, String> {
-        let mut stream =
-            TcpStream::connect(SERVER_ADDRESS).expect("client should connect to the server stream");
-        stream
-            .write_all(&index.to_be_bytes())
-            .expect("file index should be sent to the server");
-
-        let mut json_proof_buf = Vec::new();
-        if let Err(e) = stream.read_to_end(&mut json_proof_buf) {
-            error!("Error reading merkle proof from server: {}", e);
-            return Err(format!("Error reading merkle proof from server: {}", e));
+    /// sync mirrors the files under the directory to the session of the last upload, sending
+    /// only the ones that changed since. Without a last upload the files are sent as a new
+    /// session. The files are named by their path in the directory and are kept on the client
+    pub async fn sync(&self, dir: &str) -> Result<(), Box<dyn Error>> {
+        let batch_path = Path::new(FILES_DATA_NAME);
+        let skipped = batch_path.canonicalize().ok();
+        let files = local_files(Path::new(dir), skipped.as_deref())?;
+        if files.is_empty() {
+            return Err(format!("{} has no files to sync", dir).into());
         }
 
-        let proof = serde_json::from_slice(&json_proof_buf)
-            .expect("merkle proof deserialization should not fail");
-
-        Ok(proof)
+        // unchanged files are only hashed, they are not read into memory
+        let batch = match batch_path.exists() {
+            true => self.inner.sync_paths(&self.batch()?, &files).await?,
+            false => {
+                let mut sources = Vec::with_capacity(files.len());
+                for (name, path) in files {
+                    sources.push((name, tokio::fs::File::open(path).await?));
+                }
+                self.inner.upload(sources).await?
+            }
+        };
+        save_batch(batch_path, &batch)?;
+        self.pin_server_key(&batch)?;
+        info!(
+            "Session {} holds the {} files of {} under merkle root {}",
+            batch.session_id(),
+            batch.files_count(),
+            dir,
+            batch.merkle_root()
+        );
+        Ok(())
     }
 
-    /// validate_file_index_and_update_root validates the requested file index and updates
-    /// the files_count and merkle_root fields if valid. Returns an error if the index is out of range.
-    fn validate_file_index_and_update_root(&mut self, index: usize) -> Result<(), String> {
-        let mut file = File::open(FILES_DATA_NAME)
-            .expect("json file holding merkle root should not fail to open");
+    /// diff compares the last upload to a local directory, another saved batch or another
+    /// session. Directories and batches are compared with the saved manifest, without the server
+    pub async fn diff(&self, target: DiffTarget) -> Result<Vec<FileDiff>, Box<dyn Error>> {
+        let batch = self.batch()?;
+        let diffs = match target {
+            DiffTarget::Dir(dir) => {
+                let skipped = Path::new(FILES_DATA_NAME).canonicalize().ok();
+                let files = local_files(Path::new(&dir), skipped.as_deref())?
+                    .into_iter()
+                    .map(|(name, path)| Ok((name, hash_leaf_reader(File::open(path)?)?)))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                batch.diff_files(&files)?
+            }
+            DiffTarget::Batch(path) => batch.diff(&load_batch(Path::new(&path))?)?,
+            DiffTarget::Session(session_id) => {
+                self.inner.diff(batch.session_id(), &session_id).await?
+            }
+        };
+        Ok(diffs)
+    }
 
-        let mut json_str = String::new();
-        file.read_to_string(&mut json_str)
-            .expect("merkle root file conversion to string should not fail");
+    /// list_files lists the files stored in the session of the last upload
+    pub async fn list_files(&self) -> Result<Vec<FileEntry>, Box<dyn Error>> {
+        let batch = self.batch()?;
+        Ok(self.inner.list(batch.session_id()).await?)
+    }
 
-        let data = DiskData::from_str(&json_str)?;
-        if index >= data.files_count {
-            return Err(String::from("file index to download is not available"));
-        }
-        self.files_count = data.files_count;
-        self.merkle_root = data.merkle_root;
+    /// grant_access gives another user access to the session of the last upload
+    pub async fn grant_access(&self, user: String, role: Role) -> Result<(), Box<dyn Error>> {
+        let batch = self.batch()?;
+        Ok(self.inner.grant(batch.session_id(), user, role).await?)
+    }
 
+    /// delete_session deletes the session of the last upload from the server, then its saved batch
+    pub async fn delete_session(&self) -> Result<(), Box<dyn Error>> {
+        let batch = self.batch()?;
+        self.inner.delete(batch.session_id()).await?;
+        std::fs::remove_file(FILES_DATA_NAME)?;
+        info!("Deleted session {}", batch.session_id());
         Ok(())
     }
 
-    /// download_file sends a download request to the server with the index, gets the
-    /// file and computes and compares the merkle root using the proof from the server
-    pub fn download_verify_and_write_file(&mut self, index: usize) -> Result<(), String> {
-        self.validate_file_index_and_update_root(index)?;
-        let mp = self.fetch_merkle_proof(index)?;
-        let generated_root = self.compute_merkle_root_from_proof(&mp, index);
-        assert_eq!(
-            self.merkle_root, generated_root,
-            "The file downloaded at index: {} is corrupt. \
-        Expected merkle root: {}, Actual merkle root: {}",
-            index, self.merkle_root, generated_root
-        );
-
-        let download_buf = mp.file_content();
-        let mut download =
-            File::create(mp.file_name()).expect("downloaded file creation should not fail");
-        download.write_all(&download_buf).unwrap();
+    /// sessions describes the sessions the user has access to on the server
+    pub async fn sessions(&self) -> Result<Vec<SessionInfo>, Box<dyn Error>> {
+        Ok(self.inner.sessions().await?)
+    }
 
+    /// export_proof writes the verified proof of the file at the index to the output file,
+    /// as CBOR if its extension is '.cbor' and as JSON otherwise
+    pub async fn export_proof(&self, index: usize, output: &str) -> Result<(), Box<dyn Error>> {
+        let batch = self.batch()?;
+        let proof = self.inner.export_proof(&batch, index).await?;
+
+        let encoded = if Path::new(output)
+            .extension()
+            .is_some_and(|ext| ext == "cbor")
+        {
+            proof.to_cbor()
+        } else {
+            proof.to_json().into_bytes()
+        };
+        std::fs::write(output, encoded)?;
+        info!("Exported the proof of file {} to {}", index, output);
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::client::Client;
-    use common::model::file_info::FileInfo;
-    use common::model::merkle::MerkleProof;
-    use sha256::digest;
-    use std::fs::File;
-    use std::io::Read;
-
-    fn parse_files() -> (Vec<String>, Vec<FileInfo>) {
-        let file_names: Vec<String> = vec![
-            String::from("../files/cv.txt"),
-            String::from("../files/food.json"),
-        ];
-        let mut files = Vec::new();
-        for (i, f) in file_names.iter().enumerate() {
-            let mut file = File::open(&f).expect("file should be present");
-            let mut file_buf = Vec::new();
-            file.read_to_end(&mut file_buf).unwrap();
-            let file_info = FileInfo::new(i, f.clone(), file_buf);
-            files.push(file_info);
+    /// verify_proof_file checks a proof file against a file and the merkle root of the last upload
+    pub fn verify_proof_file(&self, proof: &str, file_name: &str) -> Result<(), Box<dyn Error>> {
+        let batch = self.batch()?;
+        let proof = ProofFile::decode(&std::fs::read(proof)?)?;
+        if !proof.root().eq_ignore_ascii_case(batch.merkle_root()) {
+            return Err(format!(
+                "proof is for merkle root {}, the last upload has root {}",
+                proof.root(),
+                batch.merkle_root()
+            )
+            .into());
         }
-        (file_names, files)
-    }
 
-    fn get_merkle_root() -> String {
-        let (_, expected_files) = parse_files();
-        let first = digest(expected_files[0].content());
-        let second = digest(expected_files[1].content());
-        digest(format!("{}{}", first, second))
+        proof.verify(&std::fs::read(file_name)?)?;
+        info!(
+            "{} is the file at index {} of the last upload",
+            file_name,
+            proof.leaf_index()
+        );
+        Ok(())
     }
 
-    #[test]
-    fn load_files_into_memory_works() {
-        let (file_names, expected_files) = parse_files();
-        let mut client = Client::new();
-        client.load_files_into_memory(file_names);
-        assert_eq!(client.files_count, 2);
-        for (expected_file, actual_file) in client.files.iter().zip(expected_files.iter()) {
-            assert_eq!(actual_file, expected_file)
-        }
+    /// stored_receipt loads the receipt of the last upload and checks it again,
+    /// telling whether it was signed with the trusted key of the server
+    pub fn stored_receipt(&self) -> Result<(SignedReceipt, ReceiptCheck), Box<dyn Error>> {
+        let batch = self.batch()?;
+        let check = self.inner.verify_receipt(&batch)?;
+        Ok((batch.receipt().clone(), check))
     }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{load_batch, local_files, restore_path, save_batch};
+    use common::model::receipt::Receipt;
+    use ed25519_dalek::SigningKey;
+    use std::path::Path;
+    use verifile_client::Batch;
 
     #[test]
-    fn build_merkle_tree_and_save_to_disk_works() {
-        let (file_names, _) = parse_files();
-        let root_hash = get_merkle_root();
-        let mut client = Client::new();
-        client.load_files_into_memory(file_names);
-        client.build_merkle_tree_and_save_to_disk();
-        assert_eq!(client.merkle_root, root_hash);
+    fn batch_round_trips_through_disk() {
+        let receipt = Receipt::new(String::from("session"), String::from("root"), 2)
+            .sign(&SigningKey::from_bytes(&[7; 32]));
+        let batch = Batch::new(
+            String::from("session"),
+            String::from("root"),
+            2,
+            None,
+            receipt,
+        );
+
+        let path = std::env::temp_dir().join(format!("verifile-{}.json", std::process::id()));
+        save_batch(&path, &batch).unwrap();
+        let loaded = load_batch::<Batch>(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), batch);
     }
 
     #[test]
-    fn mock_server_has_correct_files() {
-        let (file_names, expected_files) = parse_files();
-        let mp = MerkleProof::new(
-            file_names[0].clone(),
-            expected_files[0].content(),
-            vec![(1, 1, digest(expected_files[1].content()))],
+    fn restored_files_stay_in_the_directory() {
+        let dir = Path::new("out");
+        assert_eq!(
+            restore_path(dir, "files/cv.txt"),
+            Some(dir.join("files/cv.txt"))
+        );
+        assert_eq!(
+            restore_path(dir, "../files/cv.txt"),
+            Some(dir.join("files/cv.txt"))
         );
-        let mut client = Client::new();
-        let hashed = client.compute_merkle_root_from_proof(&mp, 0);
-        assert_eq!(hashed, get_merkle_root())
+        assert_eq!(
+            restore_path(dir, "/etc/passwd"),
+            Some(dir.join("etc/passwd"))
+        );
+        assert_eq!(restore_path(dir, ".."), None);
     }
 
     #[test]
-    fn mock_server_does_not_have_correct_files() {
-        let (file_names, mut expected_files) = parse_files();
+    fn local_files_are_named_by_their_path_in_the_directory() {
+        let dir = std::env::temp_dir().join(format!("verifile-sync-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src/bin")).unwrap();
+        for name in ["b.txt", "a.txt", "src/bin/main.rs", "src/lib.rs"] {
+            std::fs::write(dir.join(name), name).unwrap();
+        }
+        let skipped = dir.join("a.txt").canonicalize().unwrap();
 
-        let mut altered_content = expected_files[0].content();
-        altered_content[0] = 32u8;
+        let files = local_files(&dir, Some(&skipped));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let names = files
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<String>>();
+        assert_eq!(names, vec!["b.txt", "src/bin/main.rs", "src/lib.rs"]);
+    }
 
-        let mp = MerkleProof::new(
-            file_names[0].clone(),
-            altered_content.clone(),
-            vec![(1, 1, digest(expected_files[1].content()))],
-        );
-        let mut client = Client::new();
-        let hashed = client.compute_merkle_root_from_proof(&mp, 0);
-        assert_ne!(hashed, get_merkle_root());
-        assert_eq!(
-            hashed,
-            digest(format!(
-                "{}{}",
-                digest(altered_content),
-                digest(expected_files[1].content())
-            ))
-        );
+    #[test]
+    fn missing_batch_is_an_error() {
+        assert!(load_batch::<Batch>(std::path::Path::new("missing/merkle.json")).is_err());
     }
 }
@@ -1,8 +1,9 @@
 use crate::args::Action;
 use clap::Parser;
 use env_logger::Builder;
-use log::{info, LevelFilter};
+use log::{info, warn, LevelFilter};
 use std::error::Error;
+use verifile_client::ReceiptCheck;
 
 mod args;
 mod client;
@@ -10,18 +11,119 @@ mod client;
 fn main() -> Result<(), Box<dyn Error>> {
     Builder::new().filter(None, LevelFilter::Info).init();
 
-    let mut args = args::Argument::parse();
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    let args = args::Argument::parse();
     info!("{:?}", args);
 
-    let mut client = client::Client::new();
+    args.validate()?;
 
+    let tls = args.tls()?;
+    let compression = args.compression()?;
+    let server_keys = client::load_server_keys()?;
+    let connect = |address: String| {
+        // the key given on the command line, or the one pinned the first time the server signed
+        let public_key = args
+            .server_public_key()
+            .or_else(|| server_keys.get(&address).cloned());
+        let mut client = verifile_client::Client::new(address);
+        if let Some(key_material) = args.key_material() {
+            client.set_key_material(key_material);
+        }
+        if let Some(tls) = tls.clone() {
+            client.set_tls(tls);
+        }
+        if let Some(token) = args.token() {
+            client.set_token(token);
+        }
+        if let Some(public_key) = public_key {
+            client.set_server_public_key(public_key);
+        }
+        client.set_compression(compression);
+        client
+    };
+
+    let mut inner = connect(args.address());
+    inner.set_replicas(args.replicas());
+    let mut client = client::Client::new(inner);
+    if let Some((servers, data_shards)) = args.sharding() {
+        let servers = servers.into_iter().map(connect).collect();
+        client.set_sharded(verifile_client::ShardedClient::new(servers, data_shards)?);
+    }
     match args.action() {
         Action::Send => {
-            args.validate()?;
-            client.prepare_and_send_files(args.file_names());
+            client.prepare_and_send_files(args.file_names()).await?;
         }
         Action::Download(n) => {
-            client.download_verify_and_write_file(n)?;
+            client.download_verify_and_write_file(n).await?;
+        }
+        Action::DownloadAll => {
+            client.download_all(&args.output()).await?;
+        }
+        Action::Sync => {
+            client.sync(&args.dir()).await?;
+        }
+        Action::Diff => {
+            let diffs = client.diff(args.diff_target()).await?;
+            if diffs.is_empty() {
+                info!("No files changed");
+            }
+            for diff in diffs {
+                info!("{}: {}", diff.change, diff.name);
+            }
+        }
+        Action::List => {
+            for entry in client.list_files().await? {
+                info!("{}: {}", entry.index, entry.name);
+            }
+        }
+        Action::Grant => {
+            let (user, role) = args.grant();
+            client.grant_access(user.clone(), role).await?;
+            info!("Granted {:?} access to {}", role, user);
+        }
+        Action::Export(n) => {
+            client.export_proof(n, &args.output()).await?;
+        }
+        Action::VerifyProof => {
+            client.verify_proof_file(&args.proof(), &args.file_names()[0])?;
+        }
+        Action::Delete => {
+            client.delete_session().await?;
+        }
+        Action::Sessions => {
+            for session in client.sessions().await? {
+                info!(
+                    "{}: owned by {}, {} files, {} bytes, created at {}, expires at {}",
+                    session.session_id,
+                    session.owner,
+                    session.files_count,
+                    session.size,
+                    session.created_at,
+                    session
+                        .expires_at
+                        .map_or(String::from("never"), |at| at.to_string())
+                );
+            }
+        }
+        Action::Receipt => {
+            let (receipt, check) = client.stored_receipt()?;
+            match check {
+                ReceiptCheck::Trusted => info!(
+                    "Receipt is valid, signed by the trusted server key {}",
+                    receipt.public_key()
+                ),
+                ReceiptCheck::Unverified => warn!(
+                    "Receipt matches the last upload but is unverified, no server key is trusted and it was signed by {}",
+                    receipt.public_key()
+                ),
+            }
+            info!("{:?}", receipt.receipt());
         }
     }
 
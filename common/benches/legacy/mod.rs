@@ -0,0 +1,185 @@
+//! legacy is the merkle tree as it was before it moved to flat per-level arrays, kept
+//! to compare the two in the benchmarks
+
+use sha256::digest;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub struct MerkleNode {
+    level: usize,
+    index: usize,
+    value: String,
+}
+
+impl MerkleNode {
+    pub fn new(level: usize, index: usize, value: String) -> Self {
+        Self {
+            level,
+            index,
+            value,
+        }
+    }
+}
+
+pub struct MerkleTree {
+    height: usize,
+    data: Vec<Rc<RefCell<MerkleNode>>>,
+    root: Rc<RefCell<MerkleNode>>,
+    // TODO(production): can store pointer data in the node themselves, a hashmap is suboptimal
+    store: HashMap<(usize, usize), Rc<RefCell<MerkleNode>>>,
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        Self {
+            height: 0,
+            data: Vec::new(),
+            root: Rc::new(RefCell::new(MerkleNode::new(0, 0, String::new()))),
+            store: Default::default(),
+        }
+    }
+
+    /// compute_root builds the merkle tree level by level using a queue
+    fn compute_root(&mut self) {
+        if self.data.len() == 1 {
+            self.root = Rc::clone(&self.data[0]);
+            return;
+        }
+
+        let mut queue: VecDeque<Rc<RefCell<MerkleNode>>> = VecDeque::new();
+        let mut next_level_queue: VecDeque<Rc<RefCell<MerkleNode>>> = VecDeque::new();
+
+        self.data
+            .iter()
+            .for_each(|node| queue.push_back(Rc::clone(node)));
+
+        while !queue.is_empty() {
+            let left = queue.pop_front().unwrap();
+            let left_ref = left.borrow();
+            let parent_index = left_ref.index / 2;
+            let parent_level = left_ref.level - 1;
+
+            // if there is a right node, compute the hash of the parent node using its value
+            // else just compute the hash of the left node's value with itself
+            let parent_value = match queue.pop_front() {
+                Some(right) => digest(format!("{}{}", left_ref.value, right.borrow().value)),
+                None => digest(format!("{}{}", left_ref.value, left_ref.value)),
+            };
+
+            let parent = Rc::new(RefCell::new(MerkleNode::new(
+                parent_level,
+                parent_index,
+                parent_value,
+            )));
+            self.store
+                .insert((parent_level, parent_index), Rc::clone(&parent));
+
+            if parent_level == 0 {
+                self.root = parent;
+                return;
+            }
+
+            next_level_queue.push_back(Rc::clone(&parent));
+
+            if queue.is_empty() {
+                queue = next_level_queue;
+                next_level_queue = VecDeque::new();
+            }
+        }
+    }
+
+    /// get_sibling_from_node_level_and_index gets the sibling node of a node given its id
+    fn get_sibling_from_node_level_and_index(
+        &self,
+        level: usize,
+        index: usize,
+    ) -> (usize, usize, String) {
+        if level == 0 || level > self.height {
+            panic!("Invalid level to get sibling node for");
+        }
+
+        if index > self.data.len() {
+            panic!("Invalid index to get sibling node for");
+        }
+
+        // the sibling index is either the right or left node to the current index
+        // if the current index is the left node and also the last node of its level
+        // return the current index. This means it is duplicated in the merkle tree because
+        // the number of nodes on the level is odd
+        let sibling_index =
+            if index.is_multiple_of(2) && !self.store.contains_key(&(level, index + 1)) {
+                index
+            } else if index.is_multiple_of(2) {
+                index + 1
+            } else {
+                index - 1
+            };
+
+        let node = self
+            .store
+            .get(&(level, sibling_index))
+            .expect("sibling node should be in the merkle root store");
+
+        return (level, sibling_index, node.borrow().value.clone());
+    }
+
+    /// get_merkle_path_from_node_index gets all ancestors of a leaf node in a path
+    /// given its id. The root is not included since it is part of every valid path
+    fn get_merkle_path_from_node_index(&self, mut index: usize) -> Vec<(usize, usize)> {
+        if index >= self.data.len() {
+            panic!("node index is invalid");
+        }
+        let mut path = vec![(0, 0); self.height];
+        for lvl in (1..self.height + 1).rev() {
+            path[lvl - 1] = (lvl, index);
+            index /= 2;
+        }
+        path
+    }
+
+    /// get_siblings_of_merkle_path_nodes gets all the siblings of the nodes in the
+    /// current node's merkle path, given the node id
+    pub fn get_siblings_of_merkle_path_nodes(&self, index: usize) -> Vec<(usize, usize, String)> {
+        self.get_merkle_path_from_node_index(index)
+            .into_iter()
+            .map(|(lvl, idx)| self.get_sibling_from_node_level_and_index(lvl, idx))
+            .collect::<Vec<(usize, usize, String)>>()
+    }
+
+    pub fn root_hash(&self) -> String {
+        return self.root.borrow().value.clone();
+    }
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Vec<Vec<u8>>> for MerkleTree {
+    fn from(data: Vec<Vec<u8>>) -> Self {
+        if data.is_empty() {
+            panic!("data cannot be empty");
+        }
+
+        let mut tree = MerkleTree::new();
+
+        // if N is the number of leaf nodes in the tree, then N = 2^H; H = log2(N)
+        tree.height = (data.len() as f64).log2().ceil() as usize;
+        tree.data = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let (level, index, value) = (tree.height, i, digest(d));
+                let node = Rc::new(RefCell::new(MerkleNode::new(level, index, value)));
+                tree.store.insert((tree.height, i), Rc::clone(&node));
+                node
+            })
+            .collect::<Vec<Rc<RefCell<MerkleNode>>>>();
+        tree.compute_root();
+        tree
+    }
+}
@@ -0,0 +1,59 @@
+//! merkle compares building trees and generating proofs with the flat tree
+//! against the legacy tree of reference counted nodes.
+//!
+//! Run with `cargo bench -p common --bench merkle`
+
+mod legacy;
+
+use common::model::merkle::{MerkleProof, MerkleTree};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+const SIZES: [usize; 3] = [1 << 10, 1 << 14, 1 << 17];
+
+fn leaves(count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|i| format!("leaf number {} of the benchmark tree", i).into_bytes())
+        .collect()
+}
+
+fn build(c: &mut Criterion) {
+    // both trees should agree before their times are compared
+    let data = leaves(1000);
+    assert_eq!(
+        MerkleTree::from(data.clone()).root_hash(),
+        legacy::MerkleTree::from(data).root_hash()
+    );
+
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    for size in SIZES {
+        let data = leaves(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("flat", size), &data, |b, data| {
+            b.iter(|| MerkleTree::from(data.clone()))
+        });
+        group.bench_with_input(BenchmarkId::new("legacy", size), &data, |b, data| {
+            b.iter(|| legacy::MerkleTree::from(data.clone()))
+        });
+    }
+    group.finish();
+}
+
+fn proof(c: &mut Criterion) {
+    let mut group = c.benchmark_group("proof");
+    for size in SIZES {
+        let tree = MerkleTree::from(leaves(size));
+        let legacy_tree = legacy::MerkleTree::from(leaves(size));
+        let index = size / 3;
+        group.bench_function(BenchmarkId::new("flat", size), |b| {
+            b.iter(|| MerkleProof::build(&tree, index, String::new(), Vec::new()))
+        });
+        group.bench_function(BenchmarkId::new("legacy", size), |b| {
+            b.iter(|| legacy_tree.get_siblings_of_merkle_path_nodes(index))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, build, proof);
+criterion_main!(benches);
@@ -0,0 +1,102 @@
+use flate2::read::{GzDecoder, GzEncoder};
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::str::FromStr;
+
+/// MIN_COMPRESSED_SIZE is the size under which messages are not worth compressing
+pub const MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// ZSTD_LEVEL trades compression ratio for speed, 3 is the default of zstd
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compression is a codec messages can be compressed with once a handshake agreed on it.
+/// Only the bytes on the wire are compressed, merkle leaves always commit to the content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// ALL lists the supported codecs, the preferred one first
+    pub const ALL: [Compression; 2] = [Compression::Zstd, Compression::Gzip];
+
+    /// name is how the codec is written in handshakes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// compress compresses the data
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let mut compressed = Vec::new();
+        match self {
+            Compression::Gzip => GzEncoder::new(data, flate2::Compression::default())
+                .read_to_end(&mut compressed)
+                .map(|_| compressed),
+            Compression::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+        }
+        .map_err(|e| format!("failed to compress with {}: {}", self.name(), e))
+    }
+
+    /// decompress decompresses data compressed with compress, failing if it holds
+    /// more than limit bytes so that a small message cannot take unbounded memory
+    pub fn decompress(&self, data: &[u8], limit: u64) -> Result<Vec<u8>, String> {
+        let decoder: Box<dyn Read + '_> = match self {
+            Compression::Gzip => Box::new(GzDecoder::new(data)),
+            Compression::Zstd => Box::new(
+                zstd::Decoder::new(data)
+                    .map_err(|e| format!("failed to decompress with zstd: {}", e))?,
+            ),
+        };
+
+        let mut decompressed = Vec::new();
+        decoder
+            .take(limit + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|e| format!("failed to decompress with {}: {}", self.name(), e))?;
+        if decompressed.len() as u64 > limit {
+            return Err(format!("decompressed message is over {} bytes", limit));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compression::ALL
+            .into_iter()
+            .find(|c| c.name() == s)
+            .ok_or_else(|| format!("{} is not a valid compression", s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Compression;
+
+    #[test]
+    fn compression_round_trip_works() {
+        let data = std::fs::read("../files/schools.csv").unwrap();
+        for compression in Compression::ALL {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compressed.len() * 3 < data.len());
+            assert_eq!(compression.decompress(&compressed, 1 << 20).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn decompression_is_bounded() {
+        let data = vec![0u8; 100_000];
+        for compression in Compression::ALL {
+            let compressed = compression.compress(&data).unwrap();
+            assert!(compression.decompress(&compressed, 99_999).is_err());
+            assert!(compression.decompress(&compressed[1..], 1 << 20).is_err());
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! diff tells which files were added, removed or modified from one batch to another.
+//!
+//! Files are matched by name and compared by hash. When both batches have their full
+//! merkle tree, the files that did not move are found by descending the trees instead
+//! of comparing every leaf.
+
+use crate::model::merkle::{MerkleTree, NodeHash};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// Change is how a file differs from one batch to the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Change {
+    /// the file is only in the new batch
+    Added,
+    /// the file is only in the old batch
+    Removed,
+    /// the file is in both batches with different content
+    Modified,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added => write!(f, "added"),
+            Change::Removed => write!(f, "removed"),
+            Change::Modified => write!(f, "modified"),
+        }
+    }
+}
+
+/// FileDiff is a file that differs from one batch to the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub name: String,
+    pub change: Change,
+}
+
+/// diff compares two batches given by the name and hash of each of their files.
+/// The differences are sorted by name
+pub fn diff(old: &[(String, NodeHash)], new: &[(String, NodeHash)]) -> Vec<FileDiff> {
+    let old_hashes = old
+        .iter()
+        .map(|(name, hash)| (name.as_str(), hash))
+        .collect::<HashMap<&str, &NodeHash>>();
+    let new_names = new
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<HashSet<&str>>();
+
+    let changed = new.iter().filter_map(|(name, hash)| {
+        let change = match old_hashes.get(name.as_str()) {
+            None => Change::Added,
+            Some(old_hash) if *old_hash != hash => Change::Modified,
+            Some(_) => return None,
+        };
+        Some(FileDiff {
+            name: name.clone(),
+            change,
+        })
+    });
+    let removed = old
+        .iter()
+        .filter(|(name, _)| !new_names.contains(name.as_str()))
+        .map(|(name, _)| FileDiff {
+            name: name.clone(),
+            change: Change::Removed,
+        });
+
+    let mut diffs = changed.chain(removed).collect::<Vec<FileDiff>>();
+    diffs.sort_by(|a, b| a.name.cmp(&b.name));
+    diffs
+}
+
+/// diff_trees compares two batches given by their merkle tree and the names of their files
+/// in index order. A file with the same name and leaf at the same index of both trees did
+/// not change, those are found by descending the trees. Only the other files are compared
+pub fn diff_trees(
+    old: &MerkleTree,
+    old_names: &[String],
+    new: &MerkleTree,
+    new_names: &[String],
+) -> Vec<FileDiff> {
+    let differing = old
+        .differing_leaves(new)
+        .into_iter()
+        .collect::<HashSet<usize>>();
+    let unchanged =
+        |index: usize| !differing.contains(&index) && old_names.get(index) == new_names.get(index);
+    let files = |tree: &MerkleTree, names: &[String]| {
+        names
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| !unchanged(*index))
+            .filter_map(|(index, name)| tree.leaf(index).map(|leaf| (name.clone(), *leaf)))
+            .collect::<Vec<(String, NodeHash)>>()
+    };
+    diff(&files(old, old_names), &files(new, new_names))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::merkle::hash_leaf;
+
+    fn files(files: &[(&str, &str)]) -> Vec<(String, NodeHash)> {
+        files
+            .iter()
+            .map(|(name, content)| (name.to_string(), hash_leaf(content.as_bytes())))
+            .collect()
+    }
+
+    fn tree(files: &[(String, NodeHash)]) -> (MerkleTree, Vec<String>) {
+        (
+            MerkleTree::from_leaf_hashes(files.iter().map(|(_, hash)| *hash).collect()),
+            files.iter().map(|(name, _)| name.clone()).collect(),
+        )
+    }
+
+    fn changes(diffs: Vec<FileDiff>) -> Vec<(String, Change)> {
+        diffs.into_iter().map(|d| (d.name, d.change)).collect()
+    }
+
+    #[test]
+    fn added_removed_and_modified_files_are_found() {
+        let old = files(&[("a.txt", "Hello"), ("b.txt", "Lorem"), ("c.txt", "Ipsum")]);
+        let new = files(&[("a.txt", "Hello"), ("c.txt", "Dolor"), ("d.txt", "Lorem")]);
+        let expected = vec![
+            (String::from("b.txt"), Change::Removed),
+            (String::from("c.txt"), Change::Modified),
+            (String::from("d.txt"), Change::Added),
+        ];
+        assert_eq!(changes(diff(&old, &new)), expected);
+
+        let (old_tree, old_names) = tree(&old);
+        let (new_tree, new_names) = tree(&new);
+        assert_eq!(
+            changes(diff_trees(&old_tree, &old_names, &new_tree, &new_names)),
+            expected
+        );
+        assert!(diff_trees(&old_tree, &old_names, &old_tree, &old_names).is_empty());
+    }
+
+    #[test]
+    fn renamed_files_with_the_same_leaf_differ() {
+        let old = files(&[("a.txt", "Hello"), ("b.txt", "Lorem")]);
+        let new = files(&[("a.txt", "Hello"), ("e.txt", "Lorem")]);
+        let (old_tree, old_names) = tree(&old);
+        let (new_tree, new_names) = tree(&new);
+        assert_eq!(
+            changes(diff_trees(&old_tree, &old_names, &new_tree, &new_names)),
+            vec![
+                (String::from("b.txt"), Change::Removed),
+                (String::from("e.txt"), Change::Added),
+            ]
+        );
+    }
+}
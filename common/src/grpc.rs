@@ -0,0 +1,38 @@
+use crate::protocol::{self, ErrorKind, ProtocolError};
+
+tonic::include_proto!("verifile.v1");
+
+/// BEARER_PREFIX starts the value of the 'authorization' metadata of a request
+pub const BEARER_PREFIX: &str = "Bearer ";
+
+impl From<ProtocolError> for tonic::Status {
+    fn from(e: ProtocolError) -> Self {
+        let code = match e.kind {
+            ErrorKind::BadRequest => tonic::Code::InvalidArgument,
+            ErrorKind::Unauthenticated => tonic::Code::Unauthenticated,
+            ErrorKind::Forbidden => tonic::Code::PermissionDenied,
+            ErrorKind::NotFound => tonic::Code::NotFound,
+            ErrorKind::QuotaExceeded => tonic::Code::ResourceExhausted,
+            ErrorKind::TooLarge => tonic::Code::ResourceExhausted,
+            ErrorKind::Unsupported => tonic::Code::FailedPrecondition,
+            ErrorKind::Conflict => tonic::Code::Aborted,
+        };
+        tonic::Status::new(code, e.message)
+    }
+}
+
+impl From<protocol::FileEntry> for FileEntry {
+    fn from(entry: protocol::FileEntry) -> Self {
+        Self {
+            index: entry.index as u64,
+            name: entry.name,
+        }
+    }
+}
+
+/// hash_from_bytes reads a 32 byte hash sent in a message
+pub(crate) fn hash_from_bytes(bytes: &[u8], field: &str) -> Result<[u8; 32], String> {
+    bytes
+        .try_into()
+        .map_err(|_| format!("{} should be 32 bytes, got {}", field, bytes.len()))
+}
@@ -0,0 +1,213 @@
+use crate::compression::Compression;
+use crate::model::proof_file::HashAlgorithm;
+use crate::protocol::{Encoding, ErrorKind, Format, ProtocolError};
+use serde::{Deserialize, Serialize};
+
+/// PROTOCOL_VERSION is the version of the protocol this build speaks. Version 1 sent a
+/// single JSON request per connection, version 2 opens connections with a handshake
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// MIN_PROTOCOL_VERSION is the oldest version this build still serves. Clients of
+/// version 1 do not send a hello, the server answers their request in JSON
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// HASH_ALGORITHMS are the hash functions merkle trees can be built with
+const HASH_ALGORITHMS: [HashAlgorithm; 1] = [HashAlgorithm::Sha256];
+
+/// ENCODINGS are the encodings messages can be sent in, the preferred one first
+const ENCODINGS: [Encoding; 2] = [Encoding::Cbor, Encoding::Json];
+
+/// Features lists by name what a peer supports, the ones it prefers first. Names are
+/// used instead of enums so that a peer can list features the other does not know yet,
+/// they are skipped when negotiating
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Features {
+    pub hash_algorithms: Vec<String>,
+    pub encodings: Vec<String>,
+    #[serde(default)]
+    pub compressions: Vec<String>,
+}
+
+impl Features {
+    /// supported lists the features of this build, with the encoding and compression
+    /// of the format first. No compression is listed if the format has none
+    pub fn supported(format: Format) -> Self {
+        let encodings = std::iter::once(format.encoding)
+            .chain(ENCODINGS.into_iter().filter(|e| *e != format.encoding))
+            .map(|e| e.name().to_string())
+            .collect();
+        let compressions = format
+            .compression
+            .into_iter()
+            .chain(
+                Compression::ALL
+                    .into_iter()
+                    .filter(|c| format.compression.is_some_and(|f| f != *c)),
+            )
+            .map(|c| c.name().to_string())
+            .collect();
+        Self {
+            hash_algorithms: HASH_ALGORITHMS
+                .iter()
+                .map(|h| h.name().to_string())
+                .collect(),
+            encodings,
+            compressions,
+        }
+    }
+}
+
+/// Hello opens a connection. The client sends it in JSON, which servers of every
+/// version can read, so that a server of version 1 can reject it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    /// newest version the client speaks
+    pub version: u32,
+    /// oldest version the client speaks
+    pub min_version: u32,
+    pub features: Features,
+}
+
+impl Hello {
+    /// new creates the hello of this build, asking for the format
+    pub fn new(format: impl Into<Format>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features: Features::supported(format.into()),
+        }
+    }
+}
+
+/// Agreement is the answer of the server to a hello: the version and features the
+/// rest of the connection uses
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Agreement {
+    pub version: u32,
+    pub hash_algorithm: HashAlgorithm,
+    pub encoding: Encoding,
+    /// compression large messages are sent with, none if the client listed none the server supports
+    #[serde(default)]
+    pub compression: Option<Compression>,
+}
+
+impl Agreement {
+    /// format is the format the rest of the connection is sent in
+    pub fn format(&self) -> Format {
+        Format {
+            encoding: self.encoding,
+            compression: self.compression,
+        }
+    }
+}
+
+/// first_supported picks the first name the client listed that is one of the supported values
+fn first_supported<T: Copy>(
+    names: &[String],
+    supported: &[T],
+    name: impl Fn(&T) -> &'static str,
+) -> Option<T> {
+    names
+        .iter()
+        .find_map(|n| supported.iter().find(|s| name(s) == n).copied())
+}
+
+/// required picks like first_supported, failing if the client listed nothing supported
+fn required<T: Copy>(
+    names: &[String],
+    supported: &[T],
+    name: impl Fn(&T) -> &'static str,
+    feature: &str,
+) -> Result<T, ProtocolError> {
+    first_supported(names, supported, name).ok_or_else(|| {
+        ProtocolError::new(
+            ErrorKind::Unsupported,
+            format!("none of the {} {:?} is supported", feature, names),
+        )
+    })
+}
+
+/// negotiate picks the newest version both sides speak and the features of the client
+/// it prefers that this build supports
+pub fn negotiate(hello: &Hello) -> Result<Agreement, ProtocolError> {
+    let version = hello.version.min(PROTOCOL_VERSION);
+    if version < hello.min_version.max(MIN_PROTOCOL_VERSION) {
+        return Err(ProtocolError::new(
+            ErrorKind::Unsupported,
+            format!(
+                "protocol versions {} to {} are not supported, the server speaks {} to {}",
+                hello.min_version, hello.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    Ok(Agreement {
+        version,
+        hash_algorithm: required(
+            &hello.features.hash_algorithms,
+            &HASH_ALGORITHMS,
+            HashAlgorithm::name,
+            "hash algorithms",
+        )?,
+        encoding: required(
+            &hello.features.encodings,
+            &ENCODINGS,
+            Encoding::name,
+            "encodings",
+        )?,
+        compression: first_supported(
+            &hello.features.compressions,
+            &Compression::ALL,
+            Compression::name,
+        ),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn preferred_features_are_agreed() {
+        let agreement = negotiate(&Hello::new(Encoding::Json)).unwrap();
+        assert_eq!(agreement.version, PROTOCOL_VERSION);
+        assert_eq!(agreement.hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(agreement.encoding, Encoding::Json);
+        assert_eq!(agreement.compression, None);
+
+        let agreement = negotiate(&Hello::new(Format {
+            encoding: Encoding::Cbor,
+            compression: Some(Compression::Gzip),
+        }))
+        .unwrap();
+        assert_eq!(agreement.compression, Some(Compression::Gzip));
+    }
+
+    #[test]
+    fn newer_clients_are_served_with_known_features() {
+        let mut hello = Hello::new(Encoding::Cbor);
+        hello.version = PROTOCOL_VERSION + 3;
+        hello
+            .features
+            .encodings
+            .insert(0, String::from("some-future-encoding"));
+        hello.features.compressions = vec![String::from("brotli"), String::from("zstd")];
+
+        let agreement = negotiate(&hello).unwrap();
+        assert_eq!(agreement.version, PROTOCOL_VERSION);
+        assert_eq!(agreement.encoding, Encoding::Cbor);
+        assert_eq!(agreement.compression, Some(Compression::Zstd));
+    }
+
+    #[test]
+    fn unsupported_versions_and_features_are_rejected() {
+        let mut hello = Hello::new(Encoding::Cbor);
+        hello.min_version = PROTOCOL_VERSION + 1;
+        hello.version = PROTOCOL_VERSION + 1;
+        assert_eq!(negotiate(&hello).unwrap_err().kind, ErrorKind::Unsupported);
+
+        let mut hello = Hello::new(Encoding::Cbor);
+        hello.features.hash_algorithms = vec![String::from("blake3")];
+        assert_eq!(negotiate(&hello).unwrap_err().kind, ErrorKind::Unsupported);
+    }
+}
@@ -1,2 +1,14 @@
+pub mod compression;
+pub mod diff;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod handshake;
 pub mod model;
+pub mod protocol;
+pub mod replication;
+#[cfg(feature = "transport")]
+pub mod tls;
+#[cfg(feature = "transport")]
+pub mod transport;
+pub mod verify;
 pub const SERVER_ADDRESS: &str = "127.0.0.1:8000";
@@ -1,10 +1,12 @@
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::str::FromStr;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct FileInfo {
     name: String,
     index: usize,
+    #[serde(with = "crate::model::wire::content")]
     content: Vec<u8>,
 }
 
@@ -28,11 +30,22 @@ impl FileInfo {
     pub fn content(&self) -> Vec<u8> {
         self.content.clone()
     }
+
+    /// size returns the size of the content in bytes
+    pub fn size(&self) -> usize {
+        self.content.len()
+    }
+
+    /// into_content takes the content out of the file without copying it
+    pub fn into_content(self) -> Vec<u8> {
+        self.content
+    }
 }
 
-impl ToString for FileInfo {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).expect("file deserialization should not fail")
+impl fmt::Display for FileInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
     }
 }
 
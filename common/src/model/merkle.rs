@@ -1,92 +1,210 @@
+use crate::verify::{check_positions, VerifyError};
 use log::error;
 use serde::{Deserialize, Serialize};
-use sha256::digest;
-use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
-use std::rc::Rc;
-
-#[derive(Debug)]
-pub struct MerkleNode {
-    level: usize,
-    index: usize,
-    value: String,
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// READ_BUFFER_SIZE is how much of a file is hashed at a time when streaming it
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// NodeHash is a SHA-256 hash of a leaf or an inner node of the tree
+pub type NodeHash = [u8; 32];
+
+/// hash_leaf hashes the data of a leaf
+pub fn hash_leaf(data: &[u8]) -> NodeHash {
+    Sha256::digest(data).into()
 }
 
-impl MerkleNode {
-    pub fn new(level: usize, index: usize, value: String) -> Self {
-        Self {
-            level,
-            index,
-            value,
+/// hash_leaf_reader hashes the data of a leaf as it is read, without holding it in memory
+pub fn hash_leaf_reader<R: Read>(mut reader: R) -> io::Result<NodeHash> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(hasher.finalize().into()),
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
 }
 
+/// hash_children hashes two nodes into their parent. For compatibility with proofs and
+/// roots made so far, the parent is the hash of the hex encoding of the children
+/// concatenated, left first. The hex encoding is done on the stack
+pub fn hash_children(left: &NodeHash, right: &NodeHash) -> NodeHash {
+    let mut buf = [0u8; 128];
+    hex::encode_to_slice(left, &mut buf[..64]).expect("buffer should fit a hex hash");
+    hex::encode_to_slice(right, &mut buf[64..]).expect("buffer should fit a hex hash");
+    Sha256::digest(buf).into()
+}
+
+/// PARALLEL_THRESHOLD is the number of nodes below which a level is hashed on the
+/// current thread, splitting smaller levels across threads costs more than it saves
+#[cfg(feature = "parallel")]
+const PARALLEL_THRESHOLD: usize = 1024;
+
+/// hash_level_sequential computes the parents of the nodes of a level in order
+fn hash_level_sequential(nodes: &[NodeHash]) -> Vec<NodeHash> {
+    nodes
+        .chunks(2)
+        .map(|pair| hash_children(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+/// hash_level computes the parents of the nodes of a level. Large levels are split
+/// across threads, the parents are collected in order so the tree is the same
+#[cfg(feature = "parallel")]
+fn hash_level(nodes: &[NodeHash]) -> Vec<NodeHash> {
+    use rayon::prelude::*;
+
+    if nodes.len() < PARALLEL_THRESHOLD {
+        return hash_level_sequential(nodes);
+    }
+    nodes
+        .par_chunks(2)
+        .map(|pair| hash_children(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn hash_level(nodes: &[NodeHash]) -> Vec<NodeHash> {
+    hash_level_sequential(nodes)
+}
+
+/// hash_leaves hashes the data of every leaf, across threads if the parallel feature is on
+#[cfg(feature = "parallel")]
+fn hash_leaves(data: &[Vec<u8>]) -> Vec<NodeHash> {
+    use rayon::prelude::*;
+
+    data.par_iter().map(|d| hash_leaf(d)).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn hash_leaves(data: &[Vec<u8>]) -> Vec<NodeHash> {
+    data.iter().map(|d| hash_leaf(d)).collect()
+}
+
+/// to_hex encodes a hash in lowercase hex
+pub fn to_hex(hash: &NodeHash) -> String {
+    let mut buf = [0u8; 64];
+    hex::encode_to_slice(hash, &mut buf).expect("buffer should fit a hex hash");
+    String::from_utf8(buf.to_vec()).expect("hex should be valid utf-8")
+}
+
+/// MerkleTree keeps every node of the tree in flat arrays, one per level.
+/// levels[0] holds the root and levels[height] the leaves. The last node of a level
+/// with an odd number of nodes is paired with itself to compute its parent
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MerkleTree {
-    height: usize,
-    data: Vec<Rc<RefCell<MerkleNode>>>,
-    root: Rc<RefCell<MerkleNode>>,
-    // TODO(production): can store pointer data in the node themselves, a hashmap is suboptimal
-    store: HashMap<(usize, usize), Rc<RefCell<MerkleNode>>>,
+    levels: Vec<Vec<NodeHash>>,
 }
 
 impl MerkleTree {
     pub fn new() -> Self {
-        Self {
-            height: 0,
-            data: Vec::new(),
-            root: Rc::new(RefCell::new(MerkleNode::new(0, 0, String::new()))),
-            store: Default::default(),
-        }
+        Self { levels: Vec::new() }
     }
 
-    /// compute_root builds the merkle tree level by level using a queue
-    fn compute_root(&mut self) {
-        if self.data.len() == 1 {
-            self.root = Rc::clone(&self.data[0]);
-            return;
+    /// from_leaf_hashes builds the tree over leaves that are already hashed
+    pub fn from_leaf_hashes(leaves: Vec<NodeHash>) -> Self {
+        if leaves.is_empty() {
+            error!("data cannot be empty");
+            panic!("data cannot be empty");
         }
 
-        let mut queue: VecDeque<Rc<RefCell<MerkleNode>>> = VecDeque::new();
-        let mut next_level_queue: VecDeque<Rc<RefCell<MerkleNode>>> = VecDeque::new();
-
-        self.data
-            .iter()
-            .for_each(|node| queue.push_back(Rc::clone(node)));
-
-        while !queue.is_empty() {
-            let left = queue.pop_front().unwrap();
-            let left_ref = left.borrow();
-            let parent_index = left_ref.index / 2;
-            let parent_level = left_ref.level - 1;
-
-            // if there is a right node, compute the hash of the parent node using its value
-            // else just compute the hash of the left node's value with itself
-            let parent_value = match queue.pop_front() {
-                Some(right) => digest(format!("{}{}", left_ref.value, right.borrow().value)),
-                None => digest(format!("{}{}", left_ref.value, left_ref.value)),
-            };
-
-            let parent = Rc::new(RefCell::new(MerkleNode::new(
-                parent_level,
-                parent_index,
-                parent_value,
-            )));
-            self.store
-                .insert((parent_level, parent_index), Rc::clone(&parent));
-
-            if parent_level == 0 {
-                self.root = parent;
-                return;
+        let mut levels = vec![leaves];
+        loop {
+            let level = levels.last().expect("tree should have a level");
+            if level.len() == 1 {
+                break;
             }
+            let parents = hash_level(level);
+            levels.push(parents);
+        }
+        levels.reverse();
+        Self { levels }
+    }
+
+    /// len returns the number of leaves of the tree
+    pub fn len(&self) -> usize {
+        self.levels.last().map_or(0, Vec::len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// height returns the number of levels below the root
+    pub fn height(&self) -> usize {
+        self.levels.len().saturating_sub(1)
+    }
 
-            next_level_queue.push_back(Rc::clone(&parent));
+    /// node returns the hash of the node at the index of the level, if there is one
+    pub fn node(&self, level: usize, index: usize) -> Option<&NodeHash> {
+        self.levels.get(level).and_then(|nodes| nodes.get(index))
+    }
+
+    /// leaf returns the hash of the leaf at the index, if there is one
+    pub fn leaf(&self, index: usize) -> Option<&NodeHash> {
+        self.node_above_leaves(0, index)
+    }
 
-            if queue.is_empty() {
-                queue = next_level_queue;
-                next_level_queue = VecDeque::new();
+    /// node_above_leaves returns the node at the index of the level the given number of
+    /// levels above the leaves, if there is one
+    pub fn node_above_leaves(&self, depth: usize, index: usize) -> Option<&NodeHash> {
+        self.height()
+            .checked_sub(depth)
+            .and_then(|level| self.node(level, index))
+    }
+
+    /// differing_leaves returns in order the indexes of the leaves that are not the same in
+    /// both trees, including the ones only one of them has. The trees are descended from their
+    /// roots and a subtree is skipped if it has the same hash and covers the same leaves on
+    /// both sides, so only the nodes on the paths to the differences are compared. Levels are
+    /// lined up from the leaves, so trees of different sizes can be compared
+    pub fn differing_leaves(&self, other: &MerkleTree) -> Vec<usize> {
+        let mut differing = Vec::new();
+        let depth = self.height().max(other.height());
+        self.collect_differing_leaves(other, depth, 0, &mut differing);
+        differing
+    }
+
+    /// collect_differing_leaves adds the differing leaves under the node at the index of
+    /// the level the given number of levels above the leaves
+    fn collect_differing_leaves(
+        &self,
+        other: &MerkleTree,
+        depth: usize,
+        index: usize,
+        differing: &mut Vec<usize>,
+    ) {
+        let (first, end) = (index << depth, (index + 1) << depth);
+        if first >= self.len().max(other.len()) {
+            return;
+        }
+        // the last node of an odd level is paired with itself, so the same hash can cover
+        // fewer leaves in one tree than in the other
+        let same = match (
+            self.node_above_leaves(depth, index),
+            other.node_above_leaves(depth, index),
+        ) {
+            (Some(node), Some(other_node)) => {
+                node == other_node && end.min(self.len()) == end.min(other.len())
             }
+            _ => false,
+        };
+        if same {
+            return;
         }
+        if depth == 0 {
+            differing.push(index);
+            return;
+        }
+        self.collect_differing_leaves(other, depth - 1, 2 * index, differing);
+        self.collect_differing_leaves(other, depth - 1, 2 * index + 1, differing);
     }
 
     /// get_sibling_from_node_level_and_index gets the sibling node of a node given its id
@@ -95,42 +213,39 @@ impl MerkleTree {
         level: usize,
         index: usize,
     ) -> (usize, usize, String) {
-        if level <= 0 || level > self.height {
+        if level == 0 || level > self.height() {
             panic!("Invalid level to get sibling node for");
         }
 
-        if index < 0 || index > self.data.len() {
+        let nodes = &self.levels[level];
+        if index >= nodes.len() {
             panic!("Invalid index to get sibling node for");
         }
 
         // the sibling index is either the right or left node to the current index
-        // if the current index is the left node and also the last node in the nodes list
+        // if the current index is the left node and also the last node of its level
         // return the current index. This means it is duplicated in the merkle tree because
-        // the length of the input data is odd
-        let sibling_index = if index % 2 == 0 && index == self.data.len() - 1 {
+        // the number of nodes on the level is odd
+        let sibling_index = if index.is_multiple_of(2) && index == nodes.len() - 1 {
             index
-        } else if index % 2 == 0 {
+        } else if index.is_multiple_of(2) {
             index + 1
         } else {
             index - 1
         };
 
-        let node = self
-            .store
-            .get(&(level, sibling_index))
-            .expect("sibling node should be in the merkle root store");
-
-        return (level, sibling_index, node.borrow().value.clone());
+        (level, sibling_index, to_hex(&nodes[sibling_index]))
     }
 
     /// get_merkle_path_from_node_index gets all ancestors of a leaf node in a path
     /// given its id. The root is not included since it is part of every valid path
     fn get_merkle_path_from_node_index(&self, mut index: usize) -> Vec<(usize, usize)> {
-        if index < 0 || index >= self.data.len() {
+        if index >= self.len() {
             panic!("node index is invalid");
         }
-        let mut path = vec![(0, 0); self.height];
-        for lvl in (1..self.height + 1).rev() {
+        let height = self.height();
+        let mut path = vec![(0, 0); height];
+        for lvl in (1..height + 1).rev() {
             path[lvl - 1] = (lvl, index);
             index /= 2;
         }
@@ -146,34 +261,226 @@ impl MerkleTree {
             .collect::<Vec<(usize, usize, String)>>()
     }
 
+    /// root returns the root of the tree, None if the tree is empty
+    pub fn root(&self) -> Option<&NodeHash> {
+        self.node(0, 0)
+    }
+
+    /// root_hash returns the hex encoded root of the tree, empty if the tree is empty
     pub fn root_hash(&self) -> String {
-        return self.root.borrow().value.clone();
+        self.root().map(to_hex).unwrap_or_default()
+    }
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl From<Vec<Vec<u8>>> for MerkleTree {
     fn from(data: Vec<Vec<u8>>) -> Self {
-        if data.is_empty() {
-            error!("data cannot be empty");
-            panic!("data cannot be empty");
+        Self::from_leaf_hashes(hash_leaves(&data))
+    }
+}
+
+/// MerkleTreeBuilder builds a tree from leaves streamed one at a time. Only the hash of
+/// each leaf is kept, so the memory used does not depend on the size of the files
+#[derive(Debug, Default)]
+pub struct MerkleTreeBuilder {
+    leaves: Vec<NodeHash>,
+}
+
+impl MerkleTreeBuilder {
+    pub fn new() -> Self {
+        Self { leaves: Vec::new() }
+    }
+
+    /// len returns the number of leaves added so far
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    /// push_data adds a leaf from data already in memory
+    pub fn push_data(&mut self, data: &[u8]) {
+        self.leaves.push(hash_leaf(data));
+    }
+
+    /// push_reader adds a leaf from everything the reader yields
+    pub fn push_reader<R: Read>(&mut self, reader: R) -> io::Result<()> {
+        self.leaves.push(hash_leaf_reader(reader)?);
+        Ok(())
+    }
+
+    /// push_path adds a leaf from the content of the file at the path
+    pub fn push_path<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.push_reader(File::open(path)?)
+    }
+
+    /// finish builds the tree over the leaves added so far, in order
+    pub fn finish(self) -> MerkleTree {
+        MerkleTree::from_leaf_hashes(self.leaves)
+    }
+}
+
+impl MerkleTree {
+    /// from_readers builds the tree over the content of the readers, streaming each one
+    pub fn from_readers<R: Read>(readers: impl IntoIterator<Item = R>) -> io::Result<Self> {
+        let mut builder = MerkleTreeBuilder::new();
+        for reader in readers {
+            builder.push_reader(reader)?;
         }
+        Ok(builder.finish())
+    }
 
-        let mut tree = MerkleTree::new();
+    /// from_paths builds the tree over the content of the files at the paths, streaming each one
+    pub fn from_paths<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> io::Result<Self> {
+        let mut builder = MerkleTreeBuilder::new();
+        for path in paths {
+            builder.push_path(path)?;
+        }
+        Ok(builder.finish())
+    }
+}
 
-        // if N is the number of leaf nodes in the tree, then N = 2^H; H = log2(N)
-        tree.height = (data.len() as f64).log2().ceil() as usize;
-        tree.data = data
-            .into_iter()
-            .enumerate()
-            .map(|(i, d)| {
-                let (level, index, value) = (tree.height, i, digest(d));
-                let node = Rc::new(RefCell::new(MerkleNode::new(level, index, value)));
-                tree.store.insert((tree.height, i), Rc::clone(&node));
-                node
-            })
-            .collect::<Vec<Rc<RefCell<MerkleNode>>>>();
-        tree.compute_root();
-        tree
+/// TREE_FILE_MAGIC starts every saved tree
+const TREE_FILE_MAGIC: &[u8; 4] = b"VFMT";
+
+/// TREE_FILE_VERSION is bumped whenever the layout of a saved tree changes
+const TREE_FILE_VERSION: u8 = 1;
+
+/// TREE_FILE_INTERNAL_LEVELS flags a saved tree that holds its internal levels
+const TREE_FILE_INTERNAL_LEVELS: u8 = 1;
+
+/// A saved tree is laid out as
+///
+/// ```text
+/// magic "VFMT" | version u8 | flags u8 | leaf count u64 BE | root [u8; 32]
+/// | leaves [u8; 32] * count | internal levels, from the one above the leaves up
+/// ```
+///
+/// The internal levels are only there if the flag is set
+impl MerkleTree {
+    /// write_to writes the tree, with its internal levels if include_internal is set
+    pub fn write_to<W: Write>(&self, mut writer: W, include_internal: bool) -> io::Result<()> {
+        let root = self
+            .root()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "tree is empty"))?;
+        let flags = if include_internal {
+            TREE_FILE_INTERNAL_LEVELS
+        } else {
+            0
+        };
+
+        writer.write_all(TREE_FILE_MAGIC)?;
+        writer.write_all(&[TREE_FILE_VERSION, flags])?;
+        writer.write_all(&(self.len() as u64).to_be_bytes())?;
+        writer.write_all(root)?;
+        // the leaves come first, then the levels between them and the root, bottom up
+        let internal = if include_internal && self.height() > 1 {
+            &self.levels[1..self.height()]
+        } else {
+            &[]
+        };
+        for level in self.levels[self.height()..]
+            .iter()
+            .chain(internal.iter().rev())
+        {
+            for node in level {
+                writer.write_all(node)?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// read_from reads a tree written by write_to. The parents are recomputed from the
+    /// leaves, or kept once checked against the level below if the internal levels were
+    /// saved, and the root must be the saved root. Loading takes one hash per node, whatever the size of the files
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, String> {
+        let read_err = |e: io::Error| format!("failed to read tree: {}", e);
+
+        let mut header = [0u8; 14];
+        reader.read_exact(&mut header).map_err(read_err)?;
+        if &header[..4] != TREE_FILE_MAGIC {
+            return Err(String::from("file is not a saved merkle tree"));
+        }
+        if header[4] != TREE_FILE_VERSION {
+            return Err(format!("tree file version {} is not supported", header[4]));
+        }
+        let with_internal = header[5] & TREE_FILE_INTERNAL_LEVELS != 0;
+        let count = u64::from_be_bytes(header[6..].try_into().expect("header should hold a u64"));
+        if count == 0 {
+            return Err(String::from("saved tree has no leaves"));
+        }
+
+        let mut root = [0u8; 32];
+        reader.read_exact(&mut root).map_err(read_err)?;
+
+        let mut read_level = |len: usize| -> Result<Vec<NodeHash>, String> {
+            // grows as nodes are read, so a corrupt count cannot allocate unbounded memory
+            let mut level = Vec::new();
+            for _ in 0..len {
+                let mut node = [0u8; 32];
+                reader.read_exact(&mut node).map_err(read_err)?;
+                level.push(node);
+            }
+            Ok(level)
+        };
+
+        let count = usize::try_from(count).map_err(|_| String::from("saved tree is too large"))?;
+        let leaves = read_level(count)?;
+        let tree = if with_internal {
+            // the saved levels are kept, each one is checked against the level below it.
+            // The root is not saved as a level, it is checked below
+            let mut levels = vec![leaves];
+            while levels.last().expect("tree should have a level").len() > 2 {
+                let below = levels.last().expect("tree should have a level");
+                let expected = hash_level(below);
+                let level = read_level(expected.len())?;
+                if level != expected {
+                    return Err(format!(
+                        "saved level {} above the leaves does not match the leaves",
+                        levels.len()
+                    ));
+                }
+                levels.push(level);
+            }
+            let top = levels.last().expect("tree should have a level");
+            if top.len() > 1 {
+                levels.push(hash_level(top));
+            }
+            levels.reverse();
+            Self { levels }
+        } else {
+            Self::from_leaf_hashes(leaves)
+        };
+
+        if tree.root() != Some(&root) {
+            return Err(format!(
+                "saved root {} does not match the root {} of the leaves",
+                to_hex(&root),
+                tree.root_hash()
+            ));
+        }
+        Ok(tree)
+    }
+
+    /// save writes the tree to the file at the path
+    pub fn save<P: AsRef<Path>>(&self, path: P, include_internal: bool) -> io::Result<()> {
+        let file = File::create(path)?;
+        self.write_to(io::BufWriter::new(file), include_internal)
+    }
+
+    /// load reads a tree saved to the file at the path
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|e| format!("failed to open tree {}: {}", path.display(), e))?;
+        Self::read_from(io::BufReader::new(file))
     }
 }
 
@@ -181,7 +488,12 @@ impl From<Vec<Vec<u8>>> for MerkleTree {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MerkleProof {
     file_name: String,
+    #[serde(with = "crate::model::wire::content")]
     file_content: Vec<u8>,
+    /// number of leaves in the tree the proof was built from
+    tree_size: usize,
+    /// (level, index, hash) of the siblings on the path of the file, from the leaf level up
+    #[serde(with = "crate::model::wire::siblings")]
     siblings: Vec<(usize, usize, String)>,
 }
 
@@ -189,11 +501,13 @@ impl MerkleProof {
     pub fn new(
         file_name: String,
         file_content: Vec<u8>,
+        tree_size: usize,
         siblings: Vec<(usize, usize, String)>,
     ) -> Self {
         Self {
             file_name,
             file_content,
+            tree_size,
             siblings,
         }
     }
@@ -204,10 +518,12 @@ impl MerkleProof {
         file_name: String,
         file_content: Vec<u8>,
     ) -> Self {
-        let siblings = tree.get_siblings_of_merkle_path_nodes(index);
+        let mut siblings = tree.get_siblings_of_merkle_path_nodes(index);
+        siblings.reverse();
         Self {
             file_name,
             siblings,
+            tree_size: tree.len(),
             file_content,
         }
     }
@@ -220,14 +536,46 @@ impl MerkleProof {
         self.file_content.clone()
     }
 
+    /// into_file_content takes the content out of the proof without copying it
+    pub fn into_file_content(self) -> Vec<u8> {
+        self.file_content
+    }
+
+    /// leaf_hash hashes the content of the file, giving the leaf the proof is for
+    pub fn leaf_hash(&self) -> NodeHash {
+        hash_leaf(&self.file_content)
+    }
+
     pub fn siblings(&self) -> Vec<(usize, usize, String)> {
         self.siblings.clone()
     }
+
+    pub fn tree_size(&self) -> usize {
+        self.tree_size
+    }
+
+    /// checked_path checks that the siblings of the proof are, in order, the ones on the
+    /// path of the leaf at the index and returns their hashes from the leaf level up,
+    /// the form common::verify::verify takes them in
+    pub fn checked_path(&self, index: usize) -> Result<Vec<String>, VerifyError> {
+        let positions = self
+            .siblings
+            .iter()
+            .map(|(level, index, _)| (*level, *index))
+            .collect::<Vec<(usize, usize)>>();
+        check_positions(index, self.tree_size, &positions)?;
+        Ok(self
+            .siblings
+            .iter()
+            .map(|(_, _, hash)| hash.clone())
+            .collect())
+    }
 }
 
-impl ToString for MerkleProof {
-    fn to_string(&self) -> String {
-        serde_json::to_string(self).expect("merkle proof deserialization should not fail")
+impl fmt::Display for MerkleProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", json)
     }
 }
 
@@ -247,10 +595,10 @@ mod test {
         ]
     }
 
-    fn build_merkle_vector(data: &Vec<Vec<u8>>) -> Vec<Vec<String>> {
+    fn build_merkle_vector(data: &[Vec<u8>]) -> Vec<Vec<String>> {
         let height = (data.len() as f64).log2().ceil() as usize;
 
-        let mut curr_vector = data.iter().map(|d| digest(d)).collect::<Vec<String>>();
+        let mut curr_vector = data.iter().map(digest).collect::<Vec<String>>();
         let mut vector = Vec::new();
         vector.push(curr_vector.clone());
         while vector.len() != height + 1 {
@@ -284,11 +632,166 @@ mod test {
         let vector = build_merkle_vector(&data);
         let merkle_tree = super::MerkleTree::from(data);
 
-        for ((lvl, idx), node_from_tree) in merkle_tree.store.iter() {
-            let data_from_vector = vector[*lvl][*idx].clone();
-            let data_from_tree = node_from_tree.borrow().value.clone();
-            assert_eq!(data_from_tree, data_from_vector);
+        assert_eq!(merkle_tree.height() + 1, vector.len());
+        for (lvl, nodes) in vector.iter().enumerate() {
+            for (idx, data_from_vector) in nodes.iter().enumerate() {
+                let data_from_tree = hex::encode(merkle_tree.node(lvl, idx).unwrap());
+                assert_eq!(&data_from_tree, data_from_vector);
+            }
         }
+        assert_eq!(merkle_tree.root_hash(), vector[0][0]);
+    }
+
+    #[test]
+    fn tree_of_a_single_leaf_has_the_leaf_as_root() {
+        let merkle_tree = super::MerkleTree::from(vec![b"Hello".to_vec()]);
+        assert_eq!(merkle_tree.height(), 0);
+        assert_eq!(merkle_tree.root_hash(), digest("Hello"));
+    }
+
+    #[test]
+    fn differing_leaves_are_found_across_sizes() {
+        let tree = super::MerkleTree::from(input_data());
+        assert!(tree.differing_leaves(&tree.clone()).is_empty());
+
+        let mut data = input_data();
+        data[5] = b"Mouse".to_vec();
+        assert_eq!(
+            tree.differing_leaves(&super::MerkleTree::from(data)),
+            vec![5]
+        );
+
+        let mut data = input_data();
+        data.push(b"Golang".to_vec());
+        data.push(b"Python".to_vec());
+        assert_eq!(
+            tree.differing_leaves(&super::MerkleTree::from(data)),
+            vec![7, 8]
+        );
+
+        let mut data = input_data();
+        data.pop();
+        data[0] = b"Hi".to_vec();
+        let smaller = super::MerkleTree::from(data);
+        assert_eq!(tree.differing_leaves(&smaller), vec![0, 6]);
+        assert_eq!(smaller.differing_leaves(&tree), vec![0, 6]);
+
+        assert_eq!(
+            tree.differing_leaves(&super::MerkleTree::new()),
+            (0..7).collect::<Vec<usize>>()
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_build_matches_sequential_build() {
+        let data = (0..5001)
+            .map(|i| format!("leaf {}", i).into_bytes())
+            .collect::<Vec<Vec<u8>>>();
+        let parallel = super::MerkleTree::from(data.clone());
+
+        let mut level = data.iter().map(|d| super::hash_leaf(d)).collect::<Vec<_>>();
+        let mut levels = vec![level.clone()];
+        while level.len() > 1 {
+            level = super::hash_level_sequential(&level);
+            levels.push(level.clone());
+        }
+        levels.reverse();
+        assert_eq!(parallel.levels, levels);
+    }
+
+    #[test]
+    fn streamed_tree_matches_tree_built_in_memory() {
+        let mut data = input_data();
+        // larger than the read buffer, so it is hashed in several reads
+        data.push((0..200_000).map(|i| (i % 251) as u8).collect());
+        let merkle_tree = super::MerkleTree::from(data.clone());
+
+        let streamed = super::MerkleTree::from_readers(data.iter().map(|d| d.as_slice())).unwrap();
+        assert_eq!(streamed, merkle_tree);
+
+        let mut builder = super::MerkleTreeBuilder::new();
+        builder.push_data(&data[0]);
+        for d in &data[1..] {
+            builder.push_reader(d.as_slice()).unwrap();
+        }
+        assert_eq!(builder.len(), data.len());
+        assert_eq!(builder.finish(), merkle_tree);
+    }
+
+    #[test]
+    fn tree_can_be_built_from_paths() {
+        let paths = ["../files/cv.txt", "../files/food.json"];
+        let data = paths
+            .iter()
+            .map(|path| std::fs::read(path).unwrap())
+            .collect::<Vec<Vec<u8>>>();
+        let merkle_tree = super::MerkleTree::from_paths(paths).unwrap();
+        assert_eq!(merkle_tree, super::MerkleTree::from(data));
+        assert!(super::MerkleTree::from_paths(["../files/missing.txt"]).is_err());
+    }
+
+    #[test]
+    fn saved_tree_loads_back() {
+        let merkle_tree = super::MerkleTree::from(input_data());
+        for include_internal in [false, true] {
+            let mut buf = Vec::new();
+            merkle_tree.write_to(&mut buf, include_internal).unwrap();
+            assert_eq!(
+                super::MerkleTree::read_from(buf.as_slice()).unwrap(),
+                merkle_tree
+            );
+        }
+
+        // every shape of tree, with levels of odd and even widths
+        for count in 1..=9 {
+            let tree = super::MerkleTree::from((0..count).map(|i| vec![i]).collect::<Vec<_>>());
+            let mut buf = Vec::new();
+            tree.write_to(&mut buf, true).unwrap();
+            assert_eq!(super::MerkleTree::read_from(buf.as_slice()).unwrap(), tree);
+        }
+
+        let single = super::MerkleTree::from(vec![b"Hello".to_vec()]);
+        let mut buf = Vec::new();
+        single.write_to(&mut buf, true).unwrap();
+        assert_eq!(buf.len(), 14 + 32 + 32);
+        assert_eq!(
+            super::MerkleTree::read_from(buf.as_slice()).unwrap(),
+            single
+        );
+    }
+
+    #[test]
+    fn corrupt_saved_tree_is_rejected() {
+        let merkle_tree = super::MerkleTree::from(input_data());
+        let mut buf = Vec::new();
+        merkle_tree.write_to(&mut buf, false).unwrap();
+        let leaves_start = 14 + 32;
+
+        // a changed leaf no longer hashes to the saved root
+        let mut corrupt = buf.clone();
+        corrupt[leaves_start] ^= 1;
+        assert!(super::MerkleTree::read_from(corrupt.as_slice()).is_err());
+
+        let mut corrupt = buf.clone();
+        corrupt[0] = b'X';
+        assert!(super::MerkleTree::read_from(corrupt.as_slice()).is_err());
+
+        buf.pop();
+        assert!(super::MerkleTree::read_from(buf.as_slice()).is_err());
+
+        // a changed internal node does not match the leaves
+        let mut buf = Vec::new();
+        merkle_tree.write_to(&mut buf, true).unwrap();
+        let internal_start = leaves_start + 32 * input_data().len();
+        buf[internal_start] ^= 1;
+        assert!(super::MerkleTree::read_from(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn tree_can_be_shared_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<super::MerkleTree>();
     }
 
     #[test]
@@ -310,7 +813,6 @@ mod test {
     #[test]
     fn get_merkle_path_from_node_index_works() {
         let data = input_data();
-        let vector = build_merkle_vector(&data);
         let merkle_tree = super::MerkleTree::from(data);
 
         let path = merkle_tree.get_merkle_path_from_node_index(0);
@@ -326,7 +828,6 @@ mod test {
     #[test]
     fn get_sibling_hashes_of_merkle_path_nodes_works() {
         let data = input_data();
-        let vector = build_merkle_vector(&data);
         let merkle_tree = super::MerkleTree::from(data);
 
         let sibling_hashes = merkle_tree.get_siblings_of_merkle_path_nodes(0);
@@ -1,2 +1,5 @@
 pub mod file_info;
 pub mod merkle;
+pub mod proof_file;
+pub mod receipt;
+mod wire;
@@ -0,0 +1,450 @@
+use crate::model::merkle::MerkleProof;
+use crate::verify::{check_sides, expected_path, verify, Side, VerifyError};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// PROOF_FORMAT names the format in every proof file, so it can be told apart from other JSON
+pub const PROOF_FORMAT: &str = "verifile-proof";
+
+/// PROOF_FORMAT_VERSION is bumped whenever the fields of a proof file change
+pub const PROOF_FORMAT_VERSION: u32 = 1;
+
+/// TREE_VERSION identifies how the tree is built: leaves are the SHA-256 of the file,
+/// a parent is the SHA-256 of the hex encoding of its children concatenated, left first,
+/// and the last node of a level with an odd number of nodes is paired with itself
+pub const TREE_VERSION: u32 = 1;
+
+/// HashAlgorithm is the hash function the tree is built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// name is how the algorithm is written in proof files and handshakes
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+/// Hash is a SHA-256 hash. It is written as a hex string in JSON and as raw bytes in CBOR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hash([u8; 32]);
+
+impl Hash {
+    pub fn of(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl FromStr for Hash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(Hash)
+            .ok_or_else(|| format!("{} is not a hex encoded sha256 hash", s))
+    }
+}
+
+impl Serialize for Hash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+struct HashVisitor;
+
+impl<'de> Visitor<'de> for HashVisitor {
+    type Value = Hash;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a sha256 hash as a hex string or 32 bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Hash, E> {
+        Hash::from_str(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Hash, E> {
+        v.try_into()
+            .map(Hash)
+            .map_err(|_| E::invalid_length(v.len(), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(HashVisitor)
+        } else {
+            deserializer.deserialize_bytes(HashVisitor)
+        }
+    }
+}
+
+/// ProofSibling is a sibling hash on the path from a leaf to the root
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofSibling {
+    /// side of the parent the sibling sits on
+    pub side: Side,
+    pub hash: Hash,
+}
+
+/// ProofFile is a self-describing merkle proof of a single file that can be exported,
+/// shared and verified later without the server. It does not hold the file itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofFile {
+    format: String,
+    version: u32,
+    hash_algorithm: HashAlgorithm,
+    tree_version: u32,
+    /// index of the file among the leaves of the tree
+    leaf_index: u64,
+    /// number of leaves in the tree
+    tree_size: u64,
+    root: Hash,
+    leaf_hash: Hash,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_name: Option<String>,
+    /// siblings ordered from the leaf level up to the root
+    siblings: Vec<ProofSibling>,
+}
+
+impl ProofFile {
+    /// from_proof converts a proof sent by the server for the leaf at the index of a tree
+    /// of tree_size leaves. The siblings must be at the positions on the path of the leaf,
+    /// the side of each one follows from its position
+    pub fn from_proof(
+        proof: &MerkleProof,
+        index: usize,
+        tree_size: usize,
+        root: &str,
+    ) -> Result<Self, String> {
+        if proof.tree_size() != tree_size {
+            return Err(VerifyError::TreeSizeMismatch {
+                expected: tree_size,
+                actual: proof.tree_size(),
+            }
+            .to_string());
+        }
+
+        let hashes = proof.checked_path(index).map_err(|e| e.to_string())?;
+        let siblings = expected_path(index, tree_size)
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .zip(hashes)
+            .map(|(node, hash)| {
+                Ok(ProofSibling {
+                    side: node.side,
+                    hash: hash.parse()?,
+                })
+            })
+            .collect::<Result<Vec<ProofSibling>, String>>()?;
+
+        Ok(Self {
+            format: String::from(PROOF_FORMAT),
+            version: PROOF_FORMAT_VERSION,
+            hash_algorithm: HashAlgorithm::Sha256,
+            tree_version: TREE_VERSION,
+            leaf_index: index as u64,
+            tree_size: tree_size as u64,
+            root: root.parse()?,
+            leaf_hash: Hash::of(&proof.file_content()),
+            file_name: Some(proof.file_name()),
+            siblings,
+        })
+    }
+
+    pub fn leaf_index(&self) -> usize {
+        self.leaf_index as usize
+    }
+
+    pub fn tree_size(&self) -> usize {
+        self.tree_size as usize
+    }
+
+    pub fn root(&self) -> String {
+        self.root.to_string()
+    }
+
+    pub fn leaf_hash(&self) -> String {
+        self.leaf_hash.to_string()
+    }
+
+    pub fn file_name(&self) -> Option<String> {
+        self.file_name.clone()
+    }
+
+    pub fn siblings(&self) -> &[ProofSibling] {
+        &self.siblings
+    }
+
+    /// check_version checks that the proof is in a format and of a tree this build understands
+    fn check_version(self) -> Result<Self, String> {
+        if self.format != PROOF_FORMAT {
+            return Err(format!("{} is not a verifile proof", self.format));
+        }
+        if self.version != PROOF_FORMAT_VERSION {
+            return Err(format!(
+                "proof format version {} is not supported",
+                self.version
+            ));
+        }
+        if self.tree_version != TREE_VERSION {
+            return Err(format!(
+                "tree version {} is not supported",
+                self.tree_version
+            ));
+        }
+        Ok(self)
+    }
+
+    /// to_json encodes the proof as pretty printed JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("proof serialization should not fail")
+    }
+
+    /// to_cbor encodes the proof as CBOR
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(self, &mut buf).expect("proof serialization should not fail");
+        buf
+    }
+
+    /// from_json decodes a proof encoded with to_json
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str::<Self>(json)
+            .map_err(|e| format!("failed to decode proof: {}", e))?
+            .check_version()
+    }
+
+    /// from_cbor decodes a proof encoded with to_cbor
+    pub fn from_cbor(buf: &[u8]) -> Result<Self, String> {
+        ciborium::from_reader::<Self, _>(buf)
+            .map_err(|e| format!("failed to decode proof: {}", e))?
+            .check_version()
+    }
+
+    /// decode decodes a proof in either encoding. A JSON proof is an object, so it starts
+    /// with '{', which is never the first byte of a CBOR map
+    pub fn decode(buf: &[u8]) -> Result<Self, String> {
+        match buf.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => {
+                let json = std::str::from_utf8(buf)
+                    .map_err(|_| String::from("failed to decode proof: invalid utf-8"))?;
+                Self::from_json(json)
+            }
+            _ => Self::from_cbor(buf),
+        }
+    }
+
+    /// verify checks that leaf_data is the file the proof was made for and that it is
+    /// the leaf at the index of the proof in the tree with the root of the proof
+    pub fn verify(&self, leaf_data: &[u8]) -> Result<(), String> {
+        if Hash::of(leaf_data) != self.leaf_hash {
+            return Err(format!(
+                "file hash {} does not match the leaf hash {} of the proof",
+                Hash::of(leaf_data),
+                self.leaf_hash
+            ));
+        }
+
+        let sides = self
+            .siblings
+            .iter()
+            .map(|sibling| sibling.side)
+            .collect::<Vec<Side>>();
+        let hashes = self
+            .siblings
+            .iter()
+            .map(|sibling| sibling.hash.to_string())
+            .collect::<Vec<String>>();
+        check_sides(self.leaf_index(), self.tree_size(), &sides)
+            .and_then(|_| {
+                verify(
+                    &self.root.to_string(),
+                    leaf_data,
+                    self.leaf_index(),
+                    self.tree_size(),
+                    &hashes,
+                )
+            })
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl From<&ProofFile> for crate::grpc::Proof {
+    fn from(proof: &ProofFile) -> Self {
+        use crate::grpc;
+
+        Self {
+            hash_algorithm: String::from("sha256"),
+            tree_version: proof.tree_version,
+            leaf_index: proof.leaf_index,
+            tree_size: proof.tree_size,
+            root: proof.root.as_bytes().to_vec(),
+            leaf_hash: proof.leaf_hash.as_bytes().to_vec(),
+            file_name: proof.file_name.clone(),
+            siblings: proof
+                .siblings
+                .iter()
+                .map(|sibling| grpc::Sibling {
+                    side: match sibling.side {
+                        Side::Left => grpc::Side::Left,
+                        Side::Right => grpc::Side::Right,
+                    } as i32,
+                    hash: sibling.hash.as_bytes().to_vec(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl TryFrom<crate::grpc::Proof> for ProofFile {
+    type Error = String;
+
+    fn try_from(proof: crate::grpc::Proof) -> Result<Self, Self::Error> {
+        use crate::grpc::{self, hash_from_bytes};
+
+        if proof.hash_algorithm != "sha256" {
+            return Err(format!(
+                "hash algorithm {} is not supported",
+                proof.hash_algorithm
+            ));
+        }
+        let siblings = proof
+            .siblings
+            .iter()
+            .enumerate()
+            .map(|(depth, sibling)| {
+                let side = match sibling.side() {
+                    grpc::Side::Left => Side::Left,
+                    grpc::Side::Right => Side::Right,
+                    grpc::Side::Unspecified => {
+                        return Err(format!("sibling {} has no side", depth))
+                    }
+                };
+                Ok(ProofSibling {
+                    side,
+                    hash: Hash(hash_from_bytes(&sibling.hash, "sibling hash")?),
+                })
+            })
+            .collect::<Result<Vec<ProofSibling>, String>>()?;
+
+        Self {
+            format: String::from(PROOF_FORMAT),
+            version: PROOF_FORMAT_VERSION,
+            hash_algorithm: HashAlgorithm::Sha256,
+            tree_version: proof.tree_version,
+            leaf_index: proof.leaf_index,
+            tree_size: proof.tree_size,
+            root: Hash(hash_from_bytes(&proof.root, "root")?),
+            leaf_hash: Hash(hash_from_bytes(&proof.leaf_hash, "leaf hash")?),
+            file_name: proof.file_name,
+            siblings,
+        }
+        .check_version()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProofFile, Side};
+    use crate::model::merkle::{MerkleProof, MerkleTree};
+
+    fn data() -> Vec<Vec<u8>> {
+        ["Hello", "Lorem", "Ipsum", "Dolor", "Sit"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn proof_file(index: usize) -> ProofFile {
+        let tree = MerkleTree::from(data());
+        let proof = MerkleProof::build(&tree, index, String::from("f.txt"), data()[index].clone());
+        ProofFile::from_proof(&proof, index, data().len(), &tree.root_hash()).unwrap()
+    }
+
+    #[test]
+    fn exported_proofs_verify() {
+        for (index, leaf) in data().iter().enumerate() {
+            let proof = proof_file(index);
+            proof.verify(leaf).unwrap();
+            assert!(proof.verify(b"tampered").is_err());
+        }
+    }
+
+    #[test]
+    fn duplicated_node_is_on_the_right() {
+        // the fifth leaf is alone on its level and the one above, so it is paired with itself
+        let proof = proof_file(4);
+        let sides = proof
+            .siblings()
+            .iter()
+            .map(|sibling| sibling.side)
+            .collect::<Vec<Side>>();
+        assert_eq!(sides, vec![Side::Right, Side::Right, Side::Left]);
+    }
+
+    #[test]
+    fn proofs_with_wrong_sides_or_size_are_rejected() {
+        let mut proof = proof_file(1);
+        proof.siblings[0].side = Side::Right;
+        assert!(proof.verify(b"Lorem").is_err());
+
+        let mut proof = proof_file(1);
+        proof.tree_size = 2;
+        assert!(proof.verify(b"Lorem").is_err());
+    }
+
+    #[test]
+    fn json_and_cbor_round_trip() {
+        let proof = proof_file(1);
+        let json = proof.to_json();
+        assert!(json.contains("\"hash_algorithm\": \"sha256\""));
+        assert_eq!(ProofFile::decode(json.as_bytes()).unwrap(), proof);
+
+        let cbor = proof.to_cbor();
+        assert!(cbor.len() < json.len());
+        assert_eq!(ProofFile::decode(&cbor).unwrap(), proof);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        let json = proof_file(1)
+            .to_json()
+            .replace("\"version\": 1", "\"version\": 2");
+        assert!(ProofFile::from_json(&json).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const RECEIPT_DOMAIN: &str = "verifile-receipt-v1";
+const TRANSITION_DOMAIN: &str = "verifile-transition-v1";
+
+/// Receipt records what the server agreed to store for an upload session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    session_id: String,
+    merkle_root: String,
+    files_count: usize,
+    /// seconds since the unix epoch at which the server stored the files
+    timestamp: u64,
+    /// root the session had before a sync replaced its files, None for an upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    previous_root: Option<String>,
+}
+
+impl Receipt {
+    pub fn new(session_id: String, merkle_root: String, files_count: usize) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system time should be after the unix epoch")
+            .as_secs();
+        Self {
+            session_id,
+            merkle_root,
+            files_count,
+            timestamp,
+            previous_root: None,
+        }
+    }
+
+    /// set_previous_root makes the receipt record the transition of a sync from the
+    /// root the session had before to the new one
+    pub fn set_previous_root(&mut self, previous_root: String) {
+        self.previous_root = Some(previous_root);
+    }
+
+    pub fn session_id(&self) -> String {
+        self.session_id.clone()
+    }
+
+    pub fn merkle_root(&self) -> String {
+        self.merkle_root.clone()
+    }
+
+    pub fn files_count(&self) -> usize {
+        self.files_count
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn previous_root(&self) -> Option<&str> {
+        self.previous_root.as_deref()
+    }
+
+    /// signing_bytes is the canonical encoding of the receipt that gets signed.
+    /// It does not depend on how the receipt is serialized on the wire or on disk.
+    /// Transitions are signed in their own domain, so that the receipt of an upload
+    /// cannot be passed off as one of a sync or the other way around
+    fn signing_bytes(&self) -> Vec<u8> {
+        match &self.previous_root {
+            None => format!(
+                "{}\n{}\n{}\n{}\n{}",
+                RECEIPT_DOMAIN, self.session_id, self.merkle_root, self.files_count, self.timestamp
+            ),
+            Some(previous_root) => format!(
+                "{}\n{}\n{}\n{}\n{}\n{}",
+                TRANSITION_DOMAIN,
+                self.session_id,
+                previous_root,
+                self.merkle_root,
+                self.files_count,
+                self.timestamp
+            ),
+        }
+        .into_bytes()
+    }
+
+    /// sign signs the receipt with the long-term key of the server
+    pub fn sign(self, key: &SigningKey) -> SignedReceipt {
+        let signature = key.sign(&self.signing_bytes());
+        SignedReceipt {
+            receipt: self,
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            signature: hex::encode(signature.to_bytes()),
+        }
+    }
+}
+
+/// SignedReceipt is a receipt with the signature of the server and the public key
+/// it can be checked with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedReceipt {
+    receipt: Receipt,
+    /// hex encoded ed25519 public key of the server
+    public_key: String,
+    /// hex encoded ed25519 signature over the receipt
+    signature: String,
+}
+
+impl SignedReceipt {
+    pub fn receipt(&self) -> &Receipt {
+        &self.receipt
+    }
+
+    pub fn public_key(&self) -> String {
+        self.public_key.clone()
+    }
+
+    /// verify checks that the receipt was signed by the holder of its public key
+    pub fn verify(&self) -> Result<(), String> {
+        let public_key: [u8; 32] = hex::decode(&self.public_key)
+            .ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(|| String::from("receipt public key is malformed"))?;
+        let public_key = VerifyingKey::from_bytes(&public_key)
+            .map_err(|_| String::from("receipt public key is invalid"))?;
+
+        let signature: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|signature| signature.try_into().ok())
+            .ok_or_else(|| String::from("receipt signature is malformed"))?;
+
+        public_key
+            .verify(
+                &self.receipt.signing_bytes(),
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| String::from("receipt signature is invalid"))
+    }
+
+    /// verify_for checks the signature of the receipt and that it covers the
+    /// expected merkle root and number of files. If a trusted public key is given,
+    /// the receipt must also have been signed with it
+    pub fn verify_for(
+        &self,
+        merkle_root: &str,
+        files_count: usize,
+        trusted_key: Option<&str>,
+    ) -> Result<(), String> {
+        self.verify()?;
+
+        if let Some(trusted_key) = trusted_key {
+            if !trusted_key.eq_ignore_ascii_case(&self.public_key) {
+                return Err(format!(
+                    "receipt was signed by {}, not by the trusted server key",
+                    self.public_key
+                ));
+            }
+        }
+        if self.receipt.merkle_root != merkle_root {
+            return Err(format!(
+                "receipt is for merkle root {}, expected {}",
+                self.receipt.merkle_root, merkle_root
+            ));
+        }
+        if self.receipt.files_count != files_count {
+            return Err(format!(
+                "receipt is for {} files, expected {}",
+                self.receipt.files_count, files_count
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl TryFrom<&SignedReceipt> for crate::grpc::Receipt {
+    type Error = String;
+
+    /// the hex encoded fields are decoded, a receipt that does not hold hex is rejected
+    fn try_from(signed: &SignedReceipt) -> Result<Self, Self::Error> {
+        let decode = |field: &str, value: &str| {
+            hex::decode(value).map_err(|e| format!("receipt {} is not hex encoded: {}", field, e))
+        };
+        let receipt = &signed.receipt;
+        Ok(Self {
+            session_id: receipt.session_id.clone(),
+            merkle_root: decode("merkle root", &receipt.merkle_root)?,
+            files_count: receipt.files_count as u64,
+            timestamp: receipt.timestamp,
+            public_key: decode("public key", &signed.public_key)?,
+            signature: decode("signature", &signed.signature)?,
+            previous_root: receipt
+                .previous_root
+                .as_deref()
+                .map(|root| decode("previous root", root))
+                .transpose()?,
+        })
+    }
+}
+
+#[cfg(feature = "grpc")]
+impl TryFrom<crate::grpc::Receipt> for SignedReceipt {
+    type Error = String;
+
+    /// the receipt is taken as it was sent, it should be checked with verify
+    fn try_from(receipt: crate::grpc::Receipt) -> Result<Self, Self::Error> {
+        let files_count = usize::try_from(receipt.files_count)
+            .map_err(|_| format!("receipt is for too many files: {}", receipt.files_count))?;
+        Ok(Self {
+            receipt: Receipt {
+                session_id: receipt.session_id,
+                merkle_root: hex::encode(receipt.merkle_root),
+                files_count,
+                timestamp: receipt.timestamp,
+                previous_root: receipt.previous_root.map(hex::encode),
+            },
+            public_key: hex::encode(receipt.public_key),
+            signature: hex::encode(receipt.signature),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Receipt;
+    use ed25519_dalek::SigningKey;
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn signed_receipt_verifies() {
+        let signed = Receipt::new(String::from("session"), String::from("root"), 3).sign(&key(1));
+        signed.verify().unwrap();
+        signed.verify_for("root", 3, None).unwrap();
+        signed
+            .verify_for("root", 3, Some(&signed.public_key()))
+            .unwrap();
+    }
+
+    #[test]
+    fn altered_receipt_does_not_verify() {
+        let mut signed =
+            Receipt::new(String::from("session"), String::from("root"), 3).sign(&key(1));
+        signed.receipt.files_count = 2;
+        assert!(signed.verify().is_err());
+    }
+
+    #[test]
+    fn receipt_for_other_upload_does_not_verify() {
+        let signed = Receipt::new(String::from("session"), String::from("root"), 3).sign(&key(1));
+        assert!(signed.verify_for("other root", 3, None).is_err());
+        assert!(signed.verify_for("root", 4, None).is_err());
+    }
+
+    #[test]
+    fn transition_is_signed_with_its_previous_root() {
+        let mut receipt = Receipt::new(String::from("session"), String::from("root"), 3);
+        receipt.set_previous_root(String::from("old root"));
+        let mut signed = receipt.sign(&key(1));
+        signed.verify_for("root", 3, None).unwrap();
+        assert_eq!(signed.receipt().previous_root(), Some("old root"));
+
+        signed.receipt.previous_root = Some(String::from("other root"));
+        assert!(signed.verify().is_err());
+        signed.receipt.previous_root = None;
+        assert!(signed.verify().is_err());
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn receipt_converts_to_grpc_and_back() {
+        use super::SignedReceipt;
+
+        let root = hex::encode([1u8; 32]);
+        let mut receipt = Receipt::new(String::from("session"), root.clone(), 3);
+        receipt.set_previous_root(hex::encode([2u8; 32]));
+        let signed = receipt.sign(&key(1));
+        let sent = crate::grpc::Receipt::try_from(&signed).unwrap();
+        assert_eq!(sent.previous_root, Some(vec![2u8; 32]));
+        let received = SignedReceipt::try_from(sent).unwrap();
+        assert_eq!(received, signed);
+        received.verify_for(&root, 3, None).unwrap();
+
+        // a root that is not hex is an error rather than an empty root
+        let signed = Receipt::new(String::from("session"), String::from("root"), 3).sign(&key(1));
+        assert!(crate::grpc::Receipt::try_from(&signed).is_err());
+    }
+
+    #[test]
+    fn receipt_from_untrusted_key_does_not_verify() {
+        let signed = Receipt::new(String::from("session"), String::from("root"), 3).sign(&key(1));
+        let trusted = hex::encode(key(2).verifying_key().as_bytes());
+        assert!(signed.verify_for("root", 3, Some(&trusted)).is_err());
+    }
+}
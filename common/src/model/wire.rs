@@ -0,0 +1,89 @@
+//! serde helpers for the fields that make up most of a message. Human readable
+//! encodings keep them as they always were, binary encodings carry them as raw bytes
+use crate::model::proof_file::Hash;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// content encodes file content as an array of numbers in JSON and as a byte string otherwise
+pub(crate) mod content {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(content: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            content.serialize(serializer)
+        } else {
+            serializer.serialize_bytes(content)
+        }
+    }
+
+    struct ContentVisitor;
+
+    impl<'de> Visitor<'de> for ContentVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "file content as bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(v)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut content = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(byte) = seq.next_element()? {
+                content.push(byte);
+            }
+            Ok(content)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_seq(ContentVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(ContentVisitor)
+        }
+    }
+}
+
+/// siblings encodes the (level, index, hash) siblings of a proof with hex hashes in JSON
+/// and with 32 byte hashes otherwise
+pub(crate) mod siblings {
+    use super::*;
+
+    type Sibling = (usize, usize, String);
+
+    pub fn serialize<S: Serializer>(
+        siblings: &[Sibling],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            return siblings.serialize(serializer);
+        }
+        siblings
+            .iter()
+            .map(|(level, index, hash)| Hash::from_str(hash).map(|hash| (*level, *index, hash)))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(serde::ser::Error::custom)?
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Sibling>, D::Error> {
+        if deserializer.is_human_readable() {
+            return Vec::<Sibling>::deserialize(deserializer);
+        }
+        Ok(Vec::<(usize, usize, Hash)>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(level, index, hash)| (level, index, hash.to_string()))
+            .collect())
+    }
+}
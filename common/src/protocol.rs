@@ -0,0 +1,579 @@
+use crate::compression::{Compression, MIN_COMPRESSED_SIZE};
+use crate::diff::FileDiff;
+use crate::handshake::{Agreement, Hello};
+use crate::model::file_info::FileInfo;
+use crate::model::merkle::MerkleProof;
+use crate::model::receipt::SignedReceipt;
+use crate::replication::{ReplicationRequest, ReplicationResponse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// DEFAULT_MAX_MESSAGE_SIZE is the size messages are rejected over unless the receiver
+/// allows larger ones. An upload is sent as a single message, so it also bounds uploads
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 1 << 30;
+
+/// Role is the access a user has to an upload session
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    /// the owner can read the session and grant access to other users
+    Owner,
+    /// a reader can list, download and get proofs of files in the session
+    ReadOnly,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Role::Owner),
+            "read-only" => Ok(Role::ReadOnly),
+            _ => Err(format!("{} is not a valid role", s)),
+        }
+    }
+}
+
+/// Request is sent by the client to the server. Each connection carries a single request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// Hello opens a connection of protocol version 2 or later, the request follows it
+    /// on the same connection
+    Hello(Hello),
+    /// Upload creates a new session owned by the caller holding the files
+    Upload { files: Vec<FileInfo> },
+    /// StartUpload starts an upload owned by the caller whose files are sent one per
+    /// request, so that no request carries more than one of them
+    StartUpload,
+    /// PutFile adds a file to an upload started with a Request::StartUpload
+    PutFile { session_id: String, file: FileInfo },
+    /// CompleteUpload creates the session of an upload once every file was put in it,
+    /// it is answered like a Request::Upload
+    CompleteUpload { session_id: String },
+    /// Download gets a file of a session along with its merkle proof
+    Download { session_id: String, index: usize },
+    /// DownloadAll gets every file of a session. The server answers with a Response::Batch,
+    /// then sends each file with its merkle proof as a Response::File, in index order
+    DownloadAll { session_id: String },
+    /// List gets the index and name of all the files in a session
+    List { session_id: String },
+    /// Grant gives another user access to a session. Only the owner can grant access
+    Grant {
+        session_id: String,
+        user: String,
+        role: Role,
+    },
+    /// Delete removes a session and its files. Only the owner can delete a session
+    Delete { session_id: String },
+    /// Sessions gets the metadata of all the sessions the caller has access to
+    Sessions,
+    /// Sync replaces the files of a session owned by the caller, keeping the ones that did
+    /// not change without sending them again. It is only applied if the session still has
+    /// the base root, so that a sync made against an older state is not lost
+    Sync {
+        session_id: String,
+        base_root: String,
+        files: Vec<SyncFile>,
+    },
+    /// Diff compares the files of two sessions the caller has access to, from the first to the second
+    Diff { from: String, to: String },
+    /// Replication is sent between a primary and its replicas, with the replication token
+    Replication(ReplicationRequest),
+}
+
+/// SyncFile is a file of a session after a sync, in index order
+#[derive(Debug, Serialize, Deserialize)]
+pub enum SyncFile {
+    /// Keep keeps the file at the index of the session, with its name
+    Keep { index: usize },
+    /// Upload sends the file, its index is its position in the sync
+    Upload(FileInfo),
+}
+
+impl Request {
+    /// operation names the kind of request, for logs and metrics
+    pub fn operation(&self) -> &'static str {
+        match self {
+            Request::Hello(_) => "hello",
+            Request::Upload { .. } => "upload",
+            Request::StartUpload => "start-upload",
+            Request::PutFile { .. } => "put-file",
+            Request::CompleteUpload { .. } => "complete-upload",
+            Request::Download { .. } => "download",
+            Request::DownloadAll { .. } => "download-all",
+            Request::List { .. } => "list",
+            Request::Grant { .. } => "grant",
+            Request::Delete { .. } => "delete",
+            Request::Sessions => "sessions",
+            Request::Sync { .. } => "sync",
+            Request::Diff { .. } => "diff",
+            Request::Replication(_) => "replication",
+        }
+    }
+}
+
+/// Envelope wraps a request with the credentials of the caller
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub token: Option<String>,
+    pub request: Request,
+}
+
+/// FileEntry describes a stored file without its content
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub index: usize,
+    pub name: String,
+}
+
+/// SessionInfo describes a stored session without its files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub session_id: String,
+    pub owner: String,
+    /// seconds since the unix epoch at which the session was uploaded
+    pub created_at: u64,
+    /// seconds since the unix epoch after which the session is deleted, if it expires
+    pub expires_at: Option<u64>,
+    pub files_count: usize,
+    /// total size of the files of the session in bytes
+    pub size: usize,
+}
+
+/// Response is sent by the server to the client in reply to a request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Hello(Agreement),
+    Uploaded {
+        session_id: String,
+        receipt: SignedReceipt,
+    },
+    /// UploadStarted answers a Request::StartUpload with the ID the session will have
+    UploadStarted {
+        session_id: String,
+    },
+    FilePut,
+    File(MerkleProof),
+    /// Batch announces the number of files that follow in answer to a Request::DownloadAll
+    Batch {
+        files_count: usize,
+    },
+    Files(Vec<FileEntry>),
+    Granted,
+    Deleted,
+    Sessions(Vec<SessionInfo>),
+    /// Synced answers a Request::Sync with a receipt of the new root that records the previous one
+    Synced {
+        receipt: SignedReceipt,
+    },
+    Diff(Vec<FileDiff>),
+    Replication(ReplicationResponse),
+    Error(ProtocolError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    /// the request could not be understood
+    BadRequest,
+    /// the caller did not give valid credentials
+    Unauthenticated,
+    /// the caller is not allowed to do this on the session
+    Forbidden,
+    /// the session or file does not exist
+    NotFound,
+    /// the upload would take the caller over its storage quota
+    QuotaExceeded,
+    /// the request is larger than the server reads
+    TooLarge,
+    /// the server does not speak the protocol version or features of the client
+    Unsupported,
+    /// the session changed since the state the request was made against
+    Conflict,
+}
+
+/// ProtocolError is returned by the server when it cannot serve a request
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Encoding is how the body of a message is encoded. Version 1 of the protocol only
+/// spoke JSON, version 2 adds CBOR, which carries file content and hashes as raw bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    Json,
+    #[default]
+    Cbor,
+}
+
+impl Encoding {
+    /// name is how the encoding is written in handshakes
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Encoding::Json => 0,
+            Encoding::Cbor => 1,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, String> {
+        match tag {
+            0 => Ok(Encoding::Json),
+            1 => Ok(Encoding::Cbor),
+            _ => Err(format!("message encoding {} is not supported", tag)),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Encoding::Json, Encoding::Cbor]
+            .into_iter()
+            .find(|e| e.name() == s)
+            .ok_or_else(|| format!("{} is not a valid encoding", s))
+    }
+}
+
+/// Format is how messages are sent: in an encoding and, once a handshake agreed on one,
+/// compressed when it makes them smaller. The format of a message is written in the top
+/// byte of its length prefix, the encoding in the low four bits and the compression in
+/// the high four. Messages of protocol version 1 are never 2^56 bytes long,
+/// so their top byte is always 0, uncompressed JSON
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Format {
+    pub encoding: Encoding,
+    pub compression: Option<Compression>,
+}
+
+impl Format {
+    fn tag(&self) -> u8 {
+        let compression = match self.compression {
+            None => 0,
+            Some(Compression::Gzip) => 1,
+            Some(Compression::Zstd) => 2,
+        };
+        compression << 4 | self.encoding.tag()
+    }
+
+    fn from_tag(tag: u8) -> Result<Self, String> {
+        let compression = match tag >> 4 {
+            0 => None,
+            1 => Some(Compression::Gzip),
+            2 => Some(Compression::Zstd),
+            c => return Err(format!("message compression {} is not supported", c)),
+        };
+        Ok(Self {
+            encoding: Encoding::from_tag(tag & 0x0f)?,
+            compression,
+        })
+    }
+}
+
+impl From<Encoding> for Format {
+    fn from(encoding: Encoding) -> Self {
+        Self {
+            encoding,
+            compression: None,
+        }
+    }
+}
+
+/// encode_message encodes a message as a big endian u64 length, with the tag of its
+/// format in the top byte, followed by the encoded message. The message is compressed
+/// if the format allows it and it gets smaller
+pub fn encode_message<T: Serialize>(
+    message: &T,
+    format: impl Into<Format>,
+) -> Result<Vec<u8>, String> {
+    let format = format.into();
+    let mut buf = vec![0u8; 8];
+    match format.encoding {
+        Encoding::Json => serde_json::to_writer(&mut buf, message).map_err(|e| e.to_string()),
+        Encoding::Cbor => ciborium::into_writer(message, &mut buf).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("failed to encode message: {}", e))?;
+
+    let mut sent = Format::from(format.encoding);
+    if let Some(compression) = format.compression {
+        if buf.len() - 8 >= MIN_COMPRESSED_SIZE {
+            let compressed = compression.compress(&buf[8..])?;
+            if compressed.len() < buf.len() - 8 {
+                buf.truncate(8);
+                buf.extend_from_slice(&compressed);
+                sent.compression = Some(compression);
+            }
+        }
+    }
+
+    let len = (buf.len() - 8) as u64;
+    if len >= 1 << 56 {
+        return Err(format!("message of {} bytes is too large", len));
+    }
+    let prefix = len | (sent.tag() as u64) << 56;
+    buf[..8].copy_from_slice(&prefix.to_be_bytes());
+    Ok(buf)
+}
+
+/// decode_message_len decodes the length prefix of a message into the format
+/// and length of the message, and checks it is not over max_size
+pub fn decode_message_len(len_buf: [u8; 8], max_size: u64) -> Result<(Format, u64), String> {
+    let format = Format::from_tag(len_buf[0])?;
+    let len = u64::from_be_bytes(len_buf) & ((1 << 56) - 1);
+    if len > max_size {
+        return Err(format!("message of {} bytes is too large", len));
+    }
+    Ok((format, len))
+}
+
+/// decode_message_body decompresses and decodes the body of a message that follows its
+/// length prefix, which cannot decompress to more than max_size
+pub fn decode_message_body<T: DeserializeOwned>(
+    body: &[u8],
+    format: Format,
+    max_size: u64,
+) -> Result<T, String> {
+    let decompressed;
+    let body = match format.compression {
+        Some(compression) => {
+            decompressed = compression.decompress(body, max_size)?;
+            decompressed.as_slice()
+        }
+        None => body,
+    };
+    match format.encoding {
+        Encoding::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+        Encoding::Cbor => ciborium::from_reader(body).map_err(|e| e.to_string()),
+    }
+    .map_err(|e| format!("failed to decode message: {}", e))
+}
+
+/// write_message writes a message encoded with encode_message
+pub fn write_message<T: Serialize>(
+    stream: &mut impl Write,
+    message: &T,
+    format: impl Into<Format>,
+) -> Result<(), String> {
+    stream
+        .write_all(&encode_message(message, format)?)
+        .and_then(|_| stream.flush())
+        .map_err(|e| format!("failed to send message: {}", e))
+}
+
+/// read_message reads a message written by write_message along with the format it was sent in,
+/// rejecting messages over DEFAULT_MAX_MESSAGE_SIZE
+pub fn read_message<T: DeserializeOwned>(stream: &mut impl Read) -> Result<(T, Format), String> {
+    read_message_within(stream, DEFAULT_MAX_MESSAGE_SIZE)
+}
+
+/// read_message_within reads a message like read_message, rejecting messages over max_size.
+/// The body grows as it is received rather than being allocated from the length prefix,
+/// so a prefix that the bytes sent do not back up does not cost memory
+pub fn read_message_within<T: DeserializeOwned>(
+    stream: &mut impl Read,
+    max_size: u64,
+) -> Result<(T, Format), String> {
+    let mut len_buf = [0u8; 8];
+    stream
+        .read_exact(&mut len_buf)
+        .map_err(|e| format!("failed to read message length: {}", e))?;
+
+    let (format, len) = decode_message_len(len_buf, max_size)?;
+    let mut body = Vec::new();
+    stream
+        .take(len)
+        .read_to_end(&mut body)
+        .map_err(|e| format!("failed to read message: {}", e))?;
+    if (body.len() as u64) < len {
+        return Err(format!(
+            "message was cut short after {} of {} bytes",
+            body.len(),
+            len
+        ));
+    }
+    Ok((decode_message_body(&body, format, max_size)?, format))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::merkle::{hash_leaf, MerkleTree};
+
+    #[test]
+    fn message_round_trip_works() {
+        let envelope = Envelope {
+            token: Some(String::from("secret")),
+            request: Request::Grant {
+                session_id: String::from("abc"),
+                user: String::from("bob"),
+                role: Role::ReadOnly,
+            },
+        };
+
+        for encoding in [Encoding::Json, Encoding::Cbor] {
+            let mut buf = Vec::new();
+            write_message(&mut buf, &envelope, encoding).unwrap();
+            let (decoded, format): (Envelope, _) = read_message(&mut buf.as_slice()).unwrap();
+
+            assert_eq!(format, Format::from(encoding));
+            assert_eq!(decoded.token, envelope.token);
+            let Request::Grant {
+                session_id,
+                user,
+                role,
+            } = decoded.request
+            else {
+                panic!("decoded request should be a grant");
+            };
+            assert_eq!(
+                (session_id.as_str(), user.as_str(), role),
+                ("abc", "bob", Role::ReadOnly)
+            );
+        }
+    }
+
+    #[test]
+    fn json_messages_of_version_1_are_still_read() {
+        // what a client of protocol version 1 sent: a plain length and the JSON body
+        let body = br#"{"token":null,"request":{"List":{"session_id":"abc"}}}"#;
+        let mut buf = (body.len() as u64).to_be_bytes().to_vec();
+        buf.extend_from_slice(body);
+
+        let (envelope, format): (Envelope, _) = read_message(&mut buf.as_slice()).unwrap();
+        assert_eq!(format, Format::from(Encoding::Json));
+        assert!(matches!(envelope.request, Request::List { session_id } if session_id == "abc"));
+    }
+
+    #[test]
+    fn binary_messages_carry_content_and_hashes_as_bytes() {
+        let content = (0..=255u8).cycle().take(100_000).collect::<Vec<u8>>();
+        let tree = MerkleTree::from(vec![content.clone(), b"other".to_vec()]);
+        let response = Response::File(MerkleProof::build(
+            &tree,
+            0,
+            String::from("data.bin"),
+            content.clone(),
+        ));
+
+        let json = encode_message(&response, Encoding::Json).unwrap();
+        let cbor = encode_message(&response, Encoding::Cbor).unwrap();
+        assert!(json.len() > 3 * content.len());
+        assert!(cbor.len() < content.len() + 256);
+
+        let (decoded, _): (Response, _) = read_message(&mut cbor.as_slice()).unwrap();
+        let Response::File(proof) = decoded else {
+            panic!("decoded response should be a file");
+        };
+        assert_eq!(proof.file_content(), content);
+        let siblings = proof.siblings();
+        assert_eq!(siblings.len(), 1);
+        assert_eq!(siblings[0].2, hex::encode(hash_leaf(b"other")));
+    }
+
+    #[test]
+    fn compressed_messages_hold_the_uncompressed_content() {
+        let content = std::fs::read("../files/schools.csv").unwrap();
+        let tree = MerkleTree::from(vec![content.clone(), b"other".to_vec()]);
+        let response = Response::File(MerkleProof::build(
+            &tree,
+            0,
+            String::from("schools.csv"),
+            content.clone(),
+        ));
+        let uncompressed = encode_message(&response, Encoding::Cbor).unwrap();
+
+        for compression in Compression::ALL {
+            let format = Format {
+                encoding: Encoding::Cbor,
+                compression: Some(compression),
+            };
+            let buf = encode_message(&response, format).unwrap();
+            assert!(buf.len() * 3 < uncompressed.len());
+
+            let (decoded, sent): (Response, _) = read_message(&mut buf.as_slice()).unwrap();
+            assert_eq!(sent, format);
+            let Response::File(proof) = decoded else {
+                panic!("decoded response should be a file");
+            };
+            // the leaf is the hash of the content, whatever the codec
+            assert_eq!(proof.file_content(), content);
+            let path = proof.checked_path(0).unwrap();
+            crate::verify::verify(&tree.root_hash(), &proof.file_content(), 0, 2, &path).unwrap();
+        }
+    }
+
+    #[test]
+    fn small_messages_are_not_compressed() {
+        let format = Format {
+            encoding: Encoding::Cbor,
+            compression: Some(Compression::Zstd),
+        };
+        let buf = encode_message(&Response::Granted, format).unwrap();
+        let (_, sent): (Response, _) = read_message(&mut buf.as_slice()).unwrap();
+        assert_eq!(sent.compression, None);
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Response::Granted, Encoding::Cbor).unwrap();
+        buf.pop();
+        assert!(read_message::<Response>(&mut buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn oversized_message_is_rejected() {
+        let buf = (DEFAULT_MAX_MESSAGE_SIZE + 1).to_be_bytes();
+        assert!(read_message::<Response>(&mut buf.as_slice()).is_err());
+
+        let mut buf = Vec::new();
+        write_message(&mut buf, &Response::Deleted, Encoding::Json).unwrap();
+        assert!(read_message_within::<Response>(&mut buf.as_slice(), 4).is_err());
+        assert!(read_message_within::<Response>(&mut buf.as_slice(), 64).is_ok());
+    }
+
+    #[test]
+    fn length_prefix_is_not_allocated_up_front() {
+        // a prefix announcing the largest message allowed, followed by a few bytes
+        let mut buf = DEFAULT_MAX_MESSAGE_SIZE.to_be_bytes().to_vec();
+        buf.extend_from_slice(b"{}");
+        let e = read_message::<Response>(&mut buf.as_slice()).unwrap_err();
+        assert!(e.contains("cut short after 2"), "{}", e);
+    }
+
+    #[test]
+    fn unknown_encoding_is_rejected() {
+        let buf = [7, 0, 0, 0, 0, 0, 0, 0];
+        assert!(read_message::<Response>(&mut buf.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+//! replication is spoken between a primary server and its replicas. The primary tells
+//! replicas which sessions changed, and replicas reconcile each of them by walking the
+//! merkle tree of the primary from the root down, fetching only the files that differ.
+//! Every request is sent with the replication token the servers share.
+
+use crate::model::file_info::FileInfo;
+use crate::model::proof_file::Hash;
+use crate::protocol::Role;
+use serde::{Deserialize, Serialize};
+
+/// MAX_NODES is the number of node hashes a single ReplicationRequest::Nodes can ask for
+pub const MAX_NODES: usize = 4096;
+
+/// MAX_LEAVES is the number of files a single ReplicationRequest::Leaves can ask for
+pub const MAX_LEAVES: usize = 64;
+
+/// ReplicationRequest is sent by a server to another one it replicates with
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationRequest {
+    /// Changed tells a replica that a session changed on the primary
+    Changed { session_id: String },
+    /// Roots gets the root and revision of every session of the primary
+    Roots,
+    /// State gets what a session of the primary is made of, apart from its files
+    State { session_id: String },
+    /// Nodes gets the hashes of nodes of the merkle tree of a session, at the indexes of
+    /// the level the given number of levels above the leaves
+    Nodes {
+        session_id: String,
+        depth: usize,
+        indexes: Vec<usize>,
+    },
+    /// Leaves gets the files at the indexes of a session
+    Leaves {
+        session_id: String,
+        indexes: Vec<usize>,
+    },
+}
+
+/// ReplicationResponse answers a ReplicationRequest
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ReplicationResponse {
+    /// Accepted answers a ReplicationRequest::Changed, the replica reconciles the session later
+    Accepted,
+    Roots(Vec<SessionRoot>),
+    /// State is None if the primary has no such session
+    State(Option<SessionState>),
+    Nodes(Vec<Hash>),
+    Leaves(Vec<FileInfo>),
+}
+
+/// SessionRoot is the merkle root and revision of a session. A replica whose copy has
+/// both does not need to reconcile it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRoot {
+    pub session_id: String,
+    pub merkle_root: String,
+    pub revision: u64,
+}
+
+/// SessionState is a session without the content of its files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionState {
+    pub owner: String,
+    pub grants: Vec<(String, Role)>,
+    /// seconds since the unix epoch at which the session was uploaded
+    pub created_at: u64,
+    /// seconds since the unix epoch after which the session is deleted, if it expires
+    pub expires_at: Option<u64>,
+    /// number of changes made to the session since it was uploaded
+    pub revision: u64,
+    pub merkle_root: String,
+    /// names of the files ordered by index
+    pub names: Vec<String>,
+}
+
+/// nodes_above_leaves returns the number of nodes of a tree of the number of leaves
+/// at the level the given number of levels above the leaves
+pub fn nodes_above_leaves(leaves: usize, depth: usize) -> usize {
+    match depth {
+        0 => leaves,
+        // the last node of an odd level is paired with itself, every level rounds up
+        _ if depth >= usize::BITS as usize => leaves.min(1),
+        _ => leaves.div_ceil(1 << depth),
+    }
+}
+
+/// tree_height returns the number of levels below the root of a tree of the number of leaves
+pub fn tree_height(leaves: usize) -> usize {
+    leaves.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::model::merkle::MerkleTree;
+
+    #[test]
+    fn level_sizes_match_the_tree() {
+        for leaves in 1..40 {
+            let tree = MerkleTree::from(
+                (0..leaves)
+                    .map(|i: usize| i.to_le_bytes().to_vec())
+                    .collect::<Vec<Vec<u8>>>(),
+            );
+            assert_eq!(tree_height(leaves), tree.height());
+            for depth in 0..=tree.height() {
+                let level = tree.height() - depth;
+                let count = (0..).take_while(|i| tree.node(level, *i).is_some()).count();
+                assert_eq!(nodes_above_leaves(leaves, depth), count);
+            }
+        }
+    }
+}
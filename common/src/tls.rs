@@ -0,0 +1,88 @@
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// load_certs reads all the PEM encoded certificates in a file
+pub fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| format!("failed to read certificates from {}: {}", path, e))?;
+
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", path));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// load_private_key reads the first PKCS8, RSA or SEC1 private key in a PEM file
+pub fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+    let mut reader = BufReader::new(file);
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| format!("failed to read private key from {}: {}", path, e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(format!("no private key found in {}", path)),
+        }
+    }
+}
+
+/// load_root_store builds a root store trusting only the certificates in a file.
+/// The client uses it to pin the certificate authority of the server
+pub fn load_root_store(path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| format!("invalid certificate authority in {}: {}", path, e))?;
+    }
+    Ok(roots)
+}
+
+/// server_config builds the TLS configuration of the server. If a client CA file
+/// is given, clients must present a certificate signed by it (mutual TLS)
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<Arc<ServerConfig>, String> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match client_ca_path {
+        Some(path) => builder.with_client_cert_verifier(Arc::new(
+            AllowAnyAuthenticatedClient::new(load_root_store(path)?),
+        )),
+        None => builder.with_no_client_auth(),
+    };
+
+    let config = builder
+        .with_single_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+        .map_err(|e| format!("invalid server certificate or key: {}", e))?;
+    Ok(Arc::new(config))
+}
+
+/// client_config builds the TLS configuration of the client. Only servers with a
+/// certificate signed by the CA in ca_path are trusted. If a certificate and key
+/// are given, they are presented to the server for mutual TLS
+pub fn client_config(
+    ca_path: &str,
+    client_auth: Option<(&str, &str)>,
+) -> Result<Arc<ClientConfig>, String> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(load_root_store(ca_path)?);
+
+    let config = match client_auth {
+        Some((cert_path, key_path)) => builder
+            .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+            .map_err(|e| format!("invalid client certificate or key: {}", e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
@@ -0,0 +1,258 @@
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, ServerName};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// ClientTls holds what the client needs to open a TLS connection to the server
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: ServerName,
+}
+
+impl ClientTls {
+    pub fn new(config: Arc<ClientConfig>, server_name: &str) -> Result<Self, String> {
+        let server_name = ServerName::try_from(server_name)
+            .map_err(|_| format!("{} is not a valid server name", server_name))?;
+        Ok(Self {
+            config,
+            server_name,
+        })
+    }
+
+    pub fn config(&self) -> Arc<ClientConfig> {
+        Arc::clone(&self.config)
+    }
+
+    pub fn server_name(&self) -> ServerName {
+        self.server_name.clone()
+    }
+}
+
+/// Stream is a connection between the client and the server,
+/// either over plain TCP or over TLS
+pub enum Stream {
+    Plain(TcpStream),
+    ServerTls(Box<rustls::StreamOwned<ServerConnection, TcpStream>>),
+    ClientTls(Box<rustls::StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// connect opens a connection to the server at the address,
+    /// completing the TLS handshake first if tls is given
+    pub fn connect(address: &str, tls: Option<&ClientTls>) -> Result<Self, String> {
+        let mut tcp = TcpStream::connect(address)
+            .map_err(|e| format!("failed to connect to {}: {}", address, e))?;
+
+        let Some(tls) = tls else {
+            return Ok(Stream::Plain(tcp));
+        };
+
+        let mut conn = ClientConnection::new(Arc::clone(&tls.config), tls.server_name.clone())
+            .map_err(|e| format!("failed to create tls connection: {}", e))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)
+                .map_err(|e| format!("tls handshake with {} failed: {}", address, e))?;
+        }
+        Ok(Stream::ClientTls(Box::new(rustls::StreamOwned::new(
+            conn, tcp,
+        ))))
+    }
+
+    /// accept wraps a connection accepted by the server,
+    /// completing the TLS handshake first if a config is given
+    pub fn accept(mut tcp: TcpStream, tls: Option<&Arc<ServerConfig>>) -> Result<Self, String> {
+        let Some(config) = tls else {
+            return Ok(Stream::Plain(tcp));
+        };
+
+        let mut conn = ServerConnection::new(Arc::clone(config))
+            .map_err(|e| format!("failed to create tls connection: {}", e))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut tcp)
+                .map_err(|e| format!("tls handshake failed: {}", e))?;
+        }
+        Ok(Stream::ServerTls(Box::new(rustls::StreamOwned::new(
+            conn, tcp,
+        ))))
+    }
+
+    /// set_timeout makes reads and writes on the stream fail once they waited for the duration
+    pub fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        let tcp = match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::ServerTls(tls) => &tls.sock,
+            Stream::ClientTls(tls) => &tls.sock,
+        };
+        tcp.set_read_timeout(Some(timeout))?;
+        tcp.set_write_timeout(Some(timeout))
+    }
+
+    /// close tells the peer that nothing more will be written on this stream,
+    /// so that its read_to_end returns. Over TLS this sends a close_notify alert,
+    /// without it the peer cannot tell a finished message from a truncated one
+    pub fn close(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.shutdown(Shutdown::Write),
+            Stream::ServerTls(tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()
+            }
+            Stream::ClientTls(tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::ServerTls(tls) => tls.read(buf),
+            Stream::ClientTls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::ServerTls(tls) => tls.write(buf),
+            Stream::ClientTls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::ServerTls(tls) => tls.flush(),
+            Stream::ClientTls(tls) => tls.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClientTls, Stream};
+    use crate::tls;
+    use rustls::ServerConfig;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    const CERTS: &str = "../certs";
+
+    fn path(name: &str) -> String {
+        format!("{}/{}", CERTS, name)
+    }
+
+    /// echo_once starts a server that accepts a single connection, reads a message
+    /// until the client closes its side and writes it back
+    fn echo_once(config: Option<Arc<ServerConfig>>) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let Ok(mut stream) = Stream::accept(tcp, config.as_ref()) else {
+                return;
+            };
+            let mut buf = Vec::new();
+            stream.read_to_end(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+            stream.close().unwrap();
+        });
+        (address, handle)
+    }
+
+    fn exchange(stream: &mut Stream) -> Vec<u8> {
+        stream.write_all(b"Hello").unwrap();
+        stream.close().unwrap();
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn plain_stream_works() {
+        let (address, handle) = echo_once(None);
+        let mut stream = Stream::connect(&address, None).unwrap();
+        assert_eq!(exchange(&mut stream), b"Hello");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn tls_stream_works() {
+        let config = tls::server_config(&path("server.pem"), &path("server.key"), None).unwrap();
+        let (address, handle) = echo_once(Some(config));
+
+        let client_config = tls::client_config(&path("ca.pem"), None).unwrap();
+        let client_tls = ClientTls::new(client_config, "localhost").unwrap();
+        let mut stream = Stream::connect(&address, Some(&client_tls)).unwrap();
+        assert_eq!(exchange(&mut stream), b"Hello");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn tls_rejects_server_signed_by_other_ca() {
+        let config = tls::server_config(&path("server.pem"), &path("server.key"), None).unwrap();
+        let (address, handle) = echo_once(Some(config));
+
+        let client_config = tls::client_config(&path("other-ca.pem"), None).unwrap();
+        let client_tls = ClientTls::new(client_config, "localhost").unwrap();
+        assert!(Stream::connect(&address, Some(&client_tls)).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn mutual_tls_works() {
+        let config = tls::server_config(
+            &path("server.pem"),
+            &path("server.key"),
+            Some(&path("ca.pem")),
+        )
+        .unwrap();
+        let (address, handle) = echo_once(Some(config));
+
+        let client_config = tls::client_config(
+            &path("ca.pem"),
+            Some((&path("client.pem"), &path("client.key"))),
+        )
+        .unwrap();
+        let client_tls = ClientTls::new(client_config, "localhost").unwrap();
+        let mut stream = Stream::connect(&address, Some(&client_tls)).unwrap();
+        assert_eq!(exchange(&mut stream), b"Hello");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn mutual_tls_rejects_client_without_certificate() {
+        let config = tls::server_config(
+            &path("server.pem"),
+            &path("server.key"),
+            Some(&path("ca.pem")),
+        )
+        .unwrap();
+        let (address, handle) = echo_once(Some(config));
+
+        let client_config = tls::client_config(&path("ca.pem"), None).unwrap();
+        let client_tls = ClientTls::new(client_config, "localhost").unwrap();
+        // with TLS 1.3 the client finishes its side of the handshake before the server
+        // checks its certificate, so the rejection shows up on the first read
+        let rejected = match Stream::connect(&address, Some(&client_tls)) {
+            Err(_) => true,
+            Ok(mut stream) => {
+                let _ = stream.write_all(b"Hello");
+                let _ = stream.close();
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).is_err()
+            }
+        };
+        assert!(rejected);
+        handle.join().unwrap();
+    }
+}
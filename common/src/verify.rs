@@ -0,0 +1,119 @@
+//! verify checks a file against the merkle root of the batch it was uploaded in.
+//!
+//! The checks live in the verifile-verify crate, which does not depend on the rest
+//! of common, and are re-exported here for the client and the server.
+
+pub use verifile_verify::*;
+
+#[cfg(test)]
+mod test {
+    use super::{check_positions, check_sides, verify, Side, VerifyError};
+    use crate::model::merkle::{MerkleProof, MerkleTree};
+
+    fn data() -> Vec<Vec<u8>> {
+        ["Hello", "Lorem", "Ipsum", "Dolor", "Sit"]
+            .iter()
+            .map(|s| s.as_bytes().to_vec())
+            .collect()
+    }
+
+    fn proof(index: usize) -> (String, MerkleProof) {
+        let tree = MerkleTree::from(data());
+        let proof = MerkleProof::build(&tree, index, String::from("f"), data()[index].clone());
+        (tree.root_hash(), proof)
+    }
+
+    fn hashes(proof: &MerkleProof) -> Vec<String> {
+        proof
+            .siblings()
+            .into_iter()
+            .map(|(_, _, hash)| hash)
+            .collect()
+    }
+
+    #[test]
+    fn every_leaf_verifies() {
+        for (index, leaf) in data().iter().enumerate() {
+            let (root, proof) = proof(index);
+            let path = proof.checked_path(index).unwrap();
+            verify(&root, leaf, index, 5, &path).unwrap();
+            verify(&root.to_uppercase(), leaf, index, 5, &path).unwrap();
+        }
+    }
+
+    #[test]
+    fn tampered_leaf_or_wrong_index_does_not_verify() {
+        let (root, proof) = proof(1);
+        let path = hashes(&proof);
+        assert!(matches!(
+            verify(&root, b"Lorem!", 1, 5, &path),
+            Err(VerifyError::RootMismatch { .. })
+        ));
+        assert!(matches!(
+            verify(&root, b"Lorem", 0, 5, &path),
+            Err(VerifyError::RootMismatch { .. })
+        ));
+        assert!(matches!(
+            verify(&root, b"Lorem", 8, 5, &path),
+            Err(VerifyError::IndexOutOfRange { .. })
+        ));
+    }
+
+    #[test]
+    fn proofs_of_the_wrong_length_are_rejected() {
+        let (root, proof) = proof(1);
+        let mut path = hashes(&proof);
+        // the same siblings would verify a leaf of a tree of another height
+        assert!(matches!(
+            verify(&root, b"Lorem", 1, 2, &path),
+            Err(VerifyError::WrongProofLength {
+                expected: 1,
+                actual: 3
+            })
+        ));
+        path.pop();
+        assert!(matches!(
+            verify(&root, b"Lorem", 1, 5, &path),
+            Err(VerifyError::WrongProofLength { .. })
+        ));
+    }
+
+    #[test]
+    fn reordered_or_misplaced_siblings_are_rejected() {
+        let (_, proof) = proof(4);
+        let mut positions = proof
+            .siblings()
+            .into_iter()
+            .map(|(level, index, _)| (level, index))
+            .collect::<Vec<_>>();
+        check_positions(4, 5, &positions).unwrap();
+
+        positions.swap(0, 1);
+        assert!(matches!(
+            check_positions(4, 5, &positions),
+            Err(VerifyError::WrongSiblingPosition { depth: 0, .. })
+        ));
+
+        // the last leaf is paired with itself, a sibling at index 3 is not on its path
+        assert!(check_positions(4, 5, &[(3, 3), (2, 2), (1, 0)]).is_err());
+        assert!(matches!(
+            check_sides(4, 5, &[Side::Left, Side::Right, Side::Left]),
+            Err(VerifyError::WrongSiblingSide { depth: 0 })
+        ));
+    }
+
+    #[test]
+    fn malformed_hashes_are_rejected() {
+        let (root, proof) = proof(1);
+        let mut path = hashes(&proof);
+        assert_eq!(
+            verify("not a hash", b"Lorem", 1, 5, &path),
+            Err(VerifyError::MalformedRoot)
+        );
+        path[1] = String::from("zz");
+        assert_eq!(
+            verify(&root, b"Lorem", 1, 5, &path),
+            Err(VerifyError::MalformedSibling { depth: 1 })
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use crate::metrics::Metrics;
+use log::{error, info};
+use serde_json::json;
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response};
+
+/// Admin serves the health, stats and metrics of the server over HTTP, on its own
+/// address so that it can be kept away from clients
+pub struct Admin {
+    http: tiny_http::Server,
+    metrics: Arc<Metrics>,
+}
+
+impl Admin {
+    /// bind listens on the address, for example 127.0.0.1:9000
+    pub fn bind(address: &str, metrics: Arc<Metrics>) -> Result<Self, String> {
+        let http = tiny_http::Server::http(address)
+            .map_err(|e| format!("failed to listen at {}: {}", address, e))?;
+        Ok(Self { http, metrics })
+    }
+
+    pub fn address(&self) -> String {
+        self.http.server_addr().to_string()
+    }
+
+    /// respond builds the response to a request of the admin endpoint
+    fn respond(&self, request: &Request) -> Response<std::io::Cursor<Vec<u8>>> {
+        let json = |value: serde_json::Value| {
+            Response::from_string(value.to_string()).with_header(
+                Header::from_bytes("Content-Type", "application/json")
+                    .expect("header should be valid"),
+            )
+        };
+
+        match (request.method(), request.url()) {
+            (Method::Get, "/health") => json(json!({
+                "status": "ok",
+                "uptime_seconds": self.metrics.uptime().as_secs(),
+            })),
+            (Method::Get, "/stats") => json(json!(self.metrics.stats())),
+            (Method::Get, "/metrics") => Response::from_string(self.metrics.render_prometheus())
+                .with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4")
+                        .expect("header should be valid"),
+                ),
+            _ => Response::from_string("not found").with_status_code(404),
+        }
+    }
+
+    /// serve answers requests until the process stops
+    pub fn serve(self) {
+        info!("Admin endpoint listening at: {}", self.address());
+        for request in self.http.incoming_requests() {
+            let response = self.respond(&request);
+            if let Err(e) = request.respond(response) {
+                error!("Failed to answer admin request: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Admin;
+    use crate::metrics::Metrics;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn get(address: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn admin_endpoint_serves_health_stats_and_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.set_storage(1, 10, 2);
+        metrics.record("upload", Duration::from_millis(1), None);
+
+        let admin = Admin::bind("127.0.0.1:0", metrics).unwrap();
+        let address = admin.address();
+        std::thread::spawn(move || admin.serve());
+
+        let health = get(&address, "/health");
+        assert!(health.starts_with("HTTP/1.1 200"));
+        assert!(health.contains("\"status\":\"ok\""));
+
+        let stats = get(&address, "/stats");
+        assert!(stats.contains("\"stored_bytes\":10"));
+        assert!(stats.contains("\"upload\""));
+
+        let metrics = get(&address, "/metrics");
+        assert!(metrics.contains("verifile_requests_total{operation=\"upload\"} 1"));
+
+        assert!(get(&address, "/missing").starts_with("HTTP/1.1 404"));
+    }
+}
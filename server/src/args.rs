@@ -0,0 +1,239 @@
+use crate::replication::Replication;
+use clap::Parser;
+use std::time::Duration;
+
+#[derive(Parser, Debug, Default)]
+#[clap(author = "Author Name", version, about)]
+pub struct Argument {
+    /// address the server listens at
+    #[clap(long, default_value_t = String::from(common::SERVER_ADDRESS))]
+    address: String,
+
+    /// PEM file with the server certificate chain, enables TLS
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<String>,
+
+    /// PEM file with the private key of the server certificate
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<String>,
+
+    /// PEM file with the CA that client certificates must be signed by, enables mutual TLS
+    #[clap(long, requires = "tls_cert")]
+    tls_client_ca: Option<String>,
+
+    /// JSON file with the users allowed to use the server, enables authentication
+    #[clap(long)]
+    users: Option<String>,
+
+    /// file with the raw 32 byte ed25519 key receipts are signed with, created if missing
+    #[clap(long)]
+    signing_key: Option<String>,
+
+    /// directory the sessions are saved in, so that they are loaded again when the server restarts
+    #[clap(long)]
+    data_dir: Option<String>,
+
+    /// seconds after which uploaded sessions expire and are deleted
+    #[clap(long)]
+    session_ttl: Option<u64>,
+
+    /// bytes each user can store across the sessions they own
+    #[clap(long)]
+    quota: Option<usize>,
+
+    /// bytes of the largest request the server reads, which bounds the size of an upload
+    #[clap(long, default_value_t = common::protocol::DEFAULT_MAX_MESSAGE_SIZE)]
+    max_message_size: u64,
+
+    /// seconds between two sweeps of expired sessions
+    #[clap(long, default_value_t = 60)]
+    sweep_interval: u64,
+
+    /// address of the HTTP API, such as 127.0.0.1:8080
+    #[clap(long)]
+    http_address: Option<String>,
+
+    /// address of the gRPC API, such as 127.0.0.1:50051
+    #[clap(long)]
+    grpc_address: Option<String>,
+
+    /// address of the HTTP endpoint serving /health, /stats and /metrics, such as 127.0.0.1:9000
+    #[clap(long)]
+    admin_address: Option<String>,
+
+    /// addresses of the replicas the sessions are forwarded to, separated by commas
+    #[clap(
+        long,
+        value_delimiter = ',',
+        requires = "replication_token",
+        conflicts_with = "primary"
+    )]
+    replicas: Vec<String>,
+
+    /// address of the primary whose sessions are replicated, makes the server a read-only replica
+    #[clap(long, requires = "replication_token")]
+    primary: Option<String>,
+
+    /// token the primary and its replicas authenticate each other with
+    #[clap(long, env = "VERIFILE_REPLICATION_TOKEN")]
+    replication_token: Option<String>,
+
+    /// seconds between two reconciliations of every session of a replica with its primary
+    #[clap(long, default_value_t = 30)]
+    reconcile_interval: u64,
+
+    /// PEM file with the CA the certificates of the other servers are signed by,
+    /// enables TLS on replication connections
+    #[clap(long)]
+    replication_ca: Option<String>,
+}
+
+impl Argument {
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    pub fn users(&self) -> Option<String> {
+        self.users.clone()
+    }
+
+    pub fn signing_key(&self) -> Option<String> {
+        self.signing_key.clone()
+    }
+
+    pub fn data_dir(&self) -> Option<String> {
+        self.data_dir.clone()
+    }
+
+    pub fn session_ttl(&self) -> Option<Duration> {
+        self.session_ttl.map(Duration::from_secs)
+    }
+
+    pub fn quota(&self) -> Option<usize> {
+        self.quota
+    }
+
+    pub fn max_message_size(&self) -> u64 {
+        self.max_message_size
+    }
+
+    pub fn sweep_interval(&self) -> Duration {
+        Duration::from_secs(self.sweep_interval)
+    }
+
+    pub fn http_address(&self) -> Option<String> {
+        self.http_address.clone()
+    }
+
+    pub fn grpc_address(&self) -> Option<String> {
+        self.grpc_address.clone()
+    }
+
+    pub fn admin_address(&self) -> Option<String> {
+        self.admin_address.clone()
+    }
+
+    /// replication returns the replicas of a primary or the primary of a replica, with the
+    /// token they share, if the server replicates
+    pub fn replication(&self) -> Option<Replication> {
+        let token = self.replication_token.clone()?;
+        match &self.primary {
+            Some(primary) => Some(Replication::replica(
+                token,
+                primary.clone(),
+                Duration::from_secs(self.reconcile_interval),
+            )),
+            None if !self.replicas.is_empty() => {
+                Some(Replication::primary(token, self.replicas.clone()))
+            }
+            None => None,
+        }
+    }
+
+    pub fn replication_ca(&self) -> Option<String> {
+        self.replication_ca.clone()
+    }
+
+    /// tls returns the certificate, key and optional client CA paths if TLS is enabled
+    pub fn tls(&self) -> Option<(String, String, Option<String>)> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                Some((cert.clone(), key.clone(), self.tls_client_ca.clone()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing_tls_arguments_works() {
+        let args = Argument::parse_from(["server"]);
+        assert!(args.tls().is_none());
+
+        let args = Argument::parse_from([
+            "server",
+            "--tls-cert",
+            "server.pem",
+            "--tls-key",
+            "server.key",
+        ]);
+        assert_eq!(
+            args.tls(),
+            Some((String::from("server.pem"), String::from("server.key"), None))
+        );
+    }
+
+    #[test]
+    fn parsing_replication_arguments_works() {
+        let args = Argument::parse_from(["server"]);
+        assert_eq!(args.address(), common::SERVER_ADDRESS);
+        assert!(args.replication().is_none());
+
+        let args = Argument::parse_from([
+            "server",
+            "--replicas",
+            "127.0.0.1:8001,127.0.0.1:8002",
+            "--replication-token",
+            "secret",
+        ]);
+        let replication = args.replication().unwrap();
+        assert!(replication.authenticate(Some("secret")));
+        assert!(replication.primary_address().is_none());
+
+        let args = Argument::parse_from([
+            "server",
+            "--address",
+            "127.0.0.1:8001",
+            "--primary",
+            "127.0.0.1:8000",
+            "--replication-token",
+            "secret",
+        ]);
+        assert_eq!(args.address(), "127.0.0.1:8001");
+        assert_eq!(
+            args.replication().unwrap().primary_address(),
+            Some("127.0.0.1:8000")
+        );
+
+        assert!(Argument::try_parse_from(["server", "--primary", "127.0.0.1:8000"]).is_err());
+        assert!(Argument::try_parse_from([
+            "server",
+            "--primary",
+            "127.0.0.1:8000",
+            "--replicas",
+            "127.0.0.1:8002",
+            "--replication-token",
+            "secret",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn tls_key_is_required_with_certificate() {
+        assert!(Argument::try_parse_from(["server", "--tls-cert", "server.pem"]).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use common::protocol::{ErrorKind, ProtocolError};
+use serde::Deserialize;
+use sha256::digest;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+/// ANONYMOUS is the user every request is made as when authentication is disabled
+pub const ANONYMOUS: &str = "anonymous";
+
+#[derive(Deserialize)]
+struct UserEntry {
+    name: String,
+    /// hex encoded sha256 digest of the API token of the user
+    token_sha256: String,
+}
+
+#[derive(Deserialize)]
+struct UsersFile {
+    users: Vec<UserEntry>,
+}
+
+/// Users authenticates the API tokens sent with requests. Only digests of the
+/// tokens are kept, so the users file does not have to be kept secret
+pub struct Users {
+    /// maps token digests to user names, None if authentication is disabled
+    tokens: Option<HashMap<String, String>>,
+}
+
+impl Users {
+    /// open disables authentication, every request is made as the anonymous user
+    pub fn open() -> Self {
+        Self { tokens: None }
+    }
+
+    /// load reads the users from a JSON file of the form
+    /// `{"users": [{"name": "alice", "token_sha256": "<hex digest>"}]}`
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("failed to open {}: {}", path, e))?;
+        let users_file: UsersFile = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("failed to parse {}: {}", path, e))?;
+        Ok(Self::from_entries(users_file.users))
+    }
+
+    fn from_entries(entries: Vec<UserEntry>) -> Self {
+        let tokens = entries
+            .into_iter()
+            .map(|entry| (entry.token_sha256.to_lowercase(), entry.name))
+            .collect();
+        Self {
+            tokens: Some(tokens),
+        }
+    }
+
+    /// authenticate returns the name of the user holding the token
+    pub fn authenticate(&self, token: Option<&str>) -> Result<String, ProtocolError> {
+        let Some(tokens) = &self.tokens else {
+            return Ok(String::from(ANONYMOUS));
+        };
+
+        let token = token.ok_or_else(|| {
+            ProtocolError::new(ErrorKind::Unauthenticated, "an API token is required")
+        })?;
+        tokens
+            .get(&digest(token))
+            .cloned()
+            .ok_or_else(|| ProtocolError::new(ErrorKind::Unauthenticated, "invalid API token"))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::{UserEntry, Users, ANONYMOUS};
+    use common::protocol::ErrorKind;
+    use sha256::digest;
+
+    /// users returns a registry where each user's token is its name followed by "-token"
+    pub fn users(names: &[&str]) -> Users {
+        Users::from_entries(
+            names
+                .iter()
+                .map(|name| UserEntry {
+                    name: name.to_string(),
+                    token_sha256: digest(format!("{}-token", name)),
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn open_users_authenticate_as_anonymous() {
+        assert_eq!(Users::open().authenticate(None).unwrap(), ANONYMOUS);
+    }
+
+    #[test]
+    fn authenticate_works() {
+        let users = users(&["alice", "bob"]);
+        assert_eq!(users.authenticate(Some("alice-token")).unwrap(), "alice");
+        assert_eq!(users.authenticate(Some("bob-token")).unwrap(), "bob");
+    }
+
+    #[test]
+    fn authenticate_rejects_missing_and_invalid_tokens() {
+        let users = users(&["alice"]);
+        let err = users.authenticate(None).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unauthenticated);
+        let err = users.authenticate(Some("mallory-token")).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::Unauthenticated);
+    }
+}
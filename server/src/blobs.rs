@@ -0,0 +1,124 @@
+use common::model::merkle::{hash_leaf, NodeHash};
+use std::collections::HashMap;
+
+/// Blob is the content of a file and the number of session files that refer to it
+struct Blob {
+    content: Vec<u8>,
+    references: usize,
+}
+
+/// BlobStore keeps the content of every stored file once, keyed by its leaf hash,
+/// so a file uploaded in several sessions is only stored once
+#[derive(Default)]
+pub struct BlobStore {
+    blobs: HashMap<NodeHash, Blob>,
+    stored_bytes: usize,
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// insert stores the content if it is not stored yet, adds a reference to it
+    /// and returns its hash
+    pub fn insert(&mut self, content: Vec<u8>) -> NodeHash {
+        let hash = hash_leaf(&content);
+        let size = content.len();
+        let blob = self.blobs.entry(hash).or_insert_with(|| Blob {
+            content,
+            references: 0,
+        });
+        if blob.references == 0 {
+            self.stored_bytes += size;
+        }
+        blob.references += 1;
+        hash
+    }
+
+    /// insert_hashed stores content whose leaf hash is already known, such as content read
+    /// back from disk, without hashing it again, and adds a reference to it
+    pub fn insert_hashed(&mut self, hash: NodeHash, content: Vec<u8>) {
+        if self.retain(&hash) {
+            return;
+        }
+        self.stored_bytes += content.len();
+        self.blobs.insert(
+            hash,
+            Blob {
+                content,
+                references: 1,
+            },
+        );
+    }
+
+    /// retain adds a reference to content that is already stored, returning false if it is not
+    pub fn retain(&mut self, hash: &NodeHash) -> bool {
+        let Some(blob) = self.blobs.get_mut(hash) else {
+            return false;
+        };
+        blob.references += 1;
+        true
+    }
+
+    /// get returns the content with the hash
+    pub fn get(&self, hash: &NodeHash) -> Option<&[u8]> {
+        self.blobs.get(hash).map(|blob| blob.content.as_slice())
+    }
+
+    /// release drops a reference to the content with the hash, and the content
+    /// itself once nothing refers to it
+    pub fn release(&mut self, hash: &NodeHash) {
+        let Some(blob) = self.blobs.get_mut(hash) else {
+            return;
+        };
+        blob.references -= 1;
+        if blob.references == 0 {
+            self.stored_bytes -= blob.content.len();
+            self.blobs.remove(hash);
+        }
+    }
+
+    /// blobs_count returns the number of distinct contents stored
+    pub fn blobs_count(&self) -> usize {
+        self.blobs.len()
+    }
+
+    /// stored_bytes returns the size of the distinct contents stored
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::BlobStore;
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let mut blobs = BlobStore::new();
+        let first = blobs.insert(b"Hello".to_vec());
+        let second = blobs.insert(b"Hello".to_vec());
+        blobs.insert(b"Lorem".to_vec());
+
+        assert_eq!(first, second);
+        assert_eq!(blobs.blobs_count(), 2);
+        assert_eq!(blobs.stored_bytes(), 10);
+        assert_eq!(blobs.get(&first), Some(b"Hello".as_slice()));
+    }
+
+    #[test]
+    fn content_is_dropped_with_its_last_reference() {
+        let mut blobs = BlobStore::new();
+        let hash = blobs.insert(b"Hello".to_vec());
+        blobs.insert(b"Hello".to_vec());
+
+        blobs.release(&hash);
+        assert_eq!(blobs.get(&hash), Some(b"Hello".as_slice()));
+
+        blobs.release(&hash);
+        assert_eq!(blobs.get(&hash), None);
+        assert_eq!(blobs.blobs_count(), 0);
+        assert_eq!(blobs.stored_bytes(), 0);
+    }
+}
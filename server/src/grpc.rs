@@ -0,0 +1,422 @@
+use crate::server::{lock, Server};
+use common::grpc::verifile_server::{Verifile, VerifileServer};
+use common::grpc::{
+    download_response, upload_request, DownloadResponse, FileRequest, GetRootResponse,
+    ListFilesResponse, Proof, SessionRequest, UploadRequest, UploadResponse, BEARER_PREFIX,
+};
+use common::model::file_info::FileInfo;
+use common::protocol::{self, Envelope, ErrorKind, ProtocolError};
+use log::{error, info};
+use std::net::TcpListener;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// CHUNK_SIZE is the size of the chunks files are downloaded in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// GrpcApi serves the operations of the server over gRPC, from the service defined in
+/// common/proto/verifile.proto. It works on the same sessions as the TCP protocol
+pub struct GrpcApi {
+    listener: TcpListener,
+}
+
+impl GrpcApi {
+    /// bind listens on the address, for example 127.0.0.1:50051
+    pub fn bind(address: &str) -> Result<Self, String> {
+        let listener = TcpListener::bind(address)
+            .map_err(|e| format!("failed to listen at {}: {}", address, e))?;
+        Ok(Self { listener })
+    }
+
+    pub fn address(&self) -> String {
+        self.listener
+            .local_addr()
+            .map(|address| address.to_string())
+            .unwrap_or_default()
+    }
+
+    /// serve answers requests on a runtime of its own until the process stops
+    pub fn serve(self, server: Arc<Mutex<Server>>) {
+        info!("gRPC API listening at: {}", self.address());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("gRPC runtime creation should not fail");
+
+        let served = runtime.block_on(async move {
+            self.listener.set_nonblocking(true)?;
+            let listener = tokio::net::TcpListener::from_std(self.listener)?;
+            tonic::transport::Server::builder()
+                .add_service(VerifileServer::new(Service { server }))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .map_err(std::io::Error::other)
+        });
+        if let Err(e) = served {
+            error!("gRPC API stopped: {}", e);
+        }
+    }
+}
+
+/// token returns the API token sent as 'authorization: Bearer <token>', if any
+fn token<T>(request: &Request<T>) -> Option<String> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX))
+        .map(|token| token.trim().to_string())
+}
+
+/// index reads the index of a file sent in a request
+fn index(index: u64) -> Result<usize, ProtocolError> {
+    usize::try_from(index)
+        .map_err(|_| ProtocolError::new(ErrorKind::BadRequest, "file index is too large"))
+}
+
+struct Service {
+    server: Arc<Mutex<Server>>,
+}
+
+impl Service {
+    /// call runs an operation on the server, started at the instant, and records it in the metrics
+    fn call<T>(
+        &self,
+        operation: &'static str,
+        started: Instant,
+        f: impl FnOnce(&mut Server) -> Result<T, ProtocolError>,
+    ) -> Result<T, ProtocolError> {
+        let mut server = lock(&self.server);
+        let result = f(&mut server);
+        server.record(operation, started, &result);
+        result.inspect_err(|e| error!("Failed to serve gRPC request: {}", e))
+    }
+}
+
+/// internal reports an answer of the server the API cannot convert, which is logged rather
+/// than taking down the thread serving the API
+fn internal(e: impl std::fmt::Display) -> Status {
+    error!("Failed to convert gRPC response: {}", e);
+    Status::internal(e.to_string())
+}
+
+type DownloadStream = Pin<Box<dyn Stream<Item = Result<DownloadResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Verifile for Service {
+    async fn upload(
+        &self,
+        request: Request<Streaming<UploadRequest>>,
+    ) -> Result<Response<UploadResponse>, Status> {
+        let started = Instant::now();
+        let token = token(&request);
+        let mut stream = request.into_inner();
+        let max_size = lock(&self.server).max_message_size();
+
+        let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+        let mut size = 0u64;
+        while let Some(message) = stream.message().await? {
+            match message.message {
+                Some(upload_request::Message::File(header)) => {
+                    files.push((header.name, Vec::new()))
+                }
+                Some(upload_request::Message::Chunk(chunk)) => {
+                    size += chunk.len() as u64;
+                    if size > max_size {
+                        return Err(Status::invalid_argument("upload is too large"));
+                    }
+                    files
+                        .last_mut()
+                        .ok_or_else(|| {
+                            Status::invalid_argument("a chunk was sent before the first file")
+                        })?
+                        .1
+                        .extend_from_slice(&chunk);
+                }
+                None => {}
+            }
+        }
+        let files = files
+            .into_iter()
+            .enumerate()
+            .map(|(index, (name, content))| FileInfo::new(index, name, content))
+            .collect();
+
+        let uploaded = self.call("grpc-upload", started, |server| {
+            server.handle_request(Envelope {
+                token,
+                request: protocol::Request::Upload { files },
+            })
+        })?;
+        let protocol::Response::Uploaded {
+            session_id,
+            receipt,
+        } = uploaded
+        else {
+            return Err(internal("an upload should be answered with a receipt"));
+        };
+        Ok(Response::new(UploadResponse {
+            session_id,
+            receipt: Some(common::grpc::Receipt::try_from(&receipt).map_err(internal)?),
+        }))
+    }
+
+    type DownloadStream = DownloadStream;
+
+    async fn download(
+        &self,
+        request: Request<FileRequest>,
+    ) -> Result<Response<Self::DownloadStream>, Status> {
+        let started = Instant::now();
+        let token = token(&request);
+        let request = request.into_inner();
+        let index = index(request.index)?;
+        let (proof, content) = self.call("grpc-download", started, |server| {
+            server.proof_file(token.as_deref(), &request.session_id, index)
+        })?;
+
+        let proof = DownloadResponse {
+            message: Some(download_response::Message::Proof((&proof).into())),
+        };
+        let chunks = content
+            .chunks(CHUNK_SIZE)
+            .map(|chunk| DownloadResponse {
+                message: Some(download_response::Message::Chunk(chunk.to_vec())),
+            })
+            .collect::<Vec<DownloadResponse>>();
+        let messages = std::iter::once(proof).chain(chunks).map(Ok);
+        Ok(Response::new(Box::pin(tokio_stream::iter(messages))))
+    }
+
+    async fn get_proof(&self, request: Request<FileRequest>) -> Result<Response<Proof>, Status> {
+        let started = Instant::now();
+        let token = token(&request);
+        let request = request.into_inner();
+        let index = index(request.index)?;
+        let (proof, _) = self.call("grpc-get-proof", started, |server| {
+            server.proof_file(token.as_deref(), &request.session_id, index)
+        })?;
+        Ok(Response::new((&proof).into()))
+    }
+
+    async fn list_files(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<ListFilesResponse>, Status> {
+        let started = Instant::now();
+        let token = token(&request);
+        let session_id = request.into_inner().session_id;
+        let files = self.call("grpc-list-files", started, |server| {
+            server.handle_request(Envelope {
+                token,
+                request: protocol::Request::List { session_id },
+            })
+        })?;
+        let protocol::Response::Files(files) = files else {
+            return Err(internal("a list should be answered with files"));
+        };
+        Ok(Response::new(ListFilesResponse {
+            files: files.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_root(
+        &self,
+        request: Request<SessionRequest>,
+    ) -> Result<Response<GetRootResponse>, Status> {
+        let started = Instant::now();
+        let token = token(&request);
+        let session_id = request.into_inner().session_id;
+        let (merkle_root, files_count) = self.call("grpc-get-root", started, |server| {
+            server.root(token.as_deref(), &session_id)
+        })?;
+        Ok(Response::new(GetRootResponse {
+            merkle_root: hex::decode(merkle_root).map_err(internal)?,
+            files_count: files_count as u64,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::GrpcApi;
+    use crate::auth;
+    use crate::server::Server;
+    use common::grpc::verifile_client::VerifileClient;
+    use common::grpc::{
+        download_response, upload_request, FileHeader, FileRequest, SessionRequest, UploadRequest,
+    };
+    use common::model::merkle::MerkleTree;
+    use common::model::proof_file::ProofFile;
+    use common::model::receipt::SignedReceipt;
+    use std::sync::{Arc, Mutex};
+    use tonic::transport::Channel;
+    use tonic::{Code, Request};
+
+    /// local_api serves the gRPC API of a server with alice and bob as users
+    async fn local_api() -> VerifileClient<Channel> {
+        let mut server = Server::new();
+        server.set_users(auth::test::users(&["alice", "bob"]));
+        let api = GrpcApi::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", api.address());
+        std::thread::spawn(move || api.serve(Arc::new(Mutex::new(server))));
+        VerifileClient::connect(address).await.unwrap()
+    }
+
+    fn as_user<T>(user: &str, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}-token", user).parse().unwrap(),
+        );
+        request
+    }
+
+    fn files() -> Vec<(String, Vec<u8>)> {
+        vec![
+            (String::from("a.txt"), b"Hello".to_vec()),
+            (String::from("b.txt"), vec![7; 200 * 1024]),
+            (String::from("c.txt"), b"Ipsum".to_vec()),
+        ]
+    }
+
+    /// upload sends the files as alice, each in chunks of at most 1000 bytes
+    async fn upload(client: &mut VerifileClient<Channel>) -> String {
+        let messages = files()
+            .into_iter()
+            .flat_map(|(name, content)| {
+                let header = upload_request::Message::File(FileHeader { name });
+                let chunks = content
+                    .chunks(1000)
+                    .map(|chunk| upload_request::Message::Chunk(chunk.to_vec()))
+                    .collect::<Vec<_>>();
+                std::iter::once(header).chain(chunks)
+            })
+            .map(|message| UploadRequest {
+                message: Some(message),
+            })
+            .collect::<Vec<_>>();
+
+        let response = client
+            .upload(as_user("alice", tokio_stream::iter(messages)))
+            .await
+            .unwrap()
+            .into_inner();
+        let receipt = SignedReceipt::try_from(response.receipt.unwrap()).unwrap();
+        receipt.verify().unwrap();
+        assert_eq!(receipt.receipt().merkle_root(), root());
+        response.session_id
+    }
+
+    fn root() -> String {
+        MerkleTree::from(
+            files()
+                .into_iter()
+                .map(|(_, content)| content)
+                .collect::<Vec<Vec<u8>>>(),
+        )
+        .root_hash()
+    }
+
+    #[tokio::test]
+    async fn files_are_uploaded_and_downloaded_with_proofs() {
+        let mut client = local_api().await;
+        let session_id = upload(&mut client).await;
+
+        let root_response = client
+            .get_root(as_user(
+                "alice",
+                SessionRequest {
+                    session_id: session_id.clone(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(hex::encode(root_response.merkle_root), root());
+        assert_eq!(root_response.files_count, 3);
+
+        let listed = client
+            .list_files(as_user(
+                "alice",
+                SessionRequest {
+                    session_id: session_id.clone(),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(listed.files[1].name, "b.txt");
+
+        let file_request = FileRequest {
+            session_id: session_id.clone(),
+            index: 1,
+        };
+        let mut stream = client
+            .download(as_user("alice", file_request.clone()))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut proof = None;
+        let mut content = Vec::new();
+        let mut chunks = 0;
+        while let Some(message) = stream.message().await.unwrap() {
+            match message.message.unwrap() {
+                download_response::Message::Proof(sent) => proof = Some(sent),
+                download_response::Message::Chunk(chunk) => {
+                    chunks += 1;
+                    content.extend_from_slice(&chunk);
+                }
+            }
+        }
+        assert!(chunks > 1);
+        assert_eq!(content, files()[1].1);
+        let proof = ProofFile::try_from(proof.unwrap()).unwrap();
+        assert_eq!(proof.root(), root());
+        proof.verify(&content).unwrap();
+
+        let proof = client
+            .get_proof(as_user("alice", file_request))
+            .await
+            .unwrap()
+            .into_inner();
+        ProofFile::try_from(proof)
+            .unwrap()
+            .verify(&content)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn errors_map_to_grpc_codes() {
+        let mut client = local_api().await;
+        let session_id = upload(&mut client).await;
+        let request = || SessionRequest {
+            session_id: session_id.clone(),
+        };
+
+        let status = client.get_root(Request::new(request())).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let status = client
+            .get_root(as_user("bob", request()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::PermissionDenied);
+
+        let status = client
+            .get_proof(as_user(
+                "alice",
+                FileRequest {
+                    session_id: session_id.clone(),
+                    index: 3,
+                },
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+}
@@ -0,0 +1,569 @@
+use crate::server::{lock, Server};
+use common::model::file_info::FileInfo;
+use common::protocol::{Envelope, ErrorKind, ProtocolError, Request, Response};
+use log::{error, info};
+use serde::Serialize;
+use serde_json::json;
+use std::io::{Cursor, Read};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tiny_http::{Header, Method};
+
+type HttpResponse = tiny_http::Response<Cursor<Vec<u8>>>;
+
+/// PROOF_HEADER holds the proof file of a downloaded file, as JSON
+pub const PROOF_HEADER: &str = "X-Verifile-Proof";
+
+/// FILE_NAME_HEADER holds the name of a file sent with PUT
+pub const FILE_NAME_HEADER: &str = "X-File-Name";
+
+/// Route is a request of the HTTP API
+#[derive(Debug, PartialEq, Eq)]
+enum Route {
+    /// POST /sessions starts an upload
+    StartUpload,
+    /// GET /sessions describes the sessions the caller has access to
+    Sessions,
+    /// PUT /sessions/{id}/files/{index} sends a file of an upload
+    PutFile { session_id: String, index: usize },
+    /// POST /sessions/{id}/complete stores the files of an upload in the session
+    CompleteUpload { session_id: String },
+    /// GET /sessions/{id}/files lists the files of a session
+    Files { session_id: String },
+    /// GET /sessions/{id}/files/{index} downloads a file with its proof in a header
+    File { session_id: String, index: usize },
+    /// GET /sessions/{id}/files/{index}/proof gets the proof file of a file
+    Proof { session_id: String, index: usize },
+    /// GET /sessions/{id}/root gets the merkle root of a session
+    Root { session_id: String },
+    /// DELETE /sessions/{id} deletes a session
+    Delete { session_id: String },
+}
+
+impl Route {
+    fn parse(method: &Method, url: &str) -> Result<Self, ProtocolError> {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments = path.trim_matches('/').split('/').collect::<Vec<&str>>();
+        let index = |segment: &str| {
+            segment.parse::<usize>().map_err(|_| {
+                ProtocolError::new(
+                    ErrorKind::BadRequest,
+                    format!("{} is not a file index", segment),
+                )
+            })
+        };
+
+        let route = match (method, segments.as_slice()) {
+            (Method::Post, ["sessions"]) => Route::StartUpload,
+            (Method::Get, ["sessions"]) => Route::Sessions,
+            (Method::Put, ["sessions", id, "files", i]) => Route::PutFile {
+                session_id: id.to_string(),
+                index: index(i)?,
+            },
+            (Method::Post, ["sessions", id, "complete"]) => Route::CompleteUpload {
+                session_id: id.to_string(),
+            },
+            (Method::Get, ["sessions", id, "files"]) => Route::Files {
+                session_id: id.to_string(),
+            },
+            (Method::Get, ["sessions", id, "files", i]) => Route::File {
+                session_id: id.to_string(),
+                index: index(i)?,
+            },
+            (Method::Get, ["sessions", id, "files", i, "proof"]) => Route::Proof {
+                session_id: id.to_string(),
+                index: index(i)?,
+            },
+            (Method::Get, ["sessions", id, "root"]) => Route::Root {
+                session_id: id.to_string(),
+            },
+            (Method::Delete, ["sessions", id]) => Route::Delete {
+                session_id: id.to_string(),
+            },
+            _ => {
+                return Err(ProtocolError::new(
+                    ErrorKind::NotFound,
+                    format!("{} {} is not a route of the API", method, path),
+                ))
+            }
+        };
+        Ok(route)
+    }
+
+    /// operation names the route in metrics
+    fn operation(&self) -> &'static str {
+        match self {
+            Route::StartUpload => "http-start-upload",
+            Route::Sessions => "http-sessions",
+            Route::PutFile { .. } => "http-put-file",
+            Route::CompleteUpload { .. } => "http-complete-upload",
+            Route::Files { .. } => "http-list",
+            Route::File { .. } => "http-download",
+            Route::Proof { .. } => "http-proof",
+            Route::Root { .. } => "http-root",
+            Route::Delete { .. } => "http-delete",
+        }
+    }
+}
+
+/// status returns the HTTP status code of a kind of error
+fn status(kind: ErrorKind) -> u16 {
+    match kind {
+        ErrorKind::BadRequest => 400,
+        ErrorKind::Unauthenticated => 401,
+        ErrorKind::Forbidden => 403,
+        ErrorKind::NotFound => 404,
+        ErrorKind::QuotaExceeded => 413,
+        ErrorKind::TooLarge => 413,
+        ErrorKind::Unsupported => 400,
+        ErrorKind::Conflict => 409,
+    }
+}
+
+fn header(name: &str, value: &str) -> Result<Header, ProtocolError> {
+    Header::from_bytes(name, value).map_err(|_| {
+        ProtocolError::new(
+            ErrorKind::BadRequest,
+            format!("{} is not a valid value of header {}", value, name),
+        )
+    })
+}
+
+fn json_response<T: Serialize>(status: u16, body: &T) -> HttpResponse {
+    let body = serde_json::to_vec(body).expect("response serialization should not fail");
+    tiny_http::Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json").expect("header should be valid"))
+}
+
+/// ascii_json encodes the value as JSON, escaping every non-ASCII character
+/// so that it can be sent in a header
+fn ascii_json<T: Serialize>(value: &T) -> String {
+    let json = serde_json::to_string(value).expect("proof serialization should not fail");
+    let mut escaped = String::with_capacity(json.len());
+    for c in json.chars() {
+        if c.is_ascii() {
+            escaped.push(c);
+        } else {
+            let mut units = [0u16; 2];
+            for unit in c.encode_utf16(&mut units) {
+                escaped.push_str(&format!("\\u{:04x}", unit));
+            }
+        }
+    }
+    escaped
+}
+
+/// HttpApi serves the operations of the server over HTTP, for clients that cannot
+/// use its TCP protocol. It works on the same sessions as the TCP protocol
+pub struct HttpApi {
+    http: tiny_http::Server,
+}
+
+impl HttpApi {
+    /// bind listens on the address, for example 127.0.0.1:8080
+    pub fn bind(address: &str) -> Result<Self, String> {
+        let http = tiny_http::Server::http(address)
+            .map_err(|e| format!("failed to listen at {}: {}", address, e))?;
+        Ok(Self { http })
+    }
+
+    pub fn address(&self) -> String {
+        self.http.server_addr().to_string()
+    }
+
+    /// serve answers requests one at a time until the process stops
+    pub fn serve(self, server: Arc<Mutex<Server>>) {
+        info!("HTTP API listening at: {}", self.address());
+        for mut request in self.http.incoming_requests() {
+            let response = handle(&server, &mut request);
+            if let Err(e) = request.respond(response) {
+                error!("Failed to answer HTTP request: {}", e);
+            }
+        }
+    }
+}
+
+/// token returns the API token sent as 'Authorization: Bearer <token>', if any
+fn token(request: &tiny_http::Request) -> Option<String> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+/// read_file reads a file of at most max_size bytes sent with PUT, named by the FILE_NAME_HEADER
+fn read_file(
+    request: &mut tiny_http::Request,
+    index: usize,
+    max_size: u64,
+) -> Result<FileInfo, ProtocolError> {
+    let name = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(FILE_NAME_HEADER))
+        .map(|header| header.value.to_string())
+        .ok_or_else(|| {
+            ProtocolError::new(
+                ErrorKind::BadRequest,
+                format!(
+                    "the file name should be sent in the {} header",
+                    FILE_NAME_HEADER
+                ),
+            )
+        })?;
+
+    let mut content = Vec::new();
+    request
+        .as_reader()
+        .take(max_size + 1)
+        .read_to_end(&mut content)
+        .map_err(|e| {
+            ProtocolError::new(ErrorKind::BadRequest, format!("failed to read file: {}", e))
+        })?;
+    if content.len() as u64 > max_size {
+        return Err(ProtocolError::new(
+            ErrorKind::TooLarge,
+            format!("file is larger than {} bytes", max_size),
+        ));
+    }
+    Ok(FileInfo::new(index, name, content))
+}
+
+/// handle serves a request of the HTTP API
+fn handle(server: &Mutex<Server>, request: &mut tiny_http::Request) -> HttpResponse {
+    let started = Instant::now();
+    let route = match Route::parse(request.method(), request.url()) {
+        Ok(route) => route,
+        Err(e) => return json_response(status(e.kind), &e),
+    };
+    let token = token(request);
+    let token = token.as_deref();
+    let file = match &route {
+        Route::PutFile { index, .. } => {
+            let max_size = lock(server).max_message_size();
+            Some(read_file(request, *index, max_size))
+        }
+        _ => None,
+    };
+
+    let mut server = lock(server);
+    let envelope = |request: Request| Envelope {
+        token: token.map(String::from),
+        request,
+    };
+    let response =
+        match &route {
+            Route::StartUpload => server
+                .start_upload(token)
+                .map(|session_id| json_response(201, &json!({ "session_id": session_id }))),
+            Route::PutFile { session_id, .. } => file
+                .expect("file should be read for a PUT")
+                .and_then(|file| server.put_file(token, session_id, file))
+                .map(|_| tiny_http::Response::from_data(Vec::new()).with_status_code(204)),
+            Route::CompleteUpload { session_id } => {
+                server
+                    .complete_upload(token, session_id)
+                    .map(|uploaded| match uploaded {
+                        Response::Uploaded {
+                            session_id,
+                            receipt,
+                        } => json_response(
+                            201,
+                            &json!({ "session_id": session_id, "receipt": receipt }),
+                        ),
+                        _ => unreachable!("an upload should be answered with a receipt"),
+                    })
+            }
+            Route::File { session_id, index } => server
+                .proof_file(token, session_id, *index)
+                .and_then(|(proof, content)| {
+                    Ok(tiny_http::Response::from_data(content)
+                        .with_header(header("Content-Type", "application/octet-stream")?)
+                        .with_header(header(PROOF_HEADER, &ascii_json(&proof))?))
+                }),
+            Route::Proof { session_id, index } => server
+                .proof_file(token, session_id, *index)
+                .map(|(proof, _)| json_response(200, &proof)),
+            Route::Root { session_id } => {
+                server
+                    .root(token, session_id)
+                    .map(|(merkle_root, files_count)| {
+                        json_response(
+                            200,
+                            &json!({ "merkle_root": merkle_root, "files_count": files_count }),
+                        )
+                    })
+            }
+            Route::Files { session_id } => server
+                .handle_request(envelope(Request::List {
+                    session_id: session_id.clone(),
+                }))
+                .map(|response| json_response(200, &response_body(response))),
+            Route::Sessions => server
+                .handle_request(envelope(Request::Sessions))
+                .map(|response| json_response(200, &response_body(response))),
+            Route::Delete { session_id } => server
+                .handle_request(envelope(Request::Delete {
+                    session_id: session_id.clone(),
+                }))
+                .map(|_| tiny_http::Response::from_data(Vec::new()).with_status_code(204)),
+        };
+    server.record(route.operation(), started, &response);
+
+    response.unwrap_or_else(|e| {
+        error!("Failed to serve HTTP request: {}", e);
+        json_response(status(e.kind), &e)
+    })
+}
+
+/// response_body returns the JSON body of a protocol response that lists things
+fn response_body(response: Response) -> serde_json::Value {
+    match response {
+        Response::Files(entries) => json!(entries),
+        Response::Sessions(sessions) => json!(sessions),
+        _ => unreachable!("only listing responses have a body"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ascii_json, HttpApi, Route, PROOF_HEADER};
+    use crate::auth;
+    use crate::server::Server;
+    use common::model::merkle::MerkleTree;
+    use common::model::proof_file::ProofFile;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+    use tiny_http::Method;
+
+    struct Reply {
+        status: u16,
+        headers: String,
+        body: Vec<u8>,
+    }
+
+    impl Reply {
+        fn json(&self) -> serde_json::Value {
+            serde_json::from_slice(&self.body).unwrap()
+        }
+
+        fn header(&self, name: &str) -> Option<String> {
+            self.headers.lines().find_map(|line| {
+                let (field, value) = line.split_once(':')?;
+                field
+                    .eq_ignore_ascii_case(name)
+                    .then(|| value.trim().to_string())
+            })
+        }
+    }
+
+    /// local_api serves the HTTP API of a server with alice and bob as users
+    fn local_api() -> String {
+        local_api_of(Server::new())
+    }
+
+    fn local_api_of(mut server: Server) -> String {
+        server.set_users(auth::test::users(&["alice", "bob"]));
+        let api = HttpApi::bind("127.0.0.1:0").unwrap();
+        let address = api.address();
+        std::thread::spawn(move || api.serve(Arc::new(Mutex::new(server))));
+        address
+    }
+
+    fn send(
+        address: &str,
+        method: &str,
+        path: &str,
+        user: Option<&str>,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Reply {
+        let mut stream = TcpStream::connect(address).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            body.len()
+        );
+        if let Some(user) = user {
+            head.push_str(&format!("Authorization: Bearer {}-token\r\n", user));
+        }
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let headers = String::from_utf8(response[..split].to_vec()).unwrap();
+        Reply {
+            status: headers[9..12].parse().unwrap(),
+            headers,
+            body: response[split + 4..].to_vec(),
+        }
+    }
+
+    /// upload uploads the files as alice and returns the session ID
+    fn upload(address: &str, files: &[(&str, &[u8])]) -> String {
+        let reply = send(address, "POST", "/sessions", Some("alice"), &[], b"");
+        assert_eq!(reply.status, 201);
+        let session_id = reply.json()["session_id"].as_str().unwrap().to_string();
+
+        for (index, (name, content)) in files.iter().enumerate() {
+            let path = format!("/sessions/{}/files/{}", session_id, index);
+            let reply = send(
+                address,
+                "PUT",
+                &path,
+                Some("alice"),
+                &[("X-File-Name", name)],
+                content,
+            );
+            assert_eq!(reply.status, 204);
+        }
+        session_id
+    }
+
+    #[test]
+    fn routes_are_parsed() {
+        assert_eq!(
+            Route::parse(&Method::Get, "/sessions/abc/files/2/proof?x=1").unwrap(),
+            Route::Proof {
+                session_id: String::from("abc"),
+                index: 2
+            }
+        );
+        assert!(Route::parse(&Method::Get, "/sessions/abc/files/two").is_err());
+        assert!(Route::parse(&Method::Patch, "/sessions").is_err());
+    }
+
+    #[test]
+    fn headers_escape_non_ascii_json() {
+        let json = ascii_json(&serde_json::json!({ "name": "ç😀.txt" }));
+        assert_eq!(json, r#"{"name":"\u00e7\ud83d\ude00.txt"}"#);
+        let decoded: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded["name"], "ç😀.txt");
+    }
+
+    #[test]
+    fn files_are_uploaded_and_downloaded_with_proofs() {
+        let address = local_api();
+        let files: [(&str, &[u8]); 3] = [
+            ("a.txt", b"Hello"),
+            ("b.txt", b"Lorem"),
+            ("c.txt", b"Ipsum"),
+        ];
+        let session_id = upload(&address, &files);
+        let root = MerkleTree::from(
+            files
+                .iter()
+                .map(|(_, content)| content.to_vec())
+                .collect::<Vec<Vec<u8>>>(),
+        )
+        .root_hash();
+
+        let path = format!("/sessions/{}/complete", session_id);
+        let reply = send(&address, "POST", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.status, 201);
+        assert_eq!(reply.json()["receipt"]["receipt"]["merkle_root"], root);
+
+        let path = format!("/sessions/{}/root", session_id);
+        let reply = send(&address, "GET", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.json()["merkle_root"], root);
+        assert_eq!(reply.json()["files_count"], 3);
+
+        let path = format!("/sessions/{}/files", session_id);
+        let reply = send(&address, "GET", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.json()[2]["name"], "c.txt");
+
+        for (index, (name, content)) in files.iter().enumerate() {
+            let path = format!("/sessions/{}/files/{}", session_id, index);
+            let reply = send(&address, "GET", &path, Some("alice"), &[], b"");
+            assert_eq!(reply.status, 200);
+            assert_eq!(reply.body, *content);
+
+            let proof = ProofFile::from_json(&reply.header(PROOF_HEADER).unwrap()).unwrap();
+            assert_eq!(proof.root(), root);
+            assert_eq!(proof.file_name().unwrap(), *name);
+            proof.verify(&reply.body).unwrap();
+        }
+
+        let path = format!("/sessions/{}/files/1/proof", session_id);
+        let reply = send(&address, "GET", &path, Some("alice"), &[], b"");
+        let proof = ProofFile::from_json(std::str::from_utf8(&reply.body).unwrap()).unwrap();
+        proof.verify(b"Lorem").unwrap();
+
+        let path = format!("/sessions/{}", session_id);
+        let reply = send(&address, "DELETE", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.status, 204);
+        let path = format!("/sessions/{}/root", session_id);
+        let reply = send(&address, "GET", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.status, 404);
+    }
+
+    #[test]
+    fn errors_map_to_http_statuses() {
+        let address = local_api();
+        let reply = send(&address, "POST", "/sessions", None, &[], b"");
+        assert_eq!(reply.status, 401);
+        assert_eq!(reply.json()["kind"], "unauthenticated");
+
+        let session_id = upload(&address, &[("a.txt", b"Hello")]);
+        let path = format!("/sessions/{}/files/2", session_id);
+        let reply = send(
+            &address,
+            "PUT",
+            &path,
+            Some("bob"),
+            &[("X-File-Name", "b.txt")],
+            b"Lorem",
+        );
+        assert_eq!(reply.status, 403);
+
+        // a file was put at index 2 but none at 1
+        send(
+            &address,
+            "PUT",
+            &path,
+            Some("alice"),
+            &[("X-File-Name", "c.txt")],
+            b"Dolor",
+        );
+        let path = format!("/sessions/{}/complete", session_id);
+        let reply = send(&address, "POST", &path, Some("alice"), &[], b"");
+        assert_eq!(reply.status, 400);
+
+        let path = format!("/sessions/{}/files/0", session_id);
+        let reply = send(&address, "PUT", &path, Some("alice"), &[], b"Hello");
+        assert_eq!(reply.status, 400);
+
+        assert_eq!(
+            send(&address, "GET", "/missing", Some("alice"), &[], b"").status,
+            404
+        );
+    }
+
+    #[test]
+    fn files_over_the_message_size_are_too_large() {
+        let mut server = Server::new();
+        server.set_max_message_size(4);
+        let address = local_api_of(server);
+        let session_id = upload(&address, &[("a.txt", b"Lore")]);
+
+        let path = format!("/sessions/{}/files/1", session_id);
+        let headers = [("X-File-Name", "b.txt")];
+        let reply = send(&address, "PUT", &path, Some("alice"), &headers, b"Lorem");
+        assert_eq!(reply.status, 413);
+        assert_eq!(reply.json()["kind"], "too-large");
+    }
+}
@@ -0,0 +1,51 @@
+use ed25519_dalek::SigningKey;
+use log::warn;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// generate_signing_key creates a new random ed25519 key
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::from_bytes(&rand::random::<[u8; 32]>())
+}
+
+/// load_or_create_signing_key reads the long-term signing key of the server from a file
+/// holding its raw 32 byte seed. The file is created with a new key if it does not exist
+pub fn load_or_create_signing_key(path: &str) -> Result<SigningKey, String> {
+    if Path::new(path).exists() {
+        let seed: [u8; 32] = fs::read(path)
+            .map_err(|e| format!("failed to read signing key {}: {}", path, e))?
+            .try_into()
+            .map_err(|_| format!("signing key {} should contain exactly 32 bytes", path))?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+
+    warn!("Signing key {} does not exist, creating a new one", path);
+    let key = generate_signing_key();
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .map_err(|e| format!("failed to write signing key {}: {}", path, e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod test {
+    use super::load_or_create_signing_key;
+
+    #[test]
+    fn created_signing_key_is_loaded_again() {
+        let path = std::env::temp_dir().join(format!("verifile-{}.key", rand::random::<u64>()));
+        let path = path.to_str().unwrap();
+
+        let created = load_or_create_signing_key(path).unwrap();
+        let loaded = load_or_create_signing_key(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(created.to_bytes(), loaded.to_bytes());
+    }
+}
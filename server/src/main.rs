@@ -1,11 +1,77 @@
+use clap::Parser;
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{info, warn, LevelFilter};
+use std::error::Error;
 
+mod admin;
+mod args;
+mod auth;
+mod blobs;
+mod grpc;
+mod http;
+mod keys;
+mod metrics;
+mod replication;
 mod server;
+mod session;
+mod store;
 
-fn main() {
+fn main() -> Result<(), Box<dyn Error>> {
     Builder::new().filter(None, LevelFilter::Info).init();
 
+    let args = args::Argument::parse();
+    info!("{:?}", args);
+
     let mut server = server::Server::new();
+    server.set_address(args.address());
+    if let Some((cert, key, client_ca)) = args.tls() {
+        let config = common::tls::server_config(&cert, &key, client_ca.as_deref())?;
+        server.set_tls_config(config);
+    }
+    if let Some(path) = args.users() {
+        server.set_users(auth::Users::load(&path)?);
+    }
+    match args.signing_key() {
+        Some(path) => server.set_signing_key(keys::load_or_create_signing_key(&path)?),
+        None => warn!("No signing key given, receipts are signed with a temporary key"),
+    }
+    if let Some(ttl) = args.session_ttl() {
+        server.set_session_ttl(ttl);
+        server.set_sweep_interval(args.sweep_interval());
+    }
+    if let Some(quota) = args.quota() {
+        server.set_quota(quota);
+    }
+    server.set_max_message_size(args.max_message_size());
+    if let Some(dir) = args.data_dir() {
+        let loaded = server.set_store(store::Store::open(&dir)?)?;
+        info!("Loaded {} sessions saved in {}", loaded, dir);
+    }
+    info!(
+        "Receipts are signed with public key {}",
+        server.public_key()
+    );
+    if let Some(address) = args.admin_address() {
+        let admin = admin::Admin::bind(&address, server.metrics())?;
+        std::thread::spawn(move || admin.serve());
+    }
+    if let Some(address) = args.http_address() {
+        server.set_http_api(http::HttpApi::bind(&address)?);
+    }
+    if let Some(address) = args.grpc_address() {
+        server.set_grpc_api(grpc::GrpcApi::bind(&address)?);
+    }
+    if let Some(mut replication) = args.replication() {
+        if let Some(ca) = args.replication_ca() {
+            replication.set_tls(common::tls::client_config(&ca, None)?);
+        }
+        match replication.primary_address() {
+            Some(primary) => info!("Replicating the sessions of primary {}", primary),
+            None => info!("Forwarding changed sessions to replicas"),
+        }
+        server.set_replication(replication);
+    }
     server.start();
+
+    Ok(())
 }
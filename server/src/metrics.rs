@@ -0,0 +1,261 @@
+use common::protocol::ErrorKind;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// LATENCY_BUCKETS are the upper bounds in seconds of the request latency histogram
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+/// Histogram counts request latencies in the LATENCY_BUCKETS, each bucket counting
+/// the requests at or under its bound like prometheus histograms do
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum_seconds: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        LATENCY_BUCKETS
+            .iter()
+            .zip(self.buckets.iter_mut())
+            .filter(|(bound, _)| seconds <= **bound)
+            .for_each(|(_, bucket)| *bucket += 1);
+        self.count += 1;
+        self.sum_seconds += seconds;
+    }
+}
+
+/// OperationStats counts the requests of one operation
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct OperationStats {
+    pub requests: u64,
+    /// failed requests by kind of error
+    pub errors: BTreeMap<String, u64>,
+    pub latency: Histogram,
+}
+
+/// Stats is a snapshot of the state of the server
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub uptime_seconds: u64,
+    pub sessions: usize,
+    /// bytes of distinct content stored
+    pub stored_bytes: usize,
+    /// number of distinct contents stored
+    pub blobs: usize,
+    pub operations: BTreeMap<&'static str, OperationStats>,
+}
+
+#[derive(Default)]
+struct Counters {
+    sessions: usize,
+    stored_bytes: usize,
+    blobs: usize,
+    operations: BTreeMap<&'static str, OperationStats>,
+}
+
+/// Metrics collects what the server does, so it can be read by the admin endpoint
+/// without waiting for the server to finish a request
+pub struct Metrics {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    fn counters(&self) -> std::sync::MutexGuard<'_, Counters> {
+        self.counters
+            .lock()
+            .expect("metrics lock should not be poisoned")
+    }
+
+    /// record counts a request of the operation that took the elapsed time
+    /// and failed with the error kind, if it failed
+    pub fn record(&self, operation: &'static str, elapsed: Duration, error: Option<ErrorKind>) {
+        let mut counters = self.counters();
+        let stats = counters.operations.entry(operation).or_default();
+        stats.requests += 1;
+        if let Some(kind) = error {
+            *stats.errors.entry(format!("{:?}", kind)).or_default() += 1;
+        }
+        stats.latency.observe(elapsed);
+    }
+
+    /// set_storage sets the number of sessions and the content the server stores
+    pub fn set_storage(&self, sessions: usize, stored_bytes: usize, blobs: usize) {
+        let mut counters = self.counters();
+        counters.sessions = sessions;
+        counters.stored_bytes = stored_bytes;
+        counters.blobs = blobs;
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// stats takes a snapshot of the metrics
+    pub fn stats(&self) -> Stats {
+        let counters = self.counters();
+        Stats {
+            uptime_seconds: self.uptime().as_secs(),
+            sessions: counters.sessions,
+            stored_bytes: counters.stored_bytes,
+            blobs: counters.blobs,
+            operations: counters.operations.clone(),
+        }
+    }
+
+    /// render_prometheus renders the metrics in the prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let stats = self.stats();
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: String| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            let _ = writeln!(out, "{} {}", name, value);
+        };
+        gauge(
+            "verifile_uptime_seconds",
+            "Seconds since the server started.",
+            self.uptime().as_secs_f64().to_string(),
+        );
+        gauge(
+            "verifile_sessions",
+            "Number of stored sessions.",
+            stats.sessions.to_string(),
+        );
+        gauge(
+            "verifile_stored_bytes",
+            "Bytes of distinct file content stored.",
+            stats.stored_bytes.to_string(),
+        );
+        gauge(
+            "verifile_blobs",
+            "Number of distinct file contents stored.",
+            stats.blobs.to_string(),
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP verifile_requests_total Requests served by operation."
+        );
+        let _ = writeln!(out, "# TYPE verifile_requests_total counter");
+        for (operation, op) in &stats.operations {
+            let _ = writeln!(
+                out,
+                "verifile_requests_total{{operation=\"{}\"}} {}",
+                operation, op.requests
+            );
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP verifile_request_errors_total Failed requests by operation and kind of error."
+        );
+        let _ = writeln!(out, "# TYPE verifile_request_errors_total counter");
+        for (operation, op) in &stats.operations {
+            for (kind, count) in &op.errors {
+                let _ = writeln!(
+                    out,
+                    "verifile_request_errors_total{{operation=\"{}\",kind=\"{}\"}} {}",
+                    operation, kind, count
+                );
+            }
+        }
+
+        let _ = writeln!(
+            out,
+            "# HELP verifile_request_duration_seconds Time taken to serve requests by operation."
+        );
+        let _ = writeln!(out, "# TYPE verifile_request_duration_seconds histogram");
+        for (operation, op) in &stats.operations {
+            let latency = &op.latency;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(latency.buckets) {
+                let _ = writeln!(
+                    out,
+                    "verifile_request_duration_seconds_bucket{{operation=\"{}\",le=\"{}\"}} {}",
+                    operation, bound, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "verifile_request_duration_seconds_bucket{{operation=\"{}\",le=\"+Inf\"}} {}",
+                operation, latency.count
+            );
+            let _ = writeln!(
+                out,
+                "verifile_request_duration_seconds_sum{{operation=\"{}\"}} {}",
+                operation, latency.sum_seconds
+            );
+            let _ = writeln!(
+                out,
+                "verifile_request_duration_seconds_count{{operation=\"{}\"}} {}",
+                operation, latency.count
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use common::protocol::ErrorKind;
+    use std::time::Duration;
+
+    #[test]
+    fn requests_are_counted_by_operation() {
+        let metrics = Metrics::new();
+        metrics.record("upload", Duration::from_millis(3), None);
+        metrics.record(
+            "upload",
+            Duration::from_secs(2),
+            Some(ErrorKind::QuotaExceeded),
+        );
+        metrics.record("list", Duration::from_micros(10), Some(ErrorKind::NotFound));
+
+        let stats = metrics.stats();
+        let upload = &stats.operations["upload"];
+        assert_eq!(upload.requests, 2);
+        assert_eq!(upload.errors["QuotaExceeded"], 1);
+        // 3 ms is under every bound from 5 ms, 2 s only under the last one
+        assert_eq!(upload.latency.buckets, [0, 1, 1, 1, 1, 1, 1, 1, 1, 2]);
+        assert_eq!(stats.operations["list"].errors["NotFound"], 1);
+    }
+
+    #[test]
+    fn metrics_render_in_prometheus_format() {
+        let metrics = Metrics::new();
+        metrics.set_storage(2, 1024, 3);
+        metrics.record(
+            "download",
+            Duration::from_millis(20),
+            Some(ErrorKind::Forbidden),
+        );
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("verifile_sessions 2\n"));
+        assert!(text.contains("verifile_stored_bytes 1024\n"));
+        assert!(text.contains("verifile_requests_total{operation=\"download\"} 1\n"));
+        assert!(text.contains(
+            "verifile_request_errors_total{operation=\"download\",kind=\"Forbidden\"} 1\n"
+        ));
+        assert!(text.contains(
+            "verifile_request_duration_seconds_bucket{operation=\"download\",le=\"0.025\"} 1\n"
+        ));
+        assert!(text.contains(
+            "verifile_request_duration_seconds_bucket{operation=\"download\",le=\"0.01\"} 0\n"
+        ));
+    }
+}
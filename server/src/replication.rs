@@ -0,0 +1,544 @@
+//! replication keeps copies of the sessions of a primary server on its replicas. The primary
+//! tells its replicas which sessions changed, and each replica reconciles a changed session
+//! by walking the merkle tree of the primary from the root down, only descending into the
+//! subtrees whose hash differs from its own copy and only fetching the files whose content it
+//! does not store yet. Replicas also reconcile every session periodically, catching up on
+//! changes they were not told about.
+
+use crate::server::{lock, Server};
+use common::model::merkle::{MerkleTree, NodeHash};
+use common::protocol::{read_message, write_message, Encoding, Envelope, Request, Response};
+use common::replication::{
+    nodes_above_leaves, tree_height, ReplicationRequest, ReplicationResponse, MAX_LEAVES, MAX_NODES,
+};
+use common::transport::{ClientTls, Stream};
+use log::{info, warn};
+use rustls::ClientConfig;
+use sha256::digest;
+use std::collections::HashMap;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+
+/// REQUEST_TIMEOUT is how long a server waits on another that stopped answering a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Mode is the part a server plays in replication
+#[derive(Debug, Clone)]
+enum Mode {
+    /// the server takes changes and forwards them to the replicas at the addresses
+    Primary { replicas: Vec<String> },
+    /// the server copies the sessions of the primary at the address, reconciling
+    /// all of them at the interval
+    Replica { primary: String, interval: Duration },
+}
+
+/// Replication is how a server replicates its sessions with other servers
+#[derive(Clone)]
+pub struct Replication {
+    /// token the primary and its replicas authenticate each other with
+    token: String,
+    /// TLS configuration used to connect to the other servers, if they serve TLS
+    tls: Option<Arc<ClientConfig>>,
+    mode: Mode,
+}
+
+impl Replication {
+    /// primary makes a server the primary of the replicas at the addresses
+    pub fn primary(token: String, replicas: Vec<String>) -> Self {
+        Self {
+            token,
+            tls: None,
+            mode: Mode::Primary { replicas },
+        }
+    }
+
+    /// replica makes a server a replica of the primary at the address
+    pub fn replica(token: String, primary: String, interval: Duration) -> Self {
+        Self {
+            token,
+            tls: None,
+            mode: Mode::Replica { primary, interval },
+        }
+    }
+
+    pub fn set_tls(&mut self, config: Arc<ClientConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// authenticate checks that a request was sent with the replication token. Digests of
+    /// the tokens are compared in constant time, so how long it takes does not tell how much
+    /// of the token was right
+    pub fn authenticate(&self, token: Option<&str>) -> bool {
+        token.is_some_and(|token| {
+            digest(token)
+                .as_bytes()
+                .ct_eq(digest(&self.token).as_bytes())
+                .into()
+        })
+    }
+
+    /// primary_address returns the address of the primary if the server is a replica
+    pub fn primary_address(&self) -> Option<&str> {
+        match &self.mode {
+            Mode::Primary { .. } => None,
+            Mode::Replica { primary, .. } => Some(primary),
+        }
+    }
+
+    /// run replicates the sessions of the server until it stops. A primary forwards the IDs
+    /// of the sessions that changed to its replicas, a replica reconciles them
+    pub fn run(&self, server: &Mutex<Server>, changed: Receiver<String>) {
+        match &self.mode {
+            Mode::Primary { replicas } => {
+                for session_id in changed {
+                    for replica in replicas {
+                        let request = ReplicationRequest::Changed {
+                            session_id: session_id.clone(),
+                        };
+                        if let Err(e) = self.send(replica, request) {
+                            warn!(
+                                "Failed to tell replica {} that session {} changed: {}",
+                                replica, session_id, e
+                            );
+                        }
+                    }
+                }
+            }
+            Mode::Replica { primary, interval } => {
+                self.reconcile_all(server, primary);
+                loop {
+                    match changed.recv_timeout(*interval) {
+                        Ok(session_id) => {
+                            if let Err(e) = self.reconcile(server, primary, &session_id) {
+                                warn!("Failed to reconcile session {}: {}", session_id, e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => self.reconcile_all(server, primary),
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// send sends a replication request to the server at the address and returns its response
+    fn send(
+        &self,
+        address: &str,
+        request: ReplicationRequest,
+    ) -> Result<ReplicationResponse, String> {
+        // the certificate of each server must be valid for the host of its address
+        let tls = match &self.tls {
+            Some(config) => {
+                let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+                Some(ClientTls::new(Arc::clone(config), host)?)
+            }
+            None => None,
+        };
+        let mut stream = Stream::connect(address, tls.as_ref())?;
+        stream
+            .set_timeout(REQUEST_TIMEOUT)
+            .map_err(|e| format!("failed to set timeout: {}", e))?;
+        let envelope = Envelope {
+            token: Some(self.token.clone()),
+            request: Request::Replication(request),
+        };
+        write_message(&mut stream, &envelope, Encoding::Cbor)?;
+        match read_message::<Response>(&mut stream)?.0 {
+            Response::Replication(response) => Ok(response),
+            Response::Error(e) => Err(format!("{} answered: {}", address, e)),
+            response => Err(format!("{} answered {:?}", address, response)),
+        }
+    }
+
+    /// reconcile_all reconciles the sessions whose root or revision differ from the primary,
+    /// and deletes the ones the primary no longer has
+    pub fn reconcile_all(&self, server: &Mutex<Server>, primary: &str) {
+        let roots = match self.send(primary, ReplicationRequest::Roots) {
+            Ok(ReplicationResponse::Roots(roots)) => roots,
+            Ok(response) => {
+                warn!("Primary {} answered {:?} to roots", primary, response);
+                return;
+            }
+            Err(e) => {
+                warn!("Failed to get the roots of primary {}: {}", primary, e);
+                return;
+            }
+        };
+
+        let local = lock(server)
+            .roots()
+            .into_iter()
+            .map(|root| (root.session_id.clone(), root))
+            .collect::<HashMap<_, _>>();
+        for session_id in local.keys() {
+            if !roots.iter().any(|root| &root.session_id == session_id) {
+                lock(server).delete_replicated(session_id);
+            }
+        }
+        for root in roots {
+            if local.get(&root.session_id) == Some(&root) {
+                continue;
+            }
+            if let Err(e) = self.reconcile(server, primary, &root.session_id) {
+                warn!("Failed to reconcile session {}: {}", root.session_id, e);
+            }
+        }
+    }
+
+    /// reconcile brings the copy of a session up to date with the primary
+    /// and returns the number of files whose content had to be fetched
+    pub fn reconcile(
+        &self,
+        server: &Mutex<Server>,
+        primary: &str,
+        session_id: &str,
+    ) -> Result<usize, String> {
+        let request = ReplicationRequest::State {
+            session_id: session_id.to_string(),
+        };
+        let state = match self.send(primary, request)? {
+            ReplicationResponse::State(Some(state)) => state,
+            ReplicationResponse::State(None) => {
+                lock(server).delete_replicated(session_id);
+                return Ok(0);
+            }
+            response => return Err(format!("unexpected response {:?}", response)),
+        };
+
+        // the server is not locked while the primary is asked, the copy is
+        // replaced at once when every file is known
+        let local = lock(server).merkle_tree(session_id);
+        let differing =
+            self.differing_leaves(primary, session_id, local.as_ref(), state.names.len())?;
+        let mut leaves = (0..state.names.len())
+            .map(|index| {
+                local
+                    .as_ref()
+                    .and_then(|tree| tree.leaf(index))
+                    .copied()
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<NodeHash>>();
+        for (index, hash) in &differing {
+            leaves[*index] = *hash;
+        }
+
+        let missing = {
+            let server = lock(server);
+            differing
+                .iter()
+                .filter(|(_, hash)| !server.has_content(hash))
+                .map(|(index, _)| *index)
+                .collect::<Vec<usize>>()
+        };
+        let mut fetched = Vec::with_capacity(missing.len());
+        for indexes in missing.chunks(MAX_LEAVES) {
+            let request = ReplicationRequest::Leaves {
+                session_id: session_id.to_string(),
+                indexes: indexes.to_vec(),
+            };
+            match self.send(primary, request)? {
+                ReplicationResponse::Leaves(files) if files.len() == indexes.len() => {
+                    fetched.extend(files)
+                }
+                response => return Err(format!("unexpected response {:?}", response)),
+            }
+        }
+
+        let count = fetched.len();
+        lock(server).apply_replicated(session_id, state, leaves, fetched)?;
+        info!(
+            "Reconciled session {}, {} files differed and {} were fetched",
+            session_id,
+            differing.len(),
+            count
+        );
+        Ok(count)
+    }
+
+    /// differing_leaves walks the merkle tree of a session of the primary with the number of
+    /// leaves from the root down, along with the local copy if there is one, and returns the
+    /// index and hash of the leaves of the primary that are not in the copy
+    fn differing_leaves(
+        &self,
+        primary: &str,
+        session_id: &str,
+        local: Option<&MerkleTree>,
+        leaves: usize,
+    ) -> Result<Vec<(usize, NodeHash)>, String> {
+        let mut depth = tree_height(leaves);
+        let mut indexes = vec![0];
+        loop {
+            let hashes = self.nodes(primary, session_id, depth, &indexes)?;
+            let differing = indexes
+                .into_iter()
+                .zip(hashes)
+                .filter(|(index, hash)| {
+                    !local.is_some_and(|tree| same_node(tree, depth, *index, hash, leaves))
+                })
+                .collect::<Vec<(usize, NodeHash)>>();
+            if depth == 0 || differing.is_empty() {
+                return Ok(differing);
+            }
+
+            depth -= 1;
+            let width = nodes_above_leaves(leaves, depth);
+            indexes = differing
+                .iter()
+                .flat_map(|(index, _)| [2 * index, 2 * index + 1])
+                .filter(|index| *index < width)
+                .collect();
+        }
+    }
+
+    /// nodes gets the hashes of the nodes at the indexes of a level of the primary's tree
+    fn nodes(
+        &self,
+        primary: &str,
+        session_id: &str,
+        depth: usize,
+        indexes: &[usize],
+    ) -> Result<Vec<NodeHash>, String> {
+        let mut hashes = Vec::with_capacity(indexes.len());
+        for indexes in indexes.chunks(MAX_NODES) {
+            let request = ReplicationRequest::Nodes {
+                session_id: session_id.to_string(),
+                depth,
+                indexes: indexes.to_vec(),
+            };
+            match self.send(primary, request)? {
+                ReplicationResponse::Nodes(nodes) if nodes.len() == indexes.len() => {
+                    hashes.extend(nodes.iter().map(|hash| *hash.as_bytes()))
+                }
+                response => return Err(format!("unexpected response {:?}", response)),
+            }
+        }
+        Ok(hashes)
+    }
+}
+
+/// same_node tells whether the local tree has the node of the primary's tree with the number
+/// of leaves. Besides the hash, both nodes must cover the same leaves, since the last node of
+/// a level is paired with itself and a smaller tree can have the same hash over fewer leaves
+fn same_node(
+    local: &MerkleTree,
+    depth: usize,
+    index: usize,
+    hash: &NodeHash,
+    leaves: usize,
+) -> bool {
+    let end = (index + 1) << depth;
+    local.node_above_leaves(depth, index) == Some(hash) && end.min(local.len()) == end.min(leaves)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth;
+    use common::model::file_info::FileInfo;
+    use common::protocol::{ErrorKind, SyncFile};
+    use common::replication::SessionRoot;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Instant;
+
+    const TOKEN: &str = "replication-token";
+
+    fn server(replication: Replication) -> Server {
+        let mut server = Server::new();
+        server.set_users(auth::test::users(&["alice"]));
+        server.set_replication(replication);
+        server
+    }
+
+    /// serve serves the server on a local port from its own thread and returns the address
+    fn serve(server: Server, listener: TcpListener) -> String {
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || server.serve(listener));
+        address
+    }
+
+    fn send(address: &str, request: Request) -> Response {
+        let mut stream = Stream::connect(address, None).unwrap();
+        let envelope = Envelope {
+            token: Some(String::from("alice-token")),
+            request,
+        };
+        write_message(&mut stream, &envelope, Encoding::Cbor).unwrap();
+        read_message::<Response>(&mut stream).unwrap().0
+    }
+
+    fn files(contents: &[&str]) -> Vec<FileInfo> {
+        contents
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                FileInfo::new(index, format!("{}.txt", index), content.as_bytes().to_vec())
+            })
+            .collect()
+    }
+
+    fn upload(address: &str, contents: &[&str]) -> String {
+        match send(
+            address,
+            Request::Upload {
+                files: files(contents),
+            },
+        ) {
+            Response::Uploaded { session_id, .. } => session_id,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    fn sync(address: &str, session_id: &str, index: usize, content: &str) {
+        let base_root = roots(address)
+            .into_iter()
+            .find(|root| root.session_id == session_id)
+            .unwrap()
+            .merkle_root;
+        let sync_files = (0..4)
+            .map(|i| match i == index {
+                true => SyncFile::Upload(FileInfo::new(
+                    i,
+                    format!("{}.txt", i),
+                    content.as_bytes().to_vec(),
+                )),
+                false => SyncFile::Keep { index: i },
+            })
+            .collect();
+        let request = Request::Sync {
+            session_id: session_id.to_string(),
+            base_root,
+            files: sync_files,
+        };
+        assert!(matches!(send(address, request), Response::Synced { .. }));
+    }
+
+    fn roots(address: &str) -> Vec<SessionRoot> {
+        let replication = Replication::primary(TOKEN.to_string(), Vec::new());
+        match replication
+            .send(address, ReplicationRequest::Roots)
+            .unwrap()
+        {
+            ReplicationResponse::Roots(roots) => roots,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn replicas_fetch_only_the_files_that_differ() {
+        let primary = serve(
+            server(Replication::primary(TOKEN.to_string(), Vec::new())),
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+        );
+        let replication =
+            Replication::replica(TOKEN.to_string(), primary.clone(), Duration::from_secs(60));
+        let replica = Mutex::new(server(replication.clone()));
+
+        let session_id = upload(&primary, &["Hello", "Lorem", "Ipsum", "Dolor"]);
+        assert_eq!(
+            replication.reconcile(&replica, &primary, &session_id),
+            Ok(4)
+        );
+        assert_eq!(lock(&replica).roots(), roots(&primary));
+
+        // only the changed file is fetched, the others are found in the same subtrees
+        sync(&primary, &session_id, 2, "Sit amet");
+        assert_eq!(
+            replication.reconcile(&replica, &primary, &session_id),
+            Ok(1)
+        );
+        assert_eq!(lock(&replica).roots(), roots(&primary));
+
+        // content the replica already stores is not fetched again
+        sync(&primary, &session_id, 3, "Hello");
+        assert_eq!(
+            replication.reconcile(&replica, &primary, &session_id),
+            Ok(0)
+        );
+        assert_eq!(lock(&replica).roots(), roots(&primary));
+
+        let request = Request::Delete {
+            session_id: session_id.clone(),
+        };
+        assert!(matches!(send(&primary, request), Response::Deleted));
+        replication.reconcile_all(&replica, &primary);
+        assert!(lock(&replica).roots().is_empty());
+    }
+
+    #[test]
+    fn primaries_forward_changes_to_their_replicas() {
+        let primary_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let replica_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary_address = primary_listener.local_addr().unwrap().to_string();
+        let replica_address = replica_listener.local_addr().unwrap().to_string();
+
+        // the replica would only reconcile every session after an hour, unless told to
+        let replica = serve(
+            server(Replication::replica(
+                TOKEN.to_string(),
+                primary_address,
+                Duration::from_secs(3600),
+            )),
+            replica_listener,
+        );
+        let primary = serve(
+            server(Replication::primary(
+                TOKEN.to_string(),
+                vec![replica_address],
+            )),
+            primary_listener,
+        );
+
+        let session_id = upload(&primary, &["Hello", "Lorem", "Ipsum", "Dolor"]);
+        sync(&primary, &session_id, 1, "Sit amet");
+        let started = Instant::now();
+        while roots(&replica) != roots(&primary) {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "replica did not catch up"
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        // reads are served by the replica, changes are turned away
+        let request = Request::Download {
+            session_id: session_id.clone(),
+            index: 1,
+        };
+        match send(&replica, request) {
+            Response::File(proof) => assert_eq!(proof.file_content(), b"Sit amet"),
+            response => panic!("unexpected response {:?}", response),
+        }
+        match send(
+            &replica,
+            Request::Upload {
+                files: files(&["Hello"]),
+            },
+        ) {
+            Response::Error(e) => assert_eq!(e.kind, ErrorKind::Forbidden),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn replication_requests_need_the_replication_token() {
+        let primary = serve(
+            server(Replication::primary(TOKEN.to_string(), Vec::new())),
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+        );
+        let intruder = Replication::primary(String::from("alice-token"), Vec::new());
+        let e = intruder
+            .send(&primary, ReplicationRequest::Roots)
+            .unwrap_err();
+        assert!(e.contains("invalid replication token"), "{}", e);
+
+        let replication = Replication::primary(TOKEN.to_string(), Vec::new());
+        assert!(replication.authenticate(Some(TOKEN)));
+        assert!(!replication.authenticate(Some(&TOKEN[1..])));
+        assert!(!replication.authenticate(None));
+    }
+}
@@ -1,94 +1,777 @@
+use crate::auth::Users;
+use crate::blobs::BlobStore;
+use crate::grpc::GrpcApi;
+use crate::http::HttpApi;
+use crate::keys;
+use crate::metrics::Metrics;
+use crate::replication::Replication;
+use crate::session::{unix_time, Session, Upload};
+use crate::store::Store;
+use common::handshake::{self, Agreement, Hello};
 use common::model::file_info::FileInfo;
-use common::model::merkle::{MerkleProof, MerkleTree};
+use common::model::merkle::{hash_leaf, MerkleProof, MerkleTree, NodeHash};
+use common::model::proof_file::ProofFile;
+use common::model::receipt::Receipt;
+use common::protocol::{
+    read_message_within, write_message, Encoding, Envelope, ErrorKind, Format, ProtocolError,
+    Request, Response, Role, SessionInfo, SyncFile, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use common::replication::{
+    ReplicationRequest, ReplicationResponse, SessionRoot, SessionState, MAX_LEAVES, MAX_NODES,
+};
+use common::transport::Stream;
 use common::SERVER_ADDRESS;
+use ed25519_dalek::SigningKey;
 use log::{error, info};
+use rustls::ServerConfig;
 use std::collections::HashMap;
-use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
-enum ServerState {
-    Receive,
-    Send,
+/// DEFAULT_SWEEP_INTERVAL is how often expired sessions are deleted
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// CONNECTION_TIMEOUT is how long the server waits on a client that stopped sending its
+/// request or reading the response before closing the connection
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// BatchSnapshot is the merkle tree and the names of the files of a session when a
+/// download-all was served. The files are sent from it one at a time, so that neither the
+/// server lock nor the content of the whole batch is held while they are written
+struct BatchSnapshot {
+    merkle_tree: MerkleTree,
+    names: Vec<String>,
 }
 
 pub struct Server {
-    store: HashMap<usize, FileInfo>,
-    state: ServerState,
-    merkle_tree: MerkleTree,
+    sessions: HashMap<String, Session>,
+    /// sessions whose files are still being sent over HTTP, by session ID
+    uploads: HashMap<String, Upload>,
+    blobs: BlobStore,
+    users: Users,
+    tls: Option<Arc<ServerConfig>>,
+    signing_key: SigningKey,
+    /// seconds after which new sessions expire, if they do
+    session_ttl: Option<u64>,
+    /// bytes each user can store across the sessions they own, if limited
+    quota: Option<usize>,
+    /// size of the largest request the server reads
+    max_message_size: u64,
+    sweep_interval: Duration,
+    metrics: Arc<Metrics>,
+    http_api: Option<HttpApi>,
+    grpc_api: Option<GrpcApi>,
+    /// address the server listens at when started
+    address: String,
+    replication: Option<Replication>,
+    /// where the IDs of changed sessions are sent once replication is running, to be
+    /// forwarded to the replicas of a primary or reconciled by a replica
+    changes: Option<Sender<String>>,
+    /// where the sessions are saved to survive a restart, if they are
+    store: Option<Store>,
 }
 
 impl Server {
     pub fn new() -> Self {
         Self {
-            store: HashMap::new(),
-            state: ServerState::Receive,
-            merkle_tree: MerkleTree::new(),
+            sessions: HashMap::new(),
+            uploads: HashMap::new(),
+            blobs: BlobStore::new(),
+            users: Users::open(),
+            tls: None,
+            signing_key: keys::generate_signing_key(),
+            session_ttl: None,
+            quota: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            metrics: Arc::new(Metrics::new()),
+            http_api: None,
+            grpc_api: None,
+            address: String::from(SERVER_ADDRESS),
+            replication: None,
+            changes: None,
+            store: None,
         }
     }
 
-    /// handle_receive_and_store_files receives files from a TCP stream,
-    /// stores them, and updates the server's state and Merkle tree
-    fn handle_receive_and_store_files(&mut self, mut stream: TcpStream) {
-        let mut json_buf = Vec::new();
-        stream
-            .read_to_end(&mut json_buf)
-            .expect("should read downloaded files");
+    /// metrics returns the metrics of the server, which can be read while it serves requests
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// update_storage_metrics records the sessions and content currently stored
+    fn update_storage_metrics(&self) {
+        self.metrics.set_storage(
+            self.sessions.len(),
+            self.blobs.stored_bytes(),
+            self.blobs.blobs_count(),
+        );
+    }
+
+    /// record records a request of the operation started at the instant, and the storage after it
+    pub fn record<T>(
+        &self,
+        operation: &'static str,
+        started: Instant,
+        result: &Result<T, ProtocolError>,
+    ) {
+        self.metrics.record(
+            operation,
+            started.elapsed(),
+            result.as_ref().err().map(|e| e.kind),
+        );
+        self.update_storage_metrics();
+    }
+
+    /// set_http_api makes the server also serve its HTTP API
+    pub fn set_http_api(&mut self, http_api: HttpApi) {
+        self.http_api = Some(http_api);
+    }
+
+    /// set_grpc_api makes the server also serve its gRPC API
+    pub fn set_grpc_api(&mut self, grpc_api: GrpcApi) {
+        self.grpc_api = Some(grpc_api);
+    }
+
+    /// set_address sets the address the server listens at when started
+    pub fn set_address(&mut self, address: String) {
+        self.address = address;
+    }
+
+    /// set_replication makes the server the primary or a replica of other servers
+    pub fn set_replication(&mut self, replication: Replication) {
+        self.replication = Some(replication);
+    }
+
+    /// set_store makes the server save its sessions to the store, and loads the sessions
+    /// saved there before. It returns the number of loaded sessions
+    pub fn set_store(&mut self, store: Store) -> Result<usize, String> {
+        let sessions = store.load(&mut self.blobs)?;
+        let loaded = sessions.len();
+        self.sessions.extend(sessions);
+        self.store = Some(store);
+        self.update_storage_metrics();
+        Ok(loaded)
+    }
+
+    /// set_session_ttl makes new sessions expire once the duration has passed since their upload
+    pub fn set_session_ttl(&mut self, ttl: Duration) {
+        self.session_ttl = Some(ttl.as_secs());
+    }
+
+    /// set_quota limits the number of bytes each user can store across the sessions they own
+    pub fn set_quota(&mut self, quota: usize) {
+        self.quota = Some(quota);
+    }
+
+    /// set_max_message_size sets the size of the largest request the server reads,
+    /// which bounds the size of an upload
+    pub fn set_max_message_size(&mut self, max_size: u64) {
+        self.max_message_size = max_size;
+    }
+
+    pub fn max_message_size(&self) -> u64 {
+        self.max_message_size
+    }
+
+    /// set_sweep_interval sets how often expired sessions are deleted
+    pub fn set_sweep_interval(&mut self, interval: Duration) {
+        self.sweep_interval = interval;
+    }
+
+    /// set_signing_key sets the long-term key receipts are signed with.
+    /// Without it, receipts are signed with a key that only lasts until the server stops
+    pub fn set_signing_key(&mut self, signing_key: SigningKey) {
+        self.signing_key = signing_key;
+    }
+
+    /// public_key returns the hex encoded key clients can check receipts with
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// set_tls_config makes the server only accept TLS connections
+    pub fn set_tls_config(&mut self, config: Arc<ServerConfig>) {
+        self.tls = Some(config);
+    }
+
+    /// set_users makes the server require an API token of one of the users with every request
+    pub fn set_users(&mut self, users: Users) {
+        self.users = users;
+    }
+
+    /// live_session gets a session that has not expired
+    fn live_session(&self, session_id: &str) -> Result<&Session, ProtocolError> {
+        // an expired session is gone even if it has not been swept yet
+        self.sessions
+            .get(session_id)
+            .filter(|session| !session.is_expired(unix_time()))
+            .ok_or_else(|| {
+                ProtocolError::new(
+                    ErrorKind::NotFound,
+                    format!("session {} does not exist", session_id),
+                )
+            })
+    }
+
+    /// session gets a session the user has at least the required access to
+    fn session(
+        &self,
+        session_id: &str,
+        user: &str,
+        required: Role,
+    ) -> Result<&Session, ProtocolError> {
+        let session = self.live_session(session_id)?;
+        session.authorize(user, required)?;
+        Ok(session)
+    }
+
+    /// check_writable rejects changes to the sessions of a replica,
+    /// which only takes them from its primary
+    fn check_writable(&self) -> Result<(), ProtocolError> {
+        match self
+            .replication
+            .as_ref()
+            .and_then(Replication::primary_address)
+        {
+            Some(primary) => Err(ProtocolError::new(
+                ErrorKind::Forbidden,
+                format!(
+                    "this server is a replica of {}, sessions can only be changed there",
+                    primary
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// changed tells the replication thread that a session changed, if replication is running
+    fn changed(&self, session_id: &str) {
+        if let Some(changes) = &self.changes {
+            // the replication thread only stops with the server
+            let _ = changes.send(session_id.to_string());
+        }
+    }
+
+    /// save saves the session with the ID to the store, or removes it from there if the
+    /// session is gone, when the server has a store. The saved content of the released
+    /// leaf hashes is removed along with it once nothing refers to it
+    fn save(&self, session_id: &str, released: &[NodeHash]) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let saved = match self.sessions.get(session_id) {
+            Some(session) => store.save(session_id, session, &self.blobs),
+            None => store.remove(session_id),
+        };
+        if let Err(e) = saved.and_then(|_| store.release(released, &self.blobs)) {
+            error!("Failed to save session {}: {}", session_id, e);
+        }
+    }
+
+    /// used_bytes returns the number of bytes stored in the sessions the user owns,
+    /// including the ones still being uploaded
+    fn used_bytes(&self, user: &str) -> usize {
+        let stored = self
+            .sessions
+            .values()
+            .filter(|session| session.owner() == user)
+            .map(|session| session.size());
+        let uploading = self
+            .uploads
+            .values()
+            .filter(|upload| upload.owner() == user)
+            .map(|upload| upload.size());
+        stored.chain(uploading).sum()
+    }
+
+    /// check_quota checks that the user can store the number of bytes on top of what they own
+    fn check_quota(&self, user: &str, size: usize) -> Result<(), ProtocolError> {
+        let Some(quota) = self.quota else {
+            return Ok(());
+        };
+
+        let used = self.used_bytes(user);
+        if used.saturating_add(size) > quota {
+            return Err(ProtocolError::new(
+                ErrorKind::QuotaExceeded,
+                format!(
+                    "upload of {} bytes exceeds the quota of {} bytes, {} already stores {} bytes",
+                    size, quota, user, used
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// handle_upload stores the files in a new session owned by the user
+    /// and returns a receipt of the upload signed by the server
+    fn handle_upload(
+        &mut self,
+        user: String,
+        files: Vec<FileInfo>,
+    ) -> Result<Response, ProtocolError> {
+        self.check_writable()?;
+        self.check_quota(&user, files.iter().map(|file| file.size()).sum())?;
+        self.store_session(hex::encode(rand::random::<[u8; 16]>()), user, files)
+    }
+
+    /// store_session stores the files in a session with the ID
+    /// and returns a receipt of the upload signed by the server
+    fn store_session(
+        &mut self,
+        session_id: String,
+        user: String,
+        files: Vec<FileInfo>,
+    ) -> Result<Response, ProtocolError> {
+        let mut session = Session::new(user.clone(), files, &mut self.blobs)?;
+        if let Some(ttl) = self.session_ttl {
+            session.set_ttl(ttl);
+        }
+        info!(
+            "{} uploaded session {}, {} bytes in {} distinct files are stored",
+            user,
+            session_id,
+            self.blobs.stored_bytes(),
+            self.blobs.blobs_count()
+        );
+
+        let receipt = Receipt::new(
+            session_id.clone(),
+            session.merkle_root(),
+            session.files_count(),
+        )
+        .sign(&self.signing_key);
+        self.sessions.insert(session_id.clone(), session);
+        self.save(&session_id, &[]);
+        self.changed(&session_id);
+        Ok(Response::Uploaded {
+            session_id,
+            receipt,
+        })
+    }
+
+    /// handle_sync replaces the files of a session owned by the user if it still has the
+    /// base root, and returns a receipt of the transition to the new root signed by the server
+    fn handle_sync(
+        &mut self,
+        user: &str,
+        session_id: String,
+        base_root: String,
+        files: Vec<SyncFile>,
+    ) -> Result<Response, ProtocolError> {
+        self.check_writable()?;
+        let session = self.session(&session_id, user, Role::Owner)?;
+        let previous_root = session.merkle_root();
+        if !previous_root.eq_ignore_ascii_case(&base_root) {
+            return Err(ProtocolError::new(
+                ErrorKind::Conflict,
+                format!(
+                    "session {} has merkle root {}, the sync was made against {}",
+                    session_id, previous_root, base_root
+                ),
+            ));
+        }
+        let grown = session
+            .synced_size(&files, &self.blobs)
+            .saturating_sub(session.size());
+        self.check_quota(user, grown)?;
+
+        let session = self
+            .sessions
+            .get_mut(&session_id)
+            .expect("session should exist after authorization");
+        let released = session.hashes();
+        session.sync(files, &mut self.blobs)?;
+        info!(
+            "{} synced session {} from merkle root {} to {}",
+            user,
+            session_id,
+            previous_root,
+            session.merkle_root()
+        );
+
+        let mut receipt = Receipt::new(
+            session_id.clone(),
+            session.merkle_root(),
+            session.files_count(),
+        );
+        receipt.set_previous_root(previous_root);
+        self.save(&session_id, &released);
+        self.changed(&session_id);
+        Ok(Response::Synced {
+            receipt: receipt.sign(&self.signing_key),
+        })
+    }
+
+    /// handle_download builds a merkle proof for the file at the index of a session
+    fn handle_download(
+        &self,
+        user: &str,
+        session_id: &str,
+        index: usize,
+    ) -> Result<Response, ProtocolError> {
+        let session = self.session(session_id, user, Role::ReadOnly)?;
+        Ok(Response::File(session.proof(index, &self.blobs)?))
+    }
+
+    /// batch_snapshot takes the merkle tree and the names of the files of a session the
+    /// user can read, which a download-all sends the files from
+    fn batch_snapshot(
+        &self,
+        token: Option<&str>,
+        session_id: &str,
+    ) -> Result<BatchSnapshot, ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        let session = self.session(session_id, &user, Role::ReadOnly)?;
+        Ok(BatchSnapshot {
+            merkle_tree: session.merkle_tree().clone(),
+            names: session.names(),
+        })
+    }
+
+    /// batch_file builds the response carrying the file at the index of a snapshot with its
+    /// proof. The server is only locked while the content is copied out of the blob store,
+    /// where it is found by its leaf hash even if the session changed since the snapshot
+    fn batch_file(server: &Mutex<Server>, snapshot: &BatchSnapshot, index: usize) -> Response {
+        let hash = snapshot
+            .merkle_tree
+            .leaf(index)
+            .expect("snapshot should have a leaf per file");
+        let content = lock(server).blobs.get(hash).map(<[u8]>::to_vec);
+        match content {
+            Some(content) => Response::File(MerkleProof::build(
+                &snapshot.merkle_tree,
+                index,
+                snapshot.names[index].clone(),
+                content,
+            )),
+            None => Response::Error(ProtocolError::new(
+                ErrorKind::NotFound,
+                format!("file {} was deleted during the download", index),
+            )),
+        }
+    }
+
+    /// delete_session removes a session and the content of its files
+    /// that no other session refers to
+    fn delete_session(&mut self, session_id: &str) -> Option<Session> {
+        let session = self.sessions.remove(session_id)?;
+        session.release(&mut self.blobs);
+        Some(session)
+    }
+
+    /// handle_delete deletes a session owned by the user
+    fn handle_delete(&mut self, user: &str, session_id: &str) -> Result<Response, ProtocolError> {
+        self.check_writable()?;
+        self.session(session_id, user, Role::Owner)?;
+        if let Some(session) = self.delete_session(session_id) {
+            self.save(session_id, &session.hashes());
+        }
+        self.changed(session_id);
+        info!("{} deleted session {}", user, session_id);
+        Ok(Response::Deleted)
+    }
 
-        let files_info: Vec<FileInfo> =
-            serde_json::from_slice(&json_buf).expect("should deserialize downloaded files");
-        let files_data = files_info
+    /// handle_sessions describes the sessions the user has access to, oldest first
+    fn handle_sessions(&self, user: &str) -> Response {
+        let now = unix_time();
+        let mut sessions = self
+            .sessions
             .iter()
-            .map(|file_info| file_info.content())
-            .collect::<Vec<Vec<u8>>>();
+            .filter(|(_, session)| session.role(user).is_some() && !session.is_expired(now))
+            .map(|(session_id, session)| session.info(session_id))
+            .collect::<Vec<SessionInfo>>();
+        sessions.sort_by(|a, b| (a.created_at, &a.session_id).cmp(&(b.created_at, &b.session_id)));
+        Response::Sessions(sessions)
+    }
 
-        self.merkle_tree = MerkleTree::from(files_data.clone());
-        self.store = files_info
-            .into_iter()
-            .fold(HashMap::new(), |mut h, file_info| {
-                h.insert(file_info.index(), file_info);
-                h
-            });
-        self.state = ServerState::Send;
+    /// sweep deletes the sessions that have expired at the given unix time
+    /// and returns how many were deleted
+    fn sweep(&mut self, now: u64) -> usize {
+        let expired = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_expired(now))
+            .map(|(session_id, _)| session_id.clone())
+            .collect::<Vec<String>>();
+        for session_id in &expired {
+            if let Some(session) = self.delete_session(session_id) {
+                self.save(session_id, &session.hashes());
+            }
+            info!("Session {} expired and was deleted", session_id);
+        }
+        // uploads that were never completed expire like sessions do
+        if let Some(ttl) = self.session_ttl {
+            self.uploads
+                .retain(|_, upload| !upload.is_expired(now, ttl));
+        }
+        self.update_storage_metrics();
+        expired.len()
     }
 
-    /// handle_send_file_with_merkle_proof Reads a file index from a TCP stream,
-    /// builds a Merkle proof for the file, and sends the proof over the stream
-    fn handle_send_file_with_merkle_proof(&self, mut stream: TcpStream) {
-        let mut buffer = [0; 8];
-        stream
-            .read_exact(&mut buffer)
-            .expect("file index should be available");
-
-        let index = u64::from_be_bytes(buffer) as usize;
-        let file_info = self
-            .store
-            .get(&index)
-            .expect("file index should be in the server store");
-
-        let mp = MerkleProof::build(
-            &self.merkle_tree,
-            index,
-            file_info.name(),
-            file_info.content(),
-        );
-        let json_proof = mp.to_string();
-        stream
-            .write_all(json_proof.as_bytes())
-            .expect("sending file content to client should not fail");
+    /// handle_grant gives another user access to a session owned by the user
+    fn handle_grant(
+        &mut self,
+        user: &str,
+        session_id: &str,
+        grantee: String,
+        role: Role,
+    ) -> Result<Response, ProtocolError> {
+        self.check_writable()?;
+        self.session(session_id, user, Role::Owner)?;
+        info!(
+            "{} granted {:?} access on session {} to {}",
+            user, role, session_id, grantee
+        );
+
+        self.sessions
+            .get_mut(session_id)
+            .expect("session should exist after authorization")
+            .grant(grantee, role);
+        self.save(session_id, &[]);
+        self.changed(session_id);
+        Ok(Response::Granted)
     }
 
-    pub fn start(&mut self) {
-        let listener = TcpListener::bind(SERVER_ADDRESS).unwrap();
-        info!("Server listening at: {}", SERVER_ADDRESS);
+    /// handle_request authenticates the caller and serves the request
+    pub fn handle_request(&mut self, envelope: Envelope) -> Result<Response, ProtocolError> {
+        // servers replicating with each other authenticate with the replication token
+        if let Request::Replication(request) = envelope.request {
+            return self
+                .handle_replication(envelope.token.as_deref(), request)
+                .map(Response::Replication);
+        }
+        let user = self.users.authenticate(envelope.token.as_deref())?;
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    match self.state.clone() {
-                        ServerState::Receive => self.handle_receive_and_store_files(stream),
-                        ServerState::Send => self.handle_send_file_with_merkle_proof(stream),
-                    };
+        match envelope.request {
+            Request::Hello(_) => Err(ProtocolError::new(
+                ErrorKind::BadRequest,
+                "a hello can only open a connection",
+            )),
+            Request::Upload { files } => self.handle_upload(user, files),
+            Request::StartUpload => Ok(Response::UploadStarted {
+                session_id: self.handle_start_upload(user)?,
+            }),
+            Request::PutFile { session_id, file } => {
+                self.handle_put_file(&user, &session_id, file)?;
+                Ok(Response::FilePut)
+            }
+            Request::CompleteUpload { session_id } => {
+                self.handle_complete_upload(user, &session_id)
+            }
+            Request::Download { session_id, index } => {
+                self.handle_download(&user, &session_id, index)
+            }
+            Request::DownloadAll { session_id } => {
+                let session = self.session(&session_id, &user, Role::ReadOnly)?;
+                Ok(Response::Batch {
+                    files_count: session.files_count(),
+                })
+            }
+            Request::List { session_id } => {
+                let session = self.session(&session_id, &user, Role::ReadOnly)?;
+                Ok(Response::Files(session.entries()))
+            }
+            Request::Grant {
+                session_id,
+                user: grantee,
+                role,
+            } => self.handle_grant(&user, &session_id, grantee, role),
+            Request::Delete { session_id } => self.handle_delete(&user, &session_id),
+            Request::Sessions => Ok(self.handle_sessions(&user)),
+            Request::Sync {
+                session_id,
+                base_root,
+                files,
+            } => self.handle_sync(&user, session_id, base_root, files),
+            Request::Diff { from, to } => {
+                let from = self.session(&from, &user, Role::ReadOnly)?;
+                let to = self.session(&to, &user, Role::ReadOnly)?;
+                Ok(Response::Diff(from.diff(to)))
+            }
+            Request::Replication(_) => unreachable!("replication requests are served above"),
+        }
+    }
+
+    /// handshake answers the hello that opened a connection with the version and features
+    /// the rest of the connection uses and returns the format they were agreed to be sent in,
+    /// None if the connection cannot go on
+    fn handshake(
+        server: &Mutex<Server>,
+        stream: &mut Stream,
+        hello: &Hello,
+        format: Format,
+    ) -> Option<Format> {
+        let started = Instant::now();
+        let agreement = handshake::negotiate(hello);
+        lock(server).record("hello", started, &agreement);
+
+        let agreed = agreement.as_ref().ok().map(Agreement::format);
+        let response = agreement.map(Response::Hello).unwrap_or_else(|e| {
+            error!("Failed to agree on a protocol: {}", e);
+            Response::Error(e)
+        });
+        if let Err(e) = write_message(stream, &response, format) {
+            error!("Failed to send response: {}", e);
+            return None;
+        }
+        if agreed.is_none() {
+            let _ = stream.close();
+        }
+        agreed
+    }
+
+    /// handle_connection reads a single request from the stream and writes back the response.
+    /// Clients of protocol version 2 and later send a hello first, version 1 clients the request
+    fn handle_connection(server: &Mutex<Server>, mut stream: Stream) {
+        let max_size = lock(server).max_message_size;
+        let (message, agreed) = match read_message_within::<Envelope>(&mut stream, max_size) {
+            Ok((
+                Envelope {
+                    request: Request::Hello(hello),
+                    ..
+                },
+                format,
+            )) => {
+                let Some(agreed) = Self::handshake(server, &mut stream, &hello, format) else {
+                    return;
+                };
+                (
+                    read_message_within::<Envelope>(&mut stream, max_size),
+                    Some(agreed),
+                )
+            }
+            message => (message, None),
+        };
+
+        let started = Instant::now();
+        let mut snapshot = None;
+        let (operation, response, format) = match message {
+            // without a handshake the response is sent in the format of the request
+            Ok((envelope, format)) => {
+                let download_all = match &envelope.request {
+                    Request::DownloadAll { session_id } => {
+                        Some((envelope.token.clone(), session_id.clone()))
+                    }
+                    _ => None,
+                };
+                let operation = envelope.request.operation();
+                let mut locked = lock(server);
+                let mut response = locked.handle_request(envelope);
+                // the files of a download-all are sent from the session the response counted
+                if let (Ok(Response::Batch { .. }), Some((token, session_id))) =
+                    (&response, download_all)
+                {
+                    match locked.batch_snapshot(token.as_deref(), &session_id) {
+                        Ok(taken) => snapshot = Some(taken),
+                        Err(e) => response = Err(e),
+                    }
+                }
+                drop(locked);
+                (operation, response, agreed.unwrap_or(format))
+            }
+            // the format of the request is not known, answer in the one every client reads
+            Err(e) => (
+                "invalid",
+                Err(ProtocolError::new(ErrorKind::BadRequest, e)),
+                agreed.unwrap_or(Format::from(Encoding::Json)),
+            ),
+        };
+        lock(server).record(operation, started, &response);
+
+        let response = response.unwrap_or_else(|e| {
+            error!("Failed to serve request: {}", e);
+            Response::Error(e)
+        });
+
+        if let Err(e) = write_message(&mut stream, &response, format)
+            .and_then(|_| match (&response, snapshot) {
+                (Response::Batch { .. }, Some(snapshot)) => (0..snapshot.merkle_tree.len())
+                    .try_for_each(|index| {
+                        let file = Self::batch_file(server, &snapshot, index);
+                        write_message(&mut stream, &file, format)
+                    }),
+                _ => Ok(()),
+            })
+            .and_then(|_| {
+                stream
+                    .close()
+                    .map_err(|e| format!("failed to close stream: {}", e))
+            })
+        {
+            error!("Failed to send response: {}", e);
+        }
+    }
+
+    /// accept serves a connection accepted by the listener, giving up on a client once
+    /// reading from it or writing to it stalls for CONNECTION_TIMEOUT
+    fn accept(server: &Mutex<Server>, tcp: TcpStream, tls: Option<&Arc<ServerConfig>>) {
+        let stream = tcp
+            .set_read_timeout(Some(CONNECTION_TIMEOUT))
+            .and_then(|_| tcp.set_write_timeout(Some(CONNECTION_TIMEOUT)))
+            .map_err(|e| e.to_string())
+            .and_then(|_| Stream::accept(tcp, tls));
+        match stream {
+            Ok(stream) => Self::handle_connection(server, stream),
+            Err(e) => {
+                error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+
+    /// start listens at the address of the server and serves connections
+    pub fn start(self) {
+        let listener = TcpListener::bind(&self.address).unwrap();
+        info!("Server listening at: {}", self.address);
+        self.serve(listener);
+    }
+
+    /// serve serves each connection of the listener from a thread of its own. The HTTP and gRPC
+    /// APIs, if there are any, are served from their own threads, if sessions expire a sweeper
+    /// thread deletes the expired ones, and if the server replicates a thread forwards or
+    /// reconciles changes
+    pub fn serve(mut self, listener: TcpListener) {
+        let tls = self.tls.clone();
+        let sweep = self.session_ttl.map(|_| self.sweep_interval);
+        let http_api = self.http_api.take();
+        let grpc_api = self.grpc_api.take();
+        let replication = self.replication.clone();
+        let (changes, changed) = mpsc::channel();
+        if replication.is_some() {
+            self.changes = Some(changes);
+        }
+        let server = Arc::new(Mutex::new(self));
+
+        if let Some(replication) = replication {
+            let server = server.clone();
+            thread::spawn(move || replication.run(&server, changed));
+        }
+        if let Some(http_api) = http_api {
+            let server = server.clone();
+            thread::spawn(move || http_api.serve(server));
+        }
+        if let Some(grpc_api) = grpc_api {
+            let server = server.clone();
+            thread::spawn(move || grpc_api.serve(server));
+        }
+        if let Some(interval) = sweep {
+            let server = server.clone();
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                lock(&server).sweep(unix_time());
+            });
+        }
+
+        for tcp in listener.incoming() {
+            match tcp {
+                Ok(tcp) => {
+                    let server = server.clone();
+                    let tls = tls.clone();
+                    thread::spawn(move || Self::accept(&server, tcp, tls.as_ref()));
                 }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
@@ -97,3 +780,1075 @@ impl Server {
         }
     }
 }
+
+/// lock locks the server shared between the threads serving it. Every API reads a request
+/// before locking the server and writes its response after, and only holds the lock while
+/// the request is served, so that a client that is slow to send its request or to read the
+/// response does not hold up the others
+pub fn lock(server: &Mutex<Server>) -> MutexGuard<'_, Server> {
+    server.lock().expect("server lock should not be poisoned")
+}
+
+/// this implementation has methods concerned with replicating sessions, serving the
+/// replication requests of other servers and applying what a replica fetched from its primary
+impl Server {
+    /// handle_replication serves a request of a server sharing the replication token
+    fn handle_replication(
+        &mut self,
+        token: Option<&str>,
+        request: ReplicationRequest,
+    ) -> Result<ReplicationResponse, ProtocolError> {
+        let replication = self.replication.as_ref().ok_or_else(|| {
+            ProtocolError::new(
+                ErrorKind::Forbidden,
+                "this server does not replicate sessions",
+            )
+        })?;
+        if !replication.authenticate(token) {
+            return Err(ProtocolError::new(
+                ErrorKind::Unauthenticated,
+                "invalid replication token",
+            ));
+        }
+
+        match request {
+            ReplicationRequest::Changed { session_id } => {
+                if replication.primary_address().is_none() {
+                    return Err(ProtocolError::new(
+                        ErrorKind::BadRequest,
+                        "this server is not a replica",
+                    ));
+                }
+                self.changed(&session_id);
+                Ok(ReplicationResponse::Accepted)
+            }
+            ReplicationRequest::Roots => Ok(ReplicationResponse::Roots(self.roots())),
+            ReplicationRequest::State { session_id } => Ok(ReplicationResponse::State(
+                self.live_session(&session_id).ok().map(Session::state),
+            )),
+            ReplicationRequest::Nodes {
+                session_id,
+                depth,
+                indexes,
+            } => {
+                check_batch("nodes", indexes.len(), MAX_NODES)?;
+                let session = self.live_session(&session_id)?;
+                Ok(ReplicationResponse::Nodes(
+                    session.node_hashes(depth, &indexes)?,
+                ))
+            }
+            ReplicationRequest::Leaves {
+                session_id,
+                indexes,
+            } => {
+                check_batch("files", indexes.len(), MAX_LEAVES)?;
+                let session = self.live_session(&session_id)?;
+                Ok(ReplicationResponse::Leaves(
+                    session.leaves(&indexes, &self.blobs)?,
+                ))
+            }
+        }
+    }
+
+    /// roots returns the root and revision of every session that has not expired, by session ID
+    pub fn roots(&self) -> Vec<SessionRoot> {
+        let now = unix_time();
+        let mut roots = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| !session.is_expired(now))
+            .map(|(session_id, session)| session.root(session_id))
+            .collect::<Vec<SessionRoot>>();
+        roots.sort_by(|a, b| a.session_id.cmp(&b.session_id));
+        roots
+    }
+
+    /// merkle_tree returns a copy of the merkle tree of a session, if it exists
+    pub fn merkle_tree(&self, session_id: &str) -> Option<MerkleTree> {
+        self.sessions
+            .get(session_id)
+            .map(|session| session.merkle_tree().clone())
+    }
+
+    /// has_content tells whether content with the leaf hash is stored
+    pub fn has_content(&self, hash: &NodeHash) -> bool {
+        self.blobs.get(hash).is_some()
+    }
+
+    /// apply_replicated replaces a session with the state fetched from the primary. The
+    /// files are given by their leaf hashes, the content of the fetched ones along with them,
+    /// the content of the others must already be stored. Nothing changes if they do not
+    /// make up the merkle root of the state
+    pub fn apply_replicated(
+        &mut self,
+        session_id: &str,
+        state: SessionState,
+        leaves: Vec<NodeHash>,
+        fetched: Vec<FileInfo>,
+    ) -> Result<(), String> {
+        if state.names.len() != leaves.len() {
+            return Err(format!(
+                "session has {} names for {} files",
+                state.names.len(),
+                leaves.len()
+            ));
+        }
+        let mut fetched = fetched
+            .into_iter()
+            .map(|file| (file.index(), file.into_content()))
+            .collect::<HashMap<usize, Vec<u8>>>();
+
+        let mut size = 0;
+        for (index, hash) in leaves.iter().enumerate() {
+            size += match fetched.get(&index) {
+                Some(content) if hash_leaf(content) == *hash => content.len(),
+                Some(_) => return Err(format!("file {} does not match its leaf hash", index)),
+                None => self
+                    .blobs
+                    .get(hash)
+                    .ok_or_else(|| format!("content of file {} is not stored", index))?
+                    .len(),
+            };
+        }
+        let files = state
+            .names
+            .iter()
+            .cloned()
+            .zip(leaves.iter().copied())
+            .collect();
+        let session = Session::from_state(state, files, size)?;
+
+        // the new files are referred to before the old ones are released,
+        // so the content they share stays stored
+        for (index, hash) in leaves.iter().enumerate() {
+            match fetched.remove(&index) {
+                Some(content) => {
+                    self.blobs.insert(content);
+                }
+                None => {
+                    self.blobs.retain(hash);
+                }
+            }
+        }
+        let released = self
+            .delete_session(session_id)
+            .map(|session| session.hashes())
+            .unwrap_or_default();
+        self.sessions.insert(session_id.to_string(), session);
+        self.save(session_id, &released);
+        self.update_storage_metrics();
+        Ok(())
+    }
+
+    /// delete_replicated deletes a session the primary no longer has
+    pub fn delete_replicated(&mut self, session_id: &str) {
+        if let Some(session) = self.delete_session(session_id) {
+            self.save(session_id, &session.hashes());
+            info!("Session {} was deleted from the primary", session_id);
+            self.update_storage_metrics();
+        }
+    }
+}
+
+/// check_batch rejects replication requests for more items than a request can ask for
+fn check_batch(items: &str, count: usize, max: usize) -> Result<(), ProtocolError> {
+    if count > max {
+        return Err(ProtocolError::new(
+            ErrorKind::BadRequest,
+            format!(
+                "at most {} {} can be asked for at once, not {}",
+                max, items, count
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// this implementation has methods concerned with serving the HTTP API, where files
+/// are sent one request at a time and a proof is sent along with each download
+impl Server {
+    /// upload gets an upload started by the user
+    fn upload(&mut self, session_id: &str, user: &str) -> Result<&mut Upload, ProtocolError> {
+        let upload = self.uploads.get_mut(session_id).ok_or_else(|| {
+            ProtocolError::new(
+                ErrorKind::NotFound,
+                format!("upload {} does not exist", session_id),
+            )
+        })?;
+        if upload.owner() != user {
+            return Err(ProtocolError::new(
+                ErrorKind::Forbidden,
+                format!("upload {} was started by another user", session_id),
+            ));
+        }
+        Ok(upload)
+    }
+
+    /// start_upload starts an upload owned by the user and returns the ID
+    /// the session will have once it is completed
+    pub fn start_upload(&mut self, token: Option<&str>) -> Result<String, ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        self.handle_start_upload(user)
+    }
+
+    fn handle_start_upload(&mut self, user: String) -> Result<String, ProtocolError> {
+        self.check_writable()?;
+        let session_id = hex::encode(rand::random::<[u8; 16]>());
+        self.uploads.insert(session_id.clone(), Upload::new(user));
+        Ok(session_id)
+    }
+
+    /// put_file adds a file to an upload, as long as the user has room for it
+    pub fn put_file(
+        &mut self,
+        token: Option<&str>,
+        session_id: &str,
+        file_info: FileInfo,
+    ) -> Result<(), ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        self.handle_put_file(&user, session_id, file_info)
+    }
+
+    fn handle_put_file(
+        &mut self,
+        user: &str,
+        session_id: &str,
+        file_info: FileInfo,
+    ) -> Result<(), ProtocolError> {
+        self.upload(session_id, user)?;
+        self.check_quota(user, file_info.size())?;
+        self.upload(session_id, user)?.put(file_info);
+        Ok(())
+    }
+
+    /// complete_upload stores the files of an upload in a session
+    /// and returns a receipt of the upload signed by the server
+    pub fn complete_upload(
+        &mut self,
+        token: Option<&str>,
+        session_id: &str,
+    ) -> Result<Response, ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        self.handle_complete_upload(user, session_id)
+    }
+
+    fn handle_complete_upload(
+        &mut self,
+        user: String,
+        session_id: &str,
+    ) -> Result<Response, ProtocolError> {
+        if let Some(index) = self.upload(session_id, &user)?.missing_index() {
+            return Err(ProtocolError::new(
+                ErrorKind::BadRequest,
+                format!("file index {} has not been sent", index),
+            ));
+        }
+        let upload = self
+            .uploads
+            .remove(session_id)
+            .expect("upload should exist after authorization");
+        self.store_session(session_id.to_string(), user, upload.into_files())
+    }
+
+    /// proof_file builds the proof file of the file at the index of a session
+    /// and returns it along with the file
+    pub fn proof_file(
+        &self,
+        token: Option<&str>,
+        session_id: &str,
+        index: usize,
+    ) -> Result<(ProofFile, Vec<u8>), ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        let session = self.session(session_id, &user, Role::ReadOnly)?;
+        let proof = session.proof(index, &self.blobs)?;
+        let proof_file =
+            ProofFile::from_proof(&proof, index, session.files_count(), &session.merkle_root())
+                .expect("proof built by the server should be valid");
+        Ok((proof_file, proof.file_content()))
+    }
+
+    /// root returns the merkle root and number of files of a session
+    pub fn root(
+        &self,
+        token: Option<&str>,
+        session_id: &str,
+    ) -> Result<(String, usize), ProtocolError> {
+        let user = self.users.authenticate(token)?;
+        let session = self.session(session_id, &user, Role::ReadOnly)?;
+        Ok((session.merkle_root(), session.files_count()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Server;
+    use crate::auth;
+    use crate::http::HttpApi;
+    use crate::session::unix_time;
+    use crate::store::Store;
+    use common::compression::Compression;
+    use common::diff::Change;
+    use common::handshake::{Hello, PROTOCOL_VERSION};
+    use common::model::file_info::FileInfo;
+    use common::model::merkle::MerkleTree;
+    use common::protocol::{
+        read_message, write_message, Encoding, Envelope, ErrorKind, FileEntry, Format, Request,
+        Response, Role, SyncFile,
+    };
+    use common::transport::Stream;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn server() -> Server {
+        let mut server = Server::new();
+        server.set_users(auth::test::users(&["alice", "bob"]));
+        server
+    }
+
+    fn send(server: &mut Server, user: Option<&str>, request: Request) -> Response {
+        let envelope = Envelope {
+            token: user.map(|user| format!("{}-token", user)),
+            request,
+        };
+        server
+            .handle_request(envelope)
+            .unwrap_or_else(Response::Error)
+    }
+
+    fn upload(server: &mut Server, user: &str) -> String {
+        let files = vec![
+            FileInfo::new(0, String::from("a.txt"), b"Hello".to_vec()),
+            FileInfo::new(1, String::from("b.txt"), b"Lorem".to_vec()),
+        ];
+        match send(server, Some(user), Request::Upload { files }) {
+            Response::Uploaded {
+                session_id,
+                receipt,
+            } => {
+                assert_eq!(receipt.receipt().session_id(), session_id);
+                receipt
+                    .verify_for(
+                        &receipt.receipt().merkle_root(),
+                        2,
+                        Some(&server.public_key()),
+                    )
+                    .unwrap();
+                session_id
+            }
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    fn error_kind(response: Response) -> ErrorKind {
+        match response {
+            Response::Error(e) => e.kind,
+            response => panic!("expected an error, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn sessions_are_loaded_again_after_a_restart() {
+        let dir = std::env::temp_dir().join(format!("verifile-{}", rand::random::<u64>()));
+        let mut server = server();
+        server.set_store(Store::open(&dir).unwrap()).unwrap();
+        let session_id = upload(&mut server, "alice");
+        let grant = Request::Grant {
+            session_id: session_id.clone(),
+            user: String::from("bob"),
+            role: Role::ReadOnly,
+        };
+        assert!(matches!(
+            send(&mut server, Some("alice"), grant),
+            Response::Granted
+        ));
+        let sync = Request::Sync {
+            session_id: session_id.clone(),
+            base_root: server.sessions[&session_id].merkle_root(),
+            files: vec![
+                SyncFile::Keep { index: 0 },
+                SyncFile::Upload(FileInfo::new(1, String::from("c.txt"), b"Ipsum".to_vec())),
+            ],
+        };
+        assert!(matches!(
+            send(&mut server, Some("alice"), sync),
+            Response::Synced { .. }
+        ));
+        let root = server.sessions[&session_id].merkle_root();
+        drop(server);
+
+        // the content replaced by the sync is not kept
+        let blobs = std::fs::read_dir(dir.join("blobs")).unwrap().count();
+        assert_eq!(blobs, 2);
+
+        let mut restarted = self::server();
+        assert_eq!(restarted.set_store(Store::open(&dir).unwrap()), Ok(1));
+        assert_eq!(restarted.sessions[&session_id].merkle_root(), root);
+        let download = Request::Download {
+            session_id: session_id.clone(),
+            index: 1,
+        };
+        let Response::File(proof) = send(&mut restarted, Some("bob"), download) else {
+            panic!("expected a file");
+        };
+        assert_eq!(proof.file_content(), b"Ipsum");
+        assert_eq!(proof.file_name(), "c.txt");
+
+        let delete = Request::Delete { session_id };
+        assert!(matches!(
+            send(&mut restarted, Some("alice"), delete),
+            Response::Deleted
+        ));
+        drop(restarted);
+
+        let mut restarted = self::server();
+        assert_eq!(restarted.set_store(Store::open(&dir).unwrap()), Ok(0));
+        assert_eq!(std::fs::read_dir(dir.join("blobs")).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn download_all_sends_files_from_a_snapshot() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let snapshot = server
+            .batch_snapshot(Some("alice-token"), &session_id)
+            .unwrap();
+        let root = snapshot.merkle_tree.root_hash();
+        let server = Mutex::new(server);
+
+        let Response::File(proof) = Server::batch_file(&server, &snapshot, 0) else {
+            panic!("expected a file");
+        };
+        assert_eq!(proof.file_content(), b"Hello");
+        let path = proof.checked_path(0).unwrap();
+        common::verify::verify(&root, b"Hello", 0, 2, &path).unwrap();
+
+        // a file whose content is gone by the time it is sent is reported on its own
+        server.lock().unwrap().delete_session(&session_id).unwrap();
+        let response = Server::batch_file(&server, &snapshot, 1);
+        assert_eq!(error_kind(response), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn owner_can_download_and_list() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::Download {
+                session_id: session_id.clone(),
+                index: 1,
+            },
+        );
+        let Response::File(proof) = response else {
+            panic!("expected a file, got {:?}", response);
+        };
+        assert_eq!(proof.file_content(), b"Lorem");
+
+        let response = send(&mut server, Some("alice"), Request::List { session_id });
+        let Response::Files(entries) = response else {
+            panic!("expected files, got {:?}", response);
+        };
+        assert_eq!(
+            entries,
+            vec![
+                FileEntry {
+                    index: 0,
+                    name: String::from("a.txt")
+                },
+                FileEntry {
+                    index: 1,
+                    name: String::from("b.txt")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unauthenticated_requests_are_rejected() {
+        let mut server = server();
+        let files = vec![FileInfo::new(0, String::from("a.txt"), b"Hello".to_vec())];
+        let response = send(&mut server, None, Request::Upload { files });
+        assert_eq!(error_kind(response), ErrorKind::Unauthenticated);
+    }
+
+    #[test]
+    fn other_users_need_a_grant() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let download = |session_id: &String| Request::Download {
+            session_id: session_id.clone(),
+            index: 0,
+        };
+
+        let response = send(&mut server, Some("bob"), download(&session_id));
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+
+        let grant = Request::Grant {
+            session_id: session_id.clone(),
+            user: String::from("bob"),
+            role: Role::ReadOnly,
+        };
+        assert!(matches!(
+            send(&mut server, Some("alice"), grant),
+            Response::Granted
+        ));
+        assert!(matches!(
+            send(&mut server, Some("bob"), download(&session_id)),
+            Response::File(_)
+        ));
+    }
+
+    #[test]
+    fn read_only_users_cannot_grant() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let grant = |user: &str| Request::Grant {
+            session_id: session_id.clone(),
+            user: String::from(user),
+            role: Role::ReadOnly,
+        };
+
+        send(&mut server, Some("alice"), grant("bob"));
+        let response = send(&mut server, Some("bob"), grant("mallory"));
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+    }
+
+    #[test]
+    fn missing_sessions_and_files_are_not_found() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::List {
+                session_id: String::from("missing"),
+            },
+        );
+        assert_eq!(error_kind(response), ErrorKind::NotFound);
+
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::Download {
+                session_id,
+                index: 2,
+            },
+        );
+        assert_eq!(error_kind(response), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn identical_files_are_stored_once() {
+        let mut server = server();
+        let first = upload(&mut server, "alice");
+        let second = upload(&mut server, "bob");
+        assert_eq!(server.blobs.blobs_count(), 2);
+
+        server.delete_session(&first).unwrap();
+        let response = send(
+            &mut server,
+            Some("bob"),
+            Request::Download {
+                session_id: second.clone(),
+                index: 0,
+            },
+        );
+        let Response::File(proof) = response else {
+            panic!("expected a file, got {:?}", response);
+        };
+        assert_eq!(proof.file_content(), b"Hello");
+
+        server.delete_session(&second).unwrap();
+        assert_eq!(server.blobs.blobs_count(), 0);
+        assert_eq!(server.blobs.stored_bytes(), 0);
+    }
+
+    #[test]
+    fn sync_keeps_unchanged_files_and_signs_the_transition() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let previous_root = server.sessions[&session_id].merkle_root();
+
+        let files = vec![
+            SyncFile::Keep { index: 0 },
+            SyncFile::Upload(FileInfo::new(1, String::from("c.txt"), b"Ipsum".to_vec())),
+        ];
+        let request = Request::Sync {
+            session_id: session_id.clone(),
+            base_root: previous_root.clone(),
+            files,
+        };
+        let response = send(&mut server, Some("alice"), request);
+        let Response::Synced { receipt } = response else {
+            panic!("expected a receipt, got {:?}", response);
+        };
+        let root = MerkleTree::from(vec![b"Hello".to_vec(), b"Ipsum".to_vec()]).root_hash();
+        receipt
+            .verify_for(&root, 2, Some(&server.public_key()))
+            .unwrap();
+        assert_eq!(
+            receipt.receipt().previous_root(),
+            Some(previous_root.as_str())
+        );
+
+        // the replaced file is no longer stored
+        assert_eq!(server.blobs.blobs_count(), 2);
+        assert_eq!(server.sessions[&session_id].size(), 10);
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::List {
+                session_id: session_id.clone(),
+            },
+        );
+        let Response::Files(entries) = response else {
+            panic!("expected files, got {:?}", response);
+        };
+        let names = entries.into_iter().map(|e| e.name).collect::<Vec<String>>();
+        assert_eq!(names, vec!["a.txt", "c.txt"]);
+    }
+
+    #[test]
+    fn sync_is_only_applied_to_the_current_root_by_the_owner() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let root = server.sessions[&session_id].merkle_root();
+        let sync = |base_root: &str, index: usize| Request::Sync {
+            session_id: session_id.clone(),
+            base_root: base_root.to_string(),
+            files: vec![SyncFile::Keep { index }],
+        };
+
+        let response = send(&mut server, Some("alice"), sync("stale", 0));
+        assert_eq!(error_kind(response), ErrorKind::Conflict);
+        let response = send(&mut server, Some("bob"), sync(&root, 0));
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+        let response = send(&mut server, Some("alice"), sync(&root, 2));
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+
+        assert_eq!(server.sessions[&session_id].merkle_root(), root);
+        assert_eq!(server.blobs.blobs_count(), 2);
+    }
+
+    #[test]
+    fn diff_compares_sessions_the_user_can_read() {
+        let mut server = server();
+        let first = upload(&mut server, "alice");
+        let files = vec![
+            FileInfo::new(0, String::from("a.txt"), b"Hello".to_vec()),
+            FileInfo::new(1, String::from("b.txt"), b"Ipsum".to_vec()),
+            FileInfo::new(2, String::from("c.txt"), b"Dolor".to_vec()),
+        ];
+        let Response::Uploaded {
+            session_id: second, ..
+        } = send(&mut server, Some("alice"), Request::Upload { files })
+        else {
+            panic!("expected the upload to succeed");
+        };
+        let diff = |from: &str, to: &str| Request::Diff {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+
+        let response = send(&mut server, Some("alice"), diff(&first, &second));
+        let Response::Diff(diffs) = response else {
+            panic!("expected a diff, got {:?}", response);
+        };
+        let changes = diffs
+            .into_iter()
+            .map(|d| (d.name, d.change))
+            .collect::<Vec<(String, Change)>>();
+        assert_eq!(
+            changes,
+            vec![
+                (String::from("b.txt"), Change::Modified),
+                (String::from("c.txt"), Change::Added),
+            ]
+        );
+
+        let bobs = upload(&mut server, "bob");
+        let response = send(&mut server, Some("alice"), diff(&first, &bobs));
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+    }
+
+    #[test]
+    fn only_the_owner_can_delete_a_session() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let delete = || Request::Delete {
+            session_id: session_id.clone(),
+        };
+
+        let response = send(&mut server, Some("bob"), delete());
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+
+        assert!(matches!(
+            send(&mut server, Some("alice"), delete()),
+            Response::Deleted
+        ));
+        let response = send(&mut server, Some("alice"), delete());
+        assert_eq!(error_kind(response), ErrorKind::NotFound);
+        assert_eq!(server.blobs.stored_bytes(), 0);
+    }
+
+    #[test]
+    fn expired_sessions_are_gone_and_swept() {
+        let mut server = server();
+        server.set_session_ttl(Duration::from_secs(3600));
+        let session_id = upload(&mut server, "alice");
+
+        assert_eq!(server.sweep(unix_time()), 0);
+        let list = || Request::List {
+            session_id: session_id.clone(),
+        };
+        assert!(matches!(
+            send(&mut server, Some("alice"), list()),
+            Response::Files(_)
+        ));
+
+        server.set_session_ttl(Duration::ZERO);
+        let expired = upload(&mut server, "alice");
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::List {
+                session_id: expired,
+            },
+        );
+        assert_eq!(error_kind(response), ErrorKind::NotFound);
+
+        assert_eq!(server.sweep(unix_time()), 1);
+        assert_eq!(server.sweep(unix_time() + 3600), 1);
+        assert!(server.sessions.is_empty());
+        assert_eq!(server.blobs.blobs_count(), 0);
+    }
+
+    #[test]
+    fn uploads_over_the_quota_are_rejected() {
+        let mut server = server();
+        // each upload is 10 bytes
+        server.set_quota(14);
+        upload(&mut server, "alice");
+        upload(&mut server, "bob");
+
+        let files = vec![FileInfo::new(0, String::from("a.txt"), b"Hello".to_vec())];
+        let response = send(&mut server, Some("alice"), Request::Upload { files });
+        assert_eq!(error_kind(response), ErrorKind::QuotaExceeded);
+        assert_eq!(server.sessions.len(), 2);
+    }
+
+    #[test]
+    fn sessions_describe_what_the_user_can_access() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        upload(&mut server, "bob");
+
+        let response = send(&mut server, Some("alice"), Request::Sessions);
+        let Response::Sessions(sessions) = response else {
+            panic!("expected sessions, got {:?}", response);
+        };
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, session_id);
+        assert_eq!(sessions[0].owner, "alice");
+        assert_eq!(sessions[0].files_count, 2);
+        assert_eq!(sessions[0].size, 10);
+        assert_eq!(sessions[0].expires_at, None);
+        assert!(sessions[0].created_at <= unix_time());
+    }
+
+    #[test]
+    fn uploads_with_wrong_indexes_are_rejected() {
+        let mut server = server();
+        let files = vec![FileInfo::new(1, String::from("a.txt"), b"Hello".to_vec())];
+        let response = send(&mut server, Some("alice"), Request::Upload { files });
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+    }
+
+    #[test]
+    fn uploads_can_be_sent_one_file_per_request() {
+        let mut server = server();
+        let Response::UploadStarted { session_id } =
+            send(&mut server, Some("alice"), Request::StartUpload)
+        else {
+            panic!("expected the upload to start");
+        };
+        let put = |index: usize, content: &[u8]| Request::PutFile {
+            session_id: session_id.clone(),
+            file: FileInfo::new(index, format!("{}.txt", index), content.to_vec()),
+        };
+
+        // only the owner of the upload adds files to it, and every file is needed to complete it
+        let response = send(&mut server, Some("bob"), put(0, b"Hello"));
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+        let response = send(&mut server, Some("alice"), put(1, b"Lorem"));
+        assert!(matches!(response, Response::FilePut));
+        let complete = || Request::CompleteUpload {
+            session_id: session_id.clone(),
+        };
+        let response = send(&mut server, Some("alice"), complete());
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+
+        send(&mut server, Some("alice"), put(0, b"Hello"));
+        let Response::Uploaded { receipt, .. } = send(&mut server, Some("alice"), complete())
+        else {
+            panic!("expected the upload to complete");
+        };
+        assert_eq!(receipt.receipt().session_id(), session_id);
+        assert_eq!(receipt.receipt().files_count(), 2);
+        let response = send(&mut server, Some("alice"), Request::List { session_id });
+        assert!(matches!(response, Response::Files(files) if files.len() == 2));
+    }
+
+    /// connect serves a single connection of a client on a local port, like start does
+    fn connect(server: Server) -> (TcpStream, thread::JoinHandle<Server>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let handle = thread::spawn(move || {
+            let (tcp, _) = listener.accept().unwrap();
+            let server = Mutex::new(server);
+            Server::handle_connection(&server, Stream::accept(tcp, None).unwrap());
+            server.into_inner().unwrap()
+        });
+        (client, handle)
+    }
+
+    fn envelope(user: &str, request: Request) -> Envelope {
+        Envelope {
+            token: Some(format!("{}-token", user)),
+            request,
+        }
+    }
+
+    #[test]
+    fn clients_from_before_the_framed_protocol_are_answered_with_an_error() {
+        // they sent their files as a JSON array read to the end of the stream, whose
+        // first bytes do not make a length prefix the server reads, so it answers at once
+        let (mut stream, handle) = connect(server());
+        stream
+            .write_all(br#"[{"name":"a.txt","index":0,"content":[72,101,108,108,111]}]"#)
+            .unwrap();
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+        handle.join().unwrap();
+
+        // or the index of the file to download as 8 bytes, which reads as an empty message
+        let (mut stream, handle) = connect(server());
+        stream.write_all(&0u64.to_be_bytes()).unwrap();
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn clients_of_version_1_are_served_without_a_handshake() {
+        let mut server = server();
+        upload(&mut server, "alice");
+        let (mut stream, handle) = connect(server);
+
+        // version 1 clients send a plain length prefix and JSON
+        let body = serde_json::to_vec(&envelope("alice", Request::Sessions)).unwrap();
+        stream
+            .write_all(&(body.len() as u64).to_be_bytes())
+            .unwrap();
+        stream.write_all(&body).unwrap();
+
+        let (response, format) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(format, Format::from(Encoding::Json));
+        assert!(matches!(response, Response::Sessions(sessions) if sessions.len() == 1));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn handshake_agrees_on_the_encoding_of_the_request() {
+        let mut server = server();
+        upload(&mut server, "alice");
+        let (mut stream, handle) = connect(server);
+
+        let hello = Envelope {
+            token: None,
+            request: Request::Hello(Hello::new(Encoding::Cbor)),
+        };
+        write_message(&mut stream, &hello, Encoding::Json).unwrap();
+        let Response::Hello(agreement) = read_message::<Response>(&mut stream).unwrap().0 else {
+            panic!("expected an agreement");
+        };
+        assert_eq!(agreement.version, PROTOCOL_VERSION);
+        assert_eq!(agreement.encoding, Encoding::Cbor);
+
+        write_message(
+            &mut stream,
+            &envelope("alice", Request::Sessions),
+            agreement.encoding,
+        )
+        .unwrap();
+        let (response, format) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(format.encoding, Encoding::Cbor);
+        assert!(matches!(response, Response::Sessions(sessions) if sessions.len() == 1));
+
+        let server = handle.join().unwrap();
+        assert_eq!(server.metrics().stats().operations["hello"].requests, 1);
+    }
+
+    #[test]
+    fn large_responses_are_compressed_as_agreed() {
+        let mut server = server();
+        let content = std::fs::read("../files/schools.csv").unwrap();
+        let files = vec![FileInfo::new(
+            0,
+            String::from("schools.csv"),
+            content.clone(),
+        )];
+        let Response::Uploaded { session_id, .. } =
+            send(&mut server, Some("alice"), Request::Upload { files })
+        else {
+            panic!("expected an upload");
+        };
+        let (mut stream, handle) = connect(server);
+
+        let format = Format {
+            encoding: Encoding::Cbor,
+            compression: Some(Compression::Gzip),
+        };
+        let hello = Envelope {
+            token: None,
+            request: Request::Hello(Hello::new(format)),
+        };
+        write_message(&mut stream, &hello, Encoding::Json).unwrap();
+        let (Response::Hello(agreement), _) = read_message::<Response>(&mut stream).unwrap() else {
+            panic!("expected an agreement");
+        };
+        assert_eq!(agreement.format(), format);
+
+        let download = Request::Download {
+            session_id,
+            index: 0,
+        };
+        write_message(&mut stream, &envelope("alice", download), format).unwrap();
+        let (response, sent) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(sent, format);
+        let Response::File(proof) = response else {
+            panic!("expected a file, got {:?}", response);
+        };
+        assert_eq!(proof.file_content(), content);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn clients_of_unsupported_versions_are_turned_away() {
+        let (mut stream, handle) = connect(server());
+
+        let mut hello = Hello::new(Encoding::Cbor);
+        hello.min_version = PROTOCOL_VERSION + 1;
+        hello.version = PROTOCOL_VERSION + 1;
+        let hello = Envelope {
+            token: None,
+            request: Request::Hello(hello),
+        };
+        write_message(&mut stream, &hello, Encoding::Json).unwrap();
+
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(error_kind(response), ErrorKind::Unsupported);
+        // the connection is closed instead of waiting for a request
+        assert!(read_message::<Response>(&mut stream).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn hello_cannot_be_sent_as_a_request() {
+        let mut server = server();
+        let response = send(
+            &mut server,
+            Some("alice"),
+            Request::Hello(Hello::new(Encoding::Cbor)),
+        );
+        assert_eq!(error_kind(response), ErrorKind::BadRequest);
+    }
+
+    #[test]
+    fn download_all_sends_every_file_after_the_batch() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let (mut stream, handle) = connect(server);
+
+        let request = Request::DownloadAll { session_id };
+        write_message(&mut stream, &envelope("alice", request), Encoding::Cbor).unwrap();
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert!(matches!(response, Response::Batch { files_count: 2 }));
+
+        for (name, content) in [("a.txt", b"Hello"), ("b.txt", b"Lorem")] {
+            let Response::File(proof) = read_message::<Response>(&mut stream).unwrap().0 else {
+                panic!("expected a file");
+            };
+            assert_eq!(proof.file_name(), name);
+            assert_eq!(proof.file_content(), content);
+        }
+        assert!(read_message::<Response>(&mut stream).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn download_all_needs_access_to_the_session() {
+        let mut server = server();
+        let session_id = upload(&mut server, "alice");
+        let (mut stream, handle) = connect(server);
+
+        let request = Request::DownloadAll { session_id };
+        write_message(&mut stream, &envelope("bob", request), Encoding::Cbor).unwrap();
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert_eq!(error_kind(response), ErrorKind::Forbidden);
+        assert!(read_message::<Response>(&mut stream).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn stalled_clients_do_not_hold_up_other_requests() {
+        let mut server = server();
+        upload(&mut server, "alice");
+        let http_api = HttpApi::bind("127.0.0.1:0").unwrap();
+        let http_address = http_api.address();
+        server.set_http_api(http_api);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        // a client that starts a request and never finishes it
+        let mut stalled = TcpStream::connect(address).unwrap();
+        stalled.write_all(&[0, 0, 0]).unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let (sent, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(http_address).unwrap();
+            stream
+                .write_all(
+                    b"GET /sessions HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+                      Authorization: Bearer alice-token\r\n\r\n",
+                )
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            sent.send(response).unwrap();
+        });
+        let response = received.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        // other clients of the protocol are served as well
+        let mut stream = TcpStream::connect(address).unwrap();
+        write_message(
+            &mut stream,
+            &envelope("alice", Request::Sessions),
+            Encoding::Cbor,
+        )
+        .unwrap();
+        let (response, _) = read_message::<Response>(&mut stream).unwrap();
+        assert!(matches!(response, Response::Sessions(sessions) if sessions.len() == 1));
+        drop(stalled);
+    }
+}
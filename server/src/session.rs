@@ -0,0 +1,484 @@
+use crate::blobs::BlobStore;
+use common::diff::{self, FileDiff};
+use common::model::file_info::FileInfo;
+use common::model::merkle::{MerkleProof, MerkleTree, NodeHash};
+use common::model::proof_file::Hash;
+use common::protocol::{ErrorKind, FileEntry, ProtocolError, Role, SessionInfo, SyncFile};
+use common::replication::{SessionRoot, SessionState};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// unix_time returns the number of seconds since the unix epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system time should be after the unix epoch")
+        .as_secs()
+}
+
+/// Upload is a session whose files are still being sent one at a time. It becomes
+/// a Session once all of them are there
+pub struct Upload {
+    owner: String,
+    files: BTreeMap<usize, FileInfo>,
+    /// seconds since the unix epoch at which the upload was started
+    created_at: u64,
+}
+
+impl Upload {
+    pub fn new(owner: String) -> Self {
+        Self {
+            owner,
+            files: BTreeMap::new(),
+            created_at: unix_time(),
+        }
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    /// size returns the total size of the files sent so far
+    pub fn size(&self) -> usize {
+        self.files.values().map(|file_info| file_info.size()).sum()
+    }
+
+    /// put adds a file to the upload, replacing the file at its index if there was one
+    pub fn put(&mut self, file_info: FileInfo) {
+        self.files.insert(file_info.index(), file_info);
+    }
+
+    /// is_expired checks if the upload was started at least ttl seconds before now
+    pub fn is_expired(&self, now: u64, ttl: u64) -> bool {
+        now >= self.created_at.saturating_add(ttl)
+    }
+
+    /// missing_index returns the lowest index no file was sent for, if the files
+    /// sent so far do not make up a batch indexed from 0
+    pub fn missing_index(&self) -> Option<usize> {
+        let next = self.files.len();
+        (0..next)
+            .zip(self.files.keys())
+            .find(|(expected, index)| expected != *index)
+            .map(|(expected, _)| expected)
+            .or((next == 0).then_some(0))
+    }
+
+    /// into_files returns the files ordered by index
+    pub fn into_files(self) -> Vec<FileInfo> {
+        self.files.into_values().collect()
+    }
+}
+
+/// StoredFile is a file of a session. Its content is kept in the blob store under its hash
+struct StoredFile {
+    name: String,
+    hash: NodeHash,
+}
+
+/// Session is a batch of files uploaded together, with the merkle tree built over
+/// them and the users that can access it
+pub struct Session {
+    owner: String,
+    grants: HashMap<String, Role>,
+    /// files ordered by index
+    files: Vec<StoredFile>,
+    merkle_tree: MerkleTree,
+    /// seconds since the unix epoch at which the session was uploaded
+    created_at: u64,
+    /// seconds since the unix epoch after which the session is deleted, if it expires
+    expires_at: Option<u64>,
+    /// total size of the files in bytes, whether or not their content is shared
+    size: usize,
+    /// number of syncs and grants made to the session since it was uploaded
+    revision: u64,
+}
+
+impl Session {
+    /// new creates a session owned by a user and stores the content of its files in the
+    /// blob store. The files must be indexed from 0 in the order they were sent, since
+    /// that is the order of the merkle leaves
+    pub fn new(
+        owner: String,
+        files: Vec<FileInfo>,
+        blobs: &mut BlobStore,
+    ) -> Result<Self, ProtocolError> {
+        if files.is_empty() {
+            return Err(ProtocolError::new(
+                ErrorKind::BadRequest,
+                "at least one file should be uploaded",
+            ));
+        }
+        if let Some((position, _)) = files
+            .iter()
+            .enumerate()
+            .find(|(position, file)| *position != file.index())
+        {
+            return Err(ProtocolError::new(
+                ErrorKind::BadRequest,
+                format!("file at position {} has the wrong index", position),
+            ));
+        }
+
+        let size = files.iter().map(|file_info| file_info.size()).sum();
+        // the blob store is keyed by leaf hash, so the tree is built from the keys
+        let files = files
+            .into_iter()
+            .map(|file_info| StoredFile {
+                name: file_info.name(),
+                hash: blobs.insert(file_info.into_content()),
+            })
+            .collect::<Vec<StoredFile>>();
+
+        Ok(Self {
+            owner,
+            grants: HashMap::new(),
+            merkle_tree: MerkleTree::from_leaf_hashes(files.iter().map(|file| file.hash).collect()),
+            files,
+            created_at: unix_time(),
+            expires_at: None,
+            size,
+            revision: 0,
+        })
+    }
+
+    /// from_state creates the copy of a session replicated from another server, with the
+    /// name and leaf hash of each file. The content of the files must already be in the
+    /// blob store, and the files must make up the merkle root of the state
+    pub fn from_state(
+        state: SessionState,
+        files: Vec<(String, NodeHash)>,
+        size: usize,
+    ) -> Result<Self, String> {
+        if files.is_empty() {
+            return Err(String::from("a session should have at least one file"));
+        }
+        let (names, leaves): (Vec<String>, Vec<NodeHash>) = files.into_iter().unzip();
+        Self::restore(
+            SessionState { names, ..state },
+            MerkleTree::from_leaf_hashes(leaves),
+            size,
+        )
+    }
+
+    /// restore creates a session from its state and the merkle tree over its files, such as
+    /// a session saved before the server restarted. The content of the files must already be
+    /// in the blob store, and the tree must have the merkle root of the state
+    pub fn restore(
+        state: SessionState,
+        merkle_tree: MerkleTree,
+        size: usize,
+    ) -> Result<Self, String> {
+        if state.names.len() != merkle_tree.len() {
+            return Err(format!(
+                "session has {} names for {} files",
+                state.names.len(),
+                merkle_tree.len()
+            ));
+        }
+        if merkle_tree.root_hash() != state.merkle_root {
+            return Err(format!(
+                "files make up merkle root {}, the session has {}",
+                merkle_tree.root_hash(),
+                state.merkle_root
+            ));
+        }
+
+        let files = state
+            .names
+            .into_iter()
+            .enumerate()
+            .map(|(index, name)| StoredFile {
+                name,
+                hash: *merkle_tree
+                    .leaf(index)
+                    .expect("tree should have a leaf per name"),
+            })
+            .collect();
+        Ok(Self {
+            owner: state.owner,
+            grants: state.grants.into_iter().collect(),
+            files,
+            merkle_tree,
+            created_at: state.created_at,
+            expires_at: state.expires_at,
+            size,
+            revision: state.revision,
+        })
+    }
+
+    /// set_ttl makes the session expire once the number of seconds has passed since it was uploaded
+    pub fn set_ttl(&mut self, ttl: u64) {
+        self.expires_at = Some(self.created_at.saturating_add(ttl));
+    }
+
+    pub fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// is_expired checks if the session has expired at the given unix time
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+
+    /// info describes the session with the ID
+    pub fn info(&self, session_id: &str) -> SessionInfo {
+        SessionInfo {
+            session_id: session_id.to_string(),
+            owner: self.owner.clone(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            files_count: self.files_count(),
+            size: self.size,
+        }
+    }
+
+    /// synced_size returns the size the session would have after the sync of the files
+    pub fn synced_size(&self, files: &[SyncFile], blobs: &BlobStore) -> usize {
+        files
+            .iter()
+            .map(|file| match file {
+                SyncFile::Keep { index } => self
+                    .files
+                    .get(*index)
+                    .and_then(|file| blobs.get(&file.hash))
+                    .map_or(0, |content| content.len()),
+                SyncFile::Upload(file_info) => file_info.size(),
+            })
+            .sum()
+    }
+
+    /// sync replaces the files of the session and rebuilds its merkle tree. Kept files
+    /// refer to the content they already have in the blob store, sent files are stored
+    /// like on upload. Nothing changes if one of the files is invalid
+    pub fn sync(
+        &mut self,
+        files: Vec<SyncFile>,
+        blobs: &mut BlobStore,
+    ) -> Result<(), ProtocolError> {
+        if files.is_empty() {
+            return Err(ProtocolError::new(
+                ErrorKind::BadRequest,
+                "a session cannot be synced to no files",
+            ));
+        }
+        for (position, file) in files.iter().enumerate() {
+            match file {
+                SyncFile::Keep { index } if *index >= self.files.len() => {
+                    return Err(ProtocolError::new(
+                        ErrorKind::BadRequest,
+                        format!("kept file index {} is not in the session", index),
+                    ))
+                }
+                SyncFile::Upload(file_info) if file_info.index() != position => {
+                    return Err(ProtocolError::new(
+                        ErrorKind::BadRequest,
+                        format!("file at position {} has the wrong index", position),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        let size = self.synced_size(&files, blobs);
+        // the new files take their references before the old ones are dropped,
+        // so that content kept by the sync is never removed from the store
+        let synced = files
+            .into_iter()
+            .map(|file| match file {
+                SyncFile::Keep { index } => {
+                    let kept = &self.files[index];
+                    blobs.retain(&kept.hash);
+                    StoredFile {
+                        name: kept.name.clone(),
+                        hash: kept.hash,
+                    }
+                }
+                SyncFile::Upload(file_info) => StoredFile {
+                    name: file_info.name(),
+                    hash: blobs.insert(file_info.into_content()),
+                },
+            })
+            .collect::<Vec<StoredFile>>();
+        self.release(blobs);
+
+        self.merkle_tree =
+            MerkleTree::from_leaf_hashes(synced.iter().map(|file| file.hash).collect());
+        self.files = synced;
+        self.size = size;
+        self.revision += 1;
+        Ok(())
+    }
+
+    /// release drops the references of the session to the content of its files
+    pub fn release(&self, blobs: &mut BlobStore) {
+        self.files.iter().for_each(|file| blobs.release(&file.hash));
+    }
+
+    pub fn merkle_root(&self) -> String {
+        self.merkle_tree.root_hash()
+    }
+
+    /// hashes returns the leaf hashes of the files, which their content is stored under
+    pub fn hashes(&self) -> Vec<NodeHash> {
+        self.files.iter().map(|file| file.hash).collect()
+    }
+
+    pub fn files_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// role returns the access a user has to the session, if any
+    pub fn role(&self, user: &str) -> Option<Role> {
+        if user == self.owner {
+            return Some(Role::Owner);
+        }
+        self.grants.get(user).copied()
+    }
+
+    /// authorize checks that a user has at least the required access to the session
+    pub fn authorize(&self, user: &str, required: Role) -> Result<(), ProtocolError> {
+        match (self.role(user), required) {
+            (Some(Role::Owner), _) | (Some(Role::ReadOnly), Role::ReadOnly) => Ok(()),
+            _ => Err(ProtocolError::new(
+                ErrorKind::Forbidden,
+                format!(
+                    "{} does not have {:?} access to the session",
+                    user, required
+                ),
+            )),
+        }
+    }
+
+    /// grant gives a user access to the session. The owner keeps its access
+    pub fn grant(&mut self, user: String, role: Role) {
+        if user != self.owner {
+            self.grants.insert(user, role);
+            self.revision += 1;
+        }
+    }
+
+    /// proof builds the merkle proof of the file at the index
+    pub fn proof(&self, index: usize, blobs: &BlobStore) -> Result<MerkleProof, ProtocolError> {
+        let file = self.files.get(index).ok_or_else(|| {
+            ProtocolError::new(
+                ErrorKind::NotFound,
+                format!("file index {} is not in the session", index),
+            )
+        })?;
+
+        let content = blobs
+            .get(&file.hash)
+            .expect("content of a session file should be in the blob store");
+
+        Ok(MerkleProof::build(
+            &self.merkle_tree,
+            index,
+            file.name.clone(),
+            content.to_vec(),
+        ))
+    }
+
+    /// diff compares the files of the session to the ones of the other session
+    pub fn diff(&self, other: &Session) -> Vec<FileDiff> {
+        diff::diff_trees(
+            &self.merkle_tree,
+            &self.names(),
+            &other.merkle_tree,
+            &other.names(),
+        )
+    }
+
+    /// names lists the names of the files in the session ordered by index
+    pub fn names(&self) -> Vec<String> {
+        self.files.iter().map(|file| file.name.clone()).collect()
+    }
+
+    pub fn merkle_tree(&self) -> &MerkleTree {
+        &self.merkle_tree
+    }
+
+    /// root describes the merkle root and revision of the session with the ID
+    pub fn root(&self, session_id: &str) -> SessionRoot {
+        SessionRoot {
+            session_id: session_id.to_string(),
+            merkle_root: self.merkle_root(),
+            revision: self.revision,
+        }
+    }
+
+    /// state describes the session apart from the content of its files, for replicas
+    pub fn state(&self) -> SessionState {
+        SessionState {
+            owner: self.owner.clone(),
+            grants: self
+                .grants
+                .iter()
+                .map(|(user, role)| (user.clone(), *role))
+                .collect(),
+            created_at: self.created_at,
+            expires_at: self.expires_at,
+            revision: self.revision,
+            merkle_root: self.merkle_root(),
+            names: self.names(),
+        }
+    }
+
+    /// node_hashes returns the hashes of the nodes at the indexes of the level the given
+    /// number of levels above the leaves
+    pub fn node_hashes(&self, depth: usize, indexes: &[usize]) -> Result<Vec<Hash>, ProtocolError> {
+        indexes
+            .iter()
+            .map(|index| {
+                self.merkle_tree
+                    .node_above_leaves(depth, *index)
+                    .map(|node| Hash::from(*node))
+                    .ok_or_else(|| {
+                        ProtocolError::new(
+                            ErrorKind::NotFound,
+                            format!("node {} of depth {} is not in the tree", index, depth),
+                        )
+                    })
+            })
+            .collect()
+    }
+
+    /// leaves returns the files at the indexes with their content
+    pub fn leaves(
+        &self,
+        indexes: &[usize],
+        blobs: &BlobStore,
+    ) -> Result<Vec<FileInfo>, ProtocolError> {
+        indexes
+            .iter()
+            .map(|index| {
+                let file = self.files.get(*index).ok_or_else(|| {
+                    ProtocolError::new(
+                        ErrorKind::NotFound,
+                        format!("file index {} is not in the session", index),
+                    )
+                })?;
+                let content = blobs
+                    .get(&file.hash)
+                    .expect("content of a session file should be in the blob store");
+                Ok(FileInfo::new(*index, file.name.clone(), content.to_vec()))
+            })
+            .collect()
+    }
+
+    /// entries lists the files in the session ordered by index
+    pub fn entries(&self) -> Vec<FileEntry> {
+        self.files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| FileEntry {
+                index,
+                name: file.name.clone(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,220 @@
+use crate::blobs::BlobStore;
+use crate::session::Session;
+use common::model::merkle::{to_hex, MerkleTree, NodeHash};
+use common::replication::SessionState;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Store keeps the sessions of the server in a directory, so that they are there again
+/// after a restart. A session is saved as its state in JSON and its merkle tree with the
+/// internal levels, and the content of its files is saved once under its leaf hash
+///
+/// ```text
+/// <dir>/sessions/<session ID>.json | <dir>/sessions/<session ID>.tree | <dir>/blobs/<leaf hash>
+/// ```
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// open opens the store in the directory, creating the directory if it does not exist
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let store = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        for dir in [store.dir.join("sessions"), store.dir.join("blobs")] {
+            fs::create_dir_all(&dir)
+                .map_err(|e| format!("failed to create {}: {}", dir.display(), e))?;
+        }
+        Ok(store)
+    }
+
+    /// session_path returns the path of a file of the session with the ID. The ID is
+    /// checked so that it cannot point out of the store
+    fn session_path(&self, session_id: &str, extension: &str) -> Result<PathBuf, String> {
+        if session_id.is_empty() || !session_id.bytes().all(|b| b.is_ascii_alphanumeric()) {
+            return Err(format!("session ID {:?} cannot be saved", session_id));
+        }
+        Ok(self
+            .dir
+            .join("sessions")
+            .join(format!("{}.{}", session_id, extension)))
+    }
+
+    /// blob_path returns the path the content with the leaf hash is saved at
+    fn blob_path(&self, hash: &NodeHash) -> PathBuf {
+        self.dir.join("blobs").join(to_hex(hash))
+    }
+
+    /// save saves the session with the ID, along with the content of its files that is
+    /// not saved yet. The state is written last: if the server stops in between, the saved
+    /// tree does not have the root of the saved state and the session fails to load
+    /// rather than being loaded with the wrong files
+    pub fn save(
+        &self,
+        session_id: &str,
+        session: &Session,
+        blobs: &BlobStore,
+    ) -> Result<(), String> {
+        let tree = self.session_path(session_id, "tree")?;
+        let path = self.session_path(session_id, "json")?;
+        for hash in session.hashes() {
+            let blob = self.blob_path(&hash);
+            if blob.exists() {
+                continue;
+            }
+            let content = blobs
+                .get(&hash)
+                .expect("content of a session file should be in the blob store");
+            replace(&blob, |file| File::create(file)?.write_all(content))?;
+        }
+
+        replace(&tree, |file| session.merkle_tree().save(file, true))?;
+        let state = serde_json::to_vec(&session.state())
+            .map_err(|e| format!("failed to encode session {}: {}", session_id, e))?;
+        replace(&path, |file| fs::write(file, &state))
+    }
+
+    /// remove deletes the saved session with the ID
+    pub fn remove(&self, session_id: &str) -> Result<(), String> {
+        for extension in ["json", "tree"] {
+            let path = self.session_path(session_id, extension)?;
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(format!("failed to remove {}: {}", path.display(), e))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// release deletes the saved content with the leaf hashes that the blob store no longer
+    /// holds, once no session refers to it
+    pub fn release(&self, hashes: &[NodeHash], blobs: &BlobStore) -> Result<(), String> {
+        for hash in hashes.iter().filter(|hash| blobs.get(hash).is_none()) {
+            let path = self.blob_path(hash);
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    return Err(format!("failed to remove {}: {}", path.display(), e))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// load reads every saved session and puts the content of its files in the blob store.
+    /// The trees are read with their internal levels, so a session of n files loads in n
+    /// hashes whatever the size of the files. The content is not hashed again: content
+    /// changed on disk is served as it is and fails verification on the client
+    pub fn load(&self, blobs: &mut BlobStore) -> Result<HashMap<String, Session>, String> {
+        let dir = self.dir.join("sessions");
+        let entries =
+            fs::read_dir(&dir).map_err(|e| format!("failed to read {}: {}", dir.display(), e))?;
+
+        let mut sessions = HashMap::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|e| format!("failed to read {}: {}", dir.display(), e))?
+                .path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let Some(session_id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let session = self
+                .load_session(session_id, &path, blobs)
+                .map_err(|e| format!("failed to load session {}: {}", session_id, e))?;
+            sessions.insert(session_id.to_string(), session);
+        }
+        Ok(sessions)
+    }
+
+    /// load_session reads the session with the ID whose state is saved at the path
+    fn load_session(
+        &self,
+        session_id: &str,
+        path: &Path,
+        blobs: &mut BlobStore,
+    ) -> Result<Session, String> {
+        let state = fs::read(path).map_err(|e| e.to_string())?;
+        let state = serde_json::from_slice::<SessionState>(&state).map_err(|e| e.to_string())?;
+        let tree = MerkleTree::load(self.session_path(session_id, "tree")?)?;
+
+        let mut size = 0;
+        for index in 0..tree.len() {
+            let hash = *tree.leaf(index).expect("tree should have its leaves");
+            if blobs.retain(&hash) {
+                size += blobs.get(&hash).map_or(0, <[u8]>::len);
+                continue;
+            }
+            let blob = self.blob_path(&hash);
+            let content =
+                fs::read(&blob).map_err(|e| format!("failed to read {}: {}", blob.display(), e))?;
+            size += content.len();
+            blobs.insert_hashed(hash, content);
+        }
+        Session::restore(state, tree, size)
+    }
+}
+
+/// replace writes a file next to the path and moves it there once it is complete,
+/// so that a crash never leaves a file of the store half written
+fn replace(path: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> Result<(), String> {
+    let mut partial = OsString::from(path.as_os_str());
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    write(&partial)
+        .and_then(|_| fs::rename(&partial, path))
+        .map_err(|e| format!("failed to write {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Store;
+    use crate::blobs::BlobStore;
+    use crate::session::Session;
+    use common::model::file_info::FileInfo;
+
+    fn files() -> Vec<FileInfo> {
+        ["Hello", "Lorem", "Ipsum", "Hello"]
+            .iter()
+            .enumerate()
+            .map(|(index, content)| {
+                FileInfo::new(index, format!("{}.txt", index), content.as_bytes().to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn saved_sessions_are_loaded_again() {
+        let dir = std::env::temp_dir().join(format!("verifile-{}", rand::random::<u64>()));
+        let store = Store::open(&dir).unwrap();
+        let mut blobs = BlobStore::new();
+        let session = Session::new(String::from("alice"), files(), &mut blobs).unwrap();
+        store.save("abc", &session, &blobs).unwrap();
+
+        let mut loaded_blobs = BlobStore::new();
+        let loaded = store.load(&mut loaded_blobs).unwrap();
+        let loaded = &loaded["abc"];
+        assert_eq!(loaded.merkle_root(), session.merkle_root());
+        assert_eq!(loaded.state(), session.state());
+        assert_eq!(loaded.size(), session.size());
+        assert_eq!(loaded_blobs.stored_bytes(), blobs.stored_bytes());
+
+        // content is removed once no session refers to it
+        session.release(&mut blobs);
+        store.remove("abc").unwrap();
+        store.release(&session.hashes(), &blobs).unwrap();
+        assert!(store.load(&mut BlobStore::new()).unwrap().is_empty());
+        assert_eq!(std::fs::read_dir(dir.join("blobs")).unwrap().count(), 0);
+
+        assert!(store.save("../abc", &session, &blobs).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use clap::{Parser, Subcommand};
+use common::model::merkle::{MerkleProof, MerkleTree};
+use common::model::proof_file::ProofFile;
+use env_logger::Builder;
+use log::{info, LevelFilter};
+use std::error::Error;
+use verifile_verify::verify;
+
+/// verifile works with verifile artifacts offline, without a connection to the server
+#[derive(Parser)]
+#[clap(author = "Author Name", version, about)]
+struct Argument {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// verify checks a file against a merkle root using a proof of its position in the tree
+    Verify {
+        /// hex encoded merkle root of the batch the file was uploaded in
+        #[clap(long)]
+        root: String,
+
+        /// proof file exported by the client, in JSON or CBOR. The JSON merkle proof
+        /// sent by the server is also accepted, along with the index of the file
+        #[clap(long)]
+        proof: String,
+
+        /// the file to verify. For an encrypted batch this is the file as it was
+        /// uploaded, before decryption
+        #[clap(long)]
+        file: String,
+
+        /// index of the file in the batch, taken from the proof file if not given
+        #[clap(long)]
+        index: Option<usize>,
+    },
+    /// root computes the merkle root of a batch of files. The files are streamed,
+    /// so only their hashes are held in memory
+    Root {
+        /// the files of the batch, in the order they were uploaded
+        #[clap(short, long, value_delimiter = ',', required = true)]
+        files: Vec<String>,
+    },
+}
+
+/// verify_proof_file verifies the file with an exported proof file. The root of the proof
+/// must be the root the caller trusts, and the index if one is given
+fn verify_proof_file(
+    root: &str,
+    proof: &ProofFile,
+    content: &[u8],
+    index: Option<usize>,
+) -> Result<usize, String> {
+    if !proof.root().eq_ignore_ascii_case(root) {
+        return Err(format!("proof is for merkle root {}", proof.root()));
+    }
+    if index.is_some_and(|index| index != proof.leaf_index()) {
+        return Err(format!("proof is for index {}", proof.leaf_index()));
+    }
+    proof.verify(content)?;
+    Ok(proof.leaf_index())
+}
+
+/// verify_server_proof verifies the file with the JSON merkle proof sent by the server
+fn verify_server_proof(
+    root: &str,
+    proof: &[u8],
+    content: &[u8],
+    index: Option<usize>,
+) -> Result<usize, String> {
+    let proof: MerkleProof = serde_json::from_slice(proof)
+        .map_err(|e| format!("proof is neither a proof file nor a server proof: {}", e))?;
+    let index =
+        index.ok_or_else(|| String::from("the index should be given with a server proof"))?;
+    proof
+        .checked_path(index)
+        .and_then(|path| verify(root, content, index, proof.tree_size(), &path))
+        .map_err(|e| e.to_string())?;
+    Ok(index)
+}
+
+/// run_verify reads the file and the proof and verifies them against the root.
+/// It returns the index of the file
+fn run_verify(
+    root: &str,
+    proof: &str,
+    file: &str,
+    index: Option<usize>,
+) -> Result<usize, Box<dyn Error>> {
+    let proof_buf =
+        std::fs::read(proof).map_err(|e| format!("failed to read proof {}: {}", proof, e))?;
+    let content =
+        std::fs::read(file).map_err(|e| format!("failed to read file {}: {}", file, e))?;
+
+    let verified = match ProofFile::decode(&proof_buf) {
+        Ok(proof) => verify_proof_file(root, &proof, &content, index),
+        Err(_) => verify_server_proof(root, &proof_buf, &content, index),
+    };
+    Ok(verified.map_err(|e| format!("{} failed verification: {}", file, e))?)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    Builder::new().filter(None, LevelFilter::Info).init();
+
+    match Argument::parse().command {
+        Command::Verify {
+            root,
+            proof,
+            file,
+            index,
+        } => {
+            let index = run_verify(&root, &proof, &file, index)?;
+            info!(
+                "{} is the file at index {} of merkle root {}",
+                file, index, root
+            );
+        }
+        Command::Root { files } => {
+            let tree = MerkleTree::from_paths(&files)
+                .map_err(|e| format!("failed to read the files: {}", e))?;
+            info!("merkle root of {} files: {}", tree.len(), tree.root_hash());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use crate::crypto::KeySource;
+use crate::error::{Error, Result};
+use common::diff::{self, FileDiff};
+use common::model::merkle::NodeHash;
+use common::model::proof_file::Hash;
+use common::model::receipt::SignedReceipt;
+use serde::{Deserialize, Serialize};
+
+/// ManifestEntry is what the client remembers of a file of a batch, to tell whether
+/// a local file changed since without asking the server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub name: String,
+    /// hash of the content of the file before it was encrypted
+    pub content_hash: Hash,
+    /// merkle leaf of the file, the hash of the content stored on the server
+    pub leaf_hash: Hash,
+}
+
+/// Batch is what the client keeps after uploading a set of files: where the server
+/// stored them and the merkle root they are verified against when downloaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    session_id: String,
+    merkle_root: String,
+    files_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encryption: Option<KeySource>,
+    receipt: SignedReceipt,
+    /// files of the batch in index order. Batches saved before manifests were kept have none
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    manifest: Vec<ManifestEntry>,
+}
+
+impl Batch {
+    pub fn new(
+        session_id: String,
+        merkle_root: String,
+        files_count: usize,
+        encryption: Option<KeySource>,
+        receipt: SignedReceipt,
+    ) -> Self {
+        Self {
+            session_id,
+            merkle_root,
+            files_count,
+            encryption,
+            receipt,
+            manifest: Vec::new(),
+        }
+    }
+
+    /// set_manifest records the files the batch was made of
+    pub fn set_manifest(&mut self, manifest: Vec<ManifestEntry>) {
+        self.manifest = manifest;
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    pub fn merkle_root(&self) -> &str {
+        &self.merkle_root
+    }
+
+    pub fn files_count(&self) -> usize {
+        self.files_count
+    }
+
+    /// encryption describes how the files were encrypted, None if they were not
+    pub fn encryption(&self) -> Option<&KeySource> {
+        self.encryption.as_ref()
+    }
+
+    pub fn receipt(&self) -> &SignedReceipt {
+        &self.receipt
+    }
+
+    pub fn manifest(&self) -> &[ManifestEntry] {
+        &self.manifest
+    }
+
+    /// file_name returns the name the file at the index was uploaded with, if the batch
+    /// has a manifest. The server sends a name along with each file, but names are not part
+    /// of the merkle tree, so only the one recorded here can be trusted
+    pub fn file_name(&self, index: usize) -> Option<&str> {
+        self.manifest.get(index).map(|entry| entry.name.as_str())
+    }
+
+    /// content_hashes lists the name and content hash of the files of the batch in index order
+    fn content_hashes(&self) -> Result<Vec<(String, NodeHash)>> {
+        if self.manifest.is_empty() {
+            return Err(Error::InvalidInput(format!(
+                "the batch of session {} has no manifest, it was saved before manifests were kept",
+                self.session_id
+            )));
+        }
+        Ok(self
+            .manifest
+            .iter()
+            .map(|entry| (entry.name.clone(), *entry.content_hash.as_bytes()))
+            .collect())
+    }
+
+    /// diff compares the files of the batch to the ones of another batch by their
+    /// manifests, without the server. Content is compared before encryption, so that
+    /// batches encrypted with different keys can be compared
+    pub fn diff(&self, other: &Batch) -> Result<Vec<FileDiff>> {
+        Ok(diff::diff(
+            &self.content_hashes()?,
+            &other.content_hashes()?,
+        ))
+    }
+
+    /// diff_files compares the files of the batch to files given by name and content hash,
+    /// telling what changed since the batch was uploaded
+    pub fn diff_files(&self, files: &[(String, NodeHash)]) -> Result<Vec<FileDiff>> {
+        Ok(diff::diff(&self.content_hashes()?, files))
+    }
+}
@@ -0,0 +1,1354 @@
+use crate::batch::{Batch, ManifestEntry};
+use crate::crypto::{FileCipher, KeyMaterial};
+use crate::error::{Error, Result};
+use crate::transport::Connection;
+use crate::verify::{verify_proof, verify_proof_leaf};
+use common::compression::Compression;
+use common::diff::FileDiff;
+use common::model::file_info::FileInfo;
+use common::model::merkle::{MerkleProof, MerkleTree};
+use common::model::proof_file::{Hash, ProofFile};
+use common::model::receipt::SignedReceipt;
+use common::protocol::{
+    Encoding, Envelope, FileEntry, Format, Request, Response, Role, SessionInfo, SyncFile,
+};
+use common::transport::ClientTls;
+use common::verify::{check_root, VerifyError};
+use log::{debug, info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// READ_BUFFER_SIZE is how much of a file is hashed at a time when streaming it
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Client uploads files to a verifile server and downloads them back,
+/// verifying every downloaded file against the merkle root of its batch
+pub struct Client {
+    address: String,
+    /// addresses of replicas of the server, which reads fail over to
+    replicas: Vec<String>,
+    tls: Option<ClientTls>,
+    token: Option<String>,
+    key_material: Option<KeyMaterial>,
+    server_public_key: Option<String>,
+    encoding: Encoding,
+    compression: Option<Compression>,
+}
+
+impl Client {
+    /// new creates a client for the server at the address. No connection is opened
+    /// until a request is made, each request is sent over its own connection
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            replicas: Vec::new(),
+            tls: None,
+            token: None,
+            key_material: None,
+            server_public_key: None,
+            encoding: Encoding::default(),
+            compression: Some(Compression::Zstd),
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// set_replicas sets the addresses of replicas of the server. Requests that only read
+    /// sessions are sent to the first of them that can be reached when the server cannot,
+    /// and what they return is verified against the same roots
+    pub fn set_replicas(&mut self, replicas: Vec<String>) {
+        self.replicas = replicas;
+    }
+
+    /// set_tls makes the client connect to the server over TLS
+    pub fn set_tls(&mut self, tls: ClientTls) {
+        self.tls = Some(tls);
+    }
+
+    /// set_token sets the API token sent with every request to the server
+    pub fn set_token(&mut self, token: String) {
+        self.token = Some(token);
+    }
+
+    /// set_key_material enables end-to-end encryption: files are encrypted before
+    /// they are sent and decrypted after they are downloaded and verified
+    pub fn set_key_material(&mut self, key_material: KeyMaterial) {
+        self.key_material = Some(key_material);
+    }
+
+    /// set_server_public_key sets the key the server must have signed upload receipts with
+    pub fn set_server_public_key(&mut self, public_key: String) {
+        self.server_public_key = Some(public_key);
+    }
+
+    /// set_encoding sets the encoding the client asks the server for. Servers of
+    /// protocol version 1 are always spoken to in JSON
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    /// set_compression sets the codec the client asks the server to compress large messages
+    /// with, the server may pick another one. None turns compression off
+    pub fn set_compression(&mut self, compression: Option<Compression>) {
+        self.compression = compression;
+    }
+
+    fn envelope(&self, request: Request) -> Envelope {
+        Envelope {
+            token: self.token.clone(),
+            request,
+        }
+    }
+
+    /// connect opens a connection to the server and agrees on the protocol in a handshake,
+    /// returning the format requests are sent in
+    async fn connect(&self) -> Result<(Connection, Format)> {
+        self.connect_to(&self.address).await
+    }
+
+    /// connect_for_read opens a connection like connect does, falling over to the replicas
+    /// in turn while the servers cannot be reached
+    async fn connect_for_read(&self) -> Result<(Connection, Format)> {
+        let mut connected = self.connect().await;
+        for replica in &self.replicas {
+            match &connected {
+                Err(e @ (Error::Connection(_) | Error::Io(_))) => {
+                    warn!("{}, reading from replica {}", e, replica)
+                }
+                _ => break,
+            }
+            connected = self.connect_to(replica).await;
+        }
+        connected
+    }
+
+    /// connect_to opens a connection to the server at the address and agrees on the protocol
+    async fn connect_to(&self, address: &str) -> Result<(Connection, Format)> {
+        let mut connection = Connection::open(address, self.tls.as_ref()).await?;
+        let format = Format {
+            encoding: self.encoding,
+            compression: self.compression,
+        };
+        let format = match connection.handshake(format).await? {
+            Some(agreement) => agreement.format(),
+            None => {
+                debug!("Server speaks protocol version 1, sending the request in JSON");
+                connection = Connection::open(address, self.tls.as_ref()).await?;
+                Format::from(Encoding::Json)
+            }
+        };
+        Ok((connection, format))
+    }
+
+    /// request sends a request to the server and waits for its response.
+    /// Errors returned by the server are turned into Error::Server
+    async fn request(&self, request: Request) -> Result<Response> {
+        let connected = self.connect().await?;
+        self.exchange(connected, request).await
+    }
+
+    /// read sends a request that only reads sessions like request does, to a replica
+    /// if the server cannot be reached
+    async fn read(&self, request: Request) -> Result<Response> {
+        let connected = self.connect_for_read().await?;
+        self.exchange(connected, request).await
+    }
+
+    async fn exchange(
+        &self,
+        (connection, format): (Connection, Format),
+        request: Request,
+    ) -> Result<Response> {
+        match connection.request(&self.envelope(request), format).await? {
+            Response::Error(e) => Err(Error::Server(e)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// this implementation has methods concerned with sending files to the server
+impl Client {
+    /// start_upload starts an upload on the server whose files are then sent one at a
+    /// time, so that no more than one of them is held in memory
+    pub(crate) async fn start_upload(&self) -> Result<Upload<'_>> {
+        let cipher = match &self.key_material {
+            Some(key_material) => Some(key_material.cipher(None)?),
+            None => None,
+        };
+        let session_id = match self.request(Request::StartUpload).await? {
+            Response::UploadStarted { session_id } => session_id,
+            _ => return Err(Error::UnexpectedResponse("start upload")),
+        };
+        Ok(Upload {
+            client: self,
+            session_id,
+            cipher,
+            manifest: Vec::new(),
+        })
+    }
+
+    /// upload sends the content read from each named source to the server as a new
+    /// session. The returned batch holds the merkle root of the files and the receipt
+    /// signed by the server, it is needed to download and verify the files later.
+    /// The files are read and sent one per request, the root is built from the hashes
+    /// of the manifest
+    pub async fn upload<R: AsyncRead + Unpin>(&self, sources: Vec<(String, R)>) -> Result<Batch> {
+        if sources.is_empty() {
+            return Err(Error::InvalidInput(String::from(
+                "at least one file should be uploaded",
+            )));
+        }
+
+        let mut upload = self.start_upload().await?;
+        for (name, mut reader) in sources {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).await?;
+            upload.put(name, content).await?;
+        }
+        upload.complete().await
+    }
+
+    /// upload_paths uploads the files at the paths, named by their path
+    pub async fn upload_paths<P: AsRef<Path>>(&self, paths: &[P]) -> Result<Batch> {
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            let file = tokio::fs::File::open(path).await?;
+            sources.push((path.to_string_lossy().into_owned(), file));
+        }
+        self.upload(sources).await
+    }
+
+    /// verify_receipt checks that the receipt of a batch was signed for its session, merkle
+    /// root and number of files. Only a receipt signed with the trusted server key proves the
+    /// server signed it, without one the receipt is reported as unverified
+    pub fn verify_receipt(&self, batch: &Batch) -> Result<ReceiptCheck> {
+        let receipt: &SignedReceipt = batch.receipt();
+        if receipt.receipt().session_id() != batch.session_id() {
+            return Err(Error::Receipt(String::from(
+                "receipt is for another session",
+            )));
+        }
+        receipt
+            .verify_for(
+                batch.merkle_root(),
+                batch.files_count(),
+                self.server_public_key.as_deref(),
+            )
+            .map_err(Error::Receipt)?;
+        Ok(match self.server_public_key {
+            Some(_) => ReceiptCheck::Trusted,
+            None => ReceiptCheck::Unverified,
+        })
+    }
+
+    /// check_receipt verifies the receipt of a batch the server just signed,
+    /// warning if it cannot be told whether the server signed it
+    fn check_receipt(&self, batch: &Batch) -> Result<()> {
+        if self.verify_receipt(batch)? == ReceiptCheck::Unverified {
+            warn!(
+                "No server key is trusted, the receipt of session {} is unverified: it was signed by {}",
+                batch.session_id(),
+                batch.receipt().public_key()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// ReceiptCheck tells what verifying the receipt of a batch proved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptCheck {
+    /// the receipt was signed for the batch with the trusted server key
+    Trusted,
+    /// the receipt was signed for the batch with the key it holds, but no server key is
+    /// trusted, and anyone can sign a receipt with a key of their own
+    Unverified,
+}
+
+/// this implementation has methods concerned with receiving and verifying files from the server
+impl Client {
+    /// get_proof fetches a file of a session along with its merkle proof, without verifying it
+    pub async fn get_proof(&self, session_id: &str, index: usize) -> Result<MerkleProof> {
+        let request = Request::Download {
+            session_id: session_id.to_string(),
+            index,
+        };
+        match self.read(request).await? {
+            Response::File(proof) => Ok(proof),
+            _ => Err(Error::UnexpectedResponse("download")),
+        }
+    }
+
+    /// decrypt decrypts the content of a verified file if its batch was encrypted on upload
+    fn decrypt(&self, batch: &Batch, content: Vec<u8>) -> Result<Vec<u8>> {
+        let Some(key_source) = batch.encryption() else {
+            return Ok(content);
+        };
+
+        let key_material = self.key_material.as_ref().ok_or_else(|| {
+            Error::Crypto(String::from(
+                "the file is encrypted, a passphrase or key file is required",
+            ))
+        })?;
+        key_material.cipher(Some(key_source))?.decrypt(&content)
+    }
+
+    /// download fetches the file at the index of a batch, verifies it against the merkle
+    /// root of the batch and writes it to the writer. Nothing is written if verification
+    /// fails. The name the file was uploaded with is returned, as recorded in the manifest
+    /// of the batch if it has one
+    pub async fn download<W: AsyncWrite + Unpin>(
+        &self,
+        batch: &Batch,
+        index: usize,
+        writer: &mut W,
+    ) -> Result<String> {
+        if index >= batch.files_count() {
+            return Err(Error::InvalidInput(format!(
+                "file index {} is not in the batch of {} files",
+                index,
+                batch.files_count()
+            )));
+        }
+
+        let proof = self.get_proof(batch.session_id(), index).await?;
+        verify_proof(&proof, index, batch.files_count(), batch.merkle_root())?;
+
+        let name = batch
+            .file_name(index)
+            .map_or_else(|| proof.file_name(), String::from);
+        let content = self.decrypt(batch, proof.into_file_content())?;
+        writer.write_all(&content).await?;
+        writer.flush().await?;
+        Ok(name)
+    }
+}
+
+/// DownloadedFile is a file of a batch received by Client::download_all
+#[derive(Debug)]
+pub struct DownloadedFile {
+    pub index: usize,
+    /// name the file was uploaded with
+    pub name: String,
+    /// the verified and decrypted content of the file, or why it could not be had
+    pub content: Result<Vec<u8>>,
+}
+
+/// this implementation has methods concerned with restoring whole batches
+impl Client {
+    /// request_batch sends a download-all request for the session and returns the connection
+    /// the files follow on, checking the server sends as many as the batch holds
+    async fn request_batch(&self, batch: &Batch) -> Result<Connection> {
+        let (mut connection, format) = self.connect_for_read().await?;
+        let request = Request::DownloadAll {
+            session_id: batch.session_id().to_string(),
+        };
+        connection.send(&self.envelope(request), format).await?;
+
+        let files_count = match connection.receive().await? {
+            Response::Batch { files_count } => files_count,
+            Response::Error(e) => return Err(Error::Server(e)),
+            _ => return Err(Error::UnexpectedResponse("download-all")),
+        };
+        if files_count != batch.files_count() {
+            return Err(Error::BatchVerification(VerifyError::TreeSizeMismatch {
+                expected: batch.files_count(),
+                actual: files_count,
+            }));
+        }
+        Ok(connection)
+    }
+
+    /// download_all_with fetches every file of a batch over a single connection and hands
+    /// each one to the handler as it arrives, checked against its own proof, so that a
+    /// single file is held in memory at a time. Files that fail carry their error, the others
+    /// their content. Only the leaf hashes are kept, to check once every file arrived that
+    /// together they rebuild the merkle root of the batch
+    pub async fn download_all_with<F>(&self, batch: &Batch, mut handler: F) -> Result<()>
+    where
+        F: FnMut(DownloadedFile) -> Result<()>,
+    {
+        let mut connection = self.request_batch(batch).await?;
+
+        let mut leaves = Vec::with_capacity(batch.files_count());
+        let mut all_verified = true;
+        for index in 0..batch.files_count() {
+            let proof = match connection.receive().await? {
+                Response::File(proof) => proof,
+                Response::Error(e) => return Err(Error::Server(e)),
+                _ => return Err(Error::UnexpectedResponse("download-all")),
+            };
+            let leaf = proof.leaf_hash();
+            leaves.push(leaf);
+            let verified = verify_proof_leaf(
+                &proof,
+                &leaf,
+                index,
+                batch.files_count(),
+                batch.merkle_root(),
+            );
+            all_verified &= verified.is_ok();
+            handler(DownloadedFile {
+                index,
+                name: batch
+                    .file_name(index)
+                    .map_or_else(|| proof.file_name(), String::from),
+                content: verified.and_then(|_| self.decrypt(batch, proof.into_file_content())),
+            })?;
+        }
+
+        // every file has a valid proof, yet together they do not rebuild the root
+        if all_verified {
+            let root = MerkleTree::from_leaf_hashes(leaves).root_hash();
+            check_root(batch.merkle_root(), &root).map_err(Error::BatchVerification)?;
+        }
+        Ok(())
+    }
+
+    /// download_all fetches every file of a batch like download_all_with and collects them,
+    /// which holds the content of the whole batch in memory
+    pub async fn download_all(&self, batch: &Batch) -> Result<Vec<DownloadedFile>> {
+        let mut files = Vec::with_capacity(batch.files_count());
+        self.download_all_with(batch, |file| {
+            files.push(file);
+            Ok(())
+        })
+        .await?;
+        Ok(files)
+    }
+}
+
+/// this implementation has methods concerned with keeping a batch in sync with local files
+impl Client {
+    /// check_manifest checks that the manifest of a batch rebuilds its merkle root, so that
+    /// the leaves a sync keeps are the ones the receipt of the batch was signed for
+    fn check_manifest(batch: &Batch) -> Result<()> {
+        let manifest = batch.manifest();
+        if manifest.is_empty() {
+            return Ok(());
+        }
+        if manifest.len() != batch.files_count() {
+            return Err(Error::BatchVerification(VerifyError::TreeSizeMismatch {
+                expected: batch.files_count(),
+                actual: manifest.len(),
+            }));
+        }
+
+        let root = Self::manifest_root(manifest);
+        check_root(batch.merkle_root(), &root).map_err(Error::BatchVerification)
+    }
+
+    /// manifest_root computes the merkle root over the leaves of a manifest
+    fn manifest_root(manifest: &[ManifestEntry]) -> String {
+        let leaves = manifest
+            .iter()
+            .map(|entry| *entry.leaf_hash.as_bytes())
+            .collect();
+        MerkleTree::from_leaf_hashes(leaves).root_hash()
+    }
+
+    /// plan_sync starts the sync of a batch, after checking its manifest. New files are
+    /// encrypted with the key of the batch, so that it still has a single one
+    fn plan_sync<'a>(&self, batch: &'a Batch, files_count: usize) -> Result<SyncPlan<'a>> {
+        if files_count == 0 {
+            return Err(Error::InvalidInput(String::from(
+                "a session cannot be synced to no files",
+            )));
+        }
+        let cipher = match (batch.encryption(), &self.key_material) {
+            (None, None) => None,
+            (Some(key_source), Some(key_material)) => Some(key_material.cipher(Some(key_source))?),
+            (Some(_), None) => {
+                return Err(Error::Crypto(String::from(
+                    "the batch is encrypted, a passphrase or key file is required",
+                )))
+            }
+            (None, Some(_)) => {
+                return Err(Error::Crypto(String::from(
+                    "the batch is not encrypted, it cannot be synced with a key",
+                )))
+            }
+        };
+        Self::check_manifest(batch)?;
+
+        Ok(SyncPlan {
+            stored: batch
+                .manifest()
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| (entry.name.as_str(), (index, entry)))
+                .collect(),
+            cipher,
+            files: Vec::with_capacity(files_count),
+            manifest: Vec::with_capacity(files_count),
+        })
+    }
+
+    /// sync makes the session of a batch hold the content read from each named source, in
+    /// order. Sources whose name and content match a file in the manifest of the batch are
+    /// kept on the server without being sent again, the others are sent. The returned batch
+    /// holds the new merkle root, computed locally, and the receipt the server signed for
+    /// the transition to it from the root of the given batch. The sources are read one at a
+    /// time and only the content of the ones that are sent is held in memory
+    pub async fn sync<R: AsyncRead + Unpin>(
+        &self,
+        batch: &Batch,
+        sources: Vec<(String, R)>,
+    ) -> Result<Batch> {
+        let mut plan = self.plan_sync(batch, sources.len())?;
+        for (name, mut reader) in sources {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).await?;
+            if !plan.keep(&name, &Hash::of(&content)) {
+                plan.send(name, content)?;
+            }
+        }
+        self.send_sync(batch, plan).await
+    }
+
+    /// sync_paths syncs the session of a batch like sync, with the files at the paths under
+    /// the given names. Each file is hashed as it is read and only the new or changed ones
+    /// are read again to be sent
+    pub async fn sync_paths<P: AsRef<Path>>(
+        &self,
+        batch: &Batch,
+        files: &[(String, P)],
+    ) -> Result<Batch> {
+        let mut plan = self.plan_sync(batch, files.len())?;
+        for (name, path) in files {
+            let content_hash = hash_reader(tokio::fs::File::open(path).await?).await?;
+            if !plan.keep(name, &content_hash) {
+                plan.send(name.clone(), tokio::fs::read(path).await?)?;
+            }
+        }
+        self.send_sync(batch, plan).await
+    }
+
+    /// send_sync sends the files of a sync that are not kept and returns the synced batch
+    async fn send_sync(&self, batch: &Batch, plan: SyncPlan<'_>) -> Result<Batch> {
+        let SyncPlan {
+            files, manifest, ..
+        } = plan;
+        let merkle_root = Self::manifest_root(&manifest);
+        let files_count = files.len();
+        let sent = files
+            .iter()
+            .filter(|file| matches!(file, SyncFile::Upload(_)))
+            .count();
+        if sent == 0 && files_count == batch.files_count() && merkle_root == batch.merkle_root() {
+            info!("Session {} is already in sync", batch.session_id());
+            let mut batch = batch.clone();
+            batch.set_manifest(manifest);
+            return Ok(batch);
+        }
+
+        let request = Request::Sync {
+            session_id: batch.session_id().to_string(),
+            base_root: batch.merkle_root().to_string(),
+            files,
+        };
+        let receipt = match self.request(request).await? {
+            Response::Synced { receipt } => receipt,
+            _ => return Err(Error::UnexpectedResponse("sync")),
+        };
+        if receipt.receipt().previous_root() != Some(batch.merkle_root()) {
+            return Err(Error::Receipt(format!(
+                "receipt does not record the transition from merkle root {}",
+                batch.merkle_root()
+            )));
+        }
+
+        let mut synced = Batch::new(
+            batch.session_id().to_string(),
+            merkle_root,
+            files_count,
+            batch.encryption().cloned(),
+            receipt,
+        );
+        synced.set_manifest(manifest);
+        self.check_receipt(&synced)?;
+        info!(
+            "Synced session {}, sent {} files and kept {}",
+            synced.session_id(),
+            sent,
+            files_count - sent
+        );
+        Ok(synced)
+    }
+}
+
+/// SyncPlan lists the files of a sync in order. The ones whose name and content match a
+/// file of the batch are kept by their index, the others are sent with their content
+struct SyncPlan<'a> {
+    /// files of the batch by name, with their index
+    stored: HashMap<&'a str, (usize, &'a ManifestEntry)>,
+    cipher: Option<FileCipher>,
+    files: Vec<SyncFile>,
+    manifest: Vec<ManifestEntry>,
+}
+
+impl SyncPlan<'_> {
+    /// keep adds the file of the batch with the name to the sync if its content has the
+    /// hash, and tells whether it did
+    fn keep(&mut self, name: &str, content_hash: &Hash) -> bool {
+        let Some((kept, entry)) = self
+            .stored
+            .get(name)
+            .filter(|(_, entry)| entry.content_hash == *content_hash)
+        else {
+            return false;
+        };
+        self.files.push(SyncFile::Keep { index: *kept });
+        self.manifest.push((*entry).clone());
+        true
+    }
+
+    /// send adds a file to the sync with its content, encrypted if the batch is
+    fn send(&mut self, name: String, content: Vec<u8>) -> Result<()> {
+        let (content, entry) = seal(self.cipher.as_ref(), &name, content)?;
+        self.manifest.push(entry);
+        let index = self.files.len();
+        self.files
+            .push(SyncFile::Upload(FileInfo::new(index, name, content)));
+        Ok(())
+    }
+}
+
+/// Upload is an upload started on the server. Its files are encrypted if key material was
+/// given and listed in a manifest as they are sent. The merkle tree is then built over the
+/// ciphertext, so the server can serve proofs without ever seeing the plaintext
+pub(crate) struct Upload<'a> {
+    client: &'a Client,
+    session_id: String,
+    cipher: Option<FileCipher>,
+    manifest: Vec<ManifestEntry>,
+}
+
+impl Upload<'_> {
+    /// put sends the next file of the upload
+    pub(crate) async fn put(&mut self, name: String, content: Vec<u8>) -> Result<()> {
+        let (content, entry) = seal(self.cipher.as_ref(), &name, content)?;
+        let file = FileInfo::new(self.manifest.len(), name, content);
+        self.manifest.push(entry);
+        let request = Request::PutFile {
+            session_id: self.session_id.clone(),
+            file,
+        };
+        match self.client.request(request).await? {
+            Response::FilePut => Ok(()),
+            _ => Err(Error::UnexpectedResponse("put file")),
+        }
+    }
+
+    /// complete creates the session holding the files that were put and returns its batch
+    pub(crate) async fn complete(self) -> Result<Batch> {
+        let request = Request::CompleteUpload {
+            session_id: self.session_id,
+        };
+        let (session_id, receipt) = match self.client.request(request).await? {
+            Response::Uploaded {
+                session_id,
+                receipt,
+            } => (session_id, receipt),
+            _ => return Err(Error::UnexpectedResponse("upload")),
+        };
+
+        let merkle_root = Client::manifest_root(&self.manifest);
+        let encryption = self.cipher.map(|cipher| cipher.source());
+        let mut batch = Batch::new(
+            session_id,
+            merkle_root,
+            self.manifest.len(),
+            encryption,
+            receipt,
+        );
+        batch.set_manifest(self.manifest);
+        self.client.check_receipt(&batch)?;
+        info!("Files sent successfully to session {}", batch.session_id());
+        Ok(batch)
+    }
+}
+
+/// seal encrypts the content of a file with the cipher, if there is one, and returns it
+/// along with its manifest entry. The content is hashed where it lies, the only copy made
+/// of it is its ciphertext
+fn seal(
+    cipher: Option<&FileCipher>,
+    name: &str,
+    content: Vec<u8>,
+) -> Result<(Vec<u8>, ManifestEntry)> {
+    let content_hash = Hash::of(&content);
+    let content = match cipher {
+        Some(cipher) => cipher.encrypt(&content)?,
+        None => content,
+    };
+    let entry = ManifestEntry {
+        name: name.to_string(),
+        content_hash,
+        leaf_hash: Hash::of(&content),
+    };
+    Ok((content, entry))
+}
+
+/// hash_reader hashes the content of a reader as it is read, without holding it in memory
+async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R) -> Result<Hash> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf).await? {
+            0 => return Ok(Hash::from(<[u8; 32]>::from(hasher.finalize()))),
+            n => hasher.update(&buf[..n]),
+        }
+    }
+}
+
+/// this implementation has methods concerned with exporting proofs of stored files
+impl Client {
+    /// export_proof fetches the proof of the file at the index of a batch, verifies it
+    /// and converts it to a proof file that can be checked later without the server
+    pub async fn export_proof(&self, batch: &Batch, index: usize) -> Result<ProofFile> {
+        if index >= batch.files_count() {
+            return Err(Error::InvalidInput(format!(
+                "file index {} is not in the batch of {} files",
+                index,
+                batch.files_count()
+            )));
+        }
+
+        let proof = self.get_proof(batch.session_id(), index).await?;
+        verify_proof(&proof, index, batch.files_count(), batch.merkle_root())?;
+        ProofFile::from_proof(&proof, index, batch.files_count(), batch.merkle_root())
+            .map_err(Error::Codec)
+    }
+}
+
+/// this implementation has methods concerned with managing sessions on the server
+impl Client {
+    /// list lists the files stored in a session
+    pub async fn list(&self, session_id: &str) -> Result<Vec<FileEntry>> {
+        let request = Request::List {
+            session_id: session_id.to_string(),
+        };
+        match self.read(request).await? {
+            Response::Files(entries) => Ok(entries),
+            _ => Err(Error::UnexpectedResponse("list")),
+        }
+    }
+
+    /// grant gives another user access to a session owned by the caller
+    pub async fn grant(&self, session_id: &str, user: String, role: Role) -> Result<()> {
+        let request = Request::Grant {
+            session_id: session_id.to_string(),
+            user,
+            role,
+        };
+        match self.request(request).await? {
+            Response::Granted => Ok(()),
+            _ => Err(Error::UnexpectedResponse("grant")),
+        }
+    }
+
+    /// delete deletes a session owned by the caller along with its files
+    pub async fn delete(&self, session_id: &str) -> Result<()> {
+        let request = Request::Delete {
+            session_id: session_id.to_string(),
+        };
+        match self.request(request).await? {
+            Response::Deleted => Ok(()),
+            _ => Err(Error::UnexpectedResponse("delete")),
+        }
+    }
+
+    /// diff compares the files of two sessions the caller has access to on the server, from the
+    /// first to the second. The server descends the merkle trees of both sessions to find
+    /// the files that differ
+    pub async fn diff(&self, from: &str, to: &str) -> Result<Vec<FileDiff>> {
+        let request = Request::Diff {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+        match self.read(request).await? {
+            Response::Diff(diffs) => Ok(diffs),
+            _ => Err(Error::UnexpectedResponse("diff")),
+        }
+    }
+
+    /// sessions describes the sessions the caller has access to
+    pub async fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        match self.read(Request::Sessions).await? {
+            Response::Sessions(sessions) => Ok(sessions),
+            _ => Err(Error::UnexpectedResponse("sessions")),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::{seal, Client, ReceiptCheck};
+    use crate::batch::{Batch, ManifestEntry};
+    use crate::crypto::KeyMaterial;
+    use crate::error::Error;
+    use common::diff::Change;
+    use common::handshake::{negotiate, PROTOCOL_VERSION};
+    use common::model::file_info::FileInfo;
+    use common::model::merkle::{MerkleProof, MerkleTree};
+    use common::model::proof_file::Hash;
+    use common::model::receipt::Receipt;
+    use common::protocol::{
+        read_message, write_message, Encoding, Envelope, ErrorKind, FileEntry, Format,
+        ProtocolError, Request, Response, SyncFile,
+    };
+    use ed25519_dalek::SigningKey;
+    use sha256::digest;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+
+    fn file_names() -> Vec<String> {
+        vec![
+            String::from("../files/cv.txt"),
+            String::from("../files/food.json"),
+        ]
+    }
+
+    fn parse_files() -> Vec<FileInfo> {
+        file_names()
+            .into_iter()
+            .enumerate()
+            .map(|(i, f)| {
+                let content = std::fs::read(&f).expect("file should be present");
+                FileInfo::new(i, f, content)
+            })
+            .collect()
+    }
+
+    fn get_merkle_root() -> String {
+        let expected_files = parse_files();
+        let first = digest(expected_files[0].content());
+        let second = digest(expected_files[1].content());
+        digest(format!("{}{}", first, second))
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    /// proofs builds the proof of every file, with the content of the file at the
+    /// tampered index changed after the tree was built
+    fn proofs(files: &[FileInfo], tampered: Option<usize>) -> Vec<MerkleProof> {
+        let tree = MerkleTree::from(files.iter().map(|f| f.content()).collect::<Vec<_>>());
+        files
+            .iter()
+            .map(|f| {
+                let mut content = f.content();
+                if tampered == Some(f.index()) {
+                    content.push(b'!');
+                }
+                MerkleProof::build(&tree, f.index(), f.name(), content)
+            })
+            .collect()
+    }
+
+    pub(crate) fn mock_server(requests: usize) -> (String, thread::JoinHandle<()>) {
+        mock_server_of_version(PROTOCOL_VERSION, requests)
+    }
+
+    /// tampering_mock_server serves the given number of requests like mock_server does,
+    /// but changes the content of the file at the index whenever it is downloaded
+    pub(crate) fn tampering_mock_server(
+        requests: usize,
+        tampered: usize,
+    ) -> (String, thread::JoinHandle<()>) {
+        serve_mock(PROTOCOL_VERSION, requests, Some(tampered))
+    }
+
+    fn mock_server_of_version(version: u32, requests: usize) -> (String, thread::JoinHandle<()>) {
+        serve_mock(version, requests, None)
+    }
+
+    /// uploaded_response answers an upload of the files with a signed receipt
+    fn uploaded_response(files: &[FileInfo]) -> Response {
+        let tree = MerkleTree::from(files.iter().map(|f| f.content()).collect::<Vec<_>>());
+        let receipt =
+            Receipt::new(String::from("session"), tree.root_hash(), files.len()).sign(&key());
+        Response::Uploaded {
+            session_id: String::from("session"),
+            receipt,
+        }
+    }
+
+    /// serve_mock serves the given number of requests like a server of the protocol version
+    /// would, keeping the uploaded files of a single session in memory
+    fn serve_mock(
+        version: u32,
+        requests: usize,
+        tampered: Option<usize>,
+    ) -> (String, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || {
+            let mut files: Vec<FileInfo> = Vec::new();
+            for _ in 0..requests {
+                let (mut stream, _) = listener.accept().unwrap();
+                let (mut envelope, mut format): (Envelope, _) = read_message(&mut stream).unwrap();
+                if let Request::Hello(hello) = &envelope.request {
+                    if version == 1 {
+                        // servers of version 1 fail to decode the hello, the client reconnects
+                        let error = ProtocolError::new(
+                            ErrorKind::BadRequest,
+                            "failed to decode message: unknown variant `Hello`",
+                        );
+                        write_message(&mut stream, &Response::Error(error), Encoding::Json)
+                            .unwrap();
+                        (stream, _) = listener.accept().unwrap();
+                        (envelope, format) = read_message(&mut stream).unwrap();
+                        assert_eq!(format, Format::from(Encoding::Json));
+                    } else {
+                        let agreement = negotiate(hello).unwrap();
+                        write_message(&mut stream, &Response::Hello(agreement.clone()), format)
+                            .unwrap();
+                        (envelope, _) = read_message(&mut stream).unwrap();
+                        format = agreement.format();
+                    }
+                }
+
+                let response = match envelope.request {
+                    Request::Hello(_) => panic!("a hello can only open a connection"),
+                    Request::Upload { files: uploaded } => {
+                        files = uploaded;
+                        uploaded_response(&files)
+                    }
+                    Request::StartUpload => {
+                        files.clear();
+                        Response::UploadStarted {
+                            session_id: String::from("session"),
+                        }
+                    }
+                    Request::PutFile { file, .. } => {
+                        files.push(file);
+                        Response::FilePut
+                    }
+                    Request::CompleteUpload { .. } => uploaded_response(&files),
+                    Request::Download { index, .. } => {
+                        Response::File(proofs(&files, tampered).remove(index))
+                    }
+                    Request::List { .. } => Response::Files(
+                        files
+                            .iter()
+                            .map(|f| FileEntry {
+                                index: f.index(),
+                                name: f.name(),
+                            })
+                            .collect(),
+                    ),
+                    Request::Grant { .. } => Response::Error(ProtocolError::new(
+                        ErrorKind::Forbidden,
+                        "only the owner can grant access",
+                    )),
+                    Request::Delete { .. } => {
+                        files.clear();
+                        Response::Deleted
+                    }
+                    Request::Sessions => Response::Sessions(Vec::new()),
+                    Request::DownloadAll { .. } => Response::Batch {
+                        files_count: files.len(),
+                    },
+                    Request::Diff { .. } => Response::Diff(Vec::new()),
+                    Request::Replication(_) => panic!("clients do not replicate sessions"),
+                    Request::Sync {
+                        files: synced,
+                        base_root,
+                        ..
+                    } => {
+                        files = synced
+                            .into_iter()
+                            .enumerate()
+                            .map(|(position, file)| match file {
+                                SyncFile::Keep { index } => FileInfo::new(
+                                    position,
+                                    files[index].name(),
+                                    files[index].content(),
+                                ),
+                                SyncFile::Upload(file_info) => file_info,
+                            })
+                            .collect();
+                        let tree =
+                            MerkleTree::from(files.iter().map(|f| f.content()).collect::<Vec<_>>());
+                        let mut receipt =
+                            Receipt::new(String::from("session"), tree.root_hash(), files.len());
+                        receipt.set_previous_root(base_root);
+                        Response::Synced {
+                            receipt: receipt.sign(&key()),
+                        }
+                    }
+                };
+                write_message(&mut stream, &response, format).unwrap();
+
+                if let Response::Batch { .. } = response {
+                    for proof in proofs(&files, tampered) {
+                        write_message(&mut stream, &Response::File(proof), format).unwrap();
+                    }
+                }
+            }
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn merkle_root_works() {
+        let manifest = parse_files()
+            .into_iter()
+            .map(|f| seal(None, &f.name(), f.content()).unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(Client::manifest_root(&manifest), get_merkle_root());
+    }
+
+    #[tokio::test]
+    async fn upload_download_export_list_and_delete_work() {
+        let (address, handle) = mock_server(8);
+        let client = Client::new(address);
+
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+        assert_eq!(batch.merkle_root(), get_merkle_root());
+        assert_eq!(batch.files_count(), 2);
+
+        let mut downloaded = Vec::new();
+        let name = client.download(&batch, 1, &mut downloaded).await.unwrap();
+        assert_eq!(name, "../files/food.json");
+        assert_eq!(downloaded, parse_files()[1].content());
+
+        let proof = client.export_proof(&batch, 0).await.unwrap();
+        proof.verify(&parse_files()[0].content()).unwrap();
+
+        let entries = client.list(batch.session_id()).await.unwrap();
+        assert_eq!(entries.len(), 2);
+
+        client.delete(batch.session_id()).await.unwrap();
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn downloads_are_named_by_the_manifest() {
+        let (address, handle) = mock_server(5);
+        let client = Client::new(address);
+        let mut batch = client.upload_paths(&file_names()).await.unwrap();
+
+        // the server still sends the name it was given
+        let mut manifest = batch.manifest().to_vec();
+        manifest[1].name = String::from("food.json");
+        batch.set_manifest(manifest);
+        let name = client.download(&batch, 1, &mut Vec::new()).await.unwrap();
+        assert_eq!(name, "food.json");
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_fail_over_to_replicas() {
+        let (replica, handle) = mock_server(6);
+        let batch = Client::new(replica.clone())
+            .upload_paths(&file_names())
+            .await
+            .unwrap();
+
+        // nothing listens at the address of the server anymore
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        drop(server);
+        let mut client = Client::new(address);
+        client.set_replicas(vec![replica]);
+
+        let mut downloaded = Vec::new();
+        client.download(&batch, 1, &mut downloaded).await.unwrap();
+        assert_eq!(downloaded, parse_files()[1].content());
+        let files = client.download_all(&batch).await.unwrap();
+        assert!(files.iter().all(|file| file.content.is_ok()));
+
+        // changes are only sent to the server
+        let e = client.delete(batch.session_id()).await.unwrap_err();
+        assert!(matches!(e, Error::Connection(_)), "{:?}", e);
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_all_verifies_the_whole_batch() {
+        let (address, handle) = mock_server(6);
+        let mut client = Client::new(address);
+        client.set_key_material(KeyMaterial::Passphrase(String::from("correct horse")));
+
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+        let files = client.download_all(&batch).await.unwrap();
+        assert_eq!(files.len(), 2);
+        for (file, expected) in files.into_iter().zip(parse_files()) {
+            assert_eq!(file.index, expected.index());
+            assert_eq!(file.name, expected.name());
+            assert_eq!(file.content.unwrap(), expected.content());
+        }
+
+        // the files are handed over one at a time, in order
+        let mut indexes = Vec::new();
+        client
+            .download_all_with(&batch, |file| {
+                assert!(file.content.is_ok());
+                indexes.push(file.index);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(indexes, vec![0, 1]);
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_all_reports_the_files_that_fail() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client::new(listener.local_addr().unwrap().to_string());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (hello, format) = read_message::<Envelope>(&mut stream).unwrap();
+            let Request::Hello(hello) = hello.request else {
+                panic!("expected a hello");
+            };
+            let agreement = negotiate(&hello).unwrap();
+            write_message(&mut stream, &Response::Hello(agreement.clone()), format).unwrap();
+
+            read_message::<Envelope>(&mut stream).unwrap();
+            let format = agreement.format();
+            let response = Response::Batch { files_count: 2 };
+            write_message(&mut stream, &response, format).unwrap();
+            for proof in proofs(&parse_files(), Some(1)) {
+                write_message(&mut stream, &Response::File(proof), format).unwrap();
+            }
+        });
+
+        let receipt = Receipt::new(String::from("session"), get_merkle_root(), 2).sign(&key());
+        let batch = Batch::new(String::from("session"), get_merkle_root(), 2, None, receipt);
+        let files = client.download_all(&batch).await.unwrap();
+        assert_eq!(
+            files[0].content.as_ref().unwrap(),
+            &parse_files()[0].content()
+        );
+        assert_eq!(files[1].name, "../files/food.json");
+        assert!(matches!(
+            files[1].content,
+            Err(Error::Verification { index: 1, .. })
+        ));
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_paths_sends_only_the_files_that_changed() {
+        let (address, handle) = mock_server(5);
+        let client = Client::new(address);
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+
+        let changed =
+            std::env::temp_dir().join(format!("verifile-sync-{}.json", std::process::id()));
+        std::fs::write(&changed, b"{}").unwrap();
+        let names = file_names();
+        let files = vec![
+            (names[0].clone(), PathBuf::from(&names[0])),
+            (names[1].clone(), changed.clone()),
+        ];
+        let synced = client.sync_paths(&batch, &files).await.unwrap();
+        std::fs::remove_file(changed).unwrap();
+
+        assert_eq!(synced.manifest()[0], batch.manifest()[0]);
+        assert_eq!(synced.manifest()[1].content_hash, Hash::of(b"{}"));
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_sends_only_the_files_that_changed() {
+        let (address, handle) = mock_server(6);
+        let mut client = Client::new(address);
+        client.set_key_material(KeyMaterial::Passphrase(String::from("correct horse")));
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+
+        let files = parse_files();
+        let sources = vec![
+            (files[0].name(), files[0].content()),
+            (files[1].name(), b"{}".to_vec()),
+            (String::from("new.txt"), b"new".to_vec()),
+        ];
+        let sources = sources
+            .into_iter()
+            .map(|(name, content)| (name, std::io::Cursor::new(content)))
+            .collect();
+        let synced = client.sync(&batch, sources).await.unwrap();
+
+        assert_eq!(synced.session_id(), batch.session_id());
+        assert_eq!(synced.files_count(), 3);
+        assert_eq!(
+            synced.receipt().receipt().previous_root(),
+            Some(batch.merkle_root())
+        );
+        // the encrypted content of the unchanged file was not sent again
+        assert_eq!(synced.manifest()[0], batch.manifest()[0]);
+        assert_ne!(synced.manifest()[1], batch.manifest()[1]);
+        let changes = batch
+            .diff(&synced)
+            .unwrap()
+            .into_iter()
+            .map(|d| (d.name, d.change))
+            .collect::<Vec<(String, Change)>>();
+        assert_eq!(
+            changes,
+            vec![
+                (files[1].name(), Change::Modified),
+                (String::from("new.txt"), Change::Added),
+            ]
+        );
+
+        let contents = client
+            .download_all(&synced)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|file| file.content.unwrap())
+            .collect::<Vec<Vec<u8>>>();
+        assert_eq!(
+            contents,
+            vec![files[0].content(), b"{}".to_vec(), b"new".to_vec()]
+        );
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_checks_the_manifest_and_skips_batches_in_sync() {
+        // no request can be made to the client, it has no server
+        let client = Client::new("");
+        let files = parse_files();
+        let sources = || {
+            files
+                .iter()
+                .map(|f| (f.name(), std::io::Cursor::new(f.content())))
+                .collect::<Vec<_>>()
+        };
+        let manifest = files
+            .iter()
+            .map(|f| ManifestEntry {
+                name: f.name(),
+                content_hash: Hash::of(&f.content()),
+                leaf_hash: Hash::of(&f.content()),
+            })
+            .collect::<Vec<ManifestEntry>>();
+        let receipt = Receipt::new(String::from("session"), get_merkle_root(), 2).sign(&key());
+        let mut batch = Batch::new(String::from("session"), get_merkle_root(), 2, None, receipt);
+
+        batch.set_manifest(manifest.clone());
+        let synced = client.sync(&batch, sources()).await.unwrap();
+        assert_eq!(synced, batch);
+
+        let mut tampered = manifest;
+        tampered[1].leaf_hash = Hash::of(b"tampered");
+        batch.set_manifest(tampered);
+        let err = client.sync(&batch, sources()).await.unwrap_err();
+        assert!(matches!(err, Error::BatchVerification(_)));
+    }
+
+    #[tokio::test]
+    async fn servers_of_version_1_are_spoken_to_in_json() {
+        let (address, handle) = mock_server_of_version(1, 5);
+        let client = Client::new(address);
+
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+        let mut downloaded = Vec::new();
+        client.download(&batch, 0, &mut downloaded).await.unwrap();
+        assert_eq!(downloaded, parse_files()[0].content());
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_returned() {
+        let (address, handle) = mock_server(1);
+        let client = Client::new(address);
+        let err = client
+            .grant(
+                "session",
+                String::from("bob"),
+                common::protocol::Role::ReadOnly,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Server(e) if e.kind == ErrorKind::Forbidden));
+        handle.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn encrypted_files_are_verified_and_decrypted() {
+        let (address, handle) = mock_server(5);
+        let mut client = Client::new(address);
+        client.set_key_material(KeyMaterial::Passphrase(String::from("correct horse")));
+
+        let batch = client.upload_paths(&file_names()).await.unwrap();
+        // the root commits to the ciphertext, not the plaintext
+        assert_ne!(batch.merkle_root(), get_merkle_root());
+        assert!(batch.encryption().is_some());
+
+        let mut downloaded = Vec::new();
+        client.download(&batch, 1, &mut downloaded).await.unwrap();
+        assert_eq!(downloaded, parse_files()[1].content());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn encrypted_file_cannot_be_decrypted_without_key() {
+        let key_material = KeyMaterial::Passphrase(String::from("correct horse"));
+        let cipher = key_material.cipher(None).unwrap();
+        let (content, _) = seal(Some(&cipher), "file", parse_files()[0].content()).unwrap();
+        let encryption = Some(cipher.source());
+
+        let receipt = Receipt::new(String::from("session"), String::new(), 2).sign(&key());
+        let batch = Batch::new(
+            String::from("session"),
+            String::new(),
+            2,
+            encryption,
+            receipt,
+        );
+        let client = Client::new("");
+        assert!(client.decrypt(&batch, content).is_err());
+    }
+
+    #[test]
+    fn verify_receipt_works() {
+        let mut client = Client::new("");
+        let batch = |session_id: &str| {
+            let receipt = Receipt::new(String::from(session_id), get_merkle_root(), 2).sign(&key());
+            Batch::new(String::from("session"), get_merkle_root(), 2, None, receipt)
+        };
+
+        // without a trusted key, a receipt only shows it was signed with the key it holds
+        assert_eq!(
+            client.verify_receipt(&batch("session")).unwrap(),
+            ReceiptCheck::Unverified
+        );
+        assert!(client.verify_receipt(&batch("other")).is_err());
+
+        client.set_server_public_key(hex::encode(key().verifying_key().as_bytes()));
+        assert_eq!(
+            client.verify_receipt(&batch("session")).unwrap(),
+            ReceiptCheck::Trusted
+        );
+
+        client.set_server_public_key(hex::encode([0u8; 32]));
+        assert!(client.verify_receipt(&batch("session")).is_err());
+    }
+
+    #[tokio::test]
+    async fn download_of_missing_index_is_rejected() {
+        let client = Client::new("");
+        let receipt = Receipt::new(String::from("session"), get_merkle_root(), 2).sign(&key());
+        let batch = Batch::new(String::from("session"), get_merkle_root(), 2, None, receipt);
+        let err = client
+            .download(&batch, 2, &mut Vec::new())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidInput(_)));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::error::{Error, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Read;
+
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const FORMAT_VERSION: u8 = 1;
+const FILE_KEY_INFO: &[u8] = b"verifile file key v1";
+
+/// KeySource describes where the master key of an encrypted batch comes from.
+/// It is saved alongside the merkle root so the same key can be derived on download
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kdf", rename_all = "kebab-case")]
+pub enum KeySource {
+    /// the master key is derived from a passphrase with argon2id and the hex encoded salt
+    Argon2id { salt: String },
+    /// the master key is the raw 32 bytes of a key file
+    KeyFile,
+}
+
+/// KeyMaterial is the secret given by the user to encrypt or decrypt a batch
+#[derive(Debug, Clone)]
+pub enum KeyMaterial {
+    Passphrase(String),
+    KeyFile(String),
+}
+
+impl KeyMaterial {
+    /// cipher builds a FileCipher for a new batch when source is None,
+    /// or for an existing batch whose key was derived as described by source
+    pub fn cipher(&self, source: Option<&KeySource>) -> Result<FileCipher> {
+        match (self, source) {
+            (KeyMaterial::Passphrase(passphrase), None) => {
+                FileCipher::from_passphrase(passphrase, None)
+            }
+            (KeyMaterial::Passphrase(passphrase), Some(KeySource::Argon2id { salt })) => {
+                FileCipher::from_passphrase(passphrase, Some(salt))
+            }
+            (KeyMaterial::KeyFile(path), None | Some(KeySource::KeyFile)) => {
+                FileCipher::from_key_file(path)
+            }
+            (KeyMaterial::Passphrase(_), Some(KeySource::KeyFile)) => Err(Error::Crypto(
+                String::from("files were encrypted with a key file, not a passphrase"),
+            )),
+            (KeyMaterial::KeyFile(_), Some(KeySource::Argon2id { .. })) => Err(Error::Crypto(
+                String::from("files were encrypted with a passphrase, not a key file"),
+            )),
+        }
+    }
+}
+
+/// FileCipher encrypts and decrypts file contents with XChaCha20-Poly1305.
+/// Every file gets its own key derived from the master key and a random salt
+/// so that no two files are ever sealed under the same key
+pub struct FileCipher {
+    master_key: [u8; KEY_LEN],
+    source: KeySource,
+}
+
+impl FileCipher {
+    /// from_passphrase derives the master key from a passphrase. A fresh salt is
+    /// generated when none is given, i.e. when a new batch is being encrypted
+    pub fn from_passphrase(passphrase: &str, salt: Option<&str>) -> Result<Self> {
+        let salt = match salt {
+            Some(salt) => {
+                hex::decode(salt).map_err(|_| Error::Crypto(String::from("invalid key salt")))?
+            }
+            None => {
+                let mut salt = vec![0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                salt
+            }
+        };
+
+        let mut master_key = [0u8; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut master_key)
+            .map_err(|e| Error::Crypto(format!("failed to derive key from passphrase: {}", e)))?;
+
+        Ok(Self {
+            master_key,
+            source: KeySource::Argon2id {
+                salt: hex::encode(salt),
+            },
+        })
+    }
+
+    /// from_key_file reads the master key from a file holding exactly 32 bytes
+    pub fn from_key_file(path: &str) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+
+        let master_key: [u8; KEY_LEN] = buf.try_into().map_err(|_| {
+            Error::Crypto(format!("key file should contain exactly {} bytes", KEY_LEN))
+        })?;
+
+        Ok(Self {
+            master_key,
+            source: KeySource::KeyFile,
+        })
+    }
+
+    pub fn source(&self) -> KeySource {
+        self.source.clone()
+    }
+
+    /// derive_file_key derives the key of a single file from the master key and the file salt
+    fn derive_file_key(&self, salt: &[u8]) -> XChaCha20Poly1305 {
+        let mut file_key = [0u8; KEY_LEN];
+        Hkdf::<Sha256>::new(Some(salt), &self.master_key)
+            .expand(FILE_KEY_INFO, &mut file_key)
+            .expect("32 bytes is a valid hkdf output length");
+        XChaCha20Poly1305::new(&file_key.into())
+    }
+
+    /// encrypt seals the plaintext and returns `version || salt || nonce || ciphertext`
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let ciphertext = self
+            .derive_file_key(&salt)
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| Error::Crypto(String::from("file encryption failed")))?;
+
+        let mut sealed = Vec::with_capacity(1 + SALT_LEN + NONCE_LEN + ciphertext.len());
+        sealed.push(FORMAT_VERSION);
+        sealed.extend_from_slice(&salt);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// decrypt opens a buffer produced by encrypt. It fails if the key is wrong
+    /// or if the ciphertext has been tampered with
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < 1 + SALT_LEN + NONCE_LEN || sealed[0] != FORMAT_VERSION {
+            return Err(Error::Crypto(String::from(
+                "encrypted file has an invalid format",
+            )));
+        }
+
+        let (salt, rest) = sealed[1..].split_at(SALT_LEN);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+        self.derive_file_key(salt)
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                Error::Crypto(String::from("file decryption failed, the key may be wrong"))
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileCipher, KeySource};
+
+    #[test]
+    fn encrypt_and_decrypt_works() {
+        let cipher = FileCipher::from_passphrase("correct horse", None).unwrap();
+        let sealed = cipher.encrypt(b"Hello").unwrap();
+        assert_ne!(&sealed[..], b"Hello");
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn same_plaintext_encrypts_differently() {
+        let cipher = FileCipher::from_passphrase("correct horse", None).unwrap();
+        assert_ne!(
+            cipher.encrypt(b"Hello").unwrap(),
+            cipher.encrypt(b"Hello").unwrap()
+        );
+    }
+
+    #[test]
+    fn passphrase_with_same_salt_derives_same_key() {
+        let cipher = FileCipher::from_passphrase("correct horse", None).unwrap();
+        let sealed = cipher.encrypt(b"Hello").unwrap();
+
+        let KeySource::Argon2id { salt } = cipher.source() else {
+            panic!("passphrase cipher should use argon2id");
+        };
+        let cipher = FileCipher::from_passphrase("correct horse", Some(&salt)).unwrap();
+        assert_eq!(cipher.decrypt(&sealed).unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn decrypt_with_wrong_key_fails() {
+        let cipher = FileCipher::from_passphrase("correct horse", None).unwrap();
+        let sealed = cipher.encrypt(b"Hello").unwrap();
+
+        let KeySource::Argon2id { salt } = cipher.source() else {
+            panic!("passphrase cipher should use argon2id");
+        };
+        let cipher = FileCipher::from_passphrase("battery staple", Some(&salt)).unwrap();
+        assert!(cipher.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn decrypt_tampered_ciphertext_fails() {
+        let cipher = FileCipher::from_passphrase("correct horse", None).unwrap();
+        let mut sealed = cipher.encrypt(b"Hello").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(cipher.decrypt(&sealed).is_err());
+    }
+}
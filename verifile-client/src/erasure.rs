@@ -0,0 +1,276 @@
+//! erasure implements systematic Reed-Solomon erasure coding over GF(256). Content is split
+//! into k data shards and n - k parity shards are computed from them, so that the content
+//! can be rebuilt from any k of the n shards.
+//!
+//! Shard i is made of the data shards with the coefficients of row i of the generator
+//! matrix: the rows of the data shards are the identity and the rows of the parity shards
+//! form a Cauchy matrix, so any k rows of the generator can be inverted.
+
+use crate::error::{Error, Result};
+
+/// POLYNOMIAL is x^8 + x^4 + x^3 + x^2 + 1, the irreducible polynomial the field is built with
+const POLYNOMIAL: u16 = 0x11d;
+
+/// MAX_SHARDS is the number of distinct elements of the field, each shard needs its own
+pub const MAX_SHARDS: usize = 256;
+
+/// Tables holds the powers of the generator 2 of the field and their logarithms. The powers
+/// are repeated so that the sum of two logarithms can index them without a modulo
+struct Tables {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const TABLES: Tables = build_tables();
+
+const fn build_tables() -> Tables {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= POLYNOMIAL;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    Tables { exp, log }
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    TABLES.exp[TABLES.log[a as usize] as usize + TABLES.log[b as usize] as usize]
+}
+
+/// inv returns the multiplicative inverse of an element that is not zero
+fn inv(a: u8) -> u8 {
+    TABLES.exp[255 - TABLES.log[a as usize] as usize]
+}
+
+/// ReedSolomon splits content into shards and rebuilds it from any data_shards of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReedSolomon {
+    data_shards: usize,
+    total_shards: usize,
+}
+
+impl ReedSolomon {
+    /// new creates a code of total_shards shards, data_shards of which are enough to rebuild
+    /// the content
+    pub fn new(data_shards: usize, total_shards: usize) -> Result<Self> {
+        if data_shards == 0 || data_shards > total_shards || total_shards > MAX_SHARDS {
+            return Err(Error::InvalidInput(format!(
+                "{} of {} shards is not a valid erasure code, at least 1 and at most {} shards are needed",
+                data_shards, total_shards, MAX_SHARDS
+            )));
+        }
+        Ok(Self {
+            data_shards,
+            total_shards,
+        })
+    }
+
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn total_shards(&self) -> usize {
+        self.total_shards
+    }
+
+    /// row returns the coefficients of the data shards shard index is made of
+    fn row(&self, index: usize) -> Vec<u8> {
+        (0..self.data_shards)
+            .map(|column| match index < self.data_shards {
+                true => u8::from(column == index),
+                // the parity shards and the data shards are distinct elements of the field
+                false => inv(index as u8 ^ column as u8),
+            })
+            .collect()
+    }
+
+    /// encode splits the content into the shards, the data shards first. The last data shard
+    /// is padded with zeros so that every shard has the same size
+    pub fn encode(&self, content: &[u8]) -> Vec<Vec<u8>> {
+        let shard_size = content.len().div_ceil(self.data_shards);
+        let mut shards = (0..self.data_shards)
+            .map(|index| {
+                let start = (index * shard_size).min(content.len());
+                let end = (start + shard_size).min(content.len());
+                let mut shard = content[start..end].to_vec();
+                shard.resize(shard_size, 0);
+                shard
+            })
+            .collect::<Vec<Vec<u8>>>();
+
+        for index in self.data_shards..self.total_shards {
+            let mut parity = vec![0u8; shard_size];
+            for (coefficient, data) in self.row(index).into_iter().zip(&shards) {
+                for (byte, data) in parity.iter_mut().zip(data) {
+                    *byte ^= mul(coefficient, *data);
+                }
+            }
+            shards.push(parity);
+        }
+        shards
+    }
+
+    /// reconstruct rebuilds content of the size from its shards, given in index order with
+    /// None for the ones that are missing. Any data_shards of them are enough
+    pub fn reconstruct(&self, shards: &[Option<Vec<u8>>], size: usize) -> Result<Vec<u8>> {
+        if shards.len() != self.total_shards {
+            return Err(Error::Erasure(format!(
+                "{} shards were given for a code of {}",
+                shards.len(),
+                self.total_shards
+            )));
+        }
+        let available = shards
+            .iter()
+            .enumerate()
+            .filter_map(|(index, shard)| shard.as_ref().map(|shard| (index, shard)))
+            .take(self.data_shards)
+            .collect::<Vec<(usize, &Vec<u8>)>>();
+        if available.len() < self.data_shards {
+            return Err(Error::Erasure(format!(
+                "only {} of the {} shards needed are available",
+                available.len(),
+                self.data_shards
+            )));
+        }
+        let shard_size = available[0].1.len();
+        if available.iter().any(|(_, shard)| shard.len() != shard_size) {
+            return Err(Error::Erasure(String::from(
+                "shards are not of the same size",
+            )));
+        }
+        if size > shard_size * self.data_shards {
+            return Err(Error::Erasure(format!(
+                "{} bytes cannot be rebuilt from shards of {} bytes",
+                size, shard_size
+            )));
+        }
+
+        // shards are made of the data shards with the rows of the available shards, so the
+        // data shards are made of the available shards with the rows of the inverse
+        let matrix = available
+            .iter()
+            .map(|(index, _)| self.row(*index))
+            .collect::<Vec<Vec<u8>>>();
+        let inverse = invert(matrix)?;
+
+        let mut content = Vec::with_capacity(shard_size * self.data_shards);
+        for row in inverse {
+            let mut data = vec![0u8; shard_size];
+            for (coefficient, (_, shard)) in row.into_iter().zip(&available) {
+                for (byte, shard) in data.iter_mut().zip(shard.iter()) {
+                    *byte ^= mul(coefficient, *shard);
+                }
+            }
+            content.extend(data);
+        }
+        content.truncate(size);
+        Ok(content)
+    }
+}
+
+/// invert inverts a square matrix with Gauss-Jordan elimination
+fn invert(mut matrix: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let size = matrix.len();
+    let mut inverse = (0..size)
+        .map(|row| (0..size).map(|column| u8::from(row == column)).collect())
+        .collect::<Vec<Vec<u8>>>();
+
+    for column in 0..size {
+        let pivot = (column..size)
+            .find(|row| matrix[*row][column] != 0)
+            .ok_or_else(|| Error::Erasure(String::from("shards do not make up the content")))?;
+        matrix.swap(column, pivot);
+        inverse.swap(column, pivot);
+
+        let scale = inv(matrix[column][column]);
+        for value in matrix[column].iter_mut().chain(inverse[column].iter_mut()) {
+            *value = mul(*value, scale);
+        }
+        for row in 0..size {
+            let factor = matrix[row][column];
+            if row == column || factor == 0 {
+                continue;
+            }
+            for index in 0..size {
+                matrix[row][index] ^= mul(factor, matrix[column][index]);
+                inverse[row][index] ^= mul(factor, inverse[column][index]);
+            }
+        }
+    }
+    Ok(inverse)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field_inverses_multiply_to_one() {
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+        assert_eq!(mul(0, 7), 0);
+    }
+
+    #[test]
+    fn content_is_rebuilt_from_any_data_shards() {
+        let code = ReedSolomon::new(3, 5).unwrap();
+        let content = b"Lorem ipsum dolor sit amet".to_vec();
+        let shards = code.encode(&content);
+        assert_eq!(shards.len(), 5);
+        assert_eq!(shards[0], b"Lorem ips");
+
+        // every way of losing two of the five shards
+        for lost in 0..5 {
+            for also_lost in lost + 1..5 {
+                let received = shards
+                    .iter()
+                    .enumerate()
+                    .map(|(index, shard)| {
+                        (index != lost && index != also_lost).then(|| shard.clone())
+                    })
+                    .collect::<Vec<Option<Vec<u8>>>>();
+                assert_eq!(code.reconstruct(&received, content.len()).unwrap(), content);
+            }
+        }
+
+        let mut received = shards.into_iter().map(Some).collect::<Vec<_>>();
+        received[0] = None;
+        received[3] = None;
+        received[4] = None;
+        assert!(matches!(
+            code.reconstruct(&received, content.len()),
+            Err(Error::Erasure(_))
+        ));
+    }
+
+    #[test]
+    fn codes_need_shards_for_the_data() {
+        assert!(ReedSolomon::new(0, 3).is_err());
+        assert!(ReedSolomon::new(4, 3).is_err());
+        assert!(ReedSolomon::new(2, MAX_SHARDS + 1).is_err());
+
+        let code = ReedSolomon::new(2, 2).unwrap();
+        let shards = code.encode(b"");
+        assert_eq!(
+            code.reconstruct(&shards.into_iter().map(Some).collect::<Vec<_>>(), 0)
+                .unwrap(),
+            b""
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use common::protocol::ProtocolError;
+use common::verify::VerifyError;
+use thiserror::Error;
+
+/// Error is returned by every fallible operation of the client
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("connection to the server failed: {0}")]
+    Connection(String),
+
+    #[error("invalid message: {0}")]
+    Codec(String),
+
+    #[error("server rejected the request: {0}")]
+    Server(#[from] ProtocolError),
+
+    #[error("unexpected response from the server to {0}")]
+    UnexpectedResponse(&'static str),
+
+    #[error("file at index {index} failed verification: {source}")]
+    Verification {
+        index: usize,
+        #[source]
+        source: VerifyError,
+    },
+
+    #[error("batch failed verification: {0}")]
+    BatchVerification(#[source] VerifyError),
+
+    #[error("invalid receipt: {0}")]
+    Receipt(String),
+
+    #[error("encryption error: {0}")]
+    Crypto(String),
+
+    #[error("erasure coding error: {0}")]
+    Erasure(String),
+
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
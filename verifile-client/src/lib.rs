@@ -0,0 +1,27 @@
+//! verifile-client uploads files to a verifile server and downloads them back,
+//! verifying every downloaded file against the merkle root computed on upload.
+//!
+//! ```no_run
+//! # async fn run() -> verifile_client::Result<()> {
+//! let client = verifile_client::Client::new(common::SERVER_ADDRESS);
+//! let batch = client.upload_paths(&["files/cv.txt", "files/food.json"]).await?;
+//!
+//! let mut content = Vec::new();
+//! client.download(&batch, 1, &mut content).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod batch;
+mod client;
+pub mod crypto;
+pub mod erasure;
+mod error;
+pub mod sharding;
+mod transport;
+pub mod verify;
+
+pub use batch::{Batch, ManifestEntry};
+pub use client::{Client, DownloadedFile, ReceiptCheck};
+pub use error::{Error, Result};
+pub use sharding::{ShardedBatch, ShardedClient};
@@ -0,0 +1,393 @@
+//! sharding spreads each batch across several servers with erasure coding, so that no single
+//! server has to be trusted to keep the files. Every file is split into n shards, any k of
+//! which rebuild it, and shard i of every file is uploaded to server i as a batch of its own.
+//!
+//! The batch is identified by a merkle root over the hashes of every shard, in file order and
+//! then shard order. A shard is only used once it was verified against the root of the batch
+//! of its server and matched its leaf in the tree of the whole batch, so a server that is down or sends a
+//! corrupted shard only costs the shards it holds.
+
+use crate::batch::Batch;
+use crate::client::{Client, DownloadedFile};
+use crate::erasure::ReedSolomon;
+use crate::error::{Error, Result};
+use common::model::merkle::MerkleTree;
+use common::model::proof_file::Hash;
+use common::verify::check_root;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// ShardedFile is what a ShardedBatch keeps of a file: what it is rebuilt into
+/// and the hashes its shards are checked against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardedFile {
+    pub name: String,
+    /// size of the file in bytes, the shards are padded to the same size
+    pub size: usize,
+    /// hash of the content of the file
+    pub content_hash: Hash,
+    /// merkle leaves of the shards of the file in shard order, the hashes of their content
+    pub shards: Vec<Hash>,
+}
+
+/// Share is the batch of the shards uploaded to one of the servers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Share {
+    pub address: String,
+    pub batch: Batch,
+}
+
+/// ShardedBatch is what the client keeps after spreading a set of files across servers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardedBatch {
+    /// number of shards of a file that are enough to rebuild it
+    data_shards: usize,
+    /// root of the merkle tree over the shards of every file
+    merkle_root: String,
+    files: Vec<ShardedFile>,
+    /// the batches of the servers in shard order
+    shares: Vec<Share>,
+}
+
+impl ShardedBatch {
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    pub fn merkle_root(&self) -> &str {
+        &self.merkle_root
+    }
+
+    pub fn files(&self) -> &[ShardedFile] {
+        &self.files
+    }
+
+    pub fn shares(&self) -> &[Share] {
+        &self.shares
+    }
+
+    /// shards_root computes the root of the merkle tree over the shards of the files
+    fn shards_root(files: &[ShardedFile]) -> String {
+        let leaves = files
+            .iter()
+            .flat_map(|file| file.shards.iter().map(|shard| *shard.as_bytes()))
+            .collect();
+        MerkleTree::from_leaf_hashes(leaves).root_hash()
+    }
+
+    /// verify checks that the shard hashes make up the merkle root of the batch
+    /// and that every file has a shard per server
+    fn verify(&self) -> Result<()> {
+        if let Some(file) = self
+            .files
+            .iter()
+            .find(|file| file.shards.len() != self.shares.len())
+        {
+            return Err(Error::InvalidInput(format!(
+                "{} has {} shards for {} servers",
+                file.name,
+                file.shards.len(),
+                self.shares.len()
+            )));
+        }
+        let root = Self::shards_root(&self.files);
+        check_root(&self.merkle_root, &root).map_err(Error::BatchVerification)
+    }
+}
+
+/// ShardedClient spreads batches across servers, one client per server
+pub struct ShardedClient {
+    servers: Vec<Client>,
+    code: ReedSolomon,
+}
+
+impl ShardedClient {
+    /// new creates a client spreading each file across the servers, any data_shards of which
+    /// are enough to download it back
+    pub fn new(servers: Vec<Client>, data_shards: usize) -> Result<Self> {
+        let code = ReedSolomon::new(data_shards, servers.len())?;
+        Ok(Self { servers, code })
+    }
+
+    /// check_shares checks that the batch was spread across the servers of the client
+    fn check_shares(&self, batch: &ShardedBatch) -> Result<()> {
+        let addresses = batch.shares.iter().map(|share| share.address.as_str());
+        if batch.data_shards != self.code.data_shards()
+            || !addresses.eq(self.servers.iter().map(Client::address))
+        {
+            return Err(Error::InvalidInput(format!(
+                "the batch was spread {} of {} across other servers",
+                batch.data_shards,
+                batch.shares.len()
+            )));
+        }
+        batch.verify()
+    }
+
+    /// upload splits the content read from each named source into shards and uploads
+    /// shard i of every file to server i. Every server must take its shards. The files
+    /// are read one at a time and their shards sent before the next one is read
+    pub async fn upload<R: AsyncRead + Unpin>(
+        &self,
+        sources: Vec<(String, R)>,
+    ) -> Result<ShardedBatch> {
+        if sources.is_empty() {
+            return Err(Error::InvalidInput(String::from(
+                "at least one file should be uploaded",
+            )));
+        }
+
+        let mut uploads = Vec::with_capacity(self.servers.len());
+        for server in &self.servers {
+            uploads.push(server.start_upload().await?);
+        }
+        let mut sharded_files = Vec::with_capacity(sources.len());
+        for (name, mut reader) in sources {
+            let mut content = Vec::new();
+            reader.read_to_end(&mut content).await?;
+            let shards = self.code.encode(&content);
+            sharded_files.push(ShardedFile {
+                name: name.clone(),
+                size: content.len(),
+                content_hash: Hash::of(&content),
+                shards: shards.iter().map(|shard| Hash::of(shard)).collect(),
+            });
+            for (upload, shard) in uploads.iter_mut().zip(shards) {
+                upload.put(name.clone(), shard).await?;
+            }
+        }
+
+        let mut shares = Vec::with_capacity(self.servers.len());
+        for (server, upload) in self.servers.iter().zip(uploads) {
+            shares.push(Share {
+                address: server.address().to_string(),
+                batch: upload.complete().await?,
+            });
+        }
+
+        let merkle_root = ShardedBatch::shards_root(&sharded_files);
+        info!(
+            "Files spread {} of {} across servers with merkle root {}",
+            self.code.data_shards(),
+            self.code.total_shards(),
+            merkle_root
+        );
+        Ok(ShardedBatch {
+            data_shards: self.code.data_shards(),
+            merkle_root,
+            files: sharded_files,
+            shares,
+        })
+    }
+
+    /// upload_paths spreads the files at the paths, named by their path
+    pub async fn upload_paths<P: AsRef<Path>>(&self, paths: &[P]) -> Result<ShardedBatch> {
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in paths {
+            let path = path.as_ref();
+            let file = tokio::fs::File::open(path).await?;
+            sources.push((path.to_string_lossy().into_owned(), file));
+        }
+        self.upload(sources).await
+    }
+
+    /// rebuild rebuilds a file from the shards that were received, in shard order. A shard
+    /// that does not match its hash is left out like a missing one
+    fn rebuild(&self, file: &ShardedFile, received: Vec<Option<Vec<u8>>>) -> Result<Vec<u8>> {
+        let shards = received
+            .into_iter()
+            .zip(&file.shards)
+            .enumerate()
+            .map(|(server, (shard, hash))| {
+                let shard = shard?;
+                if Hash::of(&shard) != *hash {
+                    warn!(
+                        "Shard {} of {} does not match its hash, leaving it out",
+                        server, file.name
+                    );
+                    return None;
+                }
+                Some(shard)
+            })
+            .collect::<Vec<Option<Vec<u8>>>>();
+
+        let content = self.code.reconstruct(&shards, file.size)?;
+        if Hash::of(&content) != file.content_hash {
+            return Err(Error::Erasure(format!(
+                "{} was rebuilt into other content than was uploaded",
+                file.name
+            )));
+        }
+        Ok(content)
+    }
+
+    /// download fetches the shards of the file at the index from the servers in turn until
+    /// enough of them were verified, rebuilds the file and writes it to the writer. Nothing
+    /// is written if the file cannot be rebuilt. The name the file was uploaded with is returned
+    pub async fn download<W: AsyncWrite + Unpin>(
+        &self,
+        batch: &ShardedBatch,
+        index: usize,
+        writer: &mut W,
+    ) -> Result<String> {
+        self.check_shares(batch)?;
+        let file = batch.files.get(index).ok_or_else(|| {
+            Error::InvalidInput(format!(
+                "file index {} is not in the batch of {} files",
+                index,
+                batch.files.len()
+            ))
+        })?;
+
+        let mut received = vec![None; self.servers.len()];
+        let mut verified = 0;
+        for (server, (client, share)) in self.servers.iter().zip(&batch.shares).enumerate() {
+            if verified == self.code.data_shards() {
+                break;
+            }
+            let mut shard = Vec::new();
+            match client.download(&share.batch, index, &mut shard).await {
+                Ok(_) if Hash::of(&shard) == file.shards[server] => {
+                    received[server] = Some(shard);
+                    verified += 1;
+                }
+                Ok(_) => warn!("Shard {} of {} does not match its hash", server, file.name),
+                Err(e) => warn!(
+                    "Failed to get shard {} of {} from {}: {}",
+                    server, file.name, share.address, e
+                ),
+            }
+        }
+
+        let content = self.rebuild(file, received)?;
+        writer.write_all(&content).await?;
+        writer.flush().await?;
+        Ok(file.name.clone())
+    }
+
+    /// download_all fetches the shards of every file from every server and rebuilds the files.
+    /// A server that cannot be reached or sends a shard that fails verification only loses
+    /// its shards. Files that cannot be rebuilt carry their error, the others their content
+    pub async fn download_all(&self, batch: &ShardedBatch) -> Result<Vec<DownloadedFile>> {
+        self.check_shares(batch)?;
+
+        let mut received = vec![vec![None; self.servers.len()]; batch.files.len()];
+        for (server, (client, share)) in self.servers.iter().zip(&batch.shares).enumerate() {
+            let downloaded = match client.download_all(&share.batch).await {
+                Ok(downloaded) => downloaded,
+                Err(e) => {
+                    warn!("Failed to get the shards held by {}: {}", share.address, e);
+                    continue;
+                }
+            };
+            for (shards, shard) in received.iter_mut().zip(downloaded) {
+                match shard.content {
+                    Ok(content) => shards[server] = Some(content),
+                    Err(e) => warn!(
+                        "Shard {} of file {} from {} failed: {}",
+                        server, shard.index, share.address, e
+                    ),
+                }
+            }
+        }
+
+        Ok(batch
+            .files
+            .iter()
+            .zip(received)
+            .enumerate()
+            .map(|(index, (file, shards))| DownloadedFile {
+                index,
+                name: file.name.clone(),
+                content: self.rebuild(file, shards),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::client::test::{mock_server, tampering_mock_server};
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    fn sources() -> Vec<(String, Cursor<Vec<u8>>)> {
+        vec![
+            (
+                String::from("a.txt"),
+                Cursor::new(b"Lorem ipsum dolor sit amet".to_vec()),
+            ),
+            (String::from("b.txt"), Cursor::new(b"Hello".to_vec())),
+        ]
+    }
+
+    #[tokio::test]
+    async fn files_are_rebuilt_from_any_verified_shards() {
+        // the third server goes away after the upload,
+        // the second one corrupts the shards of the second file
+        let (first, first_handle) = mock_server(6);
+        let (second, second_handle) = tampering_mock_server(6, 1);
+        let (third, third_handle) = mock_server(4);
+        let (fourth, fourth_handle) = mock_server(6);
+        let servers = [first, second, third, fourth]
+            .into_iter()
+            .map(Client::new)
+            .collect();
+        let client = ShardedClient::new(servers, 2).unwrap();
+
+        let batch = client.upload(sources()).await.unwrap();
+        assert_eq!(batch.shares().len(), 4);
+        assert!(batch.files().iter().all(|file| file.shards.len() == 4));
+        assert_eq!(
+            batch.merkle_root(),
+            ShardedBatch::shards_root(batch.files())
+        );
+        third_handle.join().unwrap();
+
+        let files = client.download_all(&batch).await.unwrap();
+        for (file, (name, source)) in files.into_iter().zip(sources()) {
+            assert_eq!(file.name, name);
+            assert_eq!(file.content.unwrap(), source.into_inner());
+        }
+
+        // the first shard verifies, the next two fail and the last one completes the file
+        let mut content = Vec::new();
+        let name = client.download(&batch, 1, &mut content).await.unwrap();
+        assert_eq!(name, "b.txt");
+        assert_eq!(content, b"Hello");
+
+        for handle in [first_handle, second_handle, fourth_handle] {
+            handle.join().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn files_without_enough_shards_are_lost() {
+        let (first, first_handle) = mock_server(5);
+        let (second, second_handle) = mock_server(4);
+        let servers = vec![Client::new(first), Client::new(second)];
+        let client = ShardedClient::new(servers, 2).unwrap();
+
+        let batch = client.upload(sources()).await.unwrap();
+        second_handle.join().unwrap();
+        let files = client.download_all(&batch).await.unwrap();
+        assert!(files
+            .iter()
+            .all(|file| matches!(file.content, Err(Error::Erasure(_)))));
+        first_handle.join().unwrap();
+
+        // a batch is only downloaded from the servers it was spread across
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other = Client::new(listener.local_addr().unwrap().to_string());
+        let client = ShardedClient::new(vec![other, Client::new("127.0.0.1:1")], 2).unwrap();
+        let mut content = Vec::new();
+        assert!(matches!(
+            client.download(&batch, 0, &mut content).await,
+            Err(Error::InvalidInput(_))
+        ));
+        assert!(content.is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::{Error, Result};
+use common::handshake::{Agreement, Hello};
+use common::protocol::{
+    decode_message_body, decode_message_len, encode_message, Encoding, Envelope, ErrorKind, Format,
+    Request, Response, DEFAULT_MAX_MESSAGE_SIZE,
+};
+use common::transport::ClientTls;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+/// Connection is a connection to the server, either over plain TCP or over TLS.
+/// The server serves a single request per connection
+pub struct Connection {
+    stream: Box<dyn AsyncStream>,
+}
+
+impl Connection {
+    /// open connects to the server at the address, completing the TLS handshake first if tls is given
+    pub async fn open(address: &str, tls: Option<&ClientTls>) -> Result<Self> {
+        let tcp = TcpStream::connect(address)
+            .await
+            .map_err(|e| Error::Connection(format!("failed to connect to {}: {}", address, e)))?;
+
+        let Some(tls) = tls else {
+            return Ok(Self {
+                stream: Box::new(tcp),
+            });
+        };
+
+        let stream = TlsConnector::from(tls.config())
+            .connect(tls.server_name(), tcp)
+            .await
+            .map_err(|e| {
+                Error::Connection(format!("tls handshake with {} failed: {}", address, e))
+            })?;
+        Ok(Self {
+            stream: Box::new(stream),
+        })
+    }
+
+    /// handshake sends a hello asking for the format and returns what the server agreed
+    /// to. Servers of protocol version 1 reject the hello and close the connection,
+    /// None is returned for them and a new connection must be opened
+    pub async fn handshake(&mut self, format: Format) -> Result<Option<Agreement>> {
+        let hello = Envelope {
+            token: None,
+            request: Request::Hello(Hello::new(format)),
+        };
+        match self.exchange(&hello, Encoding::Json.into()).await? {
+            Response::Hello(agreement) => Ok(Some(agreement)),
+            Response::Error(e) if e.kind == ErrorKind::BadRequest => Ok(None),
+            Response::Error(e) => Err(Error::Server(e)),
+            _ => Err(Error::UnexpectedResponse("hello")),
+        }
+    }
+
+    /// request sends the envelope in the format and reads the response of the server
+    pub async fn request(mut self, envelope: &Envelope, format: Format) -> Result<Response> {
+        self.exchange(envelope, format).await
+    }
+
+    async fn exchange(&mut self, envelope: &Envelope, format: Format) -> Result<Response> {
+        self.send(envelope, format).await?;
+        self.receive().await
+    }
+
+    /// send sends the envelope in the format without waiting for a response
+    pub async fn send(&mut self, envelope: &Envelope, format: Format) -> Result<()> {
+        let message = encode_message(envelope, format).map_err(Error::Codec)?;
+        self.stream.write_all(&message).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// receive reads the next response of the server
+    pub async fn receive(&mut self) -> Result<Response> {
+        let mut len_buf = [0u8; 8];
+        self.stream.read_exact(&mut len_buf).await?;
+        let (format, len) =
+            decode_message_len(len_buf, DEFAULT_MAX_MESSAGE_SIZE).map_err(Error::Codec)?;
+        // the body grows with the bytes received instead of trusting the length prefix
+        let mut body = Vec::new();
+        (&mut self.stream).take(len).read_to_end(&mut body).await?;
+        if (body.len() as u64) < len {
+            return Err(Error::Codec(format!(
+                "message was cut short after {} of {} bytes",
+                body.len(),
+                len
+            )));
+        }
+        decode_message_body(&body, format, DEFAULT_MAX_MESSAGE_SIZE).map_err(Error::Codec)
+    }
+}
@@ -0,0 +1,140 @@
+use crate::error::{Error, Result};
+use common::model::merkle::MerkleProof;
+use common::model::merkle::NodeHash;
+use common::verify::{compute_root, verify_leaf, VerifyError};
+
+/// compute_root_from_proof computes the root of the merkle tree by walking up
+/// from the file in the proof through its siblings, after checking that they
+/// are on the path of the file
+pub fn compute_root_from_proof(proof: &MerkleProof, index: usize) -> Result<String> {
+    proof
+        .checked_path(index)
+        .and_then(|path| compute_root(&proof.file_content(), index, proof.tree_size(), &path))
+        .map_err(|source| Error::Verification { index, source })
+}
+
+/// verify_proof checks that the file in the proof is the leaf at the index
+/// of the merkle tree of tree_size leaves with the given root
+pub fn verify_proof(
+    proof: &MerkleProof,
+    index: usize,
+    tree_size: usize,
+    merkle_root: &str,
+) -> Result<()> {
+    verify_proof_leaf(proof, &proof.leaf_hash(), index, tree_size, merkle_root)
+}
+
+/// verify_proof_leaf is verify_proof for a proof whose file is already hashed into leaf
+pub fn verify_proof_leaf(
+    proof: &MerkleProof,
+    leaf: &NodeHash,
+    index: usize,
+    tree_size: usize,
+    merkle_root: &str,
+) -> Result<()> {
+    if proof.tree_size() != tree_size {
+        return Err(Error::Verification {
+            index,
+            source: VerifyError::TreeSizeMismatch {
+                expected: tree_size,
+                actual: proof.tree_size(),
+            },
+        });
+    }
+
+    proof
+        .checked_path(index)
+        .and_then(|path| verify_leaf(merkle_root, leaf, index, tree_size, &path))
+        .map_err(|source| Error::Verification { index, source })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compute_root_from_proof, verify_proof};
+    use crate::error::Error;
+    use common::model::merkle::MerkleProof;
+    use common::verify::VerifyError;
+    use sha256::digest;
+
+    fn files() -> Vec<Vec<u8>> {
+        vec![
+            std::fs::read("../files/cv.txt").unwrap(),
+            std::fs::read("../files/food.json").unwrap(),
+        ]
+    }
+
+    fn merkle_root() -> String {
+        let files = files();
+        digest(format!("{}{}", digest(&files[0]), digest(&files[1])))
+    }
+
+    #[test]
+    fn mock_server_has_correct_files() {
+        let files = files();
+        let mp = MerkleProof::new(
+            String::from("cv.txt"),
+            files[0].clone(),
+            2,
+            vec![(1, 1, digest(&files[1]))],
+        );
+        assert_eq!(compute_root_from_proof(&mp, 0).unwrap(), merkle_root());
+        verify_proof(&mp, 0, 2, &merkle_root()).unwrap();
+    }
+
+    #[test]
+    fn mock_server_does_not_have_correct_files() {
+        let files = files();
+
+        let mut altered_content = files[0].clone();
+        altered_content[0] = 32u8;
+
+        let mp = MerkleProof::new(
+            String::from("cv.txt"),
+            altered_content.clone(),
+            2,
+            vec![(1, 1, digest(&files[1]))],
+        );
+        let hashed = compute_root_from_proof(&mp, 0).unwrap();
+        assert_ne!(hashed, merkle_root());
+        assert_eq!(
+            hashed,
+            digest(format!("{}{}", digest(altered_content), digest(&files[1])))
+        );
+        assert!(matches!(
+            verify_proof(&mp, 0, 2, &merkle_root()),
+            Err(Error::Verification { index: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn proofs_for_other_positions_or_sizes_are_rejected() {
+        let files = files();
+        let mp = MerkleProof::new(
+            String::from("cv.txt"),
+            files[0].clone(),
+            2,
+            vec![(1, 0, digest(&files[1]))],
+        );
+        assert!(matches!(
+            verify_proof(&mp, 0, 2, &merkle_root()),
+            Err(Error::Verification {
+                source: VerifyError::WrongSiblingPosition { .. },
+                ..
+            })
+        ));
+
+        let mp = MerkleProof::new(
+            String::from("cv.txt"),
+            files[0].clone(),
+            2,
+            vec![(1, 1, digest(&files[1]))],
+        );
+        assert!(matches!(
+            verify_proof(&mp, 0, 3, &merkle_root()),
+            Err(Error::Verification {
+                source: VerifyError::TreeSizeMismatch { .. },
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,404 @@
+//! verifile-verify checks a file against the merkle root of the batch it was uploaded in.
+//!
+//! It only needs the file, its index and the sibling hashes on its path to the root,
+//! so a proof can be checked offline without a client session or a connection to
+//! the server. The crate is `no_std` and only needs an allocator and sha2.
+
+#![no_std]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+/// Hash is a SHA-256 hash of the tree
+pub type Hash = [u8; 32];
+
+/// Side is the side of its parent a sibling hash sits on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// PathNode is the expected position of a sibling on the path from a leaf to the root.
+/// Levels are counted from the root, which is at level 0, so the leaves are at the
+/// height of the tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathNode {
+    pub level: usize,
+    /// index of the sibling among the nodes of its level
+    pub index: usize,
+    /// side of the parent the sibling sits on
+    pub side: Side,
+}
+
+/// VerifyError is returned when a file cannot be verified against a merkle root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// the root is not a hex encoded SHA-256 hash
+    MalformedRoot,
+    /// a sibling hash of the proof is not a hex encoded SHA-256 hash.
+    /// The depth is counted from the leaf
+    MalformedSibling { depth: usize },
+    /// the index is not the index of a leaf of a tree of tree_size leaves
+    IndexOutOfRange { index: usize, tree_size: usize },
+    /// the proof does not have one sibling per level of the tree
+    WrongProofLength { expected: usize, actual: usize },
+    /// a sibling of the proof is not where the path of the leaf goes through
+    WrongSiblingPosition {
+        depth: usize,
+        expected: (usize, usize),
+        actual: (usize, usize),
+    },
+    /// a sibling of the proof sits on the wrong side of its parent
+    WrongSiblingSide { depth: usize },
+    /// the proof is for a tree of another size than expected
+    TreeSizeMismatch { expected: usize, actual: usize },
+    /// the root computed from the file and the proof is not the expected root
+    RootMismatch { expected: Hash, actual: Hash },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::MalformedRoot => write!(f, "merkle root is not a sha256 hex hash"),
+            VerifyError::MalformedSibling { depth } => {
+                write!(f, "sibling at depth {} is not a sha256 hex hash", depth)
+            }
+            VerifyError::IndexOutOfRange { index, tree_size } => write!(
+                f,
+                "index {} is out of range for a tree of {} leaves",
+                index, tree_size
+            ),
+            VerifyError::WrongProofLength { expected, actual } => {
+                write!(f, "proof has {} siblings, expected {}", actual, expected)
+            }
+            VerifyError::WrongSiblingPosition {
+                depth,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "sibling at depth {} is at {:?}, expected {:?}",
+                depth, actual, expected
+            ),
+            VerifyError::WrongSiblingSide { depth } => {
+                write!(f, "sibling at depth {} is on the wrong side", depth)
+            }
+            VerifyError::TreeSizeMismatch { expected, actual } => write!(
+                f,
+                "proof is for a tree of {} leaves, expected {}",
+                actual, expected
+            ),
+            VerifyError::RootMismatch { expected, actual } => {
+                write!(f, "computed merkle root ")?;
+                write_hex(f, actual)?;
+                write!(f, " does not match expected root ")?;
+                write_hex(f, expected)
+            }
+        }
+    }
+}
+
+impl core::error::Error for VerifyError {}
+
+/// write_hex writes a hash in lowercase hex, like the tree encodes it
+fn write_hex(f: &mut fmt::Formatter<'_>, hash: &Hash) -> fmt::Result {
+    hash.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+}
+
+/// HEX_DIGITS are the digits the hashes of the tree are encoded with
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// is_hash checks that a string is a hex encoded SHA-256 hash
+fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// decode_hash decodes a hex encoded SHA-256 hash
+fn decode_hash(hash: &str) -> Option<Hash> {
+    if !is_hash(hash) {
+        return None;
+    }
+    let mut decoded = [0u8; 32];
+    for (byte, pair) in decoded.iter_mut().zip(hash.as_bytes().chunks(2)) {
+        let digits = core::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+    Some(decoded)
+}
+
+/// to_hex encodes a hash in lowercase hex, like the tree does
+fn to_hex(hash: &[u8]) -> String {
+    let mut hex = String::with_capacity(hash.len() * 2);
+    for byte in hash {
+        hex.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+        hex.push(HEX_DIGITS[(byte & 0xf) as usize] as char);
+    }
+    hex
+}
+
+/// hash_pair hashes two nodes into their parent. The tree hashes the concatenation
+/// of the hex encoding of the children, left first
+fn hash_pair(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(left.to_ascii_lowercase().as_bytes());
+    hasher.update(right.to_ascii_lowercase().as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+/// expected_path computes where the siblings on the path from the leaf at the index
+/// to the root of a tree of tree_size leaves are, from the leaf level up. The last node
+/// of a level with an odd number of nodes is paired with itself, so its sibling is at
+/// its own position, on the right
+pub fn expected_path(index: usize, tree_size: usize) -> Result<Vec<PathNode>, VerifyError> {
+    if index >= tree_size {
+        return Err(VerifyError::IndexOutOfRange { index, tree_size });
+    }
+
+    let mut height = 0;
+    while (1usize << height) < tree_size {
+        height += 1;
+    }
+
+    let mut path = Vec::with_capacity(height);
+    let mut node_index = index;
+    let mut width = tree_size;
+    for level in (1..=height).rev() {
+        let (sibling_index, side) = if !node_index.is_multiple_of(2) {
+            (node_index - 1, Side::Left)
+        } else if node_index + 1 < width {
+            (node_index + 1, Side::Right)
+        } else {
+            (node_index, Side::Right)
+        };
+        path.push(PathNode {
+            level,
+            index: sibling_index,
+            side,
+        });
+        node_index /= 2;
+        width = width.div_ceil(2);
+    }
+    Ok(path)
+}
+
+/// walk_path walks up the tree from the hex encoded hash of a leaf to the root
+fn walk_path<S: AsRef<str>>(leaf: String, path: &[(Side, S)]) -> Result<String, VerifyError> {
+    let mut curr_hash = leaf;
+    for (depth, (side, sibling)) in path.iter().enumerate() {
+        let sibling = sibling.as_ref();
+        if !is_hash(sibling) {
+            return Err(VerifyError::MalformedSibling { depth });
+        }
+        curr_hash = match side {
+            Side::Right => hash_pair(&curr_hash, sibling),
+            Side::Left => hash_pair(sibling, &curr_hash),
+        };
+    }
+    Ok(curr_hash)
+}
+
+/// compute_root_from_path walks up the tree from the leaf to the root. path holds the
+/// sibling hashes from the leaf level up to the root, with the side each one sits on
+pub fn compute_root_from_path<S: AsRef<str>>(
+    leaf_data: &[u8],
+    path: &[(Side, S)],
+) -> Result<String, VerifyError> {
+    walk_path(to_hex(&Sha256::digest(leaf_data)), path)
+}
+
+/// compute_root walks up the tree of tree_size leaves from the leaf at the index to the
+/// root. proof holds the sibling hashes on the path, from the leaf level up to the root,
+/// and must have exactly one sibling per level
+pub fn compute_root<S: AsRef<str>>(
+    leaf_data: &[u8],
+    index: usize,
+    tree_size: usize,
+    proof: &[S],
+) -> Result<String, VerifyError> {
+    compute_root_from_leaf(&Sha256::digest(leaf_data).into(), index, tree_size, proof)
+}
+
+/// compute_root_from_leaf is compute_root for a leaf whose data is already hashed
+pub fn compute_root_from_leaf<S: AsRef<str>>(
+    leaf: &Hash,
+    index: usize,
+    tree_size: usize,
+    proof: &[S],
+) -> Result<String, VerifyError> {
+    let expected = expected_path(index, tree_size)?;
+    if proof.len() != expected.len() {
+        return Err(VerifyError::WrongProofLength {
+            expected: expected.len(),
+            actual: proof.len(),
+        });
+    }
+
+    let path = expected
+        .iter()
+        .zip(proof)
+        .map(|(node, sibling)| (node.side, sibling.as_ref()))
+        .collect::<Vec<(Side, &str)>>();
+    walk_path(to_hex(leaf), &path)
+}
+
+/// check_positions checks that the (level, index) positions a proof gives its siblings,
+/// from the leaf level up, are the ones on the path of the leaf at the index
+pub fn check_positions(
+    index: usize,
+    tree_size: usize,
+    positions: &[(usize, usize)],
+) -> Result<(), VerifyError> {
+    let expected = expected_path(index, tree_size)?;
+    if positions.len() != expected.len() {
+        return Err(VerifyError::WrongProofLength {
+            expected: expected.len(),
+            actual: positions.len(),
+        });
+    }
+
+    for (depth, (node, &actual)) in expected.iter().zip(positions).enumerate() {
+        if (node.level, node.index) != actual {
+            return Err(VerifyError::WrongSiblingPosition {
+                depth,
+                expected: (node.level, node.index),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// check_sides checks that the sides a proof gives its siblings, from the leaf level up,
+/// are the ones on the path of the leaf at the index
+pub fn check_sides(index: usize, tree_size: usize, sides: &[Side]) -> Result<(), VerifyError> {
+    let expected = expected_path(index, tree_size)?;
+    if sides.len() != expected.len() {
+        return Err(VerifyError::WrongProofLength {
+            expected: expected.len(),
+            actual: sides.len(),
+        });
+    }
+
+    match expected
+        .iter()
+        .zip(sides)
+        .position(|(node, side)| node.side != *side)
+    {
+        Some(depth) => Err(VerifyError::WrongSiblingSide { depth }),
+        None => Ok(()),
+    }
+}
+
+/// check_root compares a computed hex encoded root against the expected one, ignoring
+/// the case of the hex digits
+pub fn check_root(root: &str, actual: &str) -> Result<(), VerifyError> {
+    let expected = decode_hash(root).ok_or(VerifyError::MalformedRoot)?;
+    let actual = decode_hash(actual).ok_or(VerifyError::MalformedRoot)?;
+    if expected != actual {
+        return Err(VerifyError::RootMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// verify_path checks that leaf_data is a leaf of the merkle tree with the hex encoded
+/// root, given the sibling hashes on its path and the side each one sits on. It does not
+/// know where the leaf is, check_sides should be used to check the path first
+pub fn verify_path<S: AsRef<str>>(
+    root: &str,
+    leaf_data: &[u8],
+    path: &[(Side, S)],
+) -> Result<(), VerifyError> {
+    check_root(root, &compute_root_from_path(leaf_data, path)?)
+}
+
+/// verify checks that leaf_data is the leaf at the index of the merkle tree of tree_size
+/// leaves with the hex encoded root. proof holds the sibling hashes from the leaf level
+/// up to the root, the side of each one follows from the index and the size of the tree
+pub fn verify<S: AsRef<str>>(
+    root: &str,
+    leaf_data: &[u8],
+    index: usize,
+    tree_size: usize,
+    proof: &[S],
+) -> Result<(), VerifyError> {
+    check_root(root, &compute_root(leaf_data, index, tree_size, proof)?)
+}
+
+/// verify_leaf is verify for a leaf whose data is already hashed
+pub fn verify_leaf<S: AsRef<str>>(
+    root: &str,
+    leaf: &Hash,
+    index: usize,
+    tree_size: usize,
+    proof: &[S],
+) -> Result<(), VerifyError> {
+    check_root(
+        root,
+        &compute_root_from_leaf(leaf, index, tree_size, proof)?,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_root, expected_path, verify, verify_leaf, Side, VerifyError};
+    use alloc::string::ToString;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use sha2::Digest;
+
+    const ROOT: &str = "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969";
+
+    #[test]
+    fn expected_path_pairs_the_last_odd_node_with_itself() {
+        let path = expected_path(4, 5).unwrap();
+        let positions = path
+            .iter()
+            .map(|node| (node.level, node.index, node.side))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            vec![(3, 4, Side::Right), (2, 2, Side::Right), (1, 0, Side::Left)]
+        );
+        assert!(expected_path(0, 1).unwrap().is_empty());
+        assert!(expected_path(5, 5).is_err());
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        // sha256 of "Hello"
+        let proof: [&str; 0] = [];
+        verify(ROOT, b"Hello", 0, 1, &proof).unwrap();
+        verify(&ROOT.to_uppercase(), b"Hello", 0, 1, &proof).unwrap();
+        let leaf: [u8; 32] = sha2::Sha256::digest(b"Hello").into();
+        verify_leaf(ROOT, &leaf, 0, 1, &proof).unwrap();
+        assert!(matches!(
+            verify(ROOT, b"Hello!", 0, 1, &proof),
+            Err(VerifyError::RootMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn root_mismatch_is_shown_in_hex() {
+        let other = "00".repeat(32);
+        let error = check_root(ROOT, &other).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            alloc::format!(
+                "computed merkle root {} does not match expected root {}",
+                other,
+                ROOT
+            )
+        );
+        assert_eq!(check_root("zz", ROOT), Err(VerifyError::MalformedRoot));
+    }
+}